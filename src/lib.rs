@@ -40,6 +40,8 @@
 //!     .with_connection_string(None)
 //!     // Sets the service namespace and name.  Default is empty.
 //!     .with_service_config("namespace", "name")
+//!     // Applies a preset of defaults for the environment, which explicit settings override.  Default is no profile.
+//!     .with_profile(axum_insights::Profile::Production)
 //!     // Sets the HTTP client to use for sending telemetry.  Default is reqwest async client.
 //!     .with_client(reqwest::Client::new())
 //!     // Sets whether or not live metrics are collected.  Default is false.
@@ -54,8 +56,18 @@
 //!     .with_runtime(opentelemetry_sdk::runtime::Tokio)
 //!     // Sets whether or not to catch panics, and emit a trace for them.  Default is false.
 //!     .with_catch_panic(true)
+//!     // Sets whether or not to log to the console as well, in the format of the profile.  Default is false.
+//!     .with_console(false)
 //!     // Sets whether or not to make this telemetry layer a noop.  Default is false.
 //!     .with_noop(true)
+//!     // Sets whether or not to finalize the request span when a connection is upgraded.  Default is false.
+//!     .with_close_on_upgrade(true)
+//!     // Sets the interval at which long-lived responses emit heartbeat events.  Default is no heartbeats.
+//!     .with_connection_heartbeat(std::time::Duration::from_secs(60))
+//!     // Sets whether or not to record a summary of multipart uploads.  Default is false.
+//!     .with_multipart_attributes(true)
+//!     // Sets whether or not to record compression related attributes.  Default is false.
+//!     .with_compression_attributes(true)
 //!     // Sets a function to extract extra fields from the request.  Default is no extra fields.
 //!     .with_field_mapper(|parts| {
 //!         let mut map = HashMap::new();
@@ -66,10 +78,26 @@
 //!     .with_panic_mapper(|panic| {
 //!         (500, WebError { message: panic })
 //!     })
+//!     // Sets the request headers captured for `with_contextual_panic_mapper`, which also receives the request method and route.  Default is none.
+//!     .with_panic_context_headers(["x-request-id"])
+//!     // Sets a function to make a sampling decision for each request.  Default is to defer to the trace config sampler.
+//!     .with_request_sampler(|parts| {
+//!         if parts.uri.path() == "/health" { axum_insights::SamplingDecision::Drop } else { axum_insights::SamplingDecision::Default }
+//!     })
+//!     // Sets a header that forces full capture (sampling, bodies, and DEBUG events) of a single request.  Default is none.
+//!     .with_debug_trace_header("x-debug-trace", Some("s3cr3t"))
+//!     // Sets whether or not to record request metrics (duration, count, and failures) as custom metrics.  Default is false.
+//!     .with_metrics(true)
 //!     // Sets a function to determine the success-iness of a status.  Default is (100 - 399 => true).
 //!     .with_success_filter(|status| {
 //!         status.is_success() || status.is_redirection() || status.is_informational() || status == http::StatusCode::NOT_FOUND
 //!     })
+//!     // Sets static dimensions that are attached to all telemetry.  Default is no dimensions.
+//!     .with_global_dimensions(HashMap::from([("cluster".to_owned(), "west-1".to_owned())]))
+//!     // Sets the service version.  Default is the trace config version, then the build information version (use `service_version!`).
+//!     .with_service_version(axum_insights::service_version!())
+//!     // Sets the build information that is attached to all telemetry (the git SHA and timestamp come from `GIT_SHA` and `BUILD_TIMESTAMP` at compile time).  Default is none.
+//!     .with_build_info(axum_insights::build_info!())
 //!     // Sets the common error type for the application, and will automatically extract information from handlers that return that error.
 //!     .with_error_type::<WebError>()
 //!     .build_and_set_global_default()
@@ -123,25 +151,30 @@
 
 use std::{
     backtrace::Backtrace,
-    collections::HashMap,
+    collections::{HashMap, VecDeque},
     error::Error,
+    future::Future,
     panic::{self, AssertUnwindSafe},
-    sync::Arc,
+    pin::Pin,
+    sync::{atomic::{AtomicBool, AtomicU64, Ordering}, Arc, Mutex, RwLock},
     task::{Context, Poll},
+    time::{Duration, Instant, SystemTime},
 };
 
-use axum::{extract::MatchedPath, response::Response, RequestPartsExt, body::Body};
+use axum::{extract::MatchedPath, response::{IntoResponse, Response}, body::{Body, Bytes}};
 use futures::{future::BoxFuture, FutureExt};
+use http_body::{Frame, SizeHint};
 use http::StatusCode;
 use http_body_util::BodyExt;
 use hyper::Request;
-use opentelemetry::KeyValue;
-use opentelemetry_sdk::{runtime::{RuntimeChannel, Tokio}, trace::Config};
+use opentelemetry::{metrics::{Counter, Histogram, Meter}, propagation::{Extractor, Injector, TextMapPropagator}, trace::{Link, SamplingResult, SpanContext, SpanId, SpanKind, TraceContextExt, TraceFlags, TraceId}, KeyValue};
+use opentelemetry_sdk::{export::trace::{SpanData, SpanExporter}, metrics::{PeriodicReader, SdkMeterProvider}, resource::{ResourceDetector, TelemetryResourceDetector}, runtime::{RuntimeChannel, Tokio}, trace::{BatchSpanProcessor, Config, IdGenerator, RandomIdGenerator, Sampler, ShouldSample, SpanEvents, SpanLinks, SpanProcessor}, InstrumentationLibrary, Resource};
 use opentelemetry_application_insights::HttpClient;
 use reqwest::Client;
 use serde::{de::DeserializeOwned, Serialize};
 use tower::{Layer, Service};
 use tracing::{Instrument, Span, Level};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
 use tracing_subscriber::{filter::LevelFilter, prelude::__tracing_subscriber_SubscriberExt, Registry};
 
 // Re-exports.
@@ -162,6 +195,74 @@ pub mod exports {
     pub use tracing_subscriber;
 }
 
+// Macros.
+
+/// Captures the [`BuildInfo`] of the calling crate at compile time.
+/// 
+/// The version is taken from the calling crate's `CARGO_PKG_VERSION`.  The git SHA is taken from the first of the
+/// `GIT_SHA`, `GITHUB_SHA`, or `BUILD_SOURCEVERSION` environment variables that is set at compile time, and the
+/// build timestamp is taken from `BUILD_TIMESTAMP` or `SOURCE_DATE_EPOCH`.
+/// 
+/// The git SHA and the build timestamp are `None` unless the build environment exports one of these variables (GitHub
+/// Actions sets `GITHUB_SHA`, and Azure Pipelines sets `BUILD_SOURCEVERSION`, but nothing sets a timestamp by default).
+/// 
+/// ```
+/// use axum_insights::{build_info, BuildInfo};
+/// 
+/// let info: BuildInfo = build_info!();
+/// 
+/// assert_eq!(info.version, env!("CARGO_PKG_VERSION"));
+/// ```
+/// 
+/// To capture them in any environment, set them from a build script (`build.rs`) of the calling crate.
+/// 
+/// ```ignore
+/// fn main() {
+///     if let Ok(output) = std::process::Command::new("git").args(["rev-parse", "HEAD"]).output() {
+///         println!("cargo:rustc-env=GIT_SHA={}", String::from_utf8_lossy(&output.stdout).trim());
+///     }
+/// 
+///     let timestamp = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs();
+///     println!("cargo:rustc-env=BUILD_TIMESTAMP={}", timestamp);
+/// }
+/// ```
+#[macro_export]
+macro_rules! build_info {
+    () => {
+        $crate::BuildInfo {
+            version: env!("CARGO_PKG_VERSION").to_owned(),
+            git_sha: option_env!("GIT_SHA")
+                .or(option_env!("GITHUB_SHA"))
+                .or(option_env!("BUILD_SOURCEVERSION"))
+                .map(str::to_owned),
+            build_timestamp: option_env!("BUILD_TIMESTAMP")
+                .or(option_env!("SOURCE_DATE_EPOCH"))
+                .map(str::to_owned),
+        }
+    };
+}
+
+/// Gets the version of the calling crate at compile time (its `CARGO_PKG_VERSION`), for [`AppInsights::with_service_version`].
+/// 
+/// Since a library cannot see the version of the crate that uses it, this has to expand in the calling crate.
+/// 
+/// ```
+/// use axum_insights::{service_version, AppInsights, Ready};
+/// 
+/// let i: AppInsights<Ready> = AppInsights::default()
+///     .with_connection_string(None)
+///     .with_service_config("namespace", "name")
+///     .with_service_version(service_version!());
+/// 
+/// assert_eq!(service_version!(), env!("CARGO_PKG_VERSION"));
+/// ```
+#[macro_export]
+macro_rules! service_version {
+    () => {
+        env!("CARGO_PKG_VERSION")
+    };
+}
+
 // Traits.
 
 /// A trait that extracts relevant information from a global error type.
@@ -200,13 +301,18 @@ pub mod exports {
 /// instrument all of your handlers to extract some of the error information from your error type (only attempts the extraction
 /// for 400s and 500s).
 /// 
-/// Implementing this trait allows the [`AppInsightsLayer`] to extract the error message and backtrace from your error type,
-/// and add that information to the resulting traces.
+/// Implementing this trait allows the [`AppInsightsLayer`] to extract the error message, backtrace, and code from your error type,
+/// and add that information to the resulting traces.  The message becomes the status message of the request, and the code (if any)
+/// is recorded as a separate attribute, so that both can be queried directly.
 pub trait AppInsightsError {
     /// The message of the error.
     fn message(&self) -> Option<String>;
     /// The backtrace of the error.
     fn backtrace(&self) -> Option<String>;
+    /// The application-specific code of the error (e.g., `rate_limited`), which is recorded as the `error.code` attribute.
+    fn code(&self) -> Option<String> {
+        None
+    }
 }
 
 impl AppInsightsError for () {
@@ -219,6 +325,32 @@ impl AppInsightsError for () {
     }
 }
 
+/// A source of the current time, which is used by the middleware to measure durations.
+/// 
+/// The default is the [`SystemClock`].  In tests, a [`ManualClock`] makes the measured durations (e.g., of the spans, the
+/// request metrics, and the heartbeat events) deterministic.
+pub trait Clock: Send + Sync + 'static {
+    /// The current instant.
+    fn now(&self) -> Instant;
+
+    /// The current wall-clock time, which is used for the start and end times of the spans.
+    /// 
+    /// This should move in lockstep with [`Clock::now`].  The default reads the system time.
+    fn system_time(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+impl<T: Clock + ?Sized> Clock for Arc<T> {
+    fn now(&self) -> Instant {
+        (**self).now()
+    }
+
+    fn system_time(&self) -> SystemTime {
+        (**self).system_time()
+    }
+}
+
 // Types.
 
 /// The base state of the [`AppInsights`] builder struct.
@@ -230,8 +362,346 @@ pub struct WithConnectionString;
 /// The state of the [`AppInsights`] builder struct after a connection string and service config have been set.
 pub struct Ready;
 
-type OptionalPanicMapper<E> = Option<Arc<dyn Fn(String) -> (u16, E) + Send + Sync + 'static>>;
+/// Build metadata for the application, usually captured at compile time via [`build_info!`].
+/// 
+/// This is set via [`AppInsights::with_build_info`], and it is attached to all telemetry, so that regressions
+/// can be correlated with releases.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct BuildInfo {
+    /// The version of the application (recorded as `service.version`, unless overridden via [`AppInsights::with_service_version`]).
+    pub version: String,
+    /// The git commit SHA the application was built from (recorded as `build.git_sha`).
+    pub git_sha: Option<String>,
+    /// The time at which the application was built (recorded as `build.timestamp`).
+    pub build_timestamp: Option<String>,
+}
+
+impl BuildInfo {
+    fn into_key_values(self) -> Vec<KeyValue> {
+        let mut key_values = Vec::new();
+
+        if let Some(git_sha) = self.git_sha {
+            key_values.push(KeyValue::new("build.git_sha", git_sha));
+        }
+
+        if let Some(build_timestamp) = self.build_timestamp {
+            key_values.push(KeyValue::new("build.timestamp", build_timestamp));
+        }
+
+        key_values
+    }
+}
+
+/// A preset bundle of defaults for a deployment environment.
+/// 
+/// Profiles are applied via [`AppInsights::with_profile`], and any setting that is set explicitly overrides the profile's
+/// value (regardless of the order of the calls).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Profile {
+    /// Verbose telemetry for local development: everything is sampled at the DEBUG level, no live metrics, up to 1 MiB of
+    /// a failed response body is buffered, nothing is redacted, and the console (if enabled) is pretty-printed.
+    Development,
+    /// Telemetry for pre-production environments: everything is sampled at the INFO level, live metrics, up to 64 KiB of a
+    /// failed response body is buffered, sensitive values (e.g., `authorization`, cookies, tokens, and keys) are redacted, and
+    /// the console (if enabled) is compact.
+    Staging,
+    /// Telemetry for production environments: a quarter of the requests are sampled at the INFO level, live metrics, up to
+    /// 4 KiB of a failed response body is buffered, sensitive and personal values (e.g., the values that staging redacts, client
+    /// addresses, and emails) are redacted, and the console (if enabled) is compact.
+    Production,
+}
+
+/// The settings that were set explicitly on the builder, which a [`Profile`] does not override.
+#[derive(Clone, Copy, Debug, Default)]
+struct ExplicitSettings {
+    sample_rate: bool,
+    minimum_level: bool,
+    live_metrics: bool,
+    max_error_body_capture: bool,
+    capture_redactor: bool,
+}
+
+/// The format of the console layer (see [`AppInsights::with_console`]), which is chosen by the [`Profile`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ConsoleFormat {
+    /// The multi-line format, with the source locations, for reading locally.
+    Pretty,
+    /// The single-line format, for collecting as logs.
+    Compact,
+}
+
+/// A preset policy that determines the success-iness of a status.
+/// 
+/// Policies are applied via [`AppInsights::with_success_policy`], and they are a shorthand for common
+/// [`AppInsights::with_success_filter`] closures.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SuccessPolicy {
+    /// Only server errors (500 - 599) are failures.
+    StrictServerErrorsOnly,
+    /// The default policy (100 - 399 => true), but a 404 is also a success.
+    IgnoreNotFound,
+    /// The default policy (100 - 399 => true), but client errors (400 - 499) are also successes.
+    IgnoreClientErrors,
+}
+
+impl SuccessPolicy {
+    /// Determines whether the given status is a success under this policy.
+    /// 
+    /// ```
+    /// use axum_insights::SuccessPolicy;
+    /// use http::StatusCode;
+    /// 
+    /// assert!(SuccessPolicy::IgnoreNotFound.is_success(StatusCode::NOT_FOUND));
+    /// assert!(!SuccessPolicy::IgnoreNotFound.is_success(StatusCode::BAD_REQUEST));
+    /// ```
+    pub fn is_success(self, status: StatusCode) -> bool {
+        let is_default_success = status.is_success() || status.is_redirection() || status.is_informational();
+
+        match self {
+            SuccessPolicy::StrictServerErrorsOnly => !status.is_server_error(),
+            SuccessPolicy::IgnoreNotFound => is_default_success || status == StatusCode::NOT_FOUND,
+            SuccessPolicy::IgnoreClientErrors => is_default_success || status.is_client_error(),
+        }
+    }
+}
+
+/// How the build degrades when the telemetry pipeline cannot be created, which is given to [`AppInsights::with_fallback_on_error`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FallbackMode {
+    /// Disable telemetry entirely, as if [`AppInsights::with_noop`] had been set.
+    Noop,
+    /// Keep the local subscriber (and the middleware), but do not export anything to Application Insights.
+    LocalLogOnly,
+}
+
+/// The backend that the telemetry is exported to, which is given to [`AppInsights::with_exporter`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ExporterBackend {
+    /// Export to Application Insights with the given connection string.  If None, then no telemetry is sent.
+    ApplicationInsights {
+        /// The connection string of the Application Insights resource.
+        connection_string: Option<String>,
+    },
+    /// Export the traces to an OTLP collector over HTTP (e.g., `http://localhost:4318/v1/traces`).  Requires the `otlp` feature.
+    #[cfg(feature = "otlp")]
+    Otlp {
+        /// The endpoint of the collector.
+        endpoint: String,
+    },
+}
+
+/// A per-request sampling decision, which is returned from the function given to [`AppInsights::with_request_sampler`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SamplingDecision {
+    /// Defer to the configured sampler of the trace config.
+    #[default]
+    Default,
+    /// Always sample the request (and everything within it).
+    Sample,
+    /// Never sample the request (or anything within it).
+    Drop,
+}
+
+/// The [`Clock`] that reads the system's monotonic clock.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// A [`Clock`] that only moves when it is advanced, for deterministic tests.
+/// 
+/// ```
+/// use axum_insights::{Clock, ManualClock};
+/// use std::time::Duration;
+/// 
+/// let clock = ManualClock::new();
+/// let start = clock.now();
+/// 
+/// clock.advance(Duration::from_millis(250));
+/// 
+/// assert_eq!(clock.now() - start, Duration::from_millis(250));
+/// ```
+#[derive(Debug)]
+pub struct ManualClock {
+    base: Instant,
+    base_time: SystemTime,
+    elapsed: Mutex<Duration>,
+}
+
+impl ManualClock {
+    /// Creates a new clock that is stopped at the current instant (and time).
+    pub fn new() -> Self {
+        Self {
+            base: Instant::now(),
+            base_time: SystemTime::now(),
+            elapsed: Mutex::new(Duration::ZERO),
+        }
+    }
+
+    /// Moves the clock forward by the given duration.
+    pub fn advance(&self, duration: Duration) {
+        *self.elapsed.lock().unwrap() += duration;
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        self.base + *self.elapsed.lock().unwrap()
+    }
+
+    fn system_time(&self) -> SystemTime {
+        self.base_time + *self.elapsed.lock().unwrap()
+    }
+}
+
+/// A snapshot of the request that caused a panic, which is given to [`AppInsights::with_contextual_panic_mapper`].
+/// 
+/// Only the headers selected with [`AppInsights::with_panic_context_headers`] are captured.
+#[derive(Clone, Debug, Default)]
+pub struct PanicContext {
+    /// The HTTP method of the request.
+    pub method: String,
+    /// The full URI of the request.
+    pub uri: String,
+    /// The matched route of the request (e.g., `/users/:id`), or the path if no route matched.
+    pub route: String,
+    /// The selected headers of the request.
+    pub headers: http::HeaderMap,
+}
+
+/// The user and session of a request, which is returned from the function given to [`AppInsights::with_user_mapper`].
+/// 
+/// These populate the user context of the request telemetry, which drives the usage analytics (e.g., users and sessions) of the portal.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct UserContext {
+    /// The anonymous user id, which is recorded as `ai.user.id`.
+    pub user_id: Option<String>,
+    /// The session id, which is recorded as `ai.session.id`.
+    pub session_id: Option<String>,
+    /// The authenticated user id, which is recorded as `enduser.id` (and `ai.user.authUserId`).
+    pub auth_user_id: Option<String>,
+}
+
+/// The details of a typed error, which are stashed in the response extensions so that the middleware does not need to
+/// deserialize them from the response body.
+/// 
+/// Handlers can insert these into the extensions of an error response directly, or return a [`TrackedError`], which does it for them.
+/// 
+/// ```
+/// use axum::response::{IntoResponse, Response};
+/// use axum_insights::{AppInsightsError, ErrorDetails};
+/// use http::StatusCode;
+/// 
+/// struct NotFound;
+/// 
+/// impl AppInsightsError for NotFound {
+///     fn message(&self) -> Option<String> {
+///         Some("the widget does not exist".to_owned())
+///     }
+/// 
+///     fn backtrace(&self) -> Option<String> {
+///         None
+///     }
+/// }
+/// 
+/// let mut response: Response = (StatusCode::NOT_FOUND, "not found").into_response();
+/// response.extensions_mut().insert(ErrorDetails::new(&NotFound));
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct ErrorDetails {
+    message: Option<String>,
+    backtrace: Option<String>,
+    code: Option<String>,
+}
+
+impl ErrorDetails {
+    /// Captures the details of the given error.
+    pub fn new<T>(error: &T) -> Self
+    where
+        T: AppInsightsError,
+    {
+        Self {
+            message: error.message(),
+            backtrace: error.backtrace(),
+            code: error.code(),
+        }
+    }
+}
+
+/// A response wrapper that stashes the [`ErrorDetails`] of the inner error into the response extensions.
+/// 
+/// ```
+/// use axum::response::{IntoResponse, Response};
+/// use axum_insights::{AppInsightsError, TrackedError};
+/// use http::StatusCode;
+/// 
+/// struct Conflict;
+/// 
+/// impl AppInsightsError for Conflict {
+///     fn message(&self) -> Option<String> {
+///         Some("the widget already exists".to_owned())
+///     }
+/// 
+///     fn backtrace(&self) -> Option<String> {
+///         None
+///     }
+/// }
+/// 
+/// impl IntoResponse for Conflict {
+///     fn into_response(self) -> Response {
+///         StatusCode::CONFLICT.into_response()
+///     }
+/// }
+/// 
+/// async fn handler() -> Result<String, TrackedError<Conflict>> {
+///     Err(TrackedError(Conflict))
+/// }
+/// ```
+#[derive(Clone, Debug)]
+pub struct TrackedError<T>(pub T);
+
+impl<T> From<T> for TrackedError<T>
+where
+    T: AppInsightsError,
+{
+    fn from(error: T) -> Self {
+        Self(error)
+    }
+}
+
+impl<T> IntoResponse for TrackedError<T>
+where
+    T: AppInsightsError + IntoResponse,
+{
+    fn into_response(self) -> Response {
+        let details = ErrorDetails::new(&self.0);
+        let mut response = self.0.into_response();
+
+        response.extensions_mut().insert(details);
+
+        response
+    }
+}
+
+type OptionalPanicMapper<E> = Option<Arc<dyn Fn(String, &PanicContext) -> (u16, E) + Send + Sync + 'static>>;
 type OptionalFieldMapper = Option<Arc<dyn Fn(&http::request::Parts) -> HashMap<String, String> + Send + Sync + 'static>>;
+type OptionalRequestFilter = Option<Arc<dyn Fn(&http::request::Parts) -> bool + Send + Sync + 'static>>;
+type OptionalCaptureRedactor = Option<Arc<dyn Fn(&str, &str) -> String + Send + Sync + 'static>>;
+type OptionalUserMapper = Option<Arc<dyn Fn(&http::request::Parts) -> UserContext + Send + Sync + 'static>>;
+type OptionalRequestSampler = Option<Arc<dyn Fn(&http::request::Parts) -> SamplingDecision + Send + Sync + 'static>>;
+type OptionalOperationGrouper = Option<Arc<dyn Fn(&str, &http::request::Parts) -> String + Send + Sync + 'static>>;
+type OptionalPropagator = Option<Arc<dyn TextMapPropagator + Send + Sync + 'static>>;
+type OptionalAsyncFieldMapper = Option<Arc<dyn Fn(&http::request::Parts) -> BoxFuture<'static, HashMap<String, String>> + Send + Sync + 'static>>;
 type OptionalSuccessFilter = Option<Arc<dyn Fn(StatusCode) -> bool + Send + Sync + 'static>>;
 
 /// The complete [`AppInsights`] builder struct.
@@ -242,6 +712,38 @@ pub struct AppInsightsComplete<P, E> {
     field_mapper: OptionalFieldMapper,
     panic_mapper: OptionalPanicMapper<P>,
     success_filter: OptionalSuccessFilter,
+    should_close_on_upgrade: bool,
+    heartbeat_interval: Option<Duration>,
+    should_record_multipart: bool,
+    should_record_compression: bool,
+    panic_context_headers: Arc<Vec<http::HeaderName>>,
+    request_sampler: OptionalRequestSampler,
+    debug_trace_header: Option<Arc<DebugTraceHeader>>,
+    operation_grouper: OptionalOperationGrouper,
+    should_extract_trace_context: bool,
+    should_inject_trace_context: bool,
+    error_response_headers: Arc<Vec<http::HeaderName>>,
+    request_metrics: Option<Arc<RequestMetrics>>,
+    propagator: OptionalPropagator,
+    async_field_mapper: OptionalAsyncFieldMapper,
+    clock: Arc<dyn Clock>,
+    should_deserialize_error_body: bool,
+    request_filter: OptionalRequestFilter,
+    excluded_routes: Arc<Vec<String>>,
+    captured_request_headers: Arc<Vec<http::HeaderName>>,
+    captured_response_headers: Arc<Vec<http::HeaderName>>,
+    captured_query_parameters: Arc<Vec<String>>,
+    capture_redactor: OptionalCaptureRedactor,
+    rate_report: Option<Arc<RateReport>>,
+    retry_detector: Option<Arc<RetryDetector>>,
+    shutdown_handle: ShutdownHandle,
+    user_mapper: OptionalUserMapper,
+    should_bias_errors: bool,
+    slow_request_threshold: Option<Duration>,
+    max_error_body_capture: usize,
+    fallback_error: Option<Arc<str>>,
+    reload_handle: ReloadHandle,
+    stats_handle: StatsHandle,
     _phantom: std::marker::PhantomData<E>,
 }
 
@@ -262,6 +764,46 @@ pub struct AppInsights<S = Base, C = Client, R = Tokio, U = Registry, P = (), E
     field_mapper: OptionalFieldMapper,
     panic_mapper: OptionalPanicMapper<P>,
     success_filter: OptionalSuccessFilter,
+    global_dimensions: HashMap<String, String>,
+    build_info: Option<BuildInfo>,
+    service_version: Option<String>,
+    should_close_on_upgrade: bool,
+    heartbeat_interval: Option<Duration>,
+    should_record_multipart: bool,
+    should_record_compression: bool,
+    panic_context_headers: Vec<http::HeaderName>,
+    request_sampler: OptionalRequestSampler,
+    debug_trace_header: Option<Arc<DebugTraceHeader>>,
+    operation_grouper: OptionalOperationGrouper,
+    should_extract_trace_context: bool,
+    should_inject_trace_context: bool,
+    effective_sample_rate: Option<f64>,
+    error_response_headers: Vec<http::HeaderName>,
+    should_record_metrics: bool,
+    propagator: OptionalPropagator,
+    async_field_mapper: OptionalAsyncFieldMapper,
+    clock: Arc<dyn Clock>,
+    fallback_mode: Option<FallbackMode>,
+    should_deserialize_error_body: bool,
+    request_filter: OptionalRequestFilter,
+    excluded_routes: Vec<String>,
+    resource_attributes: Vec<KeyValue>,
+    should_detect_host_name: bool,
+    captured_request_headers: Vec<http::HeaderName>,
+    captured_response_headers: Vec<http::HeaderName>,
+    captured_query_parameters: Vec<String>,
+    capture_redactor: OptionalCaptureRedactor,
+    rate_report_interval: Option<Duration>,
+    retry_detector: Option<Arc<RetryDetector>>,
+    user_mapper: OptionalUserMapper,
+    tracer_provider: Option<opentelemetry_sdk::trace::TracerProvider>,
+    should_bias_errors: bool,
+    slow_request_threshold: Option<Duration>,
+    otlp_endpoint: Option<String>,
+    max_error_body_capture: usize,
+    explicit_settings: ExplicitSettings,
+    should_log_to_console: bool,
+    console_format: ConsoleFormat,
     _phantom1: std::marker::PhantomData<S>,
     _phantom2: std::marker::PhantomData<E>,
 }
@@ -282,13 +824,164 @@ impl Default for AppInsights<Base> {
             field_mapper: None,
             panic_mapper: None,
             success_filter: None,
+            global_dimensions: HashMap::new(),
+            build_info: None,
+            service_version: None,
+            should_close_on_upgrade: false,
+            heartbeat_interval: None,
+            should_record_multipart: false,
+            should_record_compression: false,
+            panic_context_headers: Vec::new(),
+            request_sampler: None,
+            debug_trace_header: None,
+            operation_grouper: None,
+            should_extract_trace_context: true,
+            should_inject_trace_context: false,
+            effective_sample_rate: None,
+            error_response_headers: Vec::new(),
+            should_record_metrics: false,
+            propagator: None,
+            async_field_mapper: None,
+            clock: Arc::new(SystemClock),
+            fallback_mode: None,
+            should_deserialize_error_body: true,
+            request_filter: None,
+            excluded_routes: Vec::new(),
+            resource_attributes: Vec::new(),
+            should_detect_host_name: true,
+            captured_request_headers: Vec::new(),
+            captured_response_headers: Vec::new(),
+            captured_query_parameters: Vec::new(),
+            capture_redactor: None,
+            rate_report_interval: None,
+            retry_detector: None,
+            user_mapper: None,
+            tracer_provider: None,
+            should_bias_errors: false,
+            slow_request_threshold: None,
+            otlp_endpoint: None,
+            max_error_body_capture: DEFAULT_MAX_ERROR_BODY_CAPTURE,
+            explicit_settings: ExplicitSettings::default(),
+            should_log_to_console: false,
+            console_format: ConsoleFormat::Compact,
             _phantom1: std::marker::PhantomData,
             _phantom2: std::marker::PhantomData,
         }
     }
 }
 
+impl AppInsights<Base> {
+    /// Creates the builder on top of an existing tracer provider, for applications that build their OpenTelemetry pipeline elsewhere.
+    /// 
+    /// The middleware, the mappers, and the panic handling work as usual, but the spans are exported by the given provider.  Since
+    /// the provider is owned by the application, it is not installed globally, and its resource cannot be changed: the resource
+    /// attributes of this builder (the global dimensions, the service version, the build information, the explicit resource
+    /// attributes, the host information, and the SDK versions) are stamped onto every span as attributes instead.  The settings that
+    /// configure the exporter ([`AppInsights::with_sample_rate`], [`AppInsights::with_effective_sample_rate`],
+    /// [`AppInsights::with_live_metrics`], and [`AppInsights::with_metrics`]) do not apply, and the build emits a warning if any of
+    /// them is set.  The [`ShutdownHandle`] flushes and shuts down the given provider.
+    /// 
+    /// The sampling overrides also need the pipeline, since they wrap its sampler (and its exporter), so they have no effect on the
+    /// given provider: the decisions of [`AppInsights::with_request_sampler`] are only recorded as `sampling.priority`, requests with
+    /// the [`AppInsights::with_debug_trace_header`] header are not force-sampled (though their bodies are still captured), and
+    /// [`AppInsights::with_error_biased_sampling`] is ignored.  The build emits a warning if any of them is set.
+    /// 
+    /// ```
+    /// use axum_insights::{AppInsights, Ready};
+    /// use opentelemetry_sdk::trace::TracerProvider;
+    /// 
+    /// let tracer_provider = TracerProvider::builder().build();
+    /// 
+    /// let i: AppInsights<Ready> = AppInsights::from_tracer_provider(tracer_provider)
+    ///     .with_catch_panic(true);
+    /// ```
+    pub fn from_tracer_provider(tracer_provider: opentelemetry_sdk::trace::TracerProvider) -> AppInsights<Ready> {
+        AppInsights::default()
+            .with_connection_string(None)
+            .with_trace_config(Config::default())
+            .with_tracer_provider(tracer_provider)
+    }
+}
+
 impl<C, R, U, P, E> AppInsights<Base, C, R, U, P, E> {
+    /// Sets the backend to export the telemetry to, which is either Application Insights (the same as [`AppInsights::with_connection_string`])
+    /// or, with the `otlp` feature, an OTLP collector.
+    /// 
+    /// The middleware (and its field mappers, panic handling, success filter, etc.) is the same for every backend.  However, the request
+    /// metrics, live metrics, and [`AppInsights::with_error_biased_sampling`] are only available with Application Insights.
+    /// 
+    /// ```
+    /// use axum_insights::{AppInsights, ExporterBackend, WithConnectionString};
+    /// 
+    /// let i: AppInsights<WithConnectionString> = AppInsights::default()
+    ///     .with_exporter(ExporterBackend::ApplicationInsights { connection_string: None });
+    /// ```
+    pub fn with_exporter(self, backend: ExporterBackend) -> AppInsights<WithConnectionString, C, R, U, P, E> {
+        let (connection_string, otlp_endpoint) = match backend {
+            ExporterBackend::ApplicationInsights { connection_string } => (connection_string, None),
+            #[cfg(feature = "otlp")]
+            ExporterBackend::Otlp { endpoint } => (None, Some(endpoint)),
+        };
+
+        AppInsights {
+            connection_string,
+            config: self.config,
+            client: self.client,
+            enable_live_metrics: self.enable_live_metrics,
+            sample_rate: self.sample_rate,
+            batch_runtime: self.batch_runtime,
+            minimum_level: self.minimum_level,
+            subscriber: self.subscriber,
+            should_catch_panic: self.should_catch_panic,
+            is_noop: self.is_noop,
+            field_mapper: self.field_mapper,
+            panic_mapper: self.panic_mapper,
+            success_filter: self.success_filter,
+            global_dimensions: self.global_dimensions,
+            build_info: self.build_info,
+            service_version: self.service_version,
+            should_close_on_upgrade: self.should_close_on_upgrade,
+            heartbeat_interval: self.heartbeat_interval,
+            should_record_multipart: self.should_record_multipart,
+            should_record_compression: self.should_record_compression,
+            panic_context_headers: self.panic_context_headers,
+            request_sampler: self.request_sampler,
+            debug_trace_header: self.debug_trace_header,
+            operation_grouper: self.operation_grouper,
+            should_extract_trace_context: self.should_extract_trace_context,
+            should_inject_trace_context: self.should_inject_trace_context,
+            effective_sample_rate: self.effective_sample_rate,
+            error_response_headers: self.error_response_headers,
+            should_record_metrics: self.should_record_metrics,
+            propagator: self.propagator,
+            async_field_mapper: self.async_field_mapper,
+            clock: self.clock,
+            fallback_mode: self.fallback_mode,
+            should_deserialize_error_body: self.should_deserialize_error_body,
+            request_filter: self.request_filter,
+            excluded_routes: self.excluded_routes,
+            resource_attributes: self.resource_attributes,
+            should_detect_host_name: self.should_detect_host_name,
+            captured_request_headers: self.captured_request_headers,
+            captured_response_headers: self.captured_response_headers,
+            captured_query_parameters: self.captured_query_parameters,
+            capture_redactor: self.capture_redactor,
+            rate_report_interval: self.rate_report_interval,
+            retry_detector: self.retry_detector,
+            user_mapper: self.user_mapper,
+            tracer_provider: self.tracer_provider,
+            should_bias_errors: self.should_bias_errors,
+            slow_request_threshold: self.slow_request_threshold,
+            otlp_endpoint,
+            max_error_body_capture: self.max_error_body_capture,
+            explicit_settings: self.explicit_settings,
+            should_log_to_console: self.should_log_to_console,
+            console_format: self.console_format,
+            _phantom1: std::marker::PhantomData,
+            _phantom2: std::marker::PhantomData,
+        }
+    }
+
     /// Sets the connection string to use for telemetry.
     /// 
     /// If this is not set, then no telemetry will be sent.
@@ -314,6 +1007,46 @@ impl<C, R, U, P, E> AppInsights<Base, C, R, U, P, E> {
             field_mapper: self.field_mapper,
             panic_mapper: self.panic_mapper,
             success_filter: self.success_filter,
+            global_dimensions: self.global_dimensions,
+            build_info: self.build_info,
+            service_version: self.service_version,
+            should_close_on_upgrade: self.should_close_on_upgrade,
+            heartbeat_interval: self.heartbeat_interval,
+            should_record_multipart: self.should_record_multipart,
+            should_record_compression: self.should_record_compression,
+            panic_context_headers: self.panic_context_headers,
+            request_sampler: self.request_sampler,
+            debug_trace_header: self.debug_trace_header,
+            operation_grouper: self.operation_grouper,
+            should_extract_trace_context: self.should_extract_trace_context,
+            should_inject_trace_context: self.should_inject_trace_context,
+            effective_sample_rate: self.effective_sample_rate,
+            error_response_headers: self.error_response_headers,
+            should_record_metrics: self.should_record_metrics,
+            propagator: self.propagator,
+            async_field_mapper: self.async_field_mapper,
+            clock: self.clock,
+            fallback_mode: self.fallback_mode,
+            should_deserialize_error_body: self.should_deserialize_error_body,
+            request_filter: self.request_filter,
+            excluded_routes: self.excluded_routes,
+            resource_attributes: self.resource_attributes,
+            should_detect_host_name: self.should_detect_host_name,
+            captured_request_headers: self.captured_request_headers,
+            captured_response_headers: self.captured_response_headers,
+            captured_query_parameters: self.captured_query_parameters,
+            capture_redactor: self.capture_redactor,
+            rate_report_interval: self.rate_report_interval,
+            retry_detector: self.retry_detector,
+            user_mapper: self.user_mapper,
+            tracer_provider: self.tracer_provider,
+            should_bias_errors: self.should_bias_errors,
+            slow_request_threshold: self.slow_request_threshold,
+            otlp_endpoint: self.otlp_endpoint,
+            max_error_body_capture: self.max_error_body_capture,
+            explicit_settings: self.explicit_settings,
+            should_log_to_console: self.should_log_to_console,
+            console_format: self.console_format,
             _phantom1: std::marker::PhantomData,
             _phantom2: std::marker::PhantomData,
         }
@@ -352,6 +1085,46 @@ impl<C, R, U, P, E> AppInsights<WithConnectionString, C, R, U, P, E> {
             field_mapper: self.field_mapper,
             panic_mapper: self.panic_mapper,
             success_filter: self.success_filter,
+            global_dimensions: self.global_dimensions,
+            build_info: self.build_info,
+            service_version: self.service_version,
+            should_close_on_upgrade: self.should_close_on_upgrade,
+            heartbeat_interval: self.heartbeat_interval,
+            should_record_multipart: self.should_record_multipart,
+            should_record_compression: self.should_record_compression,
+            panic_context_headers: self.panic_context_headers,
+            request_sampler: self.request_sampler,
+            debug_trace_header: self.debug_trace_header,
+            operation_grouper: self.operation_grouper,
+            should_extract_trace_context: self.should_extract_trace_context,
+            should_inject_trace_context: self.should_inject_trace_context,
+            effective_sample_rate: self.effective_sample_rate,
+            error_response_headers: self.error_response_headers,
+            should_record_metrics: self.should_record_metrics,
+            propagator: self.propagator,
+            async_field_mapper: self.async_field_mapper,
+            clock: self.clock,
+            fallback_mode: self.fallback_mode,
+            should_deserialize_error_body: self.should_deserialize_error_body,
+            request_filter: self.request_filter,
+            excluded_routes: self.excluded_routes,
+            resource_attributes: self.resource_attributes,
+            should_detect_host_name: self.should_detect_host_name,
+            captured_request_headers: self.captured_request_headers,
+            captured_response_headers: self.captured_response_headers,
+            captured_query_parameters: self.captured_query_parameters,
+            capture_redactor: self.capture_redactor,
+            rate_report_interval: self.rate_report_interval,
+            retry_detector: self.retry_detector,
+            user_mapper: self.user_mapper,
+            tracer_provider: self.tracer_provider,
+            should_bias_errors: self.should_bias_errors,
+            slow_request_threshold: self.slow_request_threshold,
+            otlp_endpoint: self.otlp_endpoint,
+            max_error_body_capture: self.max_error_body_capture,
+            explicit_settings: self.explicit_settings,
+            should_log_to_console: self.should_log_to_console,
+            console_format: self.console_format,
             _phantom1: std::marker::PhantomData,
             _phantom2: std::marker::PhantomData,
         }
@@ -382,6 +1155,46 @@ impl<C, R, U, P, E> AppInsights<WithConnectionString, C, R, U, P, E> {
             field_mapper: self.field_mapper,
             panic_mapper: self.panic_mapper,
             success_filter: self.success_filter,
+            global_dimensions: self.global_dimensions,
+            build_info: self.build_info,
+            service_version: self.service_version,
+            should_close_on_upgrade: self.should_close_on_upgrade,
+            heartbeat_interval: self.heartbeat_interval,
+            should_record_multipart: self.should_record_multipart,
+            should_record_compression: self.should_record_compression,
+            panic_context_headers: self.panic_context_headers,
+            request_sampler: self.request_sampler,
+            debug_trace_header: self.debug_trace_header,
+            operation_grouper: self.operation_grouper,
+            should_extract_trace_context: self.should_extract_trace_context,
+            should_inject_trace_context: self.should_inject_trace_context,
+            effective_sample_rate: self.effective_sample_rate,
+            error_response_headers: self.error_response_headers,
+            should_record_metrics: self.should_record_metrics,
+            propagator: self.propagator,
+            async_field_mapper: self.async_field_mapper,
+            clock: self.clock,
+            fallback_mode: self.fallback_mode,
+            should_deserialize_error_body: self.should_deserialize_error_body,
+            request_filter: self.request_filter,
+            excluded_routes: self.excluded_routes,
+            resource_attributes: self.resource_attributes,
+            should_detect_host_name: self.should_detect_host_name,
+            captured_request_headers: self.captured_request_headers,
+            captured_response_headers: self.captured_response_headers,
+            captured_query_parameters: self.captured_query_parameters,
+            capture_redactor: self.capture_redactor,
+            rate_report_interval: self.rate_report_interval,
+            retry_detector: self.retry_detector,
+            user_mapper: self.user_mapper,
+            tracer_provider: self.tracer_provider,
+            should_bias_errors: self.should_bias_errors,
+            slow_request_threshold: self.slow_request_threshold,
+            otlp_endpoint: self.otlp_endpoint,
+            max_error_body_capture: self.max_error_body_capture,
+            explicit_settings: self.explicit_settings,
+            should_log_to_console: self.should_log_to_console,
+            console_format: self.console_format,
             _phantom1: std::marker::PhantomData,
             _phantom2: std::marker::PhantomData,
         }
@@ -389,6 +1202,156 @@ impl<C, R, U, P, E> AppInsights<WithConnectionString, C, R, U, P, E> {
 }
 
 impl<C, R, U, P, E> AppInsights<Ready, C, R, U, P, E> {
+    /// Sets the existing tracer provider that exports the spans (see [`AppInsights::from_tracer_provider`]).
+    fn with_tracer_provider(self, tracer_provider: opentelemetry_sdk::trace::TracerProvider) -> AppInsights<Ready, C, R, U, P, E> {
+        AppInsights {
+            connection_string: self.connection_string,
+            config: self.config,
+            client: self.client,
+            enable_live_metrics: self.enable_live_metrics,
+            sample_rate: self.sample_rate,
+            batch_runtime: self.batch_runtime,
+            minimum_level: self.minimum_level,
+            subscriber: self.subscriber,
+            should_catch_panic: self.should_catch_panic,
+            is_noop: self.is_noop,
+            field_mapper: self.field_mapper,
+            panic_mapper: self.panic_mapper,
+            success_filter: self.success_filter,
+            global_dimensions: self.global_dimensions,
+            build_info: self.build_info,
+            service_version: self.service_version,
+            should_close_on_upgrade: self.should_close_on_upgrade,
+            heartbeat_interval: self.heartbeat_interval,
+            should_record_multipart: self.should_record_multipart,
+            should_record_compression: self.should_record_compression,
+            panic_context_headers: self.panic_context_headers,
+            request_sampler: self.request_sampler,
+            debug_trace_header: self.debug_trace_header,
+            operation_grouper: self.operation_grouper,
+            should_extract_trace_context: self.should_extract_trace_context,
+            should_inject_trace_context: self.should_inject_trace_context,
+            effective_sample_rate: self.effective_sample_rate,
+            error_response_headers: self.error_response_headers,
+            should_record_metrics: self.should_record_metrics,
+            propagator: self.propagator,
+            async_field_mapper: self.async_field_mapper,
+            clock: self.clock,
+            fallback_mode: self.fallback_mode,
+            should_deserialize_error_body: self.should_deserialize_error_body,
+            request_filter: self.request_filter,
+            excluded_routes: self.excluded_routes,
+            resource_attributes: self.resource_attributes,
+            should_detect_host_name: self.should_detect_host_name,
+            captured_request_headers: self.captured_request_headers,
+            captured_response_headers: self.captured_response_headers,
+            captured_query_parameters: self.captured_query_parameters,
+            capture_redactor: self.capture_redactor,
+            rate_report_interval: self.rate_report_interval,
+            retry_detector: self.retry_detector,
+            user_mapper: self.user_mapper,
+            tracer_provider: Some(tracer_provider),
+            should_bias_errors: self.should_bias_errors,
+            slow_request_threshold: self.slow_request_threshold,
+            otlp_endpoint: self.otlp_endpoint,
+            max_error_body_capture: self.max_error_body_capture,
+            explicit_settings: self.explicit_settings,
+            should_log_to_console: self.should_log_to_console,
+            console_format: self.console_format,
+            _phantom1: std::marker::PhantomData,
+            _phantom2: std::marker::PhantomData,
+        }
+    }
+
+    /// Applies a preset [`Profile`] of defaults for the given environment.
+    /// 
+    /// The profile sets the sample rate, minimum level, live metrics, how much of a failed response body is buffered (see
+    /// [`AppInsights::with_max_error_body_capture`]), how strictly the captured headers and query parameters are redacted (see
+    /// [`AppInsights::with_capture_redactor`]), and the format of the console.  The console itself is opt-in (see
+    /// [`AppInsights::with_console`]), and so is panic catching (see [`AppInsights::with_catch_panic`]), since the profile should
+    /// not change the behavior of the application.
+    /// 
+    /// Settings that are set explicitly always win over the profile, regardless of the order of the calls (i.e., the profile only
+    /// fills in the settings that were not set).  If several profiles are applied, then the last one wins.
+    /// 
+    /// ```
+    /// use axum_insights::{AppInsights, Profile, Ready};
+    /// use tracing_subscriber::filter::LevelFilter;
+    /// 
+    /// let i: AppInsights<Ready> = AppInsights::default()
+    ///     .with_connection_string(None)
+    ///     .with_service_config("namespace", "name")
+    ///     .with_minimum_level(LevelFilter::WARN)
+    ///     .with_profile(Profile::Production);
+    /// ```
+    pub fn with_profile(self, profile: Profile) -> AppInsights<Ready, C, R, U, P, E> {
+        let (sample_rate, minimum_level, enable_live_metrics, max_error_body_capture, capture_redactor, console_format): (_, _, _, _, OptionalCaptureRedactor, _) = match profile {
+            Profile::Development => (1.0, LevelFilter::DEBUG, false, 1024 * 1024, None, ConsoleFormat::Pretty),
+            Profile::Staging => (1.0, LevelFilter::INFO, true, DEFAULT_MAX_ERROR_BODY_CAPTURE, Some(Arc::new(redact_sensitive_capture)), ConsoleFormat::Compact),
+            Profile::Production => (0.25, LevelFilter::INFO, true, 4 * 1024, Some(Arc::new(redact_strict_capture)), ConsoleFormat::Compact),
+        };
+
+        let explicit = self.explicit_settings;
+
+        AppInsights {
+            connection_string: self.connection_string,
+            config: self.config,
+            client: self.client,
+            enable_live_metrics: if explicit.live_metrics { self.enable_live_metrics } else { enable_live_metrics },
+            sample_rate: if explicit.sample_rate { self.sample_rate } else { sample_rate },
+            batch_runtime: self.batch_runtime,
+            minimum_level: if explicit.minimum_level { self.minimum_level } else { minimum_level },
+            subscriber: self.subscriber,
+            should_catch_panic: self.should_catch_panic,
+            is_noop: self.is_noop,
+            field_mapper: self.field_mapper,
+            panic_mapper: self.panic_mapper,
+            success_filter: self.success_filter,
+            global_dimensions: self.global_dimensions,
+            build_info: self.build_info,
+            service_version: self.service_version,
+            should_close_on_upgrade: self.should_close_on_upgrade,
+            heartbeat_interval: self.heartbeat_interval,
+            should_record_multipart: self.should_record_multipart,
+            should_record_compression: self.should_record_compression,
+            panic_context_headers: self.panic_context_headers,
+            request_sampler: self.request_sampler,
+            debug_trace_header: self.debug_trace_header,
+            operation_grouper: self.operation_grouper,
+            should_extract_trace_context: self.should_extract_trace_context,
+            should_inject_trace_context: self.should_inject_trace_context,
+            effective_sample_rate: self.effective_sample_rate,
+            error_response_headers: self.error_response_headers,
+            should_record_metrics: self.should_record_metrics,
+            propagator: self.propagator,
+            async_field_mapper: self.async_field_mapper,
+            clock: self.clock,
+            fallback_mode: self.fallback_mode,
+            should_deserialize_error_body: self.should_deserialize_error_body,
+            request_filter: self.request_filter,
+            excluded_routes: self.excluded_routes,
+            resource_attributes: self.resource_attributes,
+            should_detect_host_name: self.should_detect_host_name,
+            captured_request_headers: self.captured_request_headers,
+            captured_response_headers: self.captured_response_headers,
+            captured_query_parameters: self.captured_query_parameters,
+            capture_redactor: if explicit.capture_redactor { self.capture_redactor } else { capture_redactor },
+            rate_report_interval: self.rate_report_interval,
+            retry_detector: self.retry_detector,
+            user_mapper: self.user_mapper,
+            tracer_provider: self.tracer_provider,
+            should_bias_errors: self.should_bias_errors,
+            slow_request_threshold: self.slow_request_threshold,
+            otlp_endpoint: self.otlp_endpoint,
+            max_error_body_capture: if explicit.max_error_body_capture { self.max_error_body_capture } else { max_error_body_capture },
+            explicit_settings: self.explicit_settings,
+            should_log_to_console: self.should_log_to_console,
+            console_format,
+            _phantom1: std::marker::PhantomData,
+            _phantom2: std::marker::PhantomData,
+        }
+    }
+
     /// Sets the HTTP client to use for sending telemetry.  The default is reqwest async client.
     /// 
     /// ```
@@ -399,7 +1362,10 @@ impl<C, R, U, P, E> AppInsights<Ready, C, R, U, P, E> {
     ///     .with_service_config("namespace", "name")
     ///     .with_client(reqwest::Client::new());
     /// ```
-    pub fn with_client(self, client: C) -> AppInsights<Ready, C, R, U, P, E> {
+    pub fn with_client<T>(self, client: T) -> AppInsights<Ready, T, R, U, P, E>
+    where
+        T: HttpClient,
+    {
         AppInsights {
             connection_string: self.connection_string,
             config: self.config,
@@ -414,6 +1380,46 @@ impl<C, R, U, P, E> AppInsights<Ready, C, R, U, P, E> {
             field_mapper: self.field_mapper,
             panic_mapper: self.panic_mapper,
             success_filter: self.success_filter,
+            global_dimensions: self.global_dimensions,
+            build_info: self.build_info,
+            service_version: self.service_version,
+            should_close_on_upgrade: self.should_close_on_upgrade,
+            heartbeat_interval: self.heartbeat_interval,
+            should_record_multipart: self.should_record_multipart,
+            should_record_compression: self.should_record_compression,
+            panic_context_headers: self.panic_context_headers,
+            request_sampler: self.request_sampler,
+            debug_trace_header: self.debug_trace_header,
+            operation_grouper: self.operation_grouper,
+            should_extract_trace_context: self.should_extract_trace_context,
+            should_inject_trace_context: self.should_inject_trace_context,
+            effective_sample_rate: self.effective_sample_rate,
+            error_response_headers: self.error_response_headers,
+            should_record_metrics: self.should_record_metrics,
+            propagator: self.propagator,
+            async_field_mapper: self.async_field_mapper,
+            clock: self.clock,
+            fallback_mode: self.fallback_mode,
+            should_deserialize_error_body: self.should_deserialize_error_body,
+            request_filter: self.request_filter,
+            excluded_routes: self.excluded_routes,
+            resource_attributes: self.resource_attributes,
+            should_detect_host_name: self.should_detect_host_name,
+            captured_request_headers: self.captured_request_headers,
+            captured_response_headers: self.captured_response_headers,
+            captured_query_parameters: self.captured_query_parameters,
+            capture_redactor: self.capture_redactor,
+            rate_report_interval: self.rate_report_interval,
+            retry_detector: self.retry_detector,
+            user_mapper: self.user_mapper,
+            tracer_provider: self.tracer_provider,
+            should_bias_errors: self.should_bias_errors,
+            slow_request_threshold: self.slow_request_threshold,
+            otlp_endpoint: self.otlp_endpoint,
+            max_error_body_capture: self.max_error_body_capture,
+            explicit_settings: self.explicit_settings,
+            should_log_to_console: self.should_log_to_console,
+            console_format: self.console_format,
             _phantom1: std::marker::PhantomData,
             _phantom2: std::marker::PhantomData,
         }
@@ -445,6 +1451,46 @@ impl<C, R, U, P, E> AppInsights<Ready, C, R, U, P, E> {
             field_mapper: self.field_mapper,
             panic_mapper: self.panic_mapper,
             success_filter: self.success_filter,
+            global_dimensions: self.global_dimensions,
+            build_info: self.build_info,
+            service_version: self.service_version,
+            should_close_on_upgrade: self.should_close_on_upgrade,
+            heartbeat_interval: self.heartbeat_interval,
+            should_record_multipart: self.should_record_multipart,
+            should_record_compression: self.should_record_compression,
+            panic_context_headers: self.panic_context_headers,
+            request_sampler: self.request_sampler,
+            debug_trace_header: self.debug_trace_header,
+            operation_grouper: self.operation_grouper,
+            should_extract_trace_context: self.should_extract_trace_context,
+            should_inject_trace_context: self.should_inject_trace_context,
+            effective_sample_rate: self.effective_sample_rate,
+            error_response_headers: self.error_response_headers,
+            should_record_metrics: self.should_record_metrics,
+            propagator: self.propagator,
+            async_field_mapper: self.async_field_mapper,
+            clock: self.clock,
+            fallback_mode: self.fallback_mode,
+            should_deserialize_error_body: self.should_deserialize_error_body,
+            request_filter: self.request_filter,
+            excluded_routes: self.excluded_routes,
+            resource_attributes: self.resource_attributes,
+            should_detect_host_name: self.should_detect_host_name,
+            captured_request_headers: self.captured_request_headers,
+            captured_response_headers: self.captured_response_headers,
+            captured_query_parameters: self.captured_query_parameters,
+            capture_redactor: self.capture_redactor,
+            rate_report_interval: self.rate_report_interval,
+            retry_detector: self.retry_detector,
+            user_mapper: self.user_mapper,
+            tracer_provider: self.tracer_provider,
+            should_bias_errors: self.should_bias_errors,
+            slow_request_threshold: self.slow_request_threshold,
+            otlp_endpoint: self.otlp_endpoint,
+            max_error_body_capture: self.max_error_body_capture,
+            explicit_settings: ExplicitSettings { live_metrics: true, ..self.explicit_settings },
+            should_log_to_console: self.should_log_to_console,
+            console_format: self.console_format,
             _phantom1: std::marker::PhantomData,
             _phantom2: std::marker::PhantomData,
         }
@@ -452,6 +1498,11 @@ impl<C, R, U, P, E> AppInsights<Ready, C, R, U, P, E> {
 
     /// Sets the sample rate for telemetry.  The default is 1.0.
     /// 
+    /// Below 1.0, a parent-based trace id ratio sampler is installed in place of the sampler of the trace config, and the rate is
+    /// passed to Application Insights as the sampling weight (`itemCount`) of each item, so that the counts in the portal remain
+    /// statistically correct (except for the items that are exported regardless of the rate, which count once).  If you use a custom
+    /// sampler, then use [`AppInsights::with_effective_sample_rate`] instead.
+    /// 
     /// ```
     /// use axum_insights::{AppInsights, Ready};
     /// 
@@ -475,6 +1526,46 @@ impl<C, R, U, P, E> AppInsights<Ready, C, R, U, P, E> {
             field_mapper: self.field_mapper,
             panic_mapper: self.panic_mapper,
             success_filter: self.success_filter,
+            global_dimensions: self.global_dimensions,
+            build_info: self.build_info,
+            service_version: self.service_version,
+            should_close_on_upgrade: self.should_close_on_upgrade,
+            heartbeat_interval: self.heartbeat_interval,
+            should_record_multipart: self.should_record_multipart,
+            should_record_compression: self.should_record_compression,
+            panic_context_headers: self.panic_context_headers,
+            request_sampler: self.request_sampler,
+            debug_trace_header: self.debug_trace_header,
+            operation_grouper: self.operation_grouper,
+            should_extract_trace_context: self.should_extract_trace_context,
+            should_inject_trace_context: self.should_inject_trace_context,
+            effective_sample_rate: self.effective_sample_rate,
+            error_response_headers: self.error_response_headers,
+            should_record_metrics: self.should_record_metrics,
+            propagator: self.propagator,
+            async_field_mapper: self.async_field_mapper,
+            clock: self.clock,
+            fallback_mode: self.fallback_mode,
+            should_deserialize_error_body: self.should_deserialize_error_body,
+            request_filter: self.request_filter,
+            excluded_routes: self.excluded_routes,
+            resource_attributes: self.resource_attributes,
+            should_detect_host_name: self.should_detect_host_name,
+            captured_request_headers: self.captured_request_headers,
+            captured_response_headers: self.captured_response_headers,
+            captured_query_parameters: self.captured_query_parameters,
+            capture_redactor: self.capture_redactor,
+            rate_report_interval: self.rate_report_interval,
+            retry_detector: self.retry_detector,
+            user_mapper: self.user_mapper,
+            tracer_provider: self.tracer_provider,
+            should_bias_errors: self.should_bias_errors,
+            slow_request_threshold: self.slow_request_threshold,
+            otlp_endpoint: self.otlp_endpoint,
+            max_error_body_capture: self.max_error_body_capture,
+            explicit_settings: ExplicitSettings { sample_rate: true, ..self.explicit_settings },
+            should_log_to_console: self.should_log_to_console,
+            console_format: self.console_format,
             _phantom1: std::marker::PhantomData,
             _phantom2: std::marker::PhantomData,
         }
@@ -506,6 +1597,46 @@ impl<C, R, U, P, E> AppInsights<Ready, C, R, U, P, E> {
             field_mapper: self.field_mapper,
             panic_mapper: self.panic_mapper,
             success_filter: self.success_filter,
+            global_dimensions: self.global_dimensions,
+            build_info: self.build_info,
+            service_version: self.service_version,
+            should_close_on_upgrade: self.should_close_on_upgrade,
+            heartbeat_interval: self.heartbeat_interval,
+            should_record_multipart: self.should_record_multipart,
+            should_record_compression: self.should_record_compression,
+            panic_context_headers: self.panic_context_headers,
+            request_sampler: self.request_sampler,
+            debug_trace_header: self.debug_trace_header,
+            operation_grouper: self.operation_grouper,
+            should_extract_trace_context: self.should_extract_trace_context,
+            should_inject_trace_context: self.should_inject_trace_context,
+            effective_sample_rate: self.effective_sample_rate,
+            error_response_headers: self.error_response_headers,
+            should_record_metrics: self.should_record_metrics,
+            propagator: self.propagator,
+            async_field_mapper: self.async_field_mapper,
+            clock: self.clock,
+            fallback_mode: self.fallback_mode,
+            should_deserialize_error_body: self.should_deserialize_error_body,
+            request_filter: self.request_filter,
+            excluded_routes: self.excluded_routes,
+            resource_attributes: self.resource_attributes,
+            should_detect_host_name: self.should_detect_host_name,
+            captured_request_headers: self.captured_request_headers,
+            captured_response_headers: self.captured_response_headers,
+            captured_query_parameters: self.captured_query_parameters,
+            capture_redactor: self.capture_redactor,
+            rate_report_interval: self.rate_report_interval,
+            retry_detector: self.retry_detector,
+            user_mapper: self.user_mapper,
+            tracer_provider: self.tracer_provider,
+            should_bias_errors: self.should_bias_errors,
+            slow_request_threshold: self.slow_request_threshold,
+            otlp_endpoint: self.otlp_endpoint,
+            max_error_body_capture: self.max_error_body_capture,
+            explicit_settings: ExplicitSettings { minimum_level: true, ..self.explicit_settings },
+            should_log_to_console: self.should_log_to_console,
+            console_format: self.console_format,
             _phantom1: std::marker::PhantomData,
             _phantom2: std::marker::PhantomData,
         }
@@ -537,6 +1668,46 @@ impl<C, R, U, P, E> AppInsights<Ready, C, R, U, P, E> {
             field_mapper: self.field_mapper,
             panic_mapper: self.panic_mapper,
             success_filter: self.success_filter,
+            global_dimensions: self.global_dimensions,
+            build_info: self.build_info,
+            service_version: self.service_version,
+            should_close_on_upgrade: self.should_close_on_upgrade,
+            heartbeat_interval: self.heartbeat_interval,
+            should_record_multipart: self.should_record_multipart,
+            should_record_compression: self.should_record_compression,
+            panic_context_headers: self.panic_context_headers,
+            request_sampler: self.request_sampler,
+            debug_trace_header: self.debug_trace_header,
+            operation_grouper: self.operation_grouper,
+            should_extract_trace_context: self.should_extract_trace_context,
+            should_inject_trace_context: self.should_inject_trace_context,
+            effective_sample_rate: self.effective_sample_rate,
+            error_response_headers: self.error_response_headers,
+            should_record_metrics: self.should_record_metrics,
+            propagator: self.propagator,
+            async_field_mapper: self.async_field_mapper,
+            clock: self.clock,
+            fallback_mode: self.fallback_mode,
+            should_deserialize_error_body: self.should_deserialize_error_body,
+            request_filter: self.request_filter,
+            excluded_routes: self.excluded_routes,
+            resource_attributes: self.resource_attributes,
+            should_detect_host_name: self.should_detect_host_name,
+            captured_request_headers: self.captured_request_headers,
+            captured_response_headers: self.captured_response_headers,
+            captured_query_parameters: self.captured_query_parameters,
+            capture_redactor: self.capture_redactor,
+            rate_report_interval: self.rate_report_interval,
+            retry_detector: self.retry_detector,
+            user_mapper: self.user_mapper,
+            tracer_provider: self.tracer_provider,
+            should_bias_errors: self.should_bias_errors,
+            slow_request_threshold: self.slow_request_threshold,
+            otlp_endpoint: self.otlp_endpoint,
+            max_error_body_capture: self.max_error_body_capture,
+            explicit_settings: self.explicit_settings,
+            should_log_to_console: self.should_log_to_console,
+            console_format: self.console_format,
             _phantom1: std::marker::PhantomData,
             _phantom2: std::marker::PhantomData,
         }
@@ -571,6 +1742,46 @@ impl<C, R, U, P, E> AppInsights<Ready, C, R, U, P, E> {
             field_mapper: self.field_mapper,
             panic_mapper: self.panic_mapper,
             success_filter: self.success_filter,
+            global_dimensions: self.global_dimensions,
+            build_info: self.build_info,
+            service_version: self.service_version,
+            should_close_on_upgrade: self.should_close_on_upgrade,
+            heartbeat_interval: self.heartbeat_interval,
+            should_record_multipart: self.should_record_multipart,
+            should_record_compression: self.should_record_compression,
+            panic_context_headers: self.panic_context_headers,
+            request_sampler: self.request_sampler,
+            debug_trace_header: self.debug_trace_header,
+            operation_grouper: self.operation_grouper,
+            should_extract_trace_context: self.should_extract_trace_context,
+            should_inject_trace_context: self.should_inject_trace_context,
+            effective_sample_rate: self.effective_sample_rate,
+            error_response_headers: self.error_response_headers,
+            should_record_metrics: self.should_record_metrics,
+            propagator: self.propagator,
+            async_field_mapper: self.async_field_mapper,
+            clock: self.clock,
+            fallback_mode: self.fallback_mode,
+            should_deserialize_error_body: self.should_deserialize_error_body,
+            request_filter: self.request_filter,
+            excluded_routes: self.excluded_routes,
+            resource_attributes: self.resource_attributes,
+            should_detect_host_name: self.should_detect_host_name,
+            captured_request_headers: self.captured_request_headers,
+            captured_response_headers: self.captured_response_headers,
+            captured_query_parameters: self.captured_query_parameters,
+            capture_redactor: self.capture_redactor,
+            rate_report_interval: self.rate_report_interval,
+            retry_detector: self.retry_detector,
+            user_mapper: self.user_mapper,
+            tracer_provider: self.tracer_provider,
+            should_bias_errors: self.should_bias_errors,
+            slow_request_threshold: self.slow_request_threshold,
+            otlp_endpoint: self.otlp_endpoint,
+            max_error_body_capture: self.max_error_body_capture,
+            explicit_settings: self.explicit_settings,
+            should_log_to_console: self.should_log_to_console,
+            console_format: self.console_format,
             _phantom1: std::marker::PhantomData,
             _phantom2: std::marker::PhantomData,
         }
@@ -601,15 +1812,210 @@ impl<C, R, U, P, E> AppInsights<Ready, C, R, U, P, E> {
             field_mapper: self.field_mapper,
             panic_mapper: self.panic_mapper,
             success_filter: self.success_filter,
+            global_dimensions: self.global_dimensions,
+            build_info: self.build_info,
+            service_version: self.service_version,
+            should_close_on_upgrade: self.should_close_on_upgrade,
+            heartbeat_interval: self.heartbeat_interval,
+            should_record_multipart: self.should_record_multipart,
+            should_record_compression: self.should_record_compression,
+            panic_context_headers: self.panic_context_headers,
+            request_sampler: self.request_sampler,
+            debug_trace_header: self.debug_trace_header,
+            operation_grouper: self.operation_grouper,
+            should_extract_trace_context: self.should_extract_trace_context,
+            should_inject_trace_context: self.should_inject_trace_context,
+            effective_sample_rate: self.effective_sample_rate,
+            error_response_headers: self.error_response_headers,
+            should_record_metrics: self.should_record_metrics,
+            propagator: self.propagator,
+            async_field_mapper: self.async_field_mapper,
+            clock: self.clock,
+            fallback_mode: self.fallback_mode,
+            should_deserialize_error_body: self.should_deserialize_error_body,
+            request_filter: self.request_filter,
+            excluded_routes: self.excluded_routes,
+            resource_attributes: self.resource_attributes,
+            should_detect_host_name: self.should_detect_host_name,
+            captured_request_headers: self.captured_request_headers,
+            captured_response_headers: self.captured_response_headers,
+            captured_query_parameters: self.captured_query_parameters,
+            capture_redactor: self.capture_redactor,
+            rate_report_interval: self.rate_report_interval,
+            retry_detector: self.retry_detector,
+            user_mapper: self.user_mapper,
+            tracer_provider: self.tracer_provider,
+            should_bias_errors: self.should_bias_errors,
+            slow_request_threshold: self.slow_request_threshold,
+            otlp_endpoint: self.otlp_endpoint,
+            max_error_body_capture: self.max_error_body_capture,
+            explicit_settings: self.explicit_settings,
+            should_log_to_console: self.should_log_to_console,
+            console_format: self.console_format,
             _phantom1: std::marker::PhantomData,
             _phantom2: std::marker::PhantomData,
         }
     }
 
-    /// Sets whether or not to make this telemetry layer a noop.  The default is false.
+    /// Sets whether or not to log the spans and events to the console (stdout) as well, with a `tracing_subscriber` fmt layer.
+    /// The default is false.
     /// 
-    /// This is useful whenever you are running axum tests, as the global subscriber cannot be
-    /// set in a multiple times.  Effectively, this causes the telemetry layer to be a no-op.
+    /// The format is chosen by the profile (see [`AppInsights::with_profile`]): pretty-printed for development, and compact
+    /// otherwise (and without a profile).  With [`AppInsights::build_layer`], the console is part of the returned layer, and it is not
+    /// filtered by the minimum level.
+    /// 
+    /// ```
+    /// use axum_insights::{AppInsights, Profile, Ready};
+    /// 
+    /// let i: AppInsights<Ready> = AppInsights::default()
+    ///     .with_connection_string(None)
+    ///     .with_service_config("namespace", "name")
+    ///     .with_profile(Profile::Development)
+    ///     .with_console(true);
+    /// ```
+    pub fn with_console(self, should_log_to_console: bool) -> AppInsights<Ready, C, R, U, P, E> {
+        AppInsights {
+            connection_string: self.connection_string,
+            config: self.config,
+            client: self.client,
+            enable_live_metrics: self.enable_live_metrics,
+            sample_rate: self.sample_rate,
+            batch_runtime: self.batch_runtime,
+            minimum_level: self.minimum_level,
+            subscriber: self.subscriber,
+            should_catch_panic: self.should_catch_panic,
+            is_noop: self.is_noop,
+            field_mapper: self.field_mapper,
+            panic_mapper: self.panic_mapper,
+            success_filter: self.success_filter,
+            global_dimensions: self.global_dimensions,
+            build_info: self.build_info,
+            service_version: self.service_version,
+            should_close_on_upgrade: self.should_close_on_upgrade,
+            heartbeat_interval: self.heartbeat_interval,
+            should_record_multipart: self.should_record_multipart,
+            should_record_compression: self.should_record_compression,
+            panic_context_headers: self.panic_context_headers,
+            request_sampler: self.request_sampler,
+            debug_trace_header: self.debug_trace_header,
+            operation_grouper: self.operation_grouper,
+            should_extract_trace_context: self.should_extract_trace_context,
+            should_inject_trace_context: self.should_inject_trace_context,
+            effective_sample_rate: self.effective_sample_rate,
+            error_response_headers: self.error_response_headers,
+            should_record_metrics: self.should_record_metrics,
+            propagator: self.propagator,
+            async_field_mapper: self.async_field_mapper,
+            clock: self.clock,
+            fallback_mode: self.fallback_mode,
+            should_deserialize_error_body: self.should_deserialize_error_body,
+            request_filter: self.request_filter,
+            excluded_routes: self.excluded_routes,
+            resource_attributes: self.resource_attributes,
+            should_detect_host_name: self.should_detect_host_name,
+            captured_request_headers: self.captured_request_headers,
+            captured_response_headers: self.captured_response_headers,
+            captured_query_parameters: self.captured_query_parameters,
+            capture_redactor: self.capture_redactor,
+            rate_report_interval: self.rate_report_interval,
+            retry_detector: self.retry_detector,
+            user_mapper: self.user_mapper,
+            tracer_provider: self.tracer_provider,
+            should_bias_errors: self.should_bias_errors,
+            slow_request_threshold: self.slow_request_threshold,
+            otlp_endpoint: self.otlp_endpoint,
+            max_error_body_capture: self.max_error_body_capture,
+            explicit_settings: self.explicit_settings,
+            should_log_to_console,
+            console_format: self.console_format,
+            _phantom1: std::marker::PhantomData,
+            _phantom2: std::marker::PhantomData,
+        }
+    }
+
+    /// Sets how to degrade when the connection string is invalid.  The default is to fail the build.
+    /// 
+    /// This is useful when a bad connection string in one environment should degrade telemetry, rather than
+    /// prevent the application from starting.  In [`FallbackMode::Noop`], the error is emitted as a warning to the
+    /// current subscriber (if any), since no subscriber is installed.  In [`FallbackMode::LocalLogOnly`], the error is
+    /// emitted as a warning to the local subscriber.  In either mode, the error is also available from
+    /// [`AppInsightsComplete::fallback_error`], so that the application can report it however it likes.
+    /// 
+    /// ```
+    /// use axum_insights::{AppInsights, FallbackMode, Ready};
+    /// 
+    /// let i = AppInsights::default()
+    ///     .with_connection_string(Some("not a connection string".to_owned()))
+    ///     .with_service_config("namespace", "name")
+    ///     .with_fallback_on_error(FallbackMode::LocalLogOnly);
+    /// ```
+    pub fn with_fallback_on_error(self, fallback_mode: FallbackMode) -> AppInsights<Ready, C, R, U, P, E> {
+        AppInsights {
+            connection_string: self.connection_string,
+            config: self.config,
+            client: self.client,
+            enable_live_metrics: self.enable_live_metrics,
+            sample_rate: self.sample_rate,
+            batch_runtime: self.batch_runtime,
+            minimum_level: self.minimum_level,
+            subscriber: self.subscriber,
+            should_catch_panic: self.should_catch_panic,
+            is_noop: self.is_noop,
+            field_mapper: self.field_mapper,
+            panic_mapper: self.panic_mapper,
+            success_filter: self.success_filter,
+            global_dimensions: self.global_dimensions,
+            build_info: self.build_info,
+            service_version: self.service_version,
+            should_close_on_upgrade: self.should_close_on_upgrade,
+            heartbeat_interval: self.heartbeat_interval,
+            should_record_multipart: self.should_record_multipart,
+            should_record_compression: self.should_record_compression,
+            panic_context_headers: self.panic_context_headers,
+            request_sampler: self.request_sampler,
+            debug_trace_header: self.debug_trace_header,
+            operation_grouper: self.operation_grouper,
+            should_extract_trace_context: self.should_extract_trace_context,
+            should_inject_trace_context: self.should_inject_trace_context,
+            effective_sample_rate: self.effective_sample_rate,
+            error_response_headers: self.error_response_headers,
+            should_record_metrics: self.should_record_metrics,
+            propagator: self.propagator,
+            async_field_mapper: self.async_field_mapper,
+            clock: self.clock,
+            fallback_mode: Some(fallback_mode),
+            should_deserialize_error_body: self.should_deserialize_error_body,
+            request_filter: self.request_filter,
+            excluded_routes: self.excluded_routes,
+            resource_attributes: self.resource_attributes,
+            should_detect_host_name: self.should_detect_host_name,
+            captured_request_headers: self.captured_request_headers,
+            captured_response_headers: self.captured_response_headers,
+            captured_query_parameters: self.captured_query_parameters,
+            capture_redactor: self.capture_redactor,
+            rate_report_interval: self.rate_report_interval,
+            retry_detector: self.retry_detector,
+            user_mapper: self.user_mapper,
+            tracer_provider: self.tracer_provider,
+            should_bias_errors: self.should_bias_errors,
+            slow_request_threshold: self.slow_request_threshold,
+            otlp_endpoint: self.otlp_endpoint,
+            max_error_body_capture: self.max_error_body_capture,
+            explicit_settings: self.explicit_settings,
+            should_log_to_console: self.should_log_to_console,
+            console_format: self.console_format,
+            _phantom1: std::marker::PhantomData,
+            _phantom2: std::marker::PhantomData,
+        }
+    }
+
+    /// Sets whether or not to make this telemetry layer a noop.  The default is false.
+    /// 
+    /// This is useful whenever you are running axum tests, as the global subscriber cannot be
+    /// set in a multiple times.  Effectively, this causes the telemetry layer to be a no-op.
+    /// 
+    /// In noop mode, the middleware does not allocate or inspect the request (not even the headers),
+    /// so it is safe to leave the layer permanently in place for test builds.
     /// 
     /// ```
     /// use axum_insights::{AppInsights, Ready};
@@ -634,6 +2040,352 @@ impl<C, R, U, P, E> AppInsights<Ready, C, R, U, P, E> {
             field_mapper: self.field_mapper,
             panic_mapper: self.panic_mapper,
             success_filter: self.success_filter,
+            global_dimensions: self.global_dimensions,
+            build_info: self.build_info,
+            service_version: self.service_version,
+            should_close_on_upgrade: self.should_close_on_upgrade,
+            heartbeat_interval: self.heartbeat_interval,
+            should_record_multipart: self.should_record_multipart,
+            should_record_compression: self.should_record_compression,
+            panic_context_headers: self.panic_context_headers,
+            request_sampler: self.request_sampler,
+            debug_trace_header: self.debug_trace_header,
+            operation_grouper: self.operation_grouper,
+            should_extract_trace_context: self.should_extract_trace_context,
+            should_inject_trace_context: self.should_inject_trace_context,
+            effective_sample_rate: self.effective_sample_rate,
+            error_response_headers: self.error_response_headers,
+            should_record_metrics: self.should_record_metrics,
+            propagator: self.propagator,
+            async_field_mapper: self.async_field_mapper,
+            clock: self.clock,
+            fallback_mode: self.fallback_mode,
+            should_deserialize_error_body: self.should_deserialize_error_body,
+            request_filter: self.request_filter,
+            excluded_routes: self.excluded_routes,
+            resource_attributes: self.resource_attributes,
+            should_detect_host_name: self.should_detect_host_name,
+            captured_request_headers: self.captured_request_headers,
+            captured_response_headers: self.captured_response_headers,
+            captured_query_parameters: self.captured_query_parameters,
+            capture_redactor: self.capture_redactor,
+            rate_report_interval: self.rate_report_interval,
+            retry_detector: self.retry_detector,
+            user_mapper: self.user_mapper,
+            tracer_provider: self.tracer_provider,
+            should_bias_errors: self.should_bias_errors,
+            slow_request_threshold: self.slow_request_threshold,
+            otlp_endpoint: self.otlp_endpoint,
+            max_error_body_capture: self.max_error_body_capture,
+            explicit_settings: self.explicit_settings,
+            should_log_to_console: self.should_log_to_console,
+            console_format: self.console_format,
+            _phantom1: std::marker::PhantomData,
+            _phantom2: std::marker::PhantomData,
+        }
+    }
+
+    /// Sets whether or not to finalize the request span when a connection is upgraded.  The default is false.
+    /// 
+    /// When enabled, a `101 Switching Protocols` response (e.g., a websocket or tunnel) finishes the request span
+    /// immediately with an `upgraded = true` attribute, and none of the error handling is applied to the response.
+    /// The connection itself is not tracked afterwards, so long-lived sessions do not show up as multi-hour requests.
+    /// Note that any spans that are explicitly parented to the request span from the connection task will still keep
+    /// the request span open, so those tasks should create their own root spans.
+    /// 
+    /// Since the span is finished before the response is inspected any further, an upgraded request only records its status
+    /// and `upgraded`: the captured response headers (see [`AppInsights::with_captured_response_headers`]), the route tags (see
+    /// [`with_route_tags`]), the request metrics, the rate report, the debug trace body, the heartbeats, the response body size,
+    /// and the injected `traceparent` header are all skipped.
+    /// 
+    /// ```
+    /// use axum_insights::{AppInsights, Ready};
+    /// 
+    /// let i: AppInsights<Ready> = AppInsights::default()
+    ///     .with_connection_string(None)
+    ///     .with_service_config("namespace", "name")
+    ///     .with_close_on_upgrade(true);
+    /// ```
+    pub fn with_close_on_upgrade(self, should_close_on_upgrade: bool) -> AppInsights<Ready, C, R, U, P, E> {
+        AppInsights {
+            connection_string: self.connection_string,
+            config: self.config,
+            client: self.client,
+            enable_live_metrics: self.enable_live_metrics,
+            sample_rate: self.sample_rate,
+            batch_runtime: self.batch_runtime,
+            minimum_level: self.minimum_level,
+            subscriber: self.subscriber,
+            should_catch_panic: self.should_catch_panic,
+            is_noop: self.is_noop,
+            field_mapper: self.field_mapper,
+            panic_mapper: self.panic_mapper,
+            success_filter: self.success_filter,
+            global_dimensions: self.global_dimensions,
+            build_info: self.build_info,
+            service_version: self.service_version,
+            should_close_on_upgrade,
+            heartbeat_interval: self.heartbeat_interval,
+            should_record_multipart: self.should_record_multipart,
+            should_record_compression: self.should_record_compression,
+            panic_context_headers: self.panic_context_headers,
+            request_sampler: self.request_sampler,
+            debug_trace_header: self.debug_trace_header,
+            operation_grouper: self.operation_grouper,
+            should_extract_trace_context: self.should_extract_trace_context,
+            should_inject_trace_context: self.should_inject_trace_context,
+            effective_sample_rate: self.effective_sample_rate,
+            error_response_headers: self.error_response_headers,
+            should_record_metrics: self.should_record_metrics,
+            propagator: self.propagator,
+            async_field_mapper: self.async_field_mapper,
+            clock: self.clock,
+            fallback_mode: self.fallback_mode,
+            should_deserialize_error_body: self.should_deserialize_error_body,
+            request_filter: self.request_filter,
+            excluded_routes: self.excluded_routes,
+            resource_attributes: self.resource_attributes,
+            should_detect_host_name: self.should_detect_host_name,
+            captured_request_headers: self.captured_request_headers,
+            captured_response_headers: self.captured_response_headers,
+            captured_query_parameters: self.captured_query_parameters,
+            capture_redactor: self.capture_redactor,
+            rate_report_interval: self.rate_report_interval,
+            retry_detector: self.retry_detector,
+            user_mapper: self.user_mapper,
+            tracer_provider: self.tracer_provider,
+            should_bias_errors: self.should_bias_errors,
+            slow_request_threshold: self.slow_request_threshold,
+            otlp_endpoint: self.otlp_endpoint,
+            max_error_body_capture: self.max_error_body_capture,
+            explicit_settings: self.explicit_settings,
+            should_log_to_console: self.should_log_to_console,
+            console_format: self.console_format,
+            _phantom1: std::marker::PhantomData,
+            _phantom2: std::marker::PhantomData,
+        }
+    }
+
+    /// Sets the interval at which long-lived responses emit heartbeat events.  The default is no heartbeats.
+    /// 
+    /// When set, every response body that is still streaming (e.g., server-sent events) after the interval emits a
+    /// `connection.heartbeat` custom event with the bytes and messages transferred so far, so that long-lived sessions
+    /// are observable while they are active.  Upgraded connections (e.g., websockets) are not visible to the middleware,
+    /// so use a [`ConnectionHeartbeat`] for those.
+    /// 
+    /// ```
+    /// use axum_insights::{AppInsights, Ready};
+    /// use std::time::Duration;
+    /// 
+    /// let i: AppInsights<Ready> = AppInsights::default()
+    ///     .with_connection_string(None)
+    ///     .with_service_config("namespace", "name")
+    ///     .with_connection_heartbeat(Duration::from_secs(60));
+    /// ```
+    pub fn with_connection_heartbeat(self, interval: Duration) -> AppInsights<Ready, C, R, U, P, E> {
+        AppInsights {
+            connection_string: self.connection_string,
+            config: self.config,
+            client: self.client,
+            enable_live_metrics: self.enable_live_metrics,
+            sample_rate: self.sample_rate,
+            batch_runtime: self.batch_runtime,
+            minimum_level: self.minimum_level,
+            subscriber: self.subscriber,
+            should_catch_panic: self.should_catch_panic,
+            is_noop: self.is_noop,
+            field_mapper: self.field_mapper,
+            panic_mapper: self.panic_mapper,
+            success_filter: self.success_filter,
+            global_dimensions: self.global_dimensions,
+            build_info: self.build_info,
+            service_version: self.service_version,
+            should_close_on_upgrade: self.should_close_on_upgrade,
+            heartbeat_interval: Some(interval),
+            should_record_multipart: self.should_record_multipart,
+            should_record_compression: self.should_record_compression,
+            panic_context_headers: self.panic_context_headers,
+            request_sampler: self.request_sampler,
+            debug_trace_header: self.debug_trace_header,
+            operation_grouper: self.operation_grouper,
+            should_extract_trace_context: self.should_extract_trace_context,
+            should_inject_trace_context: self.should_inject_trace_context,
+            effective_sample_rate: self.effective_sample_rate,
+            error_response_headers: self.error_response_headers,
+            should_record_metrics: self.should_record_metrics,
+            propagator: self.propagator,
+            async_field_mapper: self.async_field_mapper,
+            clock: self.clock,
+            fallback_mode: self.fallback_mode,
+            should_deserialize_error_body: self.should_deserialize_error_body,
+            request_filter: self.request_filter,
+            excluded_routes: self.excluded_routes,
+            resource_attributes: self.resource_attributes,
+            should_detect_host_name: self.should_detect_host_name,
+            captured_request_headers: self.captured_request_headers,
+            captured_response_headers: self.captured_response_headers,
+            captured_query_parameters: self.captured_query_parameters,
+            capture_redactor: self.capture_redactor,
+            rate_report_interval: self.rate_report_interval,
+            retry_detector: self.retry_detector,
+            user_mapper: self.user_mapper,
+            tracer_provider: self.tracer_provider,
+            should_bias_errors: self.should_bias_errors,
+            slow_request_threshold: self.slow_request_threshold,
+            otlp_endpoint: self.otlp_endpoint,
+            max_error_body_capture: self.max_error_body_capture,
+            explicit_settings: self.explicit_settings,
+            should_log_to_console: self.should_log_to_console,
+            console_format: self.console_format,
+            _phantom1: std::marker::PhantomData,
+            _phantom2: std::marker::PhantomData,
+        }
+    }
+
+    /// Sets whether or not to record a summary of multipart uploads.  The default is false.
+    /// 
+    /// When enabled, `multipart/*` request bodies are scanned as the handler reads them, and the total upload size
+    /// (`http.request.body.size`), the number of parts (`multipart.parts`), and the distinct part content types
+    /// (`multipart.content_types`) are recorded on the request span.  No part content is recorded or buffered.
+    /// 
+    /// ```
+    /// use axum_insights::{AppInsights, Ready};
+    /// 
+    /// let i: AppInsights<Ready> = AppInsights::default()
+    ///     .with_connection_string(None)
+    ///     .with_service_config("namespace", "name")
+    ///     .with_multipart_attributes(true);
+    /// ```
+    pub fn with_multipart_attributes(self, should_record_multipart: bool) -> AppInsights<Ready, C, R, U, P, E> {
+        AppInsights {
+            connection_string: self.connection_string,
+            config: self.config,
+            client: self.client,
+            enable_live_metrics: self.enable_live_metrics,
+            sample_rate: self.sample_rate,
+            batch_runtime: self.batch_runtime,
+            minimum_level: self.minimum_level,
+            subscriber: self.subscriber,
+            should_catch_panic: self.should_catch_panic,
+            is_noop: self.is_noop,
+            field_mapper: self.field_mapper,
+            panic_mapper: self.panic_mapper,
+            success_filter: self.success_filter,
+            global_dimensions: self.global_dimensions,
+            build_info: self.build_info,
+            service_version: self.service_version,
+            should_close_on_upgrade: self.should_close_on_upgrade,
+            heartbeat_interval: self.heartbeat_interval,
+            should_record_multipart,
+            should_record_compression: self.should_record_compression,
+            panic_context_headers: self.panic_context_headers,
+            request_sampler: self.request_sampler,
+            debug_trace_header: self.debug_trace_header,
+            operation_grouper: self.operation_grouper,
+            should_extract_trace_context: self.should_extract_trace_context,
+            should_inject_trace_context: self.should_inject_trace_context,
+            effective_sample_rate: self.effective_sample_rate,
+            error_response_headers: self.error_response_headers,
+            should_record_metrics: self.should_record_metrics,
+            propagator: self.propagator,
+            async_field_mapper: self.async_field_mapper,
+            clock: self.clock,
+            fallback_mode: self.fallback_mode,
+            should_deserialize_error_body: self.should_deserialize_error_body,
+            request_filter: self.request_filter,
+            excluded_routes: self.excluded_routes,
+            resource_attributes: self.resource_attributes,
+            should_detect_host_name: self.should_detect_host_name,
+            captured_request_headers: self.captured_request_headers,
+            captured_response_headers: self.captured_response_headers,
+            captured_query_parameters: self.captured_query_parameters,
+            capture_redactor: self.capture_redactor,
+            rate_report_interval: self.rate_report_interval,
+            retry_detector: self.retry_detector,
+            user_mapper: self.user_mapper,
+            tracer_provider: self.tracer_provider,
+            should_bias_errors: self.should_bias_errors,
+            slow_request_threshold: self.slow_request_threshold,
+            otlp_endpoint: self.otlp_endpoint,
+            max_error_body_capture: self.max_error_body_capture,
+            explicit_settings: self.explicit_settings,
+            should_log_to_console: self.should_log_to_console,
+            console_format: self.console_format,
+            _phantom1: std::marker::PhantomData,
+            _phantom2: std::marker::PhantomData,
+        }
+    }
+
+    /// Sets whether or not to record compression related attributes.  The default is false.
+    /// 
+    /// When enabled, the request and response `content-encoding` headers are recorded on the request span, along with
+    /// the request and response body sizes, which are counted as the bodies stream (so chunked bodies are covered as well).
+    /// The sizes are the sizes that this layer sees, so if this layer wraps a compression layer, the recorded response size
+    /// is the compressed size, which can be compared against uncompressed responses for the same route.
+    /// 
+    /// ```
+    /// use axum_insights::{AppInsights, Ready};
+    /// 
+    /// let i: AppInsights<Ready> = AppInsights::default()
+    ///     .with_connection_string(None)
+    ///     .with_service_config("namespace", "name")
+    ///     .with_compression_attributes(true);
+    /// ```
+    pub fn with_compression_attributes(self, should_record_compression: bool) -> AppInsights<Ready, C, R, U, P, E> {
+        AppInsights {
+            connection_string: self.connection_string,
+            config: self.config,
+            client: self.client,
+            enable_live_metrics: self.enable_live_metrics,
+            sample_rate: self.sample_rate,
+            batch_runtime: self.batch_runtime,
+            minimum_level: self.minimum_level,
+            subscriber: self.subscriber,
+            should_catch_panic: self.should_catch_panic,
+            is_noop: self.is_noop,
+            field_mapper: self.field_mapper,
+            panic_mapper: self.panic_mapper,
+            success_filter: self.success_filter,
+            global_dimensions: self.global_dimensions,
+            build_info: self.build_info,
+            service_version: self.service_version,
+            should_close_on_upgrade: self.should_close_on_upgrade,
+            heartbeat_interval: self.heartbeat_interval,
+            should_record_multipart: self.should_record_multipart,
+            should_record_compression,
+            panic_context_headers: self.panic_context_headers,
+            request_sampler: self.request_sampler,
+            debug_trace_header: self.debug_trace_header,
+            operation_grouper: self.operation_grouper,
+            should_extract_trace_context: self.should_extract_trace_context,
+            should_inject_trace_context: self.should_inject_trace_context,
+            effective_sample_rate: self.effective_sample_rate,
+            error_response_headers: self.error_response_headers,
+            should_record_metrics: self.should_record_metrics,
+            propagator: self.propagator,
+            async_field_mapper: self.async_field_mapper,
+            clock: self.clock,
+            fallback_mode: self.fallback_mode,
+            should_deserialize_error_body: self.should_deserialize_error_body,
+            request_filter: self.request_filter,
+            excluded_routes: self.excluded_routes,
+            resource_attributes: self.resource_attributes,
+            should_detect_host_name: self.should_detect_host_name,
+            captured_request_headers: self.captured_request_headers,
+            captured_response_headers: self.captured_response_headers,
+            captured_query_parameters: self.captured_query_parameters,
+            capture_redactor: self.capture_redactor,
+            rate_report_interval: self.rate_report_interval,
+            retry_detector: self.retry_detector,
+            user_mapper: self.user_mapper,
+            tracer_provider: self.tracer_provider,
+            should_bias_errors: self.should_bias_errors,
+            slow_request_threshold: self.slow_request_threshold,
+            otlp_endpoint: self.otlp_endpoint,
+            max_error_body_capture: self.max_error_body_capture,
+            explicit_settings: self.explicit_settings,
+            should_log_to_console: self.should_log_to_console,
+            console_format: self.console_format,
             _phantom1: std::marker::PhantomData,
             _phantom2: std::marker::PhantomData,
         }
@@ -672,6 +2424,133 @@ impl<C, R, U, P, E> AppInsights<Ready, C, R, U, P, E> {
             field_mapper: Some(Arc::new(field_mapper)),
             panic_mapper: self.panic_mapper,
             success_filter: self.success_filter,
+            global_dimensions: self.global_dimensions,
+            build_info: self.build_info,
+            service_version: self.service_version,
+            should_close_on_upgrade: self.should_close_on_upgrade,
+            heartbeat_interval: self.heartbeat_interval,
+            should_record_multipart: self.should_record_multipart,
+            should_record_compression: self.should_record_compression,
+            panic_context_headers: self.panic_context_headers,
+            request_sampler: self.request_sampler,
+            debug_trace_header: self.debug_trace_header,
+            operation_grouper: self.operation_grouper,
+            should_extract_trace_context: self.should_extract_trace_context,
+            should_inject_trace_context: self.should_inject_trace_context,
+            effective_sample_rate: self.effective_sample_rate,
+            error_response_headers: self.error_response_headers,
+            should_record_metrics: self.should_record_metrics,
+            propagator: self.propagator,
+            async_field_mapper: self.async_field_mapper,
+            clock: self.clock,
+            fallback_mode: self.fallback_mode,
+            should_deserialize_error_body: self.should_deserialize_error_body,
+            request_filter: self.request_filter,
+            excluded_routes: self.excluded_routes,
+            resource_attributes: self.resource_attributes,
+            should_detect_host_name: self.should_detect_host_name,
+            captured_request_headers: self.captured_request_headers,
+            captured_response_headers: self.captured_response_headers,
+            captured_query_parameters: self.captured_query_parameters,
+            capture_redactor: self.capture_redactor,
+            rate_report_interval: self.rate_report_interval,
+            retry_detector: self.retry_detector,
+            user_mapper: self.user_mapper,
+            tracer_provider: self.tracer_provider,
+            should_bias_errors: self.should_bias_errors,
+            slow_request_threshold: self.slow_request_threshold,
+            otlp_endpoint: self.otlp_endpoint,
+            max_error_body_capture: self.max_error_body_capture,
+            explicit_settings: self.explicit_settings,
+            should_log_to_console: self.should_log_to_console,
+            console_format: self.console_format,
+            _phantom1: std::marker::PhantomData,
+            _phantom2: std::marker::PhantomData,
+        }
+    }
+
+    /// Sets an async function to extract extra fields from the request.  The default is no extra fields.
+    /// 
+    /// This is useful when the extra fields require an `.await` (e.g., looking up tenant information in a cache).  The function
+    /// receives the request parts, and returns a future that is awaited within the request span, before the handler runs.  The
+    /// fields are merged with (and take precedence over) the fields from [`AppInsights::with_field_mapper`].
+    /// 
+    /// ```
+    /// use axum_insights::{AppInsights, Ready};
+    /// use futures::FutureExt;
+    /// use std::collections::HashMap;
+    /// 
+    /// let i = AppInsights::default()
+    ///     .with_connection_string(None)
+    ///     .with_service_config("namespace", "name")
+    ///     .with_async_field_mapper(|parts| {
+    ///         let tenant = parts.headers.get("x-tenant").and_then(|v| v.to_str().ok()).unwrap_or_default().to_owned();
+    /// 
+    ///         async move {
+    ///             // Look up the tenant in a cache, for example.
+    ///             HashMap::from([("tenant".to_owned(), tenant)])
+    ///         }
+    ///         .boxed()
+    ///     });
+    /// ```
+    pub fn with_async_field_mapper<F>(self, async_field_mapper: F) -> AppInsights<Ready, C, R, U, P, E>
+    where
+        F: Fn(&http::request::Parts) -> BoxFuture<'static, HashMap<String, String>> + Send + Sync + 'static,
+    {
+        AppInsights {
+            connection_string: self.connection_string,
+            config: self.config,
+            client: self.client,
+            enable_live_metrics: self.enable_live_metrics,
+            sample_rate: self.sample_rate,
+            batch_runtime: self.batch_runtime,
+            minimum_level: self.minimum_level,
+            subscriber: self.subscriber,
+            should_catch_panic: self.should_catch_panic,
+            is_noop: self.is_noop,
+            field_mapper: self.field_mapper,
+            panic_mapper: self.panic_mapper,
+            success_filter: self.success_filter,
+            global_dimensions: self.global_dimensions,
+            build_info: self.build_info,
+            service_version: self.service_version,
+            should_close_on_upgrade: self.should_close_on_upgrade,
+            heartbeat_interval: self.heartbeat_interval,
+            should_record_multipart: self.should_record_multipart,
+            should_record_compression: self.should_record_compression,
+            panic_context_headers: self.panic_context_headers,
+            request_sampler: self.request_sampler,
+            debug_trace_header: self.debug_trace_header,
+            operation_grouper: self.operation_grouper,
+            should_extract_trace_context: self.should_extract_trace_context,
+            should_inject_trace_context: self.should_inject_trace_context,
+            effective_sample_rate: self.effective_sample_rate,
+            error_response_headers: self.error_response_headers,
+            should_record_metrics: self.should_record_metrics,
+            propagator: self.propagator,
+            async_field_mapper: Some(Arc::new(async_field_mapper)),
+            clock: self.clock,
+            fallback_mode: self.fallback_mode,
+            should_deserialize_error_body: self.should_deserialize_error_body,
+            request_filter: self.request_filter,
+            excluded_routes: self.excluded_routes,
+            resource_attributes: self.resource_attributes,
+            should_detect_host_name: self.should_detect_host_name,
+            captured_request_headers: self.captured_request_headers,
+            captured_response_headers: self.captured_response_headers,
+            captured_query_parameters: self.captured_query_parameters,
+            capture_redactor: self.capture_redactor,
+            rate_report_interval: self.rate_report_interval,
+            retry_detector: self.retry_detector,
+            user_mapper: self.user_mapper,
+            tracer_provider: self.tracer_provider,
+            should_bias_errors: self.should_bias_errors,
+            slow_request_threshold: self.slow_request_threshold,
+            otlp_endpoint: self.otlp_endpoint,
+            max_error_body_capture: self.max_error_body_capture,
+            explicit_settings: self.explicit_settings,
+            should_log_to_console: self.should_log_to_console,
+            console_format: self.console_format,
             _phantom1: std::marker::PhantomData,
             _phantom2: std::marker::PhantomData,
         }
@@ -709,32 +2588,76 @@ impl<C, R, U, P, E> AppInsights<Ready, C, R, U, P, E> {
             should_catch_panic: self.should_catch_panic,
             is_noop: self.is_noop,
             field_mapper: self.field_mapper,
-            panic_mapper: Some(Arc::new(panic_mapper)),
+            panic_mapper: Some(Arc::new(move |panic, _: &PanicContext| panic_mapper(panic))),
             success_filter: self.success_filter,
+            global_dimensions: self.global_dimensions,
+            build_info: self.build_info,
+            service_version: self.service_version,
+            should_close_on_upgrade: self.should_close_on_upgrade,
+            heartbeat_interval: self.heartbeat_interval,
+            should_record_multipart: self.should_record_multipart,
+            should_record_compression: self.should_record_compression,
+            panic_context_headers: self.panic_context_headers,
+            request_sampler: self.request_sampler,
+            debug_trace_header: self.debug_trace_header,
+            operation_grouper: self.operation_grouper,
+            should_extract_trace_context: self.should_extract_trace_context,
+            should_inject_trace_context: self.should_inject_trace_context,
+            effective_sample_rate: self.effective_sample_rate,
+            error_response_headers: self.error_response_headers,
+            should_record_metrics: self.should_record_metrics,
+            propagator: self.propagator,
+            async_field_mapper: self.async_field_mapper,
+            clock: self.clock,
+            fallback_mode: self.fallback_mode,
+            should_deserialize_error_body: self.should_deserialize_error_body,
+            request_filter: self.request_filter,
+            excluded_routes: self.excluded_routes,
+            resource_attributes: self.resource_attributes,
+            should_detect_host_name: self.should_detect_host_name,
+            captured_request_headers: self.captured_request_headers,
+            captured_response_headers: self.captured_response_headers,
+            captured_query_parameters: self.captured_query_parameters,
+            capture_redactor: self.capture_redactor,
+            rate_report_interval: self.rate_report_interval,
+            retry_detector: self.retry_detector,
+            user_mapper: self.user_mapper,
+            tracer_provider: self.tracer_provider,
+            should_bias_errors: self.should_bias_errors,
+            slow_request_threshold: self.slow_request_threshold,
+            otlp_endpoint: self.otlp_endpoint,
+            max_error_body_capture: self.max_error_body_capture,
+            explicit_settings: self.explicit_settings,
+            should_log_to_console: self.should_log_to_console,
+            console_format: self.console_format,
             _phantom1: std::marker::PhantomData,
             _phantom2: std::marker::PhantomData,
         }
     }
 
-    /// Sets a function to determine the success-iness of a status.  The default is (100 - 399 => true).
+    /// Sets a function to extract extra fields from a panic, with access to a snapshot of the request that panicked.
     /// 
-    /// This allows you to fine-tune which statuses are considered successful, and which are not.  If you have
-    /// lots of spurious 404s, for example, you can add that to the success statuses.
+    /// This is a variant of [`AppInsights::with_panic_mapper`] that also receives the method, route, and any headers selected
+    /// with [`AppInsights::with_panic_context_headers`], so that the panic response can include which endpoint blew up.
     /// 
     /// ```
     /// use axum_insights::{AppInsights, Ready};
-    /// use http::StatusCode;
+    /// 
+    /// struct WebError {
+    ///     message: String,
+    /// }
     /// 
     /// let i = AppInsights::default()
     ///     .with_connection_string(None)
     ///     .with_service_config("namespace", "name")
-    ///     .with_success_filter(|status| {
-    ///         status.is_success() || status.is_redirection() || status.is_informational() || status == StatusCode::NOT_FOUND
+    ///     .with_panic_context_headers(["x-request-id"])
+    ///     .with_contextual_panic_mapper(|panic, context| {
+    ///         (500, WebError { message: format!("{} {} panicked: {}", context.method, context.route, panic) })
     ///     });
     /// ```
-    pub fn with_success_filter<F>(self, success_filter: F) -> AppInsights<Ready, C, R, U, P, E>
+    pub fn with_contextual_panic_mapper<F, T>(self, panic_mapper: F) -> AppInsights<Ready, C, R, U, T, E>
     where
-        F: Fn(StatusCode) -> bool + Send + Sync + 'static,
+        F: Fn(String, &PanicContext) -> (u16, T) + Send + Sync + 'static,
     {
         AppInsights {
             connection_string: self.connection_string,
@@ -748,38 +2671,71 @@ impl<C, R, U, P, E> AppInsights<Ready, C, R, U, P, E> {
             should_catch_panic: self.should_catch_panic,
             is_noop: self.is_noop,
             field_mapper: self.field_mapper,
-            panic_mapper: self.panic_mapper,
-            success_filter: Some(Arc::new(success_filter)),
+            panic_mapper: Some(Arc::new(panic_mapper)),
+            success_filter: self.success_filter,
+            global_dimensions: self.global_dimensions,
+            build_info: self.build_info,
+            service_version: self.service_version,
+            should_close_on_upgrade: self.should_close_on_upgrade,
+            heartbeat_interval: self.heartbeat_interval,
+            should_record_multipart: self.should_record_multipart,
+            should_record_compression: self.should_record_compression,
+            panic_context_headers: self.panic_context_headers,
+            request_sampler: self.request_sampler,
+            debug_trace_header: self.debug_trace_header,
+            operation_grouper: self.operation_grouper,
+            should_extract_trace_context: self.should_extract_trace_context,
+            should_inject_trace_context: self.should_inject_trace_context,
+            effective_sample_rate: self.effective_sample_rate,
+            error_response_headers: self.error_response_headers,
+            should_record_metrics: self.should_record_metrics,
+            propagator: self.propagator,
+            async_field_mapper: self.async_field_mapper,
+            clock: self.clock,
+            fallback_mode: self.fallback_mode,
+            should_deserialize_error_body: self.should_deserialize_error_body,
+            request_filter: self.request_filter,
+            excluded_routes: self.excluded_routes,
+            resource_attributes: self.resource_attributes,
+            should_detect_host_name: self.should_detect_host_name,
+            captured_request_headers: self.captured_request_headers,
+            captured_response_headers: self.captured_response_headers,
+            captured_query_parameters: self.captured_query_parameters,
+            capture_redactor: self.capture_redactor,
+            rate_report_interval: self.rate_report_interval,
+            retry_detector: self.retry_detector,
+            user_mapper: self.user_mapper,
+            tracer_provider: self.tracer_provider,
+            should_bias_errors: self.should_bias_errors,
+            slow_request_threshold: self.slow_request_threshold,
+            otlp_endpoint: self.otlp_endpoint,
+            max_error_body_capture: self.max_error_body_capture,
+            explicit_settings: self.explicit_settings,
+            should_log_to_console: self.should_log_to_console,
+            console_format: self.console_format,
             _phantom1: std::marker::PhantomData,
             _phantom2: std::marker::PhantomData,
         }
     }
 
-    /// Sets the error type to use for telemetry.  The default is ().
+    /// Sets the request headers that are captured into the [`PanicContext`] given to [`AppInsights::with_contextual_panic_mapper`].
+    /// The default is no headers.
     /// 
     /// ```
-    /// use axum_insights::{AppInsights, AppInsightsError, Ready};
-    /// 
-    /// struct WebError {
-    ///     message: String,
-    /// }
-    /// 
-    /// impl AppInsightsError for WebError {
-    ///     fn message(&self) -> Option<String> {
-    ///         Some(self.message.clone())
-    ///     }
-    /// 
-    ///     fn backtrace(&self) -> Option<String> {
-    ///         None
-    ///     }
-    /// }
+    /// use axum_insights::AppInsights;
     /// 
     /// let i = AppInsights::default()
     ///     .with_connection_string(None)
     ///     .with_service_config("namespace", "name")
-    ///     .with_error_type::<WebError>();
+    ///     .with_panic_context_headers(["x-request-id", "user-agent"]);
     /// ```
-    pub fn with_error_type<T>(self) -> AppInsights<Ready, C, R, U, P, T> {
+    pub fn with_panic_context_headers<I, K>(self, headers: I) -> Self
+    where
+        I: IntoIterator<Item = K>,
+        K: AsRef<str>,
+    {
+        let panic_context_headers = headers.into_iter().filter_map(|h| http::HeaderName::try_from(h.as_ref()).ok()).collect();
+
         AppInsights {
             connection_string: self.connection_string,
             config: self.config,
@@ -794,508 +2750,8113 @@ impl<C, R, U, P, E> AppInsights<Ready, C, R, U, P, E> {
             field_mapper: self.field_mapper,
             panic_mapper: self.panic_mapper,
             success_filter: self.success_filter,
+            global_dimensions: self.global_dimensions,
+            build_info: self.build_info,
+            service_version: self.service_version,
+            should_close_on_upgrade: self.should_close_on_upgrade,
+            heartbeat_interval: self.heartbeat_interval,
+            should_record_multipart: self.should_record_multipart,
+            should_record_compression: self.should_record_compression,
+            panic_context_headers,
+            request_sampler: self.request_sampler,
+            debug_trace_header: self.debug_trace_header,
+            operation_grouper: self.operation_grouper,
+            should_extract_trace_context: self.should_extract_trace_context,
+            should_inject_trace_context: self.should_inject_trace_context,
+            effective_sample_rate: self.effective_sample_rate,
+            error_response_headers: self.error_response_headers,
+            should_record_metrics: self.should_record_metrics,
+            propagator: self.propagator,
+            async_field_mapper: self.async_field_mapper,
+            clock: self.clock,
+            fallback_mode: self.fallback_mode,
+            should_deserialize_error_body: self.should_deserialize_error_body,
+            request_filter: self.request_filter,
+            excluded_routes: self.excluded_routes,
+            resource_attributes: self.resource_attributes,
+            should_detect_host_name: self.should_detect_host_name,
+            captured_request_headers: self.captured_request_headers,
+            captured_response_headers: self.captured_response_headers,
+            captured_query_parameters: self.captured_query_parameters,
+            capture_redactor: self.capture_redactor,
+            rate_report_interval: self.rate_report_interval,
+            retry_detector: self.retry_detector,
+            user_mapper: self.user_mapper,
+            tracer_provider: self.tracer_provider,
+            should_bias_errors: self.should_bias_errors,
+            slow_request_threshold: self.slow_request_threshold,
+            otlp_endpoint: self.otlp_endpoint,
+            max_error_body_capture: self.max_error_body_capture,
+            explicit_settings: self.explicit_settings,
+            should_log_to_console: self.should_log_to_console,
+            console_format: self.console_format,
             _phantom1: std::marker::PhantomData,
             _phantom2: std::marker::PhantomData,
         }
     }
 
-    /// Builds the telemetry layer, and sets it as the global default.
+    /// Sets a function to determine the success-iness of a status.  The default is (100 - 399 => true).
+    /// 
+    /// This allows you to fine-tune which statuses are considered successful, and which are not.  If you have
+    /// lots of spurious 404s, for example, you can add that to the success statuses.
     /// 
     /// ```
-    /// use axum_insights::{AppInsights, AppInsightsComplete};
+    /// use axum_insights::{AppInsights, Ready};
+    /// use http::StatusCode;
     /// 
-    /// let i: AppInsightsComplete<_, _> = AppInsights::default()
+    /// let i = AppInsights::default()
     ///     .with_connection_string(None)
     ///     .with_service_config("namespace", "name")
-    ///     .build_and_set_global_default()
-    ///     .unwrap();
+    ///     .with_success_filter(|status| {
+    ///         status.is_success() || status.is_redirection() || status.is_informational() || status == StatusCode::NOT_FOUND
+    ///     });
     /// ```
-    /// 
-    /// The global default currently has to be set by this library.  If you want to use other subscribers,
-    /// then you need to use [`AppInsights::with_subscriber`] to inject that subscriber, and then
-    /// allow this call to set the global default.
-    pub fn build_and_set_global_default(self) -> Result<AppInsightsComplete<P, E>, Box<dyn Error + Send + Sync + 'static>>
+    pub fn with_success_filter<F>(self, success_filter: F) -> AppInsights<Ready, C, R, U, P, E>
     where
-        C: HttpClient + 'static,
-        R: RuntimeChannel,
-        U: tracing_subscriber::layer::SubscriberExt + for<'span> tracing_subscriber::registry::LookupSpan<'span>  + Send + Sync + 'static
+        F: Fn(StatusCode) -> bool + Send + Sync + 'static,
     {
-        if self.is_noop {
-            return Ok(AppInsightsComplete {
-                is_noop: true,
-                field_mapper: None,
-                panic_mapper: None,
-                success_filter: None,
-                _phantom: std::marker::PhantomData,
-            });
-        }
-
-        // This subscriber calculation needs to be separate in order to allow the type inference to work properly.
-        // Theoretically, we could do some magic with boxed traits to make it more readable, but this makes the types
-        // work nicely.
-        match self.subscriber {
-            Some(subscriber) => {
-                if let Some(connection_string) = self.connection_string {
-                    let tracer = opentelemetry_application_insights::new_pipeline_from_connection_string(connection_string)?
-                        .with_client(self.client)
-                        .with_live_metrics(self.enable_live_metrics)
-                        .with_trace_config(self.config)
-                        .with_sample_rate(self.sample_rate)
-                        .install_batch(self.batch_runtime);
-
-                    let telemetry = tracing_opentelemetry::layer().with_tracer(tracer);
-                    let subscriber = subscriber.with(telemetry).with(self.minimum_level);
-                    tracing::subscriber::set_global_default(subscriber)?;
-                } else {
-                    tracing::subscriber::set_global_default(subscriber.with(self.minimum_level))?;
-                }
-            },
-            None => {
-                if let Some(connection_string) = self.connection_string {
-                    let tracer = opentelemetry_application_insights::new_pipeline_from_connection_string(connection_string)?
-                        .with_client(self.client)
-                        .with_live_metrics(self.enable_live_metrics)
-                        .with_trace_config(self.config)
-                        .with_sample_rate(self.sample_rate)
-                        .install_batch(self.batch_runtime);
-
-                    let telemetry = tracing_opentelemetry::layer().with_tracer(tracer);
-                    let subscriber = tracing_subscriber::registry().with(telemetry).with(self.minimum_level);
-                    tracing::subscriber::set_global_default(subscriber)?;
-                } else {
-                    tracing::subscriber::set_global_default(tracing_subscriber::registry().with(self.minimum_level))?;
-                }
-            },
+        AppInsights {
+            connection_string: self.connection_string,
+            config: self.config,
+            client: self.client,
+            enable_live_metrics: self.enable_live_metrics,
+            sample_rate: self.sample_rate,
+            batch_runtime: self.batch_runtime,
+            minimum_level: self.minimum_level,
+            subscriber: self.subscriber,
+            should_catch_panic: self.should_catch_panic,
+            is_noop: self.is_noop,
+            field_mapper: self.field_mapper,
+            panic_mapper: self.panic_mapper,
+            success_filter: Some(Arc::new(success_filter)),
+            global_dimensions: self.global_dimensions,
+            build_info: self.build_info,
+            service_version: self.service_version,
+            should_close_on_upgrade: self.should_close_on_upgrade,
+            heartbeat_interval: self.heartbeat_interval,
+            should_record_multipart: self.should_record_multipart,
+            should_record_compression: self.should_record_compression,
+            panic_context_headers: self.panic_context_headers,
+            request_sampler: self.request_sampler,
+            debug_trace_header: self.debug_trace_header,
+            operation_grouper: self.operation_grouper,
+            should_extract_trace_context: self.should_extract_trace_context,
+            should_inject_trace_context: self.should_inject_trace_context,
+            effective_sample_rate: self.effective_sample_rate,
+            error_response_headers: self.error_response_headers,
+            should_record_metrics: self.should_record_metrics,
+            propagator: self.propagator,
+            async_field_mapper: self.async_field_mapper,
+            clock: self.clock,
+            fallback_mode: self.fallback_mode,
+            should_deserialize_error_body: self.should_deserialize_error_body,
+            request_filter: self.request_filter,
+            excluded_routes: self.excluded_routes,
+            resource_attributes: self.resource_attributes,
+            should_detect_host_name: self.should_detect_host_name,
+            captured_request_headers: self.captured_request_headers,
+            captured_response_headers: self.captured_response_headers,
+            captured_query_parameters: self.captured_query_parameters,
+            capture_redactor: self.capture_redactor,
+            rate_report_interval: self.rate_report_interval,
+            retry_detector: self.retry_detector,
+            user_mapper: self.user_mapper,
+            tracer_provider: self.tracer_provider,
+            should_bias_errors: self.should_bias_errors,
+            slow_request_threshold: self.slow_request_threshold,
+            otlp_endpoint: self.otlp_endpoint,
+            max_error_body_capture: self.max_error_body_capture,
+            explicit_settings: self.explicit_settings,
+            should_log_to_console: self.should_log_to_console,
+            console_format: self.console_format,
+            _phantom1: std::marker::PhantomData,
+            _phantom2: std::marker::PhantomData,
         }
+    }
 
-        if self.should_catch_panic {
-            let default_panic = panic::take_hook();
-
-            panic::set_hook(Box::new(move |p| {
-                let payload_string = format!("{:?}", p.payload().downcast_ref::<&str>());
-                let backtrace = Backtrace::force_capture().to_string();
-
-                // This doesn't work because this macro prescribes the name without allowing it to be overriden.
-                tracing::event!(
-                    name: "exception",
-                    Level::ERROR,
-                    ai.customEvent.name = "exception",
-                    "exception.type" = "PANIC",
-                    exception.message = payload_string,
-                    exception.stacktrace = backtrace
-                );
+    /// Sets a preset [`SuccessPolicy`] to determine the success-iness of a status.  The default is (100 - 399 => true).
+    /// 
+    /// This replaces any function set with [`AppInsights::with_success_filter`].
+    /// 
+    /// ```
+    /// use axum_insights::{AppInsights, Ready, SuccessPolicy};
+    /// 
+    /// let i = AppInsights::default()
+    ///     .with_connection_string(None)
+    ///     .with_service_config("namespace", "name")
+    ///     .with_success_policy(SuccessPolicy::IgnoreNotFound);
+    /// ```
+    pub fn with_success_policy(self, policy: SuccessPolicy) -> AppInsights<Ready, C, R, U, P, E> {
+        self.with_success_filter(move |status| policy.is_success(status))
+    }
 
-                default_panic(p);
-            }));
+    /// Sets a function that determines whether or not a request is tracked at all.  The default tracks every request.
+    /// 
+    /// Unlike a [`SamplingDecision::Drop`], a request that is filtered out (i.e., the function returns false) skips the
+    /// middleware entirely, just like the noop mode: no span is created, and no telemetry is recorded.  This is useful for
+    /// noisy endpoints (e.g., health checks) whose telemetry would otherwise dominate ingestion.
+    /// 
+    /// ```
+    /// use axum_insights::{AppInsights, Ready};
+    /// 
+    /// let i = AppInsights::default()
+    ///     .with_connection_string(None)
+    ///     .with_service_config("namespace", "name")
+    ///     .with_request_filter(|parts| parts.headers.get("user-agent").map_or(true, |v| v != "kube-probe"));
+    /// ```
+    pub fn with_request_filter<F>(self, request_filter: F) -> AppInsights<Ready, C, R, U, P, E>
+    where
+        F: Fn(&http::request::Parts) -> bool + Send + Sync + 'static,
+    {
+        AppInsights {
+            connection_string: self.connection_string,
+            config: self.config,
+            client: self.client,
+            enable_live_metrics: self.enable_live_metrics,
+            sample_rate: self.sample_rate,
+            batch_runtime: self.batch_runtime,
+            minimum_level: self.minimum_level,
+            subscriber: self.subscriber,
+            should_catch_panic: self.should_catch_panic,
+            is_noop: self.is_noop,
+            field_mapper: self.field_mapper,
+            panic_mapper: self.panic_mapper,
+            success_filter: self.success_filter,
+            global_dimensions: self.global_dimensions,
+            build_info: self.build_info,
+            service_version: self.service_version,
+            should_close_on_upgrade: self.should_close_on_upgrade,
+            heartbeat_interval: self.heartbeat_interval,
+            should_record_multipart: self.should_record_multipart,
+            should_record_compression: self.should_record_compression,
+            panic_context_headers: self.panic_context_headers,
+            request_sampler: self.request_sampler,
+            debug_trace_header: self.debug_trace_header,
+            operation_grouper: self.operation_grouper,
+            should_extract_trace_context: self.should_extract_trace_context,
+            should_inject_trace_context: self.should_inject_trace_context,
+            effective_sample_rate: self.effective_sample_rate,
+            error_response_headers: self.error_response_headers,
+            should_record_metrics: self.should_record_metrics,
+            propagator: self.propagator,
+            async_field_mapper: self.async_field_mapper,
+            clock: self.clock,
+            fallback_mode: self.fallback_mode,
+            should_deserialize_error_body: self.should_deserialize_error_body,
+            request_filter: Some(Arc::new(request_filter)),
+            excluded_routes: self.excluded_routes,
+            resource_attributes: self.resource_attributes,
+            should_detect_host_name: self.should_detect_host_name,
+            captured_request_headers: self.captured_request_headers,
+            captured_response_headers: self.captured_response_headers,
+            captured_query_parameters: self.captured_query_parameters,
+            capture_redactor: self.capture_redactor,
+            rate_report_interval: self.rate_report_interval,
+            retry_detector: self.retry_detector,
+            user_mapper: self.user_mapper,
+            tracer_provider: self.tracer_provider,
+            should_bias_errors: self.should_bias_errors,
+            slow_request_threshold: self.slow_request_threshold,
+            otlp_endpoint: self.otlp_endpoint,
+            max_error_body_capture: self.max_error_body_capture,
+            explicit_settings: self.explicit_settings,
+            should_log_to_console: self.should_log_to_console,
+            console_format: self.console_format,
+            _phantom1: std::marker::PhantomData,
+            _phantom2: std::marker::PhantomData,
         }
+    }
 
-        Ok(AppInsightsComplete {
-            is_noop: false,
+    /// Sets the routes that are not tracked at all.  The default is no routes.
+    /// 
+    /// A request is excluded if either its matched route (e.g., `/users/:id`) or its path is in the list.  Excluded requests
+    /// skip the middleware entirely, just like the ones filtered out by [`AppInsights::with_request_filter`].
+    /// 
+    /// ```
+    /// use axum_insights::{AppInsights, Ready};
+    /// 
+    /// let i = AppInsights::default()
+    ///     .with_connection_string(None)
+    ///     .with_service_config("namespace", "name")
+    ///     .with_excluded_routes(["/healthz", "/metrics"]);
+    /// ```
+    pub fn with_excluded_routes<I, K>(self, routes: I) -> AppInsights<Ready, C, R, U, P, E>
+    where
+        I: IntoIterator<Item = K>,
+        K: Into<String>,
+    {
+        AppInsights {
+            connection_string: self.connection_string,
+            config: self.config,
+            client: self.client,
+            enable_live_metrics: self.enable_live_metrics,
+            sample_rate: self.sample_rate,
+            batch_runtime: self.batch_runtime,
+            minimum_level: self.minimum_level,
+            subscriber: self.subscriber,
+            should_catch_panic: self.should_catch_panic,
+            is_noop: self.is_noop,
             field_mapper: self.field_mapper,
             panic_mapper: self.panic_mapper,
             success_filter: self.success_filter,
-            _phantom: std::marker::PhantomData,
-        })
+            global_dimensions: self.global_dimensions,
+            build_info: self.build_info,
+            service_version: self.service_version,
+            should_close_on_upgrade: self.should_close_on_upgrade,
+            heartbeat_interval: self.heartbeat_interval,
+            should_record_multipart: self.should_record_multipart,
+            should_record_compression: self.should_record_compression,
+            panic_context_headers: self.panic_context_headers,
+            request_sampler: self.request_sampler,
+            debug_trace_header: self.debug_trace_header,
+            operation_grouper: self.operation_grouper,
+            should_extract_trace_context: self.should_extract_trace_context,
+            should_inject_trace_context: self.should_inject_trace_context,
+            effective_sample_rate: self.effective_sample_rate,
+            error_response_headers: self.error_response_headers,
+            should_record_metrics: self.should_record_metrics,
+            propagator: self.propagator,
+            async_field_mapper: self.async_field_mapper,
+            clock: self.clock,
+            fallback_mode: self.fallback_mode,
+            should_deserialize_error_body: self.should_deserialize_error_body,
+            request_filter: self.request_filter,
+            excluded_routes: routes.into_iter().map(Into::into).collect(),
+            resource_attributes: self.resource_attributes,
+            should_detect_host_name: self.should_detect_host_name,
+            captured_request_headers: self.captured_request_headers,
+            captured_response_headers: self.captured_response_headers,
+            captured_query_parameters: self.captured_query_parameters,
+            capture_redactor: self.capture_redactor,
+            rate_report_interval: self.rate_report_interval,
+            retry_detector: self.retry_detector,
+            user_mapper: self.user_mapper,
+            tracer_provider: self.tracer_provider,
+            should_bias_errors: self.should_bias_errors,
+            slow_request_threshold: self.slow_request_threshold,
+            otlp_endpoint: self.otlp_endpoint,
+            max_error_body_capture: self.max_error_body_capture,
+            explicit_settings: self.explicit_settings,
+            should_log_to_console: self.should_log_to_console,
+            console_format: self.console_format,
+            _phantom1: std::marker::PhantomData,
+            _phantom2: std::marker::PhantomData,
+        }
     }
-}
 
-impl<P, E> AppInsightsComplete<P, E> {
-    /// Creates the telemetry layer.
+    /// Sets a function that extracts the user and session of each request.  The default records no user context.
+    /// 
+    /// The [`UserContext`] is recorded on the request span as `ai.user.id`, `ai.session.id`, and `enduser.id`, which Application
+    /// Insights uses to populate the users and sessions of the usage analytics.
     /// 
     /// ```
-    /// use axum::Router;
-    /// use axum_insights::{AppInsights, AppInsightsComplete};
+    /// use axum_insights::{AppInsights, Ready, UserContext};
     /// 
-    /// let i: AppInsightsComplete<_, _> = AppInsights::default()
+    /// let i = AppInsights::default()
     ///     .with_connection_string(None)
     ///     .with_service_config("namespace", "name")
-    ///     .build_and_set_global_default()
-    ///     .unwrap();
+    ///     .with_user_mapper(|parts| UserContext {
+    ///         session_id: parts.headers.get("x-session-id").and_then(|v| v.to_str().ok()).map(str::to_owned),
+    ///         ..Default::default()
+    ///     });
+    /// ```
+    pub fn with_user_mapper<F>(self, user_mapper: F) -> AppInsights<Ready, C, R, U, P, E>
+    where
+        F: Fn(&http::request::Parts) -> UserContext + Send + Sync + 'static,
+    {
+        AppInsights {
+            connection_string: self.connection_string,
+            config: self.config,
+            client: self.client,
+            enable_live_metrics: self.enable_live_metrics,
+            sample_rate: self.sample_rate,
+            batch_runtime: self.batch_runtime,
+            minimum_level: self.minimum_level,
+            subscriber: self.subscriber,
+            should_catch_panic: self.should_catch_panic,
+            is_noop: self.is_noop,
+            field_mapper: self.field_mapper,
+            panic_mapper: self.panic_mapper,
+            success_filter: self.success_filter,
+            global_dimensions: self.global_dimensions,
+            build_info: self.build_info,
+            service_version: self.service_version,
+            should_close_on_upgrade: self.should_close_on_upgrade,
+            heartbeat_interval: self.heartbeat_interval,
+            should_record_multipart: self.should_record_multipart,
+            should_record_compression: self.should_record_compression,
+            panic_context_headers: self.panic_context_headers,
+            request_sampler: self.request_sampler,
+            debug_trace_header: self.debug_trace_header,
+            operation_grouper: self.operation_grouper,
+            should_extract_trace_context: self.should_extract_trace_context,
+            should_inject_trace_context: self.should_inject_trace_context,
+            effective_sample_rate: self.effective_sample_rate,
+            error_response_headers: self.error_response_headers,
+            should_record_metrics: self.should_record_metrics,
+            propagator: self.propagator,
+            async_field_mapper: self.async_field_mapper,
+            clock: self.clock,
+            fallback_mode: self.fallback_mode,
+            should_deserialize_error_body: self.should_deserialize_error_body,
+            request_filter: self.request_filter,
+            excluded_routes: self.excluded_routes,
+            resource_attributes: self.resource_attributes,
+            should_detect_host_name: self.should_detect_host_name,
+            captured_request_headers: self.captured_request_headers,
+            captured_response_headers: self.captured_response_headers,
+            captured_query_parameters: self.captured_query_parameters,
+            capture_redactor: self.capture_redactor,
+            rate_report_interval: self.rate_report_interval,
+            retry_detector: self.retry_detector,
+            user_mapper: Some(Arc::new(user_mapper)),
+            tracer_provider: self.tracer_provider,
+            should_bias_errors: self.should_bias_errors,
+            slow_request_threshold: self.slow_request_threshold,
+            otlp_endpoint: self.otlp_endpoint,
+            max_error_body_capture: self.max_error_body_capture,
+            explicit_settings: self.explicit_settings,
+            should_log_to_console: self.should_log_to_console,
+            console_format: self.console_format,
+            _phantom1: std::marker::PhantomData,
+            _phantom2: std::marker::PhantomData,
+        }
+    }
+
+    /// Sets a function that makes a sampling decision for each request, before the request span is created.  The default
+    /// defers every request to the configured sampler of the trace config.
     /// 
-    /// let layer = i.layer();
+    /// This allows filtering and sampling to live in one place: for example, a request can be force-sampled by header, or
+    /// dropped by path.  The decision applies to the request span and (with the default parent-based sampler) to everything
+    /// within it.  A forced decision is recorded on the request span as `sampling.priority` (`1` to sample, `0` to drop).  The
+    /// force-sampled items (the request and everything within it) are exported with an item count of one, since they were not
+    /// sampled by rate.  The decisions are only applied to the pipeline that this crate builds, not with
+    /// [`AppInsights::from_tracer_provider`].
     /// 
-    /// // You likely will not need to specify `Router<()>` in your implementation.  This is just for the example.
-    /// let app: Router<()> = Router::new()
-    ///     // ...
-    ///     .layer(layer);
     /// ```
-    pub fn layer(self) -> AppInsightsLayer<P, E> {
-        AppInsightsLayer {
+    /// use axum_insights::{AppInsights, Ready, SamplingDecision};
+    /// 
+    /// let i = AppInsights::default()
+    ///     .with_connection_string(None)
+    ///     .with_service_config("namespace", "name")
+    ///     .with_request_sampler(|parts| {
+    ///         if parts.headers.get("x-debug-trace").is_some_and(|v| v == "1") {
+    ///             SamplingDecision::Sample
+    ///         } else if parts.uri.path() == "/health" {
+    ///             SamplingDecision::Drop
+    ///         } else {
+    ///             SamplingDecision::Default
+    ///         }
+    ///     });
+    /// ```
+    pub fn with_request_sampler<F>(self, request_sampler: F) -> AppInsights<Ready, C, R, U, P, E>
+    where
+        F: Fn(&http::request::Parts) -> SamplingDecision + Send + Sync + 'static,
+    {
+        AppInsights {
+            connection_string: self.connection_string,
+            config: self.config,
+            client: self.client,
+            enable_live_metrics: self.enable_live_metrics,
+            sample_rate: self.sample_rate,
+            batch_runtime: self.batch_runtime,
+            minimum_level: self.minimum_level,
+            subscriber: self.subscriber,
+            should_catch_panic: self.should_catch_panic,
             is_noop: self.is_noop,
             field_mapper: self.field_mapper,
             panic_mapper: self.panic_mapper,
             success_filter: self.success_filter,
-            _phantom: std::marker::PhantomData,
+            global_dimensions: self.global_dimensions,
+            build_info: self.build_info,
+            service_version: self.service_version,
+            should_close_on_upgrade: self.should_close_on_upgrade,
+            heartbeat_interval: self.heartbeat_interval,
+            should_record_multipart: self.should_record_multipart,
+            should_record_compression: self.should_record_compression,
+            panic_context_headers: self.panic_context_headers,
+            request_sampler: Some(Arc::new(request_sampler)),
+            debug_trace_header: self.debug_trace_header,
+            operation_grouper: self.operation_grouper,
+            should_extract_trace_context: self.should_extract_trace_context,
+            should_inject_trace_context: self.should_inject_trace_context,
+            effective_sample_rate: self.effective_sample_rate,
+            error_response_headers: self.error_response_headers,
+            should_record_metrics: self.should_record_metrics,
+            propagator: self.propagator,
+            async_field_mapper: self.async_field_mapper,
+            clock: self.clock,
+            fallback_mode: self.fallback_mode,
+            should_deserialize_error_body: self.should_deserialize_error_body,
+            request_filter: self.request_filter,
+            excluded_routes: self.excluded_routes,
+            resource_attributes: self.resource_attributes,
+            should_detect_host_name: self.should_detect_host_name,
+            captured_request_headers: self.captured_request_headers,
+            captured_response_headers: self.captured_response_headers,
+            captured_query_parameters: self.captured_query_parameters,
+            capture_redactor: self.capture_redactor,
+            rate_report_interval: self.rate_report_interval,
+            retry_detector: self.retry_detector,
+            user_mapper: self.user_mapper,
+            tracer_provider: self.tracer_provider,
+            should_bias_errors: self.should_bias_errors,
+            slow_request_threshold: self.slow_request_threshold,
+            otlp_endpoint: self.otlp_endpoint,
+            max_error_body_capture: self.max_error_body_capture,
+            explicit_settings: self.explicit_settings,
+            should_log_to_console: self.should_log_to_console,
+            console_format: self.console_format,
+            _phantom1: std::marker::PhantomData,
+            _phantom2: std::marker::PhantomData,
         }
     }
-}
 
-/// The telemetry layer.
-/// 
-/// This layer is created by [`AppInsightsComplete::layer`], and it can be used to instrument your [`axum::Router`].
-/// Generally, this type will not be used, other than to pass to [`axum::Router::layer`].
-#[derive(Clone)]
-pub struct AppInsightsLayer<P, E> {
-    is_noop: bool,
-    field_mapper: OptionalFieldMapper,
-    panic_mapper: OptionalPanicMapper<P>,
-    success_filter: OptionalSuccessFilter,
-    _phantom: std::marker::PhantomData<E>,
-}
+    /// Sets a header (e.g., `idempotency-key`) that identifies retries of the same client request.  The default is no header.
+    /// 
+    /// A request whose header value was already seen within the window is tagged as a likely client retry: the number of previous
+    /// attempts is recorded as `http.request.resend_count`.  This makes it possible to distinguish genuine traffic growth from retry
+    /// storms.  The window is measured with the clock given to [`AppInsights::with_clock`].
+    /// 
+    /// At most 4096 keys are remembered at once (the oldest are forgotten first), and keys longer than 256 bytes are not tracked.
+    /// 
+    /// ```
+    /// use axum_insights::{AppInsights, Ready};
+    /// use std::time::Duration;
+    /// 
+    /// let i = AppInsights::default()
+    ///     .with_connection_string(None)
+    ///     .with_service_config("namespace", "name")
+    ///     .with_retry_detection("idempotency-key", Duration::from_secs(60));
+    /// ```
+    pub fn with_retry_detection(self, header: &str, window: Duration) -> AppInsights<Ready, C, R, U, P, E> {
+        let retry_detector = http::HeaderName::try_from(header).ok().map(|header| Arc::new(RetryDetector::new(header, window)));
 
-impl<S, P, E> Layer<S> for AppInsightsLayer<P, E> {
-    type Service = AppInsightsMiddleware<S, P, E>;
+        AppInsights {
+            connection_string: self.connection_string,
+            config: self.config,
+            client: self.client,
+            enable_live_metrics: self.enable_live_metrics,
+            sample_rate: self.sample_rate,
+            batch_runtime: self.batch_runtime,
+            minimum_level: self.minimum_level,
+            subscriber: self.subscriber,
+            should_catch_panic: self.should_catch_panic,
+            is_noop: self.is_noop,
+            field_mapper: self.field_mapper,
+            panic_mapper: self.panic_mapper,
+            success_filter: self.success_filter,
+            global_dimensions: self.global_dimensions,
+            build_info: self.build_info,
+            service_version: self.service_version,
+            should_close_on_upgrade: self.should_close_on_upgrade,
+            heartbeat_interval: self.heartbeat_interval,
+            should_record_multipart: self.should_record_multipart,
+            should_record_compression: self.should_record_compression,
+            panic_context_headers: self.panic_context_headers,
+            request_sampler: self.request_sampler,
+            debug_trace_header: self.debug_trace_header,
+            operation_grouper: self.operation_grouper,
+            should_extract_trace_context: self.should_extract_trace_context,
+            should_inject_trace_context: self.should_inject_trace_context,
+            effective_sample_rate: self.effective_sample_rate,
+            error_response_headers: self.error_response_headers,
+            should_record_metrics: self.should_record_metrics,
+            propagator: self.propagator,
+            async_field_mapper: self.async_field_mapper,
+            clock: self.clock,
+            fallback_mode: self.fallback_mode,
+            should_deserialize_error_body: self.should_deserialize_error_body,
+            request_filter: self.request_filter,
+            excluded_routes: self.excluded_routes,
+            resource_attributes: self.resource_attributes,
+            should_detect_host_name: self.should_detect_host_name,
+            captured_request_headers: self.captured_request_headers,
+            captured_response_headers: self.captured_response_headers,
+            captured_query_parameters: self.captured_query_parameters,
+            capture_redactor: self.capture_redactor,
+            rate_report_interval: self.rate_report_interval,
+            retry_detector,
+            user_mapper: self.user_mapper,
+            tracer_provider: self.tracer_provider,
+            should_bias_errors: self.should_bias_errors,
+            slow_request_threshold: self.slow_request_threshold,
+            otlp_endpoint: self.otlp_endpoint,
+            max_error_body_capture: self.max_error_body_capture,
+            explicit_settings: self.explicit_settings,
+            should_log_to_console: self.should_log_to_console,
+            console_format: self.console_format,
+            _phantom1: std::marker::PhantomData,
+            _phantom2: std::marker::PhantomData,
+        }
+    }
 
-    fn layer(&self, inner: S) -> Self::Service {
-        AppInsightsMiddleware {
-            inner,
+    /// Sets whether or not to retain the traces of failed (and, optionally, slow) requests, regardless of the sampler.  The default is no
+    /// error-biased sampling.
+    /// 
+    /// With error-biased sampling, a request that the sampler would drop is recorded tentatively: its spans are buffered in-process until
+    /// the request finishes, and they are only exported if the status fails the success filter, or if the request took at least the
+    /// given threshold.  Forced decisions (see [`AppInsights::with_request_sampler`]) are honored as usual.  The trace context that a
+    /// tentative request propagates (e.g., to its dependencies) is not sampled, since the decision is only made once it finishes.
+    /// 
+    /// Since the whole trace of a tentative request is buffered, this trades memory for coverage.  The retained items are marked as
+    /// `sampling.retained`, and they are exported with an item count of one (rather than `1 / sample rate`), since they were not
+    /// sampled by rate.  This has no effect with [`AppInsights::from_tracer_provider`] or with the OTLP exporter.
+    /// 
+    /// ```
+    /// use axum_insights::{AppInsights, Ready};
+    /// use std::time::Duration;
+    /// 
+    /// let i = AppInsights::default()
+    ///     .with_connection_string(None)
+    ///     .with_service_config("namespace", "name")
+    ///     .with_sample_rate(0.01)
+    ///     .with_error_biased_sampling(Some(Duration::from_secs(2)));
+    /// ```
+    pub fn with_error_biased_sampling(self, slow_request_threshold: Option<Duration>) -> AppInsights<Ready, C, R, U, P, E> {
+        AppInsights {
+            connection_string: self.connection_string,
+            config: self.config,
+            client: self.client,
+            enable_live_metrics: self.enable_live_metrics,
+            sample_rate: self.sample_rate,
+            batch_runtime: self.batch_runtime,
+            minimum_level: self.minimum_level,
+            subscriber: self.subscriber,
+            should_catch_panic: self.should_catch_panic,
             is_noop: self.is_noop,
-            field_mapper: self.field_mapper.clone(),
-            panic_mapper: self.panic_mapper.clone(),
-            success_filter: self.success_filter.clone(),
-            _phantom: std::marker::PhantomData,
+            field_mapper: self.field_mapper,
+            panic_mapper: self.panic_mapper,
+            success_filter: self.success_filter,
+            global_dimensions: self.global_dimensions,
+            build_info: self.build_info,
+            service_version: self.service_version,
+            should_close_on_upgrade: self.should_close_on_upgrade,
+            heartbeat_interval: self.heartbeat_interval,
+            should_record_multipart: self.should_record_multipart,
+            should_record_compression: self.should_record_compression,
+            panic_context_headers: self.panic_context_headers,
+            request_sampler: self.request_sampler,
+            debug_trace_header: self.debug_trace_header,
+            operation_grouper: self.operation_grouper,
+            should_extract_trace_context: self.should_extract_trace_context,
+            should_inject_trace_context: self.should_inject_trace_context,
+            effective_sample_rate: self.effective_sample_rate,
+            error_response_headers: self.error_response_headers,
+            should_record_metrics: self.should_record_metrics,
+            propagator: self.propagator,
+            async_field_mapper: self.async_field_mapper,
+            clock: self.clock,
+            fallback_mode: self.fallback_mode,
+            should_deserialize_error_body: self.should_deserialize_error_body,
+            request_filter: self.request_filter,
+            excluded_routes: self.excluded_routes,
+            resource_attributes: self.resource_attributes,
+            should_detect_host_name: self.should_detect_host_name,
+            captured_request_headers: self.captured_request_headers,
+            captured_response_headers: self.captured_response_headers,
+            captured_query_parameters: self.captured_query_parameters,
+            capture_redactor: self.capture_redactor,
+            rate_report_interval: self.rate_report_interval,
+            retry_detector: self.retry_detector,
+            user_mapper: self.user_mapper,
+            tracer_provider: self.tracer_provider,
+            should_bias_errors: true,
+            slow_request_threshold,
+            otlp_endpoint: self.otlp_endpoint,
+            max_error_body_capture: self.max_error_body_capture,
+            explicit_settings: self.explicit_settings,
+            should_log_to_console: self.should_log_to_console,
+            console_format: self.console_format,
+            _phantom1: std::marker::PhantomData,
+            _phantom2: std::marker::PhantomData,
         }
     }
-}
 
-/// The telemetry middleware.
-/// 
-/// This middleware is created by [`AppInsightsLayer::layer`], and it can be used to instrument your [`axum::Router`].
-/// Generally, this type will not be used at all, is it merely satisfies the requirement that [`Layer::Service`]
-/// is a [`Service`].
-#[derive(Clone)]
-pub struct AppInsightsMiddleware<S, P, E> {
-    inner: S,
-    is_noop: bool,
-    field_mapper: OptionalFieldMapper,
-    panic_mapper: OptionalPanicMapper<P>,
-    success_filter: OptionalSuccessFilter,
-    _phantom: std::marker::PhantomData<E>,
+    /// Sets a header that forces full capture of a single request, which is useful for reproducing production issues on demand.
+    /// The default is no header.
+    /// 
+    /// A request that carries the header is always sampled, its request and response bodies are captured (up to 64 KiB each) as
+    /// `http.request.body` and `http.response.body`, and DEBUG events within it are emitted regardless of the minimum level.
+    /// If a secret is given, then the header value must match it (compared in constant time); otherwise, any value enables the
+    /// debug trace.  With [`AppInsights::from_tracer_provider`], the request is not force-sampled, since the given provider owns
+    /// the sampler.
+    /// 
+    /// Since any request may turn out to be a debug trace, this raises the maximum level of the subscriber to DEBUG (if the minimum
+    /// level is above it).  DEBUG events and spans are then no longer skipped up front: each one outside of a debug trace costs a
+    /// lookup of the current span scope before it is discarded, so avoid DEBUG instrumentation on hot paths when this is enabled.
+    /// 
+    /// ```
+    /// use axum_insights::{AppInsights, Ready};
+    /// 
+    /// let i = AppInsights::default()
+    ///     .with_connection_string(None)
+    ///     .with_service_config("namespace", "name")
+    ///     .with_debug_trace_header("x-debug-trace", Some("s3cr3t"));
+    /// ```
+    pub fn with_debug_trace_header(self, header: &str, secret: Option<&str>) -> AppInsights<Ready, C, R, U, P, E> {
+        let debug_trace_header = http::HeaderName::try_from(header).ok().map(|name| {
+            Arc::new(DebugTraceHeader {
+                name,
+                secret: secret.map(|s| s.to_owned()),
+            })
+        });
+
+        AppInsights {
+            connection_string: self.connection_string,
+            config: self.config,
+            client: self.client,
+            enable_live_metrics: self.enable_live_metrics,
+            sample_rate: self.sample_rate,
+            batch_runtime: self.batch_runtime,
+            minimum_level: self.minimum_level,
+            subscriber: self.subscriber,
+            should_catch_panic: self.should_catch_panic,
+            is_noop: self.is_noop,
+            field_mapper: self.field_mapper,
+            panic_mapper: self.panic_mapper,
+            success_filter: self.success_filter,
+            global_dimensions: self.global_dimensions,
+            build_info: self.build_info,
+            service_version: self.service_version,
+            should_close_on_upgrade: self.should_close_on_upgrade,
+            heartbeat_interval: self.heartbeat_interval,
+            should_record_multipart: self.should_record_multipart,
+            should_record_compression: self.should_record_compression,
+            panic_context_headers: self.panic_context_headers,
+            request_sampler: self.request_sampler,
+            debug_trace_header,
+            operation_grouper: self.operation_grouper,
+            should_extract_trace_context: self.should_extract_trace_context,
+            should_inject_trace_context: self.should_inject_trace_context,
+            effective_sample_rate: self.effective_sample_rate,
+            error_response_headers: self.error_response_headers,
+            should_record_metrics: self.should_record_metrics,
+            propagator: self.propagator,
+            async_field_mapper: self.async_field_mapper,
+            clock: self.clock,
+            fallback_mode: self.fallback_mode,
+            should_deserialize_error_body: self.should_deserialize_error_body,
+            request_filter: self.request_filter,
+            excluded_routes: self.excluded_routes,
+            resource_attributes: self.resource_attributes,
+            should_detect_host_name: self.should_detect_host_name,
+            captured_request_headers: self.captured_request_headers,
+            captured_response_headers: self.captured_response_headers,
+            captured_query_parameters: self.captured_query_parameters,
+            capture_redactor: self.capture_redactor,
+            rate_report_interval: self.rate_report_interval,
+            retry_detector: self.retry_detector,
+            user_mapper: self.user_mapper,
+            tracer_provider: self.tracer_provider,
+            should_bias_errors: self.should_bias_errors,
+            slow_request_threshold: self.slow_request_threshold,
+            otlp_endpoint: self.otlp_endpoint,
+            max_error_body_capture: self.max_error_body_capture,
+            explicit_settings: self.explicit_settings,
+            should_log_to_console: self.should_log_to_console,
+            console_format: self.console_format,
+            _phantom1: std::marker::PhantomData,
+            _phantom2: std::marker::PhantomData,
+        }
+    }
+
+    /// Sets a function that groups the route of a request into an operation name.  The default is the matched route.
+    /// 
+    /// The function receives the matched route (or `<unmatched>`, if no route matched) and the request parts, and returns the
+    /// route that is recorded.  This is useful for wildcard routes, where bucketing the operation names (e.g., by file extension,
+    /// or by first path segment) prevents an explosion of operation names in Application Insights.
+    /// 
+    /// ```
+    /// use axum_insights::{AppInsights, Ready};
+    /// 
+    /// let i = AppInsights::default()
+    ///     .with_connection_string(None)
+    ///     .with_service_config("namespace", "name")
+    ///     .with_operation_grouper(|route, parts| {
+    ///         match (route, parts.uri.path().rsplit_once('.')) {
+    ///             ("/files/*path", Some((_, extension))) => format!("/files/*.{}", extension),
+    ///             _ => route.to_owned(),
+    ///         }
+    ///     });
+    /// ```
+    pub fn with_operation_grouper<F>(self, operation_grouper: F) -> AppInsights<Ready, C, R, U, P, E>
+    where
+        F: Fn(&str, &http::request::Parts) -> String + Send + Sync + 'static,
+    {
+        AppInsights {
+            connection_string: self.connection_string,
+            config: self.config,
+            client: self.client,
+            enable_live_metrics: self.enable_live_metrics,
+            sample_rate: self.sample_rate,
+            batch_runtime: self.batch_runtime,
+            minimum_level: self.minimum_level,
+            subscriber: self.subscriber,
+            should_catch_panic: self.should_catch_panic,
+            is_noop: self.is_noop,
+            field_mapper: self.field_mapper,
+            panic_mapper: self.panic_mapper,
+            success_filter: self.success_filter,
+            global_dimensions: self.global_dimensions,
+            build_info: self.build_info,
+            service_version: self.service_version,
+            should_close_on_upgrade: self.should_close_on_upgrade,
+            heartbeat_interval: self.heartbeat_interval,
+            should_record_multipart: self.should_record_multipart,
+            should_record_compression: self.should_record_compression,
+            panic_context_headers: self.panic_context_headers,
+            request_sampler: self.request_sampler,
+            debug_trace_header: self.debug_trace_header,
+            operation_grouper: Some(Arc::new(operation_grouper)),
+            should_extract_trace_context: self.should_extract_trace_context,
+            should_inject_trace_context: self.should_inject_trace_context,
+            effective_sample_rate: self.effective_sample_rate,
+            error_response_headers: self.error_response_headers,
+            should_record_metrics: self.should_record_metrics,
+            propagator: self.propagator,
+            async_field_mapper: self.async_field_mapper,
+            clock: self.clock,
+            fallback_mode: self.fallback_mode,
+            should_deserialize_error_body: self.should_deserialize_error_body,
+            request_filter: self.request_filter,
+            excluded_routes: self.excluded_routes,
+            resource_attributes: self.resource_attributes,
+            should_detect_host_name: self.should_detect_host_name,
+            captured_request_headers: self.captured_request_headers,
+            captured_response_headers: self.captured_response_headers,
+            captured_query_parameters: self.captured_query_parameters,
+            capture_redactor: self.capture_redactor,
+            rate_report_interval: self.rate_report_interval,
+            retry_detector: self.retry_detector,
+            user_mapper: self.user_mapper,
+            tracer_provider: self.tracer_provider,
+            should_bias_errors: self.should_bias_errors,
+            slow_request_threshold: self.slow_request_threshold,
+            otlp_endpoint: self.otlp_endpoint,
+            max_error_body_capture: self.max_error_body_capture,
+            explicit_settings: self.explicit_settings,
+            should_log_to_console: self.should_log_to_console,
+            console_format: self.console_format,
+            _phantom1: std::marker::PhantomData,
+            _phantom2: std::marker::PhantomData,
+        }
+    }
+
+    /// Sets whether or not to continue the trace of an incoming request.  The default is true.
+    /// 
+    /// The remote parent is extracted from the W3C `traceparent` and `tracestate` headers, or from the legacy Application Insights
+    /// `Request-Id` header (in its W3C compatible `|<trace id>.<span id>.` form), so that requests from API Management, Azure Front Door,
+    /// or other instrumented services show up as part of the same end-to-end transaction.
+    /// 
+    /// ```
+    /// use axum_insights::{AppInsights, Ready};
+    /// 
+    /// let i = AppInsights::default()
+    ///     .with_connection_string(None)
+    ///     .with_service_config("namespace", "name")
+    ///     .with_trace_context_extraction(false);
+    /// ```
+    pub fn with_trace_context_extraction(self, should_extract_trace_context: bool) -> AppInsights<Ready, C, R, U, P, E> {
+        AppInsights {
+            connection_string: self.connection_string,
+            config: self.config,
+            client: self.client,
+            enable_live_metrics: self.enable_live_metrics,
+            sample_rate: self.sample_rate,
+            batch_runtime: self.batch_runtime,
+            minimum_level: self.minimum_level,
+            subscriber: self.subscriber,
+            should_catch_panic: self.should_catch_panic,
+            is_noop: self.is_noop,
+            field_mapper: self.field_mapper,
+            panic_mapper: self.panic_mapper,
+            success_filter: self.success_filter,
+            global_dimensions: self.global_dimensions,
+            build_info: self.build_info,
+            service_version: self.service_version,
+            should_close_on_upgrade: self.should_close_on_upgrade,
+            heartbeat_interval: self.heartbeat_interval,
+            should_record_multipart: self.should_record_multipart,
+            should_record_compression: self.should_record_compression,
+            panic_context_headers: self.panic_context_headers,
+            request_sampler: self.request_sampler,
+            debug_trace_header: self.debug_trace_header,
+            operation_grouper: self.operation_grouper,
+            should_extract_trace_context,
+            should_inject_trace_context: self.should_inject_trace_context,
+            effective_sample_rate: self.effective_sample_rate,
+            error_response_headers: self.error_response_headers,
+            should_record_metrics: self.should_record_metrics,
+            propagator: self.propagator,
+            async_field_mapper: self.async_field_mapper,
+            clock: self.clock,
+            fallback_mode: self.fallback_mode,
+            should_deserialize_error_body: self.should_deserialize_error_body,
+            request_filter: self.request_filter,
+            excluded_routes: self.excluded_routes,
+            resource_attributes: self.resource_attributes,
+            should_detect_host_name: self.should_detect_host_name,
+            captured_request_headers: self.captured_request_headers,
+            captured_response_headers: self.captured_response_headers,
+            captured_query_parameters: self.captured_query_parameters,
+            capture_redactor: self.capture_redactor,
+            rate_report_interval: self.rate_report_interval,
+            retry_detector: self.retry_detector,
+            user_mapper: self.user_mapper,
+            tracer_provider: self.tracer_provider,
+            should_bias_errors: self.should_bias_errors,
+            slow_request_threshold: self.slow_request_threshold,
+            otlp_endpoint: self.otlp_endpoint,
+            max_error_body_capture: self.max_error_body_capture,
+            explicit_settings: self.explicit_settings,
+            should_log_to_console: self.should_log_to_console,
+            console_format: self.console_format,
+            _phantom1: std::marker::PhantomData,
+            _phantom2: std::marker::PhantomData,
+        }
+    }
+
+    /// Sets whether or not to add the W3C `traceparent` header of the request span to the response.  The default is false.
+    /// 
+    /// ```
+    /// use axum_insights::{AppInsights, Ready};
+    /// 
+    /// let i = AppInsights::default()
+    ///     .with_connection_string(None)
+    ///     .with_service_config("namespace", "name")
+    ///     .with_trace_context_injection(true);
+    /// ```
+    pub fn with_trace_context_injection(self, should_inject_trace_context: bool) -> AppInsights<Ready, C, R, U, P, E> {
+        AppInsights {
+            connection_string: self.connection_string,
+            config: self.config,
+            client: self.client,
+            enable_live_metrics: self.enable_live_metrics,
+            sample_rate: self.sample_rate,
+            batch_runtime: self.batch_runtime,
+            minimum_level: self.minimum_level,
+            subscriber: self.subscriber,
+            should_catch_panic: self.should_catch_panic,
+            is_noop: self.is_noop,
+            field_mapper: self.field_mapper,
+            panic_mapper: self.panic_mapper,
+            success_filter: self.success_filter,
+            global_dimensions: self.global_dimensions,
+            build_info: self.build_info,
+            service_version: self.service_version,
+            should_close_on_upgrade: self.should_close_on_upgrade,
+            heartbeat_interval: self.heartbeat_interval,
+            should_record_multipart: self.should_record_multipart,
+            should_record_compression: self.should_record_compression,
+            panic_context_headers: self.panic_context_headers,
+            request_sampler: self.request_sampler,
+            debug_trace_header: self.debug_trace_header,
+            operation_grouper: self.operation_grouper,
+            should_extract_trace_context: self.should_extract_trace_context,
+            should_inject_trace_context,
+            effective_sample_rate: self.effective_sample_rate,
+            error_response_headers: self.error_response_headers,
+            should_record_metrics: self.should_record_metrics,
+            propagator: self.propagator,
+            async_field_mapper: self.async_field_mapper,
+            clock: self.clock,
+            fallback_mode: self.fallback_mode,
+            should_deserialize_error_body: self.should_deserialize_error_body,
+            request_filter: self.request_filter,
+            excluded_routes: self.excluded_routes,
+            resource_attributes: self.resource_attributes,
+            should_detect_host_name: self.should_detect_host_name,
+            captured_request_headers: self.captured_request_headers,
+            captured_response_headers: self.captured_response_headers,
+            captured_query_parameters: self.captured_query_parameters,
+            capture_redactor: self.capture_redactor,
+            rate_report_interval: self.rate_report_interval,
+            retry_detector: self.retry_detector,
+            user_mapper: self.user_mapper,
+            tracer_provider: self.tracer_provider,
+            should_bias_errors: self.should_bias_errors,
+            slow_request_threshold: self.slow_request_threshold,
+            otlp_endpoint: self.otlp_endpoint,
+            max_error_body_capture: self.max_error_body_capture,
+            explicit_settings: self.explicit_settings,
+            should_log_to_console: self.should_log_to_console,
+            console_format: self.console_format,
+            _phantom1: std::marker::PhantomData,
+            _phantom2: std::marker::PhantomData,
+        }
+    }
+
+    /// Sets the effective sample rate of a custom sampler (from [`AppInsights::with_trace_config`]).  The default is none.
+    /// 
+    /// The sampler of the trace config is kept as-is, and this rate is only passed to Application Insights as the sampling weight
+    /// (`itemCount`) of each item.  This takes precedence over [`AppInsights::with_sample_rate`].
+    /// 
+    /// ```
+    /// use axum_insights::{AppInsights, Ready};
+    /// use opentelemetry_sdk::trace::{Config, Sampler};
+    /// 
+    /// let i = AppInsights::default()
+    ///     .with_connection_string(None)
+    ///     .with_trace_config(Config::default().with_sampler(Sampler::TraceIdRatioBased(0.1)))
+    ///     .with_effective_sample_rate(0.1);
+    /// ```
+    pub fn with_effective_sample_rate(self, effective_sample_rate: f64) -> AppInsights<Ready, C, R, U, P, E> {
+        AppInsights {
+            connection_string: self.connection_string,
+            config: self.config,
+            client: self.client,
+            enable_live_metrics: self.enable_live_metrics,
+            sample_rate: self.sample_rate,
+            batch_runtime: self.batch_runtime,
+            minimum_level: self.minimum_level,
+            subscriber: self.subscriber,
+            should_catch_panic: self.should_catch_panic,
+            is_noop: self.is_noop,
+            field_mapper: self.field_mapper,
+            panic_mapper: self.panic_mapper,
+            success_filter: self.success_filter,
+            global_dimensions: self.global_dimensions,
+            build_info: self.build_info,
+            service_version: self.service_version,
+            should_close_on_upgrade: self.should_close_on_upgrade,
+            heartbeat_interval: self.heartbeat_interval,
+            should_record_multipart: self.should_record_multipart,
+            should_record_compression: self.should_record_compression,
+            panic_context_headers: self.panic_context_headers,
+            request_sampler: self.request_sampler,
+            debug_trace_header: self.debug_trace_header,
+            operation_grouper: self.operation_grouper,
+            should_extract_trace_context: self.should_extract_trace_context,
+            should_inject_trace_context: self.should_inject_trace_context,
+            effective_sample_rate: Some(effective_sample_rate),
+            error_response_headers: self.error_response_headers,
+            should_record_metrics: self.should_record_metrics,
+            propagator: self.propagator,
+            async_field_mapper: self.async_field_mapper,
+            clock: self.clock,
+            fallback_mode: self.fallback_mode,
+            should_deserialize_error_body: self.should_deserialize_error_body,
+            request_filter: self.request_filter,
+            excluded_routes: self.excluded_routes,
+            resource_attributes: self.resource_attributes,
+            should_detect_host_name: self.should_detect_host_name,
+            captured_request_headers: self.captured_request_headers,
+            captured_response_headers: self.captured_response_headers,
+            captured_query_parameters: self.captured_query_parameters,
+            capture_redactor: self.capture_redactor,
+            rate_report_interval: self.rate_report_interval,
+            retry_detector: self.retry_detector,
+            user_mapper: self.user_mapper,
+            tracer_provider: self.tracer_provider,
+            should_bias_errors: self.should_bias_errors,
+            slow_request_threshold: self.slow_request_threshold,
+            otlp_endpoint: self.otlp_endpoint,
+            max_error_body_capture: self.max_error_body_capture,
+            explicit_settings: self.explicit_settings,
+            should_log_to_console: self.should_log_to_console,
+            console_format: self.console_format,
+            _phantom1: std::marker::PhantomData,
+            _phantom2: std::marker::PhantomData,
+        }
+    }
+
+    /// Sets the interval of the rate report, which is a periodic summary of the requests of each route.  The default is no rate report.
+    /// 
+    /// At the end of each window, a `rate_report` custom event is emitted for each route that received requests, with the request
+    /// count, the failure count, and the p50 and p95 latencies (in milliseconds) of the window.  The summary is computed in-process,
+    /// and the events are always sampled, so this gives coarse visibility even when the request telemetry is aggressively sampled.
+    /// The latencies are computed over (at most) the most recent 4096 requests of each route in the window.
+    /// 
+    /// ```
+    /// use axum_insights::{AppInsights, Ready};
+    /// use std::time::Duration;
+    /// 
+    /// let i = AppInsights::default()
+    ///     .with_connection_string(None)
+    ///     .with_service_config("namespace", "name")
+    ///     .with_rate_report(Duration::from_secs(60));
+    /// ```
+    pub fn with_rate_report(self, interval: Duration) -> AppInsights<Ready, C, R, U, P, E> {
+        AppInsights {
+            connection_string: self.connection_string,
+            config: self.config,
+            client: self.client,
+            enable_live_metrics: self.enable_live_metrics,
+            sample_rate: self.sample_rate,
+            batch_runtime: self.batch_runtime,
+            minimum_level: self.minimum_level,
+            subscriber: self.subscriber,
+            should_catch_panic: self.should_catch_panic,
+            is_noop: self.is_noop,
+            field_mapper: self.field_mapper,
+            panic_mapper: self.panic_mapper,
+            success_filter: self.success_filter,
+            global_dimensions: self.global_dimensions,
+            build_info: self.build_info,
+            service_version: self.service_version,
+            should_close_on_upgrade: self.should_close_on_upgrade,
+            heartbeat_interval: self.heartbeat_interval,
+            should_record_multipart: self.should_record_multipart,
+            should_record_compression: self.should_record_compression,
+            panic_context_headers: self.panic_context_headers,
+            request_sampler: self.request_sampler,
+            debug_trace_header: self.debug_trace_header,
+            operation_grouper: self.operation_grouper,
+            should_extract_trace_context: self.should_extract_trace_context,
+            should_inject_trace_context: self.should_inject_trace_context,
+            effective_sample_rate: self.effective_sample_rate,
+            error_response_headers: self.error_response_headers,
+            should_record_metrics: self.should_record_metrics,
+            propagator: self.propagator,
+            async_field_mapper: self.async_field_mapper,
+            clock: self.clock,
+            fallback_mode: self.fallback_mode,
+            should_deserialize_error_body: self.should_deserialize_error_body,
+            request_filter: self.request_filter,
+            excluded_routes: self.excluded_routes,
+            resource_attributes: self.resource_attributes,
+            should_detect_host_name: self.should_detect_host_name,
+            captured_request_headers: self.captured_request_headers,
+            captured_response_headers: self.captured_response_headers,
+            captured_query_parameters: self.captured_query_parameters,
+            capture_redactor: self.capture_redactor,
+            rate_report_interval: Some(interval),
+            retry_detector: self.retry_detector,
+            user_mapper: self.user_mapper,
+            tracer_provider: self.tracer_provider,
+            should_bias_errors: self.should_bias_errors,
+            slow_request_threshold: self.slow_request_threshold,
+            otlp_endpoint: self.otlp_endpoint,
+            max_error_body_capture: self.max_error_body_capture,
+            explicit_settings: self.explicit_settings,
+            should_log_to_console: self.should_log_to_console,
+            console_format: self.console_format,
+            _phantom1: std::marker::PhantomData,
+            _phantom2: std::marker::PhantomData,
+        }
+    }
+
+    /// Sets the request headers that are recorded on the request span.  The default is no headers.
+    /// 
+    /// The selected headers (if present) are recorded as `http.request.header.<name>` attributes, after being passed through
+    /// the redactor given to [`AppInsights::with_capture_redactor`].
+    /// 
+    /// ```
+    /// use axum_insights::{AppInsights, Ready};
+    /// 
+    /// let i = AppInsights::default()
+    ///     .with_connection_string(None)
+    ///     .with_service_config("namespace", "name")
+    ///     .with_captured_request_headers(["x-correlation-id", "user-agent"]);
+    /// ```
+    pub fn with_captured_request_headers<I, K>(self, headers: I) -> AppInsights<Ready, C, R, U, P, E>
+    where
+        I: IntoIterator<Item = K>,
+        K: AsRef<str>,
+    {
+        let captured_request_headers = headers.into_iter().filter_map(|h| http::HeaderName::try_from(h.as_ref()).ok()).collect();
+
+        AppInsights {
+            connection_string: self.connection_string,
+            config: self.config,
+            client: self.client,
+            enable_live_metrics: self.enable_live_metrics,
+            sample_rate: self.sample_rate,
+            batch_runtime: self.batch_runtime,
+            minimum_level: self.minimum_level,
+            subscriber: self.subscriber,
+            should_catch_panic: self.should_catch_panic,
+            is_noop: self.is_noop,
+            field_mapper: self.field_mapper,
+            panic_mapper: self.panic_mapper,
+            success_filter: self.success_filter,
+            global_dimensions: self.global_dimensions,
+            build_info: self.build_info,
+            service_version: self.service_version,
+            should_close_on_upgrade: self.should_close_on_upgrade,
+            heartbeat_interval: self.heartbeat_interval,
+            should_record_multipart: self.should_record_multipart,
+            should_record_compression: self.should_record_compression,
+            panic_context_headers: self.panic_context_headers,
+            request_sampler: self.request_sampler,
+            debug_trace_header: self.debug_trace_header,
+            operation_grouper: self.operation_grouper,
+            should_extract_trace_context: self.should_extract_trace_context,
+            should_inject_trace_context: self.should_inject_trace_context,
+            effective_sample_rate: self.effective_sample_rate,
+            error_response_headers: self.error_response_headers,
+            should_record_metrics: self.should_record_metrics,
+            propagator: self.propagator,
+            async_field_mapper: self.async_field_mapper,
+            clock: self.clock,
+            fallback_mode: self.fallback_mode,
+            should_deserialize_error_body: self.should_deserialize_error_body,
+            request_filter: self.request_filter,
+            excluded_routes: self.excluded_routes,
+            resource_attributes: self.resource_attributes,
+            should_detect_host_name: self.should_detect_host_name,
+            captured_request_headers,
+            captured_response_headers: self.captured_response_headers,
+            captured_query_parameters: self.captured_query_parameters,
+            capture_redactor: self.capture_redactor,
+            rate_report_interval: self.rate_report_interval,
+            retry_detector: self.retry_detector,
+            user_mapper: self.user_mapper,
+            tracer_provider: self.tracer_provider,
+            should_bias_errors: self.should_bias_errors,
+            slow_request_threshold: self.slow_request_threshold,
+            otlp_endpoint: self.otlp_endpoint,
+            max_error_body_capture: self.max_error_body_capture,
+            explicit_settings: self.explicit_settings,
+            should_log_to_console: self.should_log_to_console,
+            console_format: self.console_format,
+            _phantom1: std::marker::PhantomData,
+            _phantom2: std::marker::PhantomData,
+        }
+    }
+
+    /// Sets the response headers that are recorded on the request span.  The default is no headers.
+    /// 
+    /// The selected headers (if present) are recorded as `http.response.header.<name>` attributes, after being passed through
+    /// the redactor given to [`AppInsights::with_capture_redactor`].
+    /// 
+    /// ```
+    /// use axum_insights::{AppInsights, Ready};
+    /// 
+    /// let i = AppInsights::default()
+    ///     .with_connection_string(None)
+    ///     .with_service_config("namespace", "name")
+    ///     .with_captured_response_headers(["retry-after"]);
+    /// ```
+    pub fn with_captured_response_headers<I, K>(self, headers: I) -> AppInsights<Ready, C, R, U, P, E>
+    where
+        I: IntoIterator<Item = K>,
+        K: AsRef<str>,
+    {
+        let captured_response_headers = headers.into_iter().filter_map(|h| http::HeaderName::try_from(h.as_ref()).ok()).collect();
+
+        AppInsights {
+            connection_string: self.connection_string,
+            config: self.config,
+            client: self.client,
+            enable_live_metrics: self.enable_live_metrics,
+            sample_rate: self.sample_rate,
+            batch_runtime: self.batch_runtime,
+            minimum_level: self.minimum_level,
+            subscriber: self.subscriber,
+            should_catch_panic: self.should_catch_panic,
+            is_noop: self.is_noop,
+            field_mapper: self.field_mapper,
+            panic_mapper: self.panic_mapper,
+            success_filter: self.success_filter,
+            global_dimensions: self.global_dimensions,
+            build_info: self.build_info,
+            service_version: self.service_version,
+            should_close_on_upgrade: self.should_close_on_upgrade,
+            heartbeat_interval: self.heartbeat_interval,
+            should_record_multipart: self.should_record_multipart,
+            should_record_compression: self.should_record_compression,
+            panic_context_headers: self.panic_context_headers,
+            request_sampler: self.request_sampler,
+            debug_trace_header: self.debug_trace_header,
+            operation_grouper: self.operation_grouper,
+            should_extract_trace_context: self.should_extract_trace_context,
+            should_inject_trace_context: self.should_inject_trace_context,
+            effective_sample_rate: self.effective_sample_rate,
+            error_response_headers: self.error_response_headers,
+            should_record_metrics: self.should_record_metrics,
+            propagator: self.propagator,
+            async_field_mapper: self.async_field_mapper,
+            clock: self.clock,
+            fallback_mode: self.fallback_mode,
+            should_deserialize_error_body: self.should_deserialize_error_body,
+            request_filter: self.request_filter,
+            excluded_routes: self.excluded_routes,
+            resource_attributes: self.resource_attributes,
+            should_detect_host_name: self.should_detect_host_name,
+            captured_request_headers: self.captured_request_headers,
+            captured_response_headers,
+            captured_query_parameters: self.captured_query_parameters,
+            capture_redactor: self.capture_redactor,
+            rate_report_interval: self.rate_report_interval,
+            retry_detector: self.retry_detector,
+            user_mapper: self.user_mapper,
+            tracer_provider: self.tracer_provider,
+            should_bias_errors: self.should_bias_errors,
+            slow_request_threshold: self.slow_request_threshold,
+            otlp_endpoint: self.otlp_endpoint,
+            max_error_body_capture: self.max_error_body_capture,
+            explicit_settings: self.explicit_settings,
+            should_log_to_console: self.should_log_to_console,
+            console_format: self.console_format,
+            _phantom1: std::marker::PhantomData,
+            _phantom2: std::marker::PhantomData,
+        }
+    }
+
+    /// Sets the query parameters that are recorded on the request span.  The default is no query parameters.
+    /// 
+    /// The selected query parameters (if present) are recorded as `url.query.<name>` attributes, after being passed through
+    /// the redactor given to [`AppInsights::with_capture_redactor`].
+    /// 
+    /// ```
+    /// use axum_insights::{AppInsights, Ready};
+    /// 
+    /// let i = AppInsights::default()
+    ///     .with_connection_string(None)
+    ///     .with_service_config("namespace", "name")
+    ///     .with_captured_query_parameters(["page", "api_key"]);
+    /// ```
+    pub fn with_captured_query_parameters<I, K>(self, parameters: I) -> AppInsights<Ready, C, R, U, P, E>
+    where
+        I: IntoIterator<Item = K>,
+        K: Into<String>,
+    {
+        AppInsights {
+            connection_string: self.connection_string,
+            config: self.config,
+            client: self.client,
+            enable_live_metrics: self.enable_live_metrics,
+            sample_rate: self.sample_rate,
+            batch_runtime: self.batch_runtime,
+            minimum_level: self.minimum_level,
+            subscriber: self.subscriber,
+            should_catch_panic: self.should_catch_panic,
+            is_noop: self.is_noop,
+            field_mapper: self.field_mapper,
+            panic_mapper: self.panic_mapper,
+            success_filter: self.success_filter,
+            global_dimensions: self.global_dimensions,
+            build_info: self.build_info,
+            service_version: self.service_version,
+            should_close_on_upgrade: self.should_close_on_upgrade,
+            heartbeat_interval: self.heartbeat_interval,
+            should_record_multipart: self.should_record_multipart,
+            should_record_compression: self.should_record_compression,
+            panic_context_headers: self.panic_context_headers,
+            request_sampler: self.request_sampler,
+            debug_trace_header: self.debug_trace_header,
+            operation_grouper: self.operation_grouper,
+            should_extract_trace_context: self.should_extract_trace_context,
+            should_inject_trace_context: self.should_inject_trace_context,
+            effective_sample_rate: self.effective_sample_rate,
+            error_response_headers: self.error_response_headers,
+            should_record_metrics: self.should_record_metrics,
+            propagator: self.propagator,
+            async_field_mapper: self.async_field_mapper,
+            clock: self.clock,
+            fallback_mode: self.fallback_mode,
+            should_deserialize_error_body: self.should_deserialize_error_body,
+            request_filter: self.request_filter,
+            excluded_routes: self.excluded_routes,
+            resource_attributes: self.resource_attributes,
+            should_detect_host_name: self.should_detect_host_name,
+            captured_request_headers: self.captured_request_headers,
+            captured_response_headers: self.captured_response_headers,
+            captured_query_parameters: parameters.into_iter().map(Into::into).collect(),
+            capture_redactor: self.capture_redactor,
+            rate_report_interval: self.rate_report_interval,
+            retry_detector: self.retry_detector,
+            user_mapper: self.user_mapper,
+            tracer_provider: self.tracer_provider,
+            should_bias_errors: self.should_bias_errors,
+            slow_request_threshold: self.slow_request_threshold,
+            otlp_endpoint: self.otlp_endpoint,
+            max_error_body_capture: self.max_error_body_capture,
+            explicit_settings: self.explicit_settings,
+            should_log_to_console: self.should_log_to_console,
+            console_format: self.console_format,
+            _phantom1: std::marker::PhantomData,
+            _phantom2: std::marker::PhantomData,
+        }
+    }
+
+    /// Sets a function that redacts the captured headers and query parameters before they are recorded.  The default records
+    /// the values as they are.
+    /// 
+    /// The function receives the name (lowercase for headers) and the value, and returns the value to record.  This is where
+    /// sensitive values (e.g., `authorization`) should be masked or hashed.
+    /// 
+    /// ```
+    /// use axum_insights::{AppInsights, Ready};
+    /// 
+    /// let i = AppInsights::default()
+    ///     .with_connection_string(None)
+    ///     .with_service_config("namespace", "name")
+    ///     .with_captured_request_headers(["authorization", "user-agent"])
+    ///     .with_capture_redactor(|name, value| match name {
+    ///         "authorization" | "api_key" => "REDACTED".to_owned(),
+    ///         _ => value.to_owned(),
+    ///     });
+    /// ```
+    pub fn with_capture_redactor<F>(self, capture_redactor: F) -> AppInsights<Ready, C, R, U, P, E>
+    where
+        F: Fn(&str, &str) -> String + Send + Sync + 'static,
+    {
+        AppInsights {
+            connection_string: self.connection_string,
+            config: self.config,
+            client: self.client,
+            enable_live_metrics: self.enable_live_metrics,
+            sample_rate: self.sample_rate,
+            batch_runtime: self.batch_runtime,
+            minimum_level: self.minimum_level,
+            subscriber: self.subscriber,
+            should_catch_panic: self.should_catch_panic,
+            is_noop: self.is_noop,
+            field_mapper: self.field_mapper,
+            panic_mapper: self.panic_mapper,
+            success_filter: self.success_filter,
+            global_dimensions: self.global_dimensions,
+            build_info: self.build_info,
+            service_version: self.service_version,
+            should_close_on_upgrade: self.should_close_on_upgrade,
+            heartbeat_interval: self.heartbeat_interval,
+            should_record_multipart: self.should_record_multipart,
+            should_record_compression: self.should_record_compression,
+            panic_context_headers: self.panic_context_headers,
+            request_sampler: self.request_sampler,
+            debug_trace_header: self.debug_trace_header,
+            operation_grouper: self.operation_grouper,
+            should_extract_trace_context: self.should_extract_trace_context,
+            should_inject_trace_context: self.should_inject_trace_context,
+            effective_sample_rate: self.effective_sample_rate,
+            error_response_headers: self.error_response_headers,
+            should_record_metrics: self.should_record_metrics,
+            propagator: self.propagator,
+            async_field_mapper: self.async_field_mapper,
+            clock: self.clock,
+            fallback_mode: self.fallback_mode,
+            should_deserialize_error_body: self.should_deserialize_error_body,
+            request_filter: self.request_filter,
+            excluded_routes: self.excluded_routes,
+            resource_attributes: self.resource_attributes,
+            should_detect_host_name: self.should_detect_host_name,
+            captured_request_headers: self.captured_request_headers,
+            captured_response_headers: self.captured_response_headers,
+            captured_query_parameters: self.captured_query_parameters,
+            capture_redactor: Some(Arc::new(capture_redactor)),
+            rate_report_interval: self.rate_report_interval,
+            retry_detector: self.retry_detector,
+            user_mapper: self.user_mapper,
+            tracer_provider: self.tracer_provider,
+            should_bias_errors: self.should_bias_errors,
+            slow_request_threshold: self.slow_request_threshold,
+            otlp_endpoint: self.otlp_endpoint,
+            max_error_body_capture: self.max_error_body_capture,
+            explicit_settings: ExplicitSettings { capture_redactor: true, ..self.explicit_settings },
+            should_log_to_console: self.should_log_to_console,
+            console_format: self.console_format,
+            _phantom1: std::marker::PhantomData,
+            _phantom2: std::marker::PhantomData,
+        }
+    }
+
+    /// Sets whether or not to deserialize the error type from the body of a failed response.  The default is true.
+    /// 
+    /// The [`ErrorDetails`] in the response extensions (e.g., from a [`TrackedError`]) are always preferred over the body.  When
+    /// they are absent, and deserialization is disabled (or fails), the exception message is synthesized from the status and a
+    /// snippet of the body.
+    /// 
+    /// ```
+    /// use axum_insights::{AppInsights, Ready};
+    /// 
+    /// let i = AppInsights::default()
+    ///     .with_connection_string(None)
+    ///     .with_service_config("namespace", "name")
+    ///     .with_error_body_deserialization(false);
+    /// ```
+    pub fn with_error_body_deserialization(self, should_deserialize_error_body: bool) -> AppInsights<Ready, C, R, U, P, E> {
+        AppInsights {
+            connection_string: self.connection_string,
+            config: self.config,
+            client: self.client,
+            enable_live_metrics: self.enable_live_metrics,
+            sample_rate: self.sample_rate,
+            batch_runtime: self.batch_runtime,
+            minimum_level: self.minimum_level,
+            subscriber: self.subscriber,
+            should_catch_panic: self.should_catch_panic,
+            is_noop: self.is_noop,
+            field_mapper: self.field_mapper,
+            panic_mapper: self.panic_mapper,
+            success_filter: self.success_filter,
+            global_dimensions: self.global_dimensions,
+            build_info: self.build_info,
+            service_version: self.service_version,
+            should_close_on_upgrade: self.should_close_on_upgrade,
+            heartbeat_interval: self.heartbeat_interval,
+            should_record_multipart: self.should_record_multipart,
+            should_record_compression: self.should_record_compression,
+            panic_context_headers: self.panic_context_headers,
+            request_sampler: self.request_sampler,
+            debug_trace_header: self.debug_trace_header,
+            operation_grouper: self.operation_grouper,
+            should_extract_trace_context: self.should_extract_trace_context,
+            should_inject_trace_context: self.should_inject_trace_context,
+            effective_sample_rate: self.effective_sample_rate,
+            error_response_headers: self.error_response_headers,
+            should_record_metrics: self.should_record_metrics,
+            propagator: self.propagator,
+            async_field_mapper: self.async_field_mapper,
+            clock: self.clock,
+            fallback_mode: self.fallback_mode,
+            should_deserialize_error_body,
+            request_filter: self.request_filter,
+            excluded_routes: self.excluded_routes,
+            resource_attributes: self.resource_attributes,
+            should_detect_host_name: self.should_detect_host_name,
+            captured_request_headers: self.captured_request_headers,
+            captured_response_headers: self.captured_response_headers,
+            captured_query_parameters: self.captured_query_parameters,
+            capture_redactor: self.capture_redactor,
+            rate_report_interval: self.rate_report_interval,
+            retry_detector: self.retry_detector,
+            user_mapper: self.user_mapper,
+            tracer_provider: self.tracer_provider,
+            should_bias_errors: self.should_bias_errors,
+            slow_request_threshold: self.slow_request_threshold,
+            otlp_endpoint: self.otlp_endpoint,
+            max_error_body_capture: self.max_error_body_capture,
+            explicit_settings: self.explicit_settings,
+            should_log_to_console: self.should_log_to_console,
+            console_format: self.console_format,
+            _phantom1: std::marker::PhantomData,
+            _phantom2: std::marker::PhantomData,
+        }
+    }
+
+    /// Sets the maximum number of bytes of a failed response body that are buffered to extract the error.  The default is 64 KiB.
+    /// 
+    /// A body that is larger than the limit is only buffered up to the limit (and then streamed on as usual), so the error is not
+    /// deserialized, and the exception message is synthesized from the beginning of the body.  A body whose size is not known up
+    /// front (e.g., a streaming or chunked response, such as server-sent events) is never buffered.  Either way, the body size
+    /// (`http.response.body.size`) is recorded when it is known, along with the status.
+    /// 
+    /// ```
+    /// use axum_insights::{AppInsights, Ready};
+    /// 
+    /// let i = AppInsights::default()
+    ///     .with_connection_string(None)
+    ///     .with_service_config("namespace", "name")
+    ///     .with_max_error_body_capture(16 * 1024);
+    /// ```
+    pub fn with_max_error_body_capture(self, max_error_body_capture: usize) -> AppInsights<Ready, C, R, U, P, E> {
+        AppInsights {
+            connection_string: self.connection_string,
+            config: self.config,
+            client: self.client,
+            enable_live_metrics: self.enable_live_metrics,
+            sample_rate: self.sample_rate,
+            batch_runtime: self.batch_runtime,
+            minimum_level: self.minimum_level,
+            subscriber: self.subscriber,
+            should_catch_panic: self.should_catch_panic,
+            is_noop: self.is_noop,
+            field_mapper: self.field_mapper,
+            panic_mapper: self.panic_mapper,
+            success_filter: self.success_filter,
+            global_dimensions: self.global_dimensions,
+            build_info: self.build_info,
+            service_version: self.service_version,
+            should_close_on_upgrade: self.should_close_on_upgrade,
+            heartbeat_interval: self.heartbeat_interval,
+            should_record_multipart: self.should_record_multipart,
+            should_record_compression: self.should_record_compression,
+            panic_context_headers: self.panic_context_headers,
+            request_sampler: self.request_sampler,
+            debug_trace_header: self.debug_trace_header,
+            operation_grouper: self.operation_grouper,
+            should_extract_trace_context: self.should_extract_trace_context,
+            should_inject_trace_context: self.should_inject_trace_context,
+            effective_sample_rate: self.effective_sample_rate,
+            error_response_headers: self.error_response_headers,
+            should_record_metrics: self.should_record_metrics,
+            propagator: self.propagator,
+            async_field_mapper: self.async_field_mapper,
+            clock: self.clock,
+            fallback_mode: self.fallback_mode,
+            should_deserialize_error_body: self.should_deserialize_error_body,
+            request_filter: self.request_filter,
+            excluded_routes: self.excluded_routes,
+            resource_attributes: self.resource_attributes,
+            should_detect_host_name: self.should_detect_host_name,
+            captured_request_headers: self.captured_request_headers,
+            captured_response_headers: self.captured_response_headers,
+            captured_query_parameters: self.captured_query_parameters,
+            capture_redactor: self.capture_redactor,
+            rate_report_interval: self.rate_report_interval,
+            retry_detector: self.retry_detector,
+            user_mapper: self.user_mapper,
+            tracer_provider: self.tracer_provider,
+            should_bias_errors: self.should_bias_errors,
+            slow_request_threshold: self.slow_request_threshold,
+            otlp_endpoint: self.otlp_endpoint,
+            max_error_body_capture,
+            explicit_settings: ExplicitSettings { max_error_body_capture: true, ..self.explicit_settings },
+            should_log_to_console: self.should_log_to_console,
+            console_format: self.console_format,
+            _phantom1: std::marker::PhantomData,
+            _phantom2: std::marker::PhantomData,
+        }
+    }
+
+    /// Sets the response headers that are recorded on the exception of a failed request.  The default is no headers.
+    /// 
+    /// Many backends communicate error semantics via headers rather than bodies, so the selected headers (if present) are
+    /// attached to the exception as the `response_headers` property.
+    /// 
+    /// ```
+    /// use axum_insights::{AppInsights, Ready};
+    /// 
+    /// let i = AppInsights::default()
+    ///     .with_connection_string(None)
+    ///     .with_service_config("namespace", "name")
+    ///     .with_error_response_headers(["x-error-code", "www-authenticate", "retry-after"]);
+    /// ```
+    pub fn with_error_response_headers<I, K>(self, headers: I) -> AppInsights<Ready, C, R, U, P, E>
+    where
+        I: IntoIterator<Item = K>,
+        K: AsRef<str>,
+    {
+        let error_response_headers = headers.into_iter().filter_map(|h| http::HeaderName::try_from(h.as_ref()).ok()).collect();
+
+        AppInsights {
+            connection_string: self.connection_string,
+            config: self.config,
+            client: self.client,
+            enable_live_metrics: self.enable_live_metrics,
+            sample_rate: self.sample_rate,
+            batch_runtime: self.batch_runtime,
+            minimum_level: self.minimum_level,
+            subscriber: self.subscriber,
+            should_catch_panic: self.should_catch_panic,
+            is_noop: self.is_noop,
+            field_mapper: self.field_mapper,
+            panic_mapper: self.panic_mapper,
+            success_filter: self.success_filter,
+            global_dimensions: self.global_dimensions,
+            build_info: self.build_info,
+            service_version: self.service_version,
+            should_close_on_upgrade: self.should_close_on_upgrade,
+            heartbeat_interval: self.heartbeat_interval,
+            should_record_multipart: self.should_record_multipart,
+            should_record_compression: self.should_record_compression,
+            panic_context_headers: self.panic_context_headers,
+            request_sampler: self.request_sampler,
+            debug_trace_header: self.debug_trace_header,
+            operation_grouper: self.operation_grouper,
+            should_extract_trace_context: self.should_extract_trace_context,
+            should_inject_trace_context: self.should_inject_trace_context,
+            effective_sample_rate: self.effective_sample_rate,
+            error_response_headers,
+            should_record_metrics: self.should_record_metrics,
+            propagator: self.propagator,
+            async_field_mapper: self.async_field_mapper,
+            clock: self.clock,
+            fallback_mode: self.fallback_mode,
+            should_deserialize_error_body: self.should_deserialize_error_body,
+            request_filter: self.request_filter,
+            excluded_routes: self.excluded_routes,
+            resource_attributes: self.resource_attributes,
+            should_detect_host_name: self.should_detect_host_name,
+            captured_request_headers: self.captured_request_headers,
+            captured_response_headers: self.captured_response_headers,
+            captured_query_parameters: self.captured_query_parameters,
+            capture_redactor: self.capture_redactor,
+            rate_report_interval: self.rate_report_interval,
+            retry_detector: self.retry_detector,
+            user_mapper: self.user_mapper,
+            tracer_provider: self.tracer_provider,
+            should_bias_errors: self.should_bias_errors,
+            slow_request_threshold: self.slow_request_threshold,
+            otlp_endpoint: self.otlp_endpoint,
+            max_error_body_capture: self.max_error_body_capture,
+            explicit_settings: self.explicit_settings,
+            should_log_to_console: self.should_log_to_console,
+            console_format: self.console_format,
+            _phantom1: std::marker::PhantomData,
+            _phantom2: std::marker::PhantomData,
+        }
+    }
+
+    /// Sets whether or not to record request metrics, which are exported as custom metrics.  The default is false.
+    /// 
+    /// This installs an OpenTelemetry metrics pipeline alongside the tracer, and the middleware records the
+    /// `http.server.request.duration` histogram (in seconds), and the `http.server.request.count` and `http.server.request.failures`
+    /// counters, with the route, method, and status as dimensions.  The metrics are only exported to Application Insights, so they
+    /// are disabled (with a warning) without a connection string, e.g., with the OTLP exporter.
+    /// 
+    /// ```
+    /// use axum_insights::{AppInsights, Ready};
+    /// 
+    /// let i = AppInsights::default()
+    ///     .with_connection_string(None)
+    ///     .with_service_config("namespace", "name")
+    ///     .with_metrics(true);
+    /// ```
+    pub fn with_metrics(self, should_record_metrics: bool) -> AppInsights<Ready, C, R, U, P, E> {
+        AppInsights {
+            connection_string: self.connection_string,
+            config: self.config,
+            client: self.client,
+            enable_live_metrics: self.enable_live_metrics,
+            sample_rate: self.sample_rate,
+            batch_runtime: self.batch_runtime,
+            minimum_level: self.minimum_level,
+            subscriber: self.subscriber,
+            should_catch_panic: self.should_catch_panic,
+            is_noop: self.is_noop,
+            field_mapper: self.field_mapper,
+            panic_mapper: self.panic_mapper,
+            success_filter: self.success_filter,
+            global_dimensions: self.global_dimensions,
+            build_info: self.build_info,
+            service_version: self.service_version,
+            should_close_on_upgrade: self.should_close_on_upgrade,
+            heartbeat_interval: self.heartbeat_interval,
+            should_record_multipart: self.should_record_multipart,
+            should_record_compression: self.should_record_compression,
+            panic_context_headers: self.panic_context_headers,
+            request_sampler: self.request_sampler,
+            debug_trace_header: self.debug_trace_header,
+            operation_grouper: self.operation_grouper,
+            should_extract_trace_context: self.should_extract_trace_context,
+            should_inject_trace_context: self.should_inject_trace_context,
+            effective_sample_rate: self.effective_sample_rate,
+            error_response_headers: self.error_response_headers,
+            should_record_metrics,
+            propagator: self.propagator,
+            async_field_mapper: self.async_field_mapper,
+            clock: self.clock,
+            fallback_mode: self.fallback_mode,
+            should_deserialize_error_body: self.should_deserialize_error_body,
+            request_filter: self.request_filter,
+            excluded_routes: self.excluded_routes,
+            resource_attributes: self.resource_attributes,
+            should_detect_host_name: self.should_detect_host_name,
+            captured_request_headers: self.captured_request_headers,
+            captured_response_headers: self.captured_response_headers,
+            captured_query_parameters: self.captured_query_parameters,
+            capture_redactor: self.capture_redactor,
+            rate_report_interval: self.rate_report_interval,
+            retry_detector: self.retry_detector,
+            user_mapper: self.user_mapper,
+            tracer_provider: self.tracer_provider,
+            should_bias_errors: self.should_bias_errors,
+            slow_request_threshold: self.slow_request_threshold,
+            otlp_endpoint: self.otlp_endpoint,
+            max_error_body_capture: self.max_error_body_capture,
+            explicit_settings: self.explicit_settings,
+            should_log_to_console: self.should_log_to_console,
+            console_format: self.console_format,
+            _phantom1: std::marker::PhantomData,
+            _phantom2: std::marker::PhantomData,
+        }
+    }
+
+    /// Sets a custom propagator (e.g., B3, Jaeger, or a composite) for interop with systems that do not use W3C trace context.
+    /// The default extracts the W3C trace context (or the legacy `Request-Id`), and injects the W3C trace context.
+    /// 
+    /// The propagator is used to extract the remote parent of incoming requests, and it is installed as the global propagator,
+    /// so that it is also used to inject the trace context into outgoing dependency calls (see [`deps::track_reqwest`]).  Without
+    /// it, the global propagator is left alone: an application that installs its own keeps it.
+    /// 
+    /// ```
+    /// use axum_insights::{AppInsights, Ready};
+    /// use opentelemetry::propagation::TextMapCompositePropagator;
+    /// use opentelemetry_sdk::propagation::{BaggagePropagator, TraceContextPropagator};
+    /// 
+    /// let i = AppInsights::default()
+    ///     .with_connection_string(None)
+    ///     .with_service_config("namespace", "name")
+    ///     .with_propagator(TextMapCompositePropagator::new(vec![
+    ///         Box::new(TraceContextPropagator::new()),
+    ///         Box::new(BaggagePropagator::new()),
+    ///     ]));
+    /// ```
+    pub fn with_propagator<T>(self, propagator: T) -> AppInsights<Ready, C, R, U, P, E>
+    where
+        T: TextMapPropagator + Send + Sync + 'static,
+    {
+        AppInsights {
+            connection_string: self.connection_string,
+            config: self.config,
+            client: self.client,
+            enable_live_metrics: self.enable_live_metrics,
+            sample_rate: self.sample_rate,
+            batch_runtime: self.batch_runtime,
+            minimum_level: self.minimum_level,
+            subscriber: self.subscriber,
+            should_catch_panic: self.should_catch_panic,
+            is_noop: self.is_noop,
+            field_mapper: self.field_mapper,
+            panic_mapper: self.panic_mapper,
+            success_filter: self.success_filter,
+            global_dimensions: self.global_dimensions,
+            build_info: self.build_info,
+            service_version: self.service_version,
+            should_close_on_upgrade: self.should_close_on_upgrade,
+            heartbeat_interval: self.heartbeat_interval,
+            should_record_multipart: self.should_record_multipart,
+            should_record_compression: self.should_record_compression,
+            panic_context_headers: self.panic_context_headers,
+            request_sampler: self.request_sampler,
+            debug_trace_header: self.debug_trace_header,
+            operation_grouper: self.operation_grouper,
+            should_extract_trace_context: self.should_extract_trace_context,
+            should_inject_trace_context: self.should_inject_trace_context,
+            effective_sample_rate: self.effective_sample_rate,
+            error_response_headers: self.error_response_headers,
+            should_record_metrics: self.should_record_metrics,
+            propagator: Some(Arc::new(propagator)),
+            async_field_mapper: self.async_field_mapper,
+            clock: self.clock,
+            fallback_mode: self.fallback_mode,
+            should_deserialize_error_body: self.should_deserialize_error_body,
+            request_filter: self.request_filter,
+            excluded_routes: self.excluded_routes,
+            resource_attributes: self.resource_attributes,
+            should_detect_host_name: self.should_detect_host_name,
+            captured_request_headers: self.captured_request_headers,
+            captured_response_headers: self.captured_response_headers,
+            captured_query_parameters: self.captured_query_parameters,
+            capture_redactor: self.capture_redactor,
+            rate_report_interval: self.rate_report_interval,
+            retry_detector: self.retry_detector,
+            user_mapper: self.user_mapper,
+            tracer_provider: self.tracer_provider,
+            should_bias_errors: self.should_bias_errors,
+            slow_request_threshold: self.slow_request_threshold,
+            otlp_endpoint: self.otlp_endpoint,
+            max_error_body_capture: self.max_error_body_capture,
+            explicit_settings: self.explicit_settings,
+            should_log_to_console: self.should_log_to_console,
+            console_format: self.console_format,
+            _phantom1: std::marker::PhantomData,
+            _phantom2: std::marker::PhantomData,
+        }
+    }
+
+    /// Sets the clock that the middleware uses to measure durations.  The default is the [`SystemClock`].
+    /// 
+    /// This drives the start and end times of the spans, the request metrics, and the heartbeat events (including the
+    /// [`ConnectionHeartbeat`]s created within a request), so a [`ManualClock`] makes all of them deterministic in tests.
+    /// 
+    /// ```
+    /// use axum_insights::{AppInsights, ManualClock, Ready};
+    /// use std::sync::Arc;
+    /// 
+    /// let clock = Arc::new(ManualClock::new());
+    /// 
+    /// let i = AppInsights::default()
+    ///     .with_connection_string(None)
+    ///     .with_service_config("namespace", "name")
+    ///     .with_clock(clock.clone());
+    /// ```
+    pub fn with_clock<T>(self, clock: T) -> AppInsights<Ready, C, R, U, P, E>
+    where
+        T: Clock,
+    {
+        AppInsights {
+            connection_string: self.connection_string,
+            config: self.config,
+            client: self.client,
+            enable_live_metrics: self.enable_live_metrics,
+            sample_rate: self.sample_rate,
+            batch_runtime: self.batch_runtime,
+            minimum_level: self.minimum_level,
+            subscriber: self.subscriber,
+            should_catch_panic: self.should_catch_panic,
+            is_noop: self.is_noop,
+            field_mapper: self.field_mapper,
+            panic_mapper: self.panic_mapper,
+            success_filter: self.success_filter,
+            global_dimensions: self.global_dimensions,
+            build_info: self.build_info,
+            service_version: self.service_version,
+            should_close_on_upgrade: self.should_close_on_upgrade,
+            heartbeat_interval: self.heartbeat_interval,
+            should_record_multipart: self.should_record_multipart,
+            should_record_compression: self.should_record_compression,
+            panic_context_headers: self.panic_context_headers,
+            request_sampler: self.request_sampler,
+            debug_trace_header: self.debug_trace_header,
+            operation_grouper: self.operation_grouper,
+            should_extract_trace_context: self.should_extract_trace_context,
+            should_inject_trace_context: self.should_inject_trace_context,
+            effective_sample_rate: self.effective_sample_rate,
+            error_response_headers: self.error_response_headers,
+            should_record_metrics: self.should_record_metrics,
+            propagator: self.propagator,
+            async_field_mapper: self.async_field_mapper,
+            clock: Arc::new(clock),
+            fallback_mode: self.fallback_mode,
+            should_deserialize_error_body: self.should_deserialize_error_body,
+            request_filter: self.request_filter,
+            excluded_routes: self.excluded_routes,
+            resource_attributes: self.resource_attributes,
+            should_detect_host_name: self.should_detect_host_name,
+            captured_request_headers: self.captured_request_headers,
+            captured_response_headers: self.captured_response_headers,
+            captured_query_parameters: self.captured_query_parameters,
+            capture_redactor: self.capture_redactor,
+            rate_report_interval: self.rate_report_interval,
+            retry_detector: self.retry_detector,
+            user_mapper: self.user_mapper,
+            tracer_provider: self.tracer_provider,
+            should_bias_errors: self.should_bias_errors,
+            slow_request_threshold: self.slow_request_threshold,
+            otlp_endpoint: self.otlp_endpoint,
+            max_error_body_capture: self.max_error_body_capture,
+            explicit_settings: self.explicit_settings,
+            should_log_to_console: self.should_log_to_console,
+            console_format: self.console_format,
+            _phantom1: std::marker::PhantomData,
+            _phantom2: std::marker::PhantomData,
+        }
+    }
+
+    /// Sets additional resource attributes, which are merged into the resource of the trace config.  The default is no attributes.
+    /// 
+    /// Unlike [`AppInsights::with_trace_config`], this keeps the resource built by [`AppInsights::with_service_config`], and
+    /// the given attributes take precedence over it.
+    /// 
+    /// ```
+    /// use axum_insights::{AppInsights, Ready};
+    /// use opentelemetry::KeyValue;
+    /// 
+    /// let i: AppInsights<Ready> = AppInsights::default()
+    ///     .with_connection_string(None)
+    ///     .with_service_config("namespace", "name")
+    ///     .with_resource_attributes(vec![
+    ///         KeyValue::new("deployment.environment", "production"),
+    ///         KeyValue::new("service.instance.id", "pod-1"),
+    ///     ]);
+    /// ```
+    pub fn with_resource_attributes<I>(self, resource_attributes: I) -> AppInsights<Ready, C, R, U, P, E>
+    where
+        I: IntoIterator<Item = KeyValue>,
+    {
+        AppInsights {
+            connection_string: self.connection_string,
+            config: self.config,
+            client: self.client,
+            enable_live_metrics: self.enable_live_metrics,
+            sample_rate: self.sample_rate,
+            batch_runtime: self.batch_runtime,
+            minimum_level: self.minimum_level,
+            subscriber: self.subscriber,
+            should_catch_panic: self.should_catch_panic,
+            is_noop: self.is_noop,
+            field_mapper: self.field_mapper,
+            panic_mapper: self.panic_mapper,
+            success_filter: self.success_filter,
+            global_dimensions: self.global_dimensions,
+            build_info: self.build_info,
+            service_version: self.service_version,
+            should_close_on_upgrade: self.should_close_on_upgrade,
+            heartbeat_interval: self.heartbeat_interval,
+            should_record_multipart: self.should_record_multipart,
+            should_record_compression: self.should_record_compression,
+            panic_context_headers: self.panic_context_headers,
+            request_sampler: self.request_sampler,
+            debug_trace_header: self.debug_trace_header,
+            operation_grouper: self.operation_grouper,
+            should_extract_trace_context: self.should_extract_trace_context,
+            should_inject_trace_context: self.should_inject_trace_context,
+            effective_sample_rate: self.effective_sample_rate,
+            error_response_headers: self.error_response_headers,
+            should_record_metrics: self.should_record_metrics,
+            propagator: self.propagator,
+            async_field_mapper: self.async_field_mapper,
+            clock: self.clock,
+            fallback_mode: self.fallback_mode,
+            should_deserialize_error_body: self.should_deserialize_error_body,
+            request_filter: self.request_filter,
+            excluded_routes: self.excluded_routes,
+            resource_attributes: resource_attributes.into_iter().collect(),
+            should_detect_host_name: self.should_detect_host_name,
+            captured_request_headers: self.captured_request_headers,
+            captured_response_headers: self.captured_response_headers,
+            captured_query_parameters: self.captured_query_parameters,
+            capture_redactor: self.capture_redactor,
+            rate_report_interval: self.rate_report_interval,
+            retry_detector: self.retry_detector,
+            user_mapper: self.user_mapper,
+            tracer_provider: self.tracer_provider,
+            should_bias_errors: self.should_bias_errors,
+            slow_request_threshold: self.slow_request_threshold,
+            otlp_endpoint: self.otlp_endpoint,
+            max_error_body_capture: self.max_error_body_capture,
+            explicit_settings: self.explicit_settings,
+            should_log_to_console: self.should_log_to_console,
+            console_format: self.console_format,
+            _phantom1: std::marker::PhantomData,
+            _phantom2: std::marker::PhantomData,
+        }
+    }
+
+    /// Sets whether or not to detect the host name of the machine (or pod).  The default is true.
+    /// 
+    /// The detected host name is recorded as `host.name`, and (unless the resource already identifies the instance via
+    /// `service.instance.id` or `k8s.pod.name`) as `service.instance.id`, which Application Insights uses as the cloud role
+    /// instance.  This allows live metrics to distinguish the instances of a service.  The host name is read from the
+    /// `HOSTNAME` or `COMPUTERNAME` environment variables, or from `/etc/hostname`.
+    /// 
+    /// ```
+    /// use axum_insights::{AppInsights, Ready};
+    /// 
+    /// let i: AppInsights<Ready> = AppInsights::default()
+    ///     .with_connection_string(None)
+    ///     .with_service_config("namespace", "name")
+    ///     .with_host_name_detection(false);
+    /// ```
+    pub fn with_host_name_detection(self, should_detect_host_name: bool) -> AppInsights<Ready, C, R, U, P, E> {
+        AppInsights {
+            connection_string: self.connection_string,
+            config: self.config,
+            client: self.client,
+            enable_live_metrics: self.enable_live_metrics,
+            sample_rate: self.sample_rate,
+            batch_runtime: self.batch_runtime,
+            minimum_level: self.minimum_level,
+            subscriber: self.subscriber,
+            should_catch_panic: self.should_catch_panic,
+            is_noop: self.is_noop,
+            field_mapper: self.field_mapper,
+            panic_mapper: self.panic_mapper,
+            success_filter: self.success_filter,
+            global_dimensions: self.global_dimensions,
+            build_info: self.build_info,
+            service_version: self.service_version,
+            should_close_on_upgrade: self.should_close_on_upgrade,
+            heartbeat_interval: self.heartbeat_interval,
+            should_record_multipart: self.should_record_multipart,
+            should_record_compression: self.should_record_compression,
+            panic_context_headers: self.panic_context_headers,
+            request_sampler: self.request_sampler,
+            debug_trace_header: self.debug_trace_header,
+            operation_grouper: self.operation_grouper,
+            should_extract_trace_context: self.should_extract_trace_context,
+            should_inject_trace_context: self.should_inject_trace_context,
+            effective_sample_rate: self.effective_sample_rate,
+            error_response_headers: self.error_response_headers,
+            should_record_metrics: self.should_record_metrics,
+            propagator: self.propagator,
+            async_field_mapper: self.async_field_mapper,
+            clock: self.clock,
+            fallback_mode: self.fallback_mode,
+            should_deserialize_error_body: self.should_deserialize_error_body,
+            request_filter: self.request_filter,
+            excluded_routes: self.excluded_routes,
+            resource_attributes: self.resource_attributes,
+            should_detect_host_name,
+            captured_request_headers: self.captured_request_headers,
+            captured_response_headers: self.captured_response_headers,
+            captured_query_parameters: self.captured_query_parameters,
+            capture_redactor: self.capture_redactor,
+            rate_report_interval: self.rate_report_interval,
+            retry_detector: self.retry_detector,
+            user_mapper: self.user_mapper,
+            tracer_provider: self.tracer_provider,
+            should_bias_errors: self.should_bias_errors,
+            slow_request_threshold: self.slow_request_threshold,
+            otlp_endpoint: self.otlp_endpoint,
+            max_error_body_capture: self.max_error_body_capture,
+            explicit_settings: self.explicit_settings,
+            should_log_to_console: self.should_log_to_console,
+            console_format: self.console_format,
+            _phantom1: std::marker::PhantomData,
+            _phantom2: std::marker::PhantomData,
+        }
+    }
+
+    /// Sets static dimensions that are attached to all telemetry.  The default is no dimensions.
+    /// 
+    /// This is useful for things like a build id, a cluster name, or a team tag.  The dimensions are added to the
+    /// resource, so they show up as custom dimensions on every request and dependency, and each of them is also
+    /// attached to every event (e.g., exceptions and custom events), since the exporter does not attach the resource
+    /// to events.
+    /// 
+    /// ```
+    /// use axum_insights::{AppInsights, Ready};
+    /// use std::collections::HashMap;
+    /// 
+    /// let i: AppInsights<Ready> = AppInsights::default()
+    ///     .with_connection_string(None)
+    ///     .with_service_config("namespace", "name")
+    ///     .with_global_dimensions(HashMap::from([
+    ///         ("cluster".to_owned(), "west-1".to_owned()),
+    ///         ("team".to_owned(), "billing".to_owned()),
+    ///     ]));
+    /// ```
+    pub fn with_global_dimensions(self, global_dimensions: HashMap<String, String>) -> AppInsights<Ready, C, R, U, P, E> {
+        AppInsights {
+            connection_string: self.connection_string,
+            config: self.config,
+            client: self.client,
+            enable_live_metrics: self.enable_live_metrics,
+            sample_rate: self.sample_rate,
+            batch_runtime: self.batch_runtime,
+            minimum_level: self.minimum_level,
+            subscriber: self.subscriber,
+            should_catch_panic: self.should_catch_panic,
+            is_noop: self.is_noop,
+            field_mapper: self.field_mapper,
+            panic_mapper: self.panic_mapper,
+            success_filter: self.success_filter,
+            global_dimensions,
+            build_info: self.build_info,
+            service_version: self.service_version,
+            should_close_on_upgrade: self.should_close_on_upgrade,
+            heartbeat_interval: self.heartbeat_interval,
+            should_record_multipart: self.should_record_multipart,
+            should_record_compression: self.should_record_compression,
+            panic_context_headers: self.panic_context_headers,
+            request_sampler: self.request_sampler,
+            debug_trace_header: self.debug_trace_header,
+            operation_grouper: self.operation_grouper,
+            should_extract_trace_context: self.should_extract_trace_context,
+            should_inject_trace_context: self.should_inject_trace_context,
+            effective_sample_rate: self.effective_sample_rate,
+            error_response_headers: self.error_response_headers,
+            should_record_metrics: self.should_record_metrics,
+            propagator: self.propagator,
+            async_field_mapper: self.async_field_mapper,
+            clock: self.clock,
+            fallback_mode: self.fallback_mode,
+            should_deserialize_error_body: self.should_deserialize_error_body,
+            request_filter: self.request_filter,
+            excluded_routes: self.excluded_routes,
+            resource_attributes: self.resource_attributes,
+            should_detect_host_name: self.should_detect_host_name,
+            captured_request_headers: self.captured_request_headers,
+            captured_response_headers: self.captured_response_headers,
+            captured_query_parameters: self.captured_query_parameters,
+            capture_redactor: self.capture_redactor,
+            rate_report_interval: self.rate_report_interval,
+            retry_detector: self.retry_detector,
+            user_mapper: self.user_mapper,
+            tracer_provider: self.tracer_provider,
+            should_bias_errors: self.should_bias_errors,
+            slow_request_threshold: self.slow_request_threshold,
+            otlp_endpoint: self.otlp_endpoint,
+            max_error_body_capture: self.max_error_body_capture,
+            explicit_settings: self.explicit_settings,
+            should_log_to_console: self.should_log_to_console,
+            console_format: self.console_format,
+            _phantom1: std::marker::PhantomData,
+            _phantom2: std::marker::PhantomData,
+        }
+    }
+
+    /// Sets the service version (`service.version`, which becomes the application version in Application Insights).
+    /// 
+    /// An explicit version always wins; otherwise, the version of the resource of the trace config (see [`AppInsights::with_trace_config`])
+    /// is kept, and if there is none, the version is taken from [`AppInsights::with_build_info`] (if set).  Since a library cannot see
+    /// the calling crate's version, it has to be captured at compile time in the calling crate, so pass [`service_version!`] here (or
+    /// use [`build_info!`]).
+    /// 
+    /// ```
+    /// use axum_insights::{service_version, AppInsights, Ready};
+    /// 
+    /// let i: AppInsights<Ready> = AppInsights::default()
+    ///     .with_connection_string(None)
+    ///     .with_service_config("namespace", "name")
+    ///     .with_service_version(service_version!());
+    /// ```
+    pub fn with_service_version(self, service_version: impl Into<String>) -> AppInsights<Ready, C, R, U, P, E> {
+        AppInsights {
+            connection_string: self.connection_string,
+            config: self.config,
+            client: self.client,
+            enable_live_metrics: self.enable_live_metrics,
+            sample_rate: self.sample_rate,
+            batch_runtime: self.batch_runtime,
+            minimum_level: self.minimum_level,
+            subscriber: self.subscriber,
+            should_catch_panic: self.should_catch_panic,
+            is_noop: self.is_noop,
+            field_mapper: self.field_mapper,
+            panic_mapper: self.panic_mapper,
+            success_filter: self.success_filter,
+            global_dimensions: self.global_dimensions,
+            build_info: self.build_info,
+            service_version: Some(service_version.into()),
+            should_close_on_upgrade: self.should_close_on_upgrade,
+            heartbeat_interval: self.heartbeat_interval,
+            should_record_multipart: self.should_record_multipart,
+            should_record_compression: self.should_record_compression,
+            panic_context_headers: self.panic_context_headers,
+            request_sampler: self.request_sampler,
+            debug_trace_header: self.debug_trace_header,
+            operation_grouper: self.operation_grouper,
+            should_extract_trace_context: self.should_extract_trace_context,
+            should_inject_trace_context: self.should_inject_trace_context,
+            effective_sample_rate: self.effective_sample_rate,
+            error_response_headers: self.error_response_headers,
+            should_record_metrics: self.should_record_metrics,
+            propagator: self.propagator,
+            async_field_mapper: self.async_field_mapper,
+            clock: self.clock,
+            fallback_mode: self.fallback_mode,
+            should_deserialize_error_body: self.should_deserialize_error_body,
+            request_filter: self.request_filter,
+            excluded_routes: self.excluded_routes,
+            resource_attributes: self.resource_attributes,
+            should_detect_host_name: self.should_detect_host_name,
+            captured_request_headers: self.captured_request_headers,
+            captured_response_headers: self.captured_response_headers,
+            captured_query_parameters: self.captured_query_parameters,
+            capture_redactor: self.capture_redactor,
+            rate_report_interval: self.rate_report_interval,
+            retry_detector: self.retry_detector,
+            user_mapper: self.user_mapper,
+            tracer_provider: self.tracer_provider,
+            should_bias_errors: self.should_bias_errors,
+            slow_request_threshold: self.slow_request_threshold,
+            otlp_endpoint: self.otlp_endpoint,
+            max_error_body_capture: self.max_error_body_capture,
+            explicit_settings: self.explicit_settings,
+            should_log_to_console: self.should_log_to_console,
+            console_format: self.console_format,
+            _phantom1: std::marker::PhantomData,
+            _phantom2: std::marker::PhantomData,
+        }
+    }
+
+    /// Sets the build information that is attached to all telemetry.  The default is no build information.
+    /// 
+    /// This is usually captured with the [`build_info!`] macro, and it allows telemetry to be sliced by release.
+    /// 
+    /// ```
+    /// use axum_insights::{build_info, AppInsights, Ready};
+    /// 
+    /// let i: AppInsights<Ready> = AppInsights::default()
+    ///     .with_connection_string(None)
+    ///     .with_service_config("namespace", "name")
+    ///     .with_build_info(build_info!());
+    /// ```
+    pub fn with_build_info(self, build_info: BuildInfo) -> AppInsights<Ready, C, R, U, P, E> {
+        AppInsights {
+            connection_string: self.connection_string,
+            config: self.config,
+            client: self.client,
+            enable_live_metrics: self.enable_live_metrics,
+            sample_rate: self.sample_rate,
+            batch_runtime: self.batch_runtime,
+            minimum_level: self.minimum_level,
+            subscriber: self.subscriber,
+            should_catch_panic: self.should_catch_panic,
+            is_noop: self.is_noop,
+            field_mapper: self.field_mapper,
+            panic_mapper: self.panic_mapper,
+            success_filter: self.success_filter,
+            global_dimensions: self.global_dimensions,
+            build_info: Some(build_info),
+            service_version: self.service_version,
+            should_close_on_upgrade: self.should_close_on_upgrade,
+            heartbeat_interval: self.heartbeat_interval,
+            should_record_multipart: self.should_record_multipart,
+            should_record_compression: self.should_record_compression,
+            panic_context_headers: self.panic_context_headers,
+            request_sampler: self.request_sampler,
+            debug_trace_header: self.debug_trace_header,
+            operation_grouper: self.operation_grouper,
+            should_extract_trace_context: self.should_extract_trace_context,
+            should_inject_trace_context: self.should_inject_trace_context,
+            effective_sample_rate: self.effective_sample_rate,
+            error_response_headers: self.error_response_headers,
+            should_record_metrics: self.should_record_metrics,
+            propagator: self.propagator,
+            async_field_mapper: self.async_field_mapper,
+            clock: self.clock,
+            fallback_mode: self.fallback_mode,
+            should_deserialize_error_body: self.should_deserialize_error_body,
+            request_filter: self.request_filter,
+            excluded_routes: self.excluded_routes,
+            resource_attributes: self.resource_attributes,
+            should_detect_host_name: self.should_detect_host_name,
+            captured_request_headers: self.captured_request_headers,
+            captured_response_headers: self.captured_response_headers,
+            captured_query_parameters: self.captured_query_parameters,
+            capture_redactor: self.capture_redactor,
+            rate_report_interval: self.rate_report_interval,
+            retry_detector: self.retry_detector,
+            user_mapper: self.user_mapper,
+            tracer_provider: self.tracer_provider,
+            should_bias_errors: self.should_bias_errors,
+            slow_request_threshold: self.slow_request_threshold,
+            otlp_endpoint: self.otlp_endpoint,
+            max_error_body_capture: self.max_error_body_capture,
+            explicit_settings: self.explicit_settings,
+            should_log_to_console: self.should_log_to_console,
+            console_format: self.console_format,
+            _phantom1: std::marker::PhantomData,
+            _phantom2: std::marker::PhantomData,
+        }
+    }
+
+    /// Sets the error type to use for telemetry.  The default is ().
+    /// 
+    /// ```
+    /// use axum_insights::{AppInsights, AppInsightsError, Ready};
+    /// 
+    /// struct WebError {
+    ///     message: String,
+    /// }
+    /// 
+    /// impl AppInsightsError for WebError {
+    ///     fn message(&self) -> Option<String> {
+    ///         Some(self.message.clone())
+    ///     }
+    /// 
+    ///     fn backtrace(&self) -> Option<String> {
+    ///         None
+    ///     }
+    /// }
+    /// 
+    /// let i = AppInsights::default()
+    ///     .with_connection_string(None)
+    ///     .with_service_config("namespace", "name")
+    ///     .with_error_type::<WebError>();
+    /// ```
+    pub fn with_error_type<T>(self) -> AppInsights<Ready, C, R, U, P, T> {
+        AppInsights {
+            connection_string: self.connection_string,
+            config: self.config,
+            client: self.client,
+            enable_live_metrics: self.enable_live_metrics,
+            sample_rate: self.sample_rate,
+            batch_runtime: self.batch_runtime,
+            minimum_level: self.minimum_level,
+            subscriber: self.subscriber,
+            should_catch_panic: self.should_catch_panic,
+            is_noop: self.is_noop,
+            field_mapper: self.field_mapper,
+            panic_mapper: self.panic_mapper,
+            success_filter: self.success_filter,
+            global_dimensions: self.global_dimensions,
+            build_info: self.build_info,
+            service_version: self.service_version,
+            should_close_on_upgrade: self.should_close_on_upgrade,
+            heartbeat_interval: self.heartbeat_interval,
+            should_record_multipart: self.should_record_multipart,
+            should_record_compression: self.should_record_compression,
+            panic_context_headers: self.panic_context_headers,
+            request_sampler: self.request_sampler,
+            debug_trace_header: self.debug_trace_header,
+            operation_grouper: self.operation_grouper,
+            should_extract_trace_context: self.should_extract_trace_context,
+            should_inject_trace_context: self.should_inject_trace_context,
+            effective_sample_rate: self.effective_sample_rate,
+            error_response_headers: self.error_response_headers,
+            should_record_metrics: self.should_record_metrics,
+            propagator: self.propagator,
+            async_field_mapper: self.async_field_mapper,
+            clock: self.clock,
+            fallback_mode: self.fallback_mode,
+            should_deserialize_error_body: self.should_deserialize_error_body,
+            request_filter: self.request_filter,
+            excluded_routes: self.excluded_routes,
+            resource_attributes: self.resource_attributes,
+            should_detect_host_name: self.should_detect_host_name,
+            captured_request_headers: self.captured_request_headers,
+            captured_response_headers: self.captured_response_headers,
+            captured_query_parameters: self.captured_query_parameters,
+            capture_redactor: self.capture_redactor,
+            rate_report_interval: self.rate_report_interval,
+            retry_detector: self.retry_detector,
+            user_mapper: self.user_mapper,
+            tracer_provider: self.tracer_provider,
+            should_bias_errors: self.should_bias_errors,
+            slow_request_threshold: self.slow_request_threshold,
+            otlp_endpoint: self.otlp_endpoint,
+            max_error_body_capture: self.max_error_body_capture,
+            explicit_settings: self.explicit_settings,
+            should_log_to_console: self.should_log_to_console,
+            console_format: self.console_format,
+            _phantom1: std::marker::PhantomData,
+            _phantom2: std::marker::PhantomData,
+        }
+    }
+
+    /// Verifies that telemetry can be sent to Application Insights, by sending a tiny test item with the configured client.
+    /// 
+    /// This is useful at startup, so that a misconfigured connection string (or an unreachable ingestion endpoint) is caught at
+    /// deploy time, rather than discovered later as missing data.  The test item is a span named `axum-insights.connectivity_check`.
+    /// If no connection string is set, then there is nothing to verify, and this succeeds.
+    /// 
+    /// ```no_run
+    /// use axum_insights::AppInsights;
+    /// 
+    /// # async fn example() {
+    /// let i = AppInsights::default()
+    ///     .with_connection_string(Some("InstrumentationKey=00000000-0000-0000-0000-000000000000".to_owned()))
+    ///     .with_service_config("namespace", "name");
+    /// 
+    /// if let Err(e) = i.verify_connectivity().await {
+    ///     eprintln!("telemetry is misconfigured: {}", e);
+    /// }
+    /// # }
+    /// ```
+    pub async fn verify_connectivity(&self) -> Result<(), Box<dyn Error + Send + Sync + 'static>>
+    where
+        C: HttpClient + Clone + 'static,
+    {
+        let Some(connection_string) = self.connection_string.as_ref() else {
+            return Ok(());
+        };
+
+        let mut exporter = opentelemetry_application_insights::Exporter::new_from_connection_string(connection_string, self.client.clone())?;
+        exporter.set_resource(self.config.resource.as_ref());
+        exporter.export(vec![connectivity_check_span()]).await?;
+
+        Ok(())
+    }
+
+    /// Builds the telemetry layer, sets it as the global default, and bundles everything that an application needs to wire it up.
+    /// 
+    /// This is a convenience method for [`AppInsights::build_and_set_global_default`], followed by [`AppInsightsComplete::layer`],
+    /// [`AppInsightsComplete::shutdown_handle`], [`AppInsightsComplete::reload_handle`], and [`AppInsightsComplete::stats_handle`].
+    /// 
+    /// ```no_run
+    /// use axum::Router;
+    /// use axum_insights::{AppInsights, AppInsightsInstallation};
+    /// 
+    /// # async fn example() {
+    /// let AppInsightsInstallation { layer, shutdown_handle, .. } = AppInsights::default()
+    ///     .with_connection_string(None)
+    ///     .with_service_config("namespace", "name")
+    ///     .install()
+    ///     .unwrap();
+    /// 
+    /// // You likely will not need to specify `Router<()>` in your implementation.  This is just for the example.
+    /// let app: Router<()> = Router::new().layer(layer);
+    /// 
+    /// let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await.unwrap();
+    /// axum::serve(listener, app).with_graceful_shutdown(async { tokio::signal::ctrl_c().await.unwrap() }).await.unwrap();
+    /// 
+    /// shutdown_handle.shutdown().await.unwrap();
+    /// # }
+    /// ```
+    pub fn install(self) -> Result<AppInsightsInstallation<P, E>, Box<dyn Error + Send + Sync + 'static>>
+    where
+        C: HttpClient + 'static,
+        R: RuntimeChannel,
+        U: tracing_subscriber::layer::SubscriberExt + for<'span> tracing_subscriber::registry::LookupSpan<'span>  + Send + Sync + 'static
+    {
+        let complete = self.build_and_set_global_default()?;
+
+        Ok(AppInsightsInstallation {
+            layer: complete.layer(),
+            shutdown_handle: complete.shutdown_handle(),
+            reload_handle: complete.reload_handle(),
+            stats_handle: complete.stats_handle(),
+            complete,
+        })
+    }
+
+    /// Builds the telemetry layer, and sets it as the global default.
+    /// 
+    /// ```
+    /// use axum_insights::{AppInsights, AppInsightsComplete};
+    /// 
+    /// let i: AppInsightsComplete<_, _> = AppInsights::default()
+    ///     .with_connection_string(None)
+    ///     .with_service_config("namespace", "name")
+    ///     .build_and_set_global_default()
+    ///     .unwrap();
+    /// ```
+    /// 
+    /// If you want to use other subscribers, then you can either use [`AppInsights::with_subscriber`] to inject that subscriber,
+    /// and then allow this call to set the global default, or use [`AppInsights::build_layer`] to compose the telemetry into
+    /// your own subscriber.
+    pub fn build_and_set_global_default(self) -> Result<AppInsightsComplete<P, E>, Box<dyn Error + Send + Sync + 'static>>
+    where
+        C: HttpClient + 'static,
+        R: RuntimeChannel,
+        U: tracing_subscriber::layer::SubscriberExt + for<'span> tracing_subscriber::registry::LookupSpan<'span>  + Send + Sync + 'static
+    {
+        let BuiltPipeline { complete, subscriber, debug_trace_filter, context_layer, fallback, should_catch_panic, console_format } = self.build_pipeline()?;
+
+        if complete.is_noop {
+            return Ok(complete);
+        }
+
+        // This subscriber calculation needs to be separate in order to allow the type inference to work properly.
+        // Theoretically, we could do some magic with boxed traits to make it more readable, but this makes the types
+        // work nicely.
+        match (subscriber, complete.shutdown_handle.tracer_provider.as_ref()) {
+            (Some(subscriber), Some(tracer_provider)) => {
+                let telemetry = tracing_opentelemetry::layer().with_tracer(create_tracer(tracer_provider));
+                let subscriber = subscriber.with(telemetry).with(context_layer).with(debug_trace_filter).with(console_layer(console_format));
+                tracing::subscriber::set_global_default(subscriber)?;
+            },
+            (Some(subscriber), None) => {
+                tracing::subscriber::set_global_default(subscriber.with(debug_trace_filter).with(console_layer(console_format)))?;
+            },
+            (None, Some(tracer_provider)) => {
+                let telemetry = tracing_opentelemetry::layer().with_tracer(create_tracer(tracer_provider));
+                let subscriber = tracing_subscriber::registry().with(telemetry).with(context_layer).with(debug_trace_filter).with(console_layer(console_format));
+                tracing::subscriber::set_global_default(subscriber)?;
+            },
+            (None, None) => {
+                tracing::subscriber::set_global_default(tracing_subscriber::registry().with(debug_trace_filter).with(console_layer(console_format)))?;
+            },
+        }
+
+        // Now that the local subscriber is installed, let it know that nothing is being exported.
+        if let Some((FallbackMode::LocalLogOnly, e)) = fallback {
+            tracing::warn!(error = %e, "telemetry is local only because the connection string is invalid");
+        }
+
+        if should_catch_panic {
+            complete.install_panic_hook();
+        }
+
+        Ok(complete)
+    }
+
+    /// Builds the telemetry pipeline, and returns the `tracing` layer that feeds it, without setting the global default.
+    /// 
+    /// This is useful for applications that already configure their own subscriber (e.g., with a fmt layer, an `EnvFilter`, or
+    /// other layers), or for tests that run in parallel.  The returned layer only sees the spans and events at (or above) the
+    /// minimum level, and it does not filter the other layers of the subscriber.  Since the subscriber is up to the caller,
+    /// [`AppInsights::with_subscriber`] is ignored, and the panic hook is not installed (see [`AppInsightsComplete::install_panic_hook`]).
+    /// 
+    /// The OpenTelemetry tracer provider and propagator are still installed globally, so that the dependency helpers work.  If a
+    /// fallback is triggered (see [`AppInsights::with_fallback_on_error`]), the warning goes to the current subscriber, which is not
+    /// yet the caller's subscriber, so the error is best reported from [`AppInsightsComplete::fallback_error`].
+    /// 
+    /// ```
+    /// use axum_insights::AppInsights;
+    /// use tracing_subscriber::prelude::*;
+    /// 
+    /// let (i, telemetry) = AppInsights::default()
+    ///     .with_connection_string(None)
+    ///     .with_service_config("namespace", "name")
+    ///     .build_layer()
+    ///     .unwrap();
+    /// 
+    /// let subscriber = tracing_subscriber::registry()
+    ///     .with(telemetry)
+    ///     .with(tracing_subscriber::fmt::layer());
+    /// 
+    /// tracing::subscriber::set_global_default(subscriber).unwrap();
+    /// 
+    /// let layer = i.layer();
+    /// ```
+    #[allow(clippy::type_complexity)]
+    pub fn build_layer<S>(self) -> Result<(AppInsightsComplete<P, E>, Box<dyn tracing_subscriber::Layer<S> + Send + Sync + 'static>), Box<dyn Error + Send + Sync + 'static>>
+    where
+        C: HttpClient + 'static,
+        R: RuntimeChannel,
+        S: tracing::Subscriber + for<'span> tracing_subscriber::registry::LookupSpan<'span> + Send + Sync + 'static,
+    {
+        let BuiltPipeline { complete, debug_trace_filter, context_layer, fallback, console_format, .. } = self.build_pipeline()?;
+
+        if let Some((FallbackMode::LocalLogOnly, e)) = &fallback {
+            tracing::warn!(error = %e, "telemetry is local only because the connection string is invalid");
+        }
+
+        let telemetry = complete
+            .shutdown_handle
+            .tracer_provider
+            .as_ref()
+            .map(|tracer_provider| tracing_subscriber::Layer::and_then(tracing_opentelemetry::layer().with_tracer(create_tracer(tracer_provider)), context_layer));
+        let layer = tracing_subscriber::Layer::with_filter(telemetry, debug_trace_filter);
+        let layer = tracing_subscriber::Layer::and_then(layer, console_layer(console_format));
+
+        Ok((complete, Box::new(layer)))
+    }
+
+    /// Builds the telemetry pipeline, and everything that the build methods need to set up the subscriber.
+    fn build_pipeline(self) -> Result<BuiltPipeline<U, P, E>, Box<dyn Error + Send + Sync + 'static>>
+    where
+        C: HttpClient + 'static,
+        R: RuntimeChannel,
+    {
+        // Validate the connection string up front, so that an invalid one can degrade to the fallback mode before anything
+        // global is set.
+        let (connection_string, fallback) = resolve_connection_string(self.connection_string, self.fallback_mode)?;
+
+        if let Some((FallbackMode::Noop, e)) = &fallback {
+            tracing::warn!(error = %e, "telemetry is disabled because the connection string is invalid");
+        }
+
+        let is_noop = self.is_noop || matches!(fallback, Some((FallbackMode::Noop, _)));
+
+        // The level filter allows DEBUG events within debug traced requests, if a debug trace header is configured.
+        let debug_trace_filter = DebugTraceFilter {
+            minimum_level: Arc::new(RwLock::new(self.minimum_level)),
+            allow_debug_traces: self.debug_trace_header.is_some(),
+        };
+
+        if is_noop {
+            return Ok(BuiltPipeline {
+                complete: AppInsightsComplete {
+                    is_noop: true,
+                    field_mapper: None,
+                    panic_mapper: None,
+                    success_filter: None,
+                    should_close_on_upgrade: false,
+                    heartbeat_interval: None,
+                    should_record_multipart: false,
+                    should_record_compression: false,
+                    panic_context_headers: Arc::default(),
+                    request_sampler: None,
+                    debug_trace_header: None,
+                    operation_grouper: None,
+                    should_extract_trace_context: false,
+                    should_inject_trace_context: false,
+                    error_response_headers: Arc::default(),
+                    request_metrics: None,
+                    propagator: None,
+                    async_field_mapper: None,
+                    clock: Arc::new(SystemClock),
+                    should_deserialize_error_body: false,
+                    request_filter: None,
+                    excluded_routes: Arc::default(),
+                    captured_request_headers: Arc::default(),
+                    captured_response_headers: Arc::default(),
+                    captured_query_parameters: Arc::default(),
+                    capture_redactor: None,
+                    rate_report: None,
+                    retry_detector: None,
+                    shutdown_handle: ShutdownHandle::default(),
+                    user_mapper: None,
+                    should_bias_errors: false,
+                    slow_request_threshold: None,
+                    max_error_body_capture: 0,
+                    fallback_error: fallback.as_ref().map(|(_, e)| Arc::from(e.to_string())),
+                    reload_handle: ReloadHandle { minimum_level: debug_trace_filter.minimum_level.clone() },
+                    stats_handle: StatsHandle::default(),
+                    _phantom: std::marker::PhantomData,
+                },
+                subscriber: None,
+                debug_trace_filter,
+                context_layer: TelemetryContextLayer::default(),
+                fallback,
+                should_catch_panic: false,
+                console_format: None,
+            });
+        }
+
+        // Stamp the global dimensions onto the resource, so that they are attached to every exported span, and onto every event
+        // (since the exporter does not attach the resource to events).
+        let global_dimensions: Vec<KeyValue> = self.global_dimensions.into_iter().map(|(k, v)| KeyValue::new(k, v)).collect();
+        let mut context_layer = TelemetryContextLayer {
+            global_dimensions: Arc::new(global_dimensions.clone()),
+            span_attributes: Arc::default(),
+            clock: Some(self.clock.clone()),
+        };
+
+        let mut resource_attributes = global_dimensions;
+
+        // Stamp the resolved service version onto the resource.
+        if let Some(service_version) = resolve_service_version(&self.config.resource, self.service_version, self.build_info.as_ref()) {
+            resource_attributes.push(KeyValue::new("service.version", service_version));
+        }
+
+        // Stamp the build information onto the resource as well.
+        if let Some(build_info) = self.build_info {
+            resource_attributes.extend(build_info.into_key_values());
+        }
+
+        // Merge in the explicit resource attributes, and then fill in the host information (if it was not given).
+        resource_attributes.extend(self.resource_attributes);
+
+        if self.should_detect_host_name {
+            let host_attributes = host_resource_attributes(&self.config.resource, &resource_attributes, detect_host_name());
+            resource_attributes.extend(host_attributes);
+        }
+
+        // Stamp the instrumentation versions, so that the items can be traced back to the version that produced them.
+        let sdk_attributes = sdk_resource_attributes(&self.config.resource, &resource_attributes);
+        resource_attributes.extend(sdk_attributes);
+
+        // The resource of an existing tracer provider cannot be changed, so the attributes are stamped onto every span instead.
+        if self.tracer_provider.is_some() {
+            context_layer.span_attributes = Arc::new(resource_attributes.clone());
+        }
+
+        let mut config = if resource_attributes.is_empty() {
+            self.config
+        } else {
+            let resource = self.config.resource.merge(&Resource::new(resource_attributes));
+
+            self.config.with_resource(resource)
+        };
+
+        // Wrap the configured (or rate based) sampler, so that the per-request sampling decisions can override it.
+        let config_sampler = std::mem::replace(&mut config.sampler, Box::new(Sampler::AlwaysOn));
+        let (inner_sampler, sample_rate) = resolve_sampler(config_sampler, self.sample_rate, self.effective_sample_rate);
+        config.sampler = Box::new(RequestSampler { inner: inner_sampler });
+
+        // Install the given propagator globally, so that it is also used to inject the trace context into outgoing dependency calls
+        // (otherwise, the global propagator that the application installed is left alone).
+        if let Some(propagator) = self.propagator.as_ref() {
+            opentelemetry::global::set_text_map_propagator(SharedPropagator(propagator.clone()));
+        }
+
+        // Share the client between the tracer and the metrics exporter.
+        let client = SharedClient(Arc::new(self.client));
+
+        // Install the metrics pipeline alongside the tracer, so that the middleware can record request metrics.
+        let (request_metrics, meter_provider) = match connection_string.as_ref() {
+            Some(connection_string) if self.should_record_metrics => {
+                let exporter = opentelemetry_application_insights::Exporter::new_from_connection_string(connection_string, client.clone())?;
+                let reader = PeriodicReader::builder(exporter, self.batch_runtime.clone()).build();
+                let meter_provider = SdkMeterProvider::builder().with_reader(reader).with_resource(config.resource.as_ref().clone()).build();
+
+                opentelemetry::global::set_meter_provider(meter_provider.clone());
+
+                (Some(Arc::new(RequestMetrics::new(&opentelemetry::global::meter("axum-insights")))), Some(meter_provider))
+            },
+            _ => (None, None),
+        };
+
+        // Start the rate report, which emits a summary of each window from a background thread until the layers are dropped.
+        let rate_report = match self.rate_report_interval {
+            Some(interval) => {
+                let rate_report = Arc::new(RateReport::default());
+                let weak_rate_report = Arc::downgrade(&rate_report);
+
+                std::thread::Builder::new().name("axum-insights-rate-report".to_owned()).spawn(move || loop {
+                    std::thread::sleep(interval);
+
+                    match weak_rate_report.upgrade() {
+                        Some(rate_report) => rate_report.emit(interval),
+                        None => break,
+                    }
+                })?;
+
+                Some(rate_report)
+            },
+            None => None,
+        };
+
+        // The per-request sampling decisions are made by the sampler (and the processor) of the pipeline that is built here, so an existing
+        // tracer provider does not honor them.
+        if self.tracer_provider.is_some() && (self.request_sampler.is_some() || self.debug_trace_header.is_some() || self.should_bias_errors) {
+            tracing::warn!(
+                request_sampler = self.request_sampler.is_some(),
+                debug_trace_header = self.debug_trace_header.is_some(),
+                error_biased_sampling = self.should_bias_errors,
+                "the sampling overrides have no effect with an existing tracer provider"
+            );
+        }
+
+        // The exporter settings configure the pipeline as well, and they cannot be applied to an existing tracer provider.
+        if self.tracer_provider.is_some() && (self.sample_rate < 1.0 || self.effective_sample_rate.is_some() || self.enable_live_metrics || self.should_record_metrics) {
+            tracing::warn!(
+                sample_rate = self.sample_rate,
+                effective_sample_rate = self.effective_sample_rate,
+                live_metrics = self.enable_live_metrics,
+                metrics = self.should_record_metrics,
+                "the exporter settings have no effect with an existing tracer provider"
+            );
+        }
+
+        // The request metrics are only exported to Application Insights, so they are silently missing without a connection string
+        // (e.g., with the OTLP exporter).
+        if self.tracer_provider.is_none() && self.should_record_metrics && request_metrics.is_none() {
+            tracing::warn!("the request metrics are disabled, since they are only exported to Application Insights (with a connection string)");
+        }
+
+        // An existing tracer provider is used as it is (and it is not installed globally, since the application owns it); otherwise,
+        // the Application Insights (or OTLP) pipeline is built and installed globally.
+        let should_bias_errors = self.should_bias_errors && self.tracer_provider.is_none() && connection_string.is_some();
+        let tracer_provider = match (self.tracer_provider, connection_string) {
+            (Some(tracer_provider), _) => Some(tracer_provider),
+            (None, Some(connection_string)) => {
+                // The pipeline is built by hand, so that the forced (and retained) items are exported with an item count of one, and so
+                // that the error-biased processor can wrap the exporting processor; live metrics are fed by a separate pipeline.
+                let live_metrics = match self.enable_live_metrics {
+                    true => Some(LiveMetricsProcessor::new(&connection_string, client.clone(), config.resource.as_ref().clone(), self.batch_runtime.clone())?),
+                    false => None,
+                };
+                let exporter = ItemCountExporter {
+                    sampled: opentelemetry_application_insights::Exporter::new_from_connection_string(&connection_string, client.clone())?.with_sample_rate(sample_rate),
+                    certain: opentelemetry_application_insights::Exporter::new_from_connection_string(&connection_string, client)?,
+                };
+                let processor = BatchSpanProcessor::builder(exporter, self.batch_runtime).build();
+
+                let mut builder = opentelemetry_sdk::trace::TracerProvider::builder();
+                if let Some(live_metrics) = live_metrics {
+                    builder = builder.with_span_processor(live_metrics);
+                }
+
+                builder = match should_bias_errors {
+                    true => builder.with_span_processor(ErrorBiasedProcessor::new(processor)),
+                    false => builder.with_span_processor(processor),
+                };
+
+                let tracer_provider = builder.with_config(config).build();
+                let _previous_provider = opentelemetry::global::set_tracer_provider(tracer_provider.clone());
+
+                Some(tracer_provider)
+            },
+            (None, None) => match self.otlp_endpoint {
+                Some(endpoint) => {
+                    let tracer_provider = build_otlp_tracer_provider(endpoint, config, self.batch_runtime)?;
+                    let _previous_provider = opentelemetry::global::set_tracer_provider(tracer_provider.clone());
+
+                    Some(tracer_provider)
+                },
+                None => None,
+            },
+        };
+
+        Ok(BuiltPipeline {
+            complete: AppInsightsComplete {
+                is_noop: false,
+                field_mapper: self.field_mapper,
+                panic_mapper: self.panic_mapper,
+                success_filter: self.success_filter,
+                should_close_on_upgrade: self.should_close_on_upgrade,
+                heartbeat_interval: self.heartbeat_interval,
+                should_record_multipart: self.should_record_multipart,
+                should_record_compression: self.should_record_compression,
+                panic_context_headers: Arc::new(self.panic_context_headers),
+                request_sampler: self.request_sampler,
+                debug_trace_header: self.debug_trace_header,
+                operation_grouper: self.operation_grouper,
+                should_extract_trace_context: self.should_extract_trace_context,
+                should_inject_trace_context: self.should_inject_trace_context,
+                error_response_headers: Arc::new(self.error_response_headers),
+                request_metrics,
+                propagator: self.propagator,
+                async_field_mapper: self.async_field_mapper,
+                clock: self.clock,
+                should_deserialize_error_body: self.should_deserialize_error_body,
+                request_filter: self.request_filter,
+                excluded_routes: Arc::new(self.excluded_routes),
+                captured_request_headers: Arc::new(self.captured_request_headers),
+                captured_response_headers: Arc::new(self.captured_response_headers),
+                captured_query_parameters: Arc::new(self.captured_query_parameters),
+                capture_redactor: self.capture_redactor,
+                rate_report,
+                retry_detector: self.retry_detector,
+                shutdown_handle: ShutdownHandle {
+                    tracer_provider,
+                    meter_provider,
+                },
+                user_mapper: self.user_mapper,
+                should_bias_errors,
+                slow_request_threshold: self.slow_request_threshold,
+                max_error_body_capture: self.max_error_body_capture,
+                fallback_error: fallback.as_ref().map(|(_, e)| Arc::from(e.to_string())),
+                reload_handle: ReloadHandle { minimum_level: debug_trace_filter.minimum_level.clone() },
+                stats_handle: StatsHandle::default(),
+                _phantom: std::marker::PhantomData,
+            },
+            subscriber: self.subscriber,
+            debug_trace_filter,
+            context_layer,
+            fallback,
+            should_catch_panic: self.should_catch_panic,
+            console_format: self.should_log_to_console.then_some(self.console_format),
+        })
+    }
+}
+
+impl<P, E> AppInsightsComplete<P, E> {
+    /// Creates the telemetry layer.
+    /// 
+    /// ```
+    /// use axum::Router;
+    /// use axum_insights::{AppInsights, AppInsightsComplete};
+    /// 
+    /// let i: AppInsightsComplete<_, _> = AppInsights::default()
+    ///     .with_connection_string(None)
+    ///     .with_service_config("namespace", "name")
+    ///     .build_and_set_global_default()
+    ///     .unwrap();
+    /// 
+    /// let layer = i.layer();
+    /// 
+    /// // You likely will not need to specify `Router<()>` in your implementation.  This is just for the example.
+    /// let app: Router<()> = Router::new()
+    ///     // ...
+    ///     .layer(layer);
+    /// ```
+    /// 
+    /// This can be called multiple times to create several layers from the same pipeline (e.g., to give each
+    /// router its own cloud role via [`AppInsightsLayer::with_cloud_role`]).
+    pub fn layer(&self) -> AppInsightsLayer<P, E> {
+        AppInsightsLayer {
+            is_noop: self.is_noop,
+            field_mapper: self.field_mapper.clone(),
+            panic_mapper: self.panic_mapper.clone(),
+            success_filter: self.success_filter.clone(),
+            cloud_role: None,
+            should_close_on_upgrade: self.should_close_on_upgrade,
+            heartbeat_interval: self.heartbeat_interval,
+            should_record_multipart: self.should_record_multipart,
+            should_record_compression: self.should_record_compression,
+            panic_context_headers: self.panic_context_headers.clone(),
+            request_sampler: self.request_sampler.clone(),
+            debug_trace_header: self.debug_trace_header.clone(),
+            operation_grouper: self.operation_grouper.clone(),
+            should_extract_trace_context: self.should_extract_trace_context,
+            should_inject_trace_context: self.should_inject_trace_context,
+            error_response_headers: self.error_response_headers.clone(),
+            request_metrics: self.request_metrics.clone(),
+            propagator: self.propagator.clone(),
+            async_field_mapper: self.async_field_mapper.clone(),
+            clock: self.clock.clone(),
+            should_deserialize_error_body: self.should_deserialize_error_body,
+            request_filter: self.request_filter.clone(),
+            excluded_routes: self.excluded_routes.clone(),
+            captured_request_headers: self.captured_request_headers.clone(),
+            captured_response_headers: self.captured_response_headers.clone(),
+            captured_query_parameters: self.captured_query_parameters.clone(),
+            capture_redactor: self.capture_redactor.clone(),
+            rate_report: self.rate_report.clone(),
+            retry_detector: self.retry_detector.clone(),
+            user_mapper: self.user_mapper.clone(),
+            should_bias_errors: self.should_bias_errors,
+            slow_request_threshold: self.slow_request_threshold,
+            max_error_body_capture: self.max_error_body_capture,
+            stats_handle: self.stats_handle.clone(),
+            _phantom: std::marker::PhantomData,
+        }
+    }
+
+    /// Creates a telemetry layer with overrides applied on top of the pipeline defaults.
+    /// 
+    /// This is useful for nested routers that need different handling than the rest of the application (e.g.,
+    /// richer field capture for high-value routes).
+    /// 
+    /// ```
+    /// use axum::Router;
+    /// use axum_insights::{AppInsights, AppInsightsComplete};
+    /// use std::collections::HashMap;
+    /// 
+    /// let i: AppInsightsComplete<_, _> = AppInsights::default()
+    ///     .with_connection_string(None)
+    ///     .with_service_config("namespace", "name")
+    ///     .build_and_set_global_default()
+    ///     .unwrap();
+    /// 
+    /// let billing: Router<()> = Router::new()
+    ///     // ...
+    ///     .layer(i.layer_with(|layer| {
+    ///         layer
+    ///             .with_field_mapper(|parts| HashMap::from([("tenant".to_owned(), "contoso".to_owned())]))
+    ///             .with_success_filter(|status| status.is_success())
+    ///     }));
+    /// 
+    /// let everything_else: Router<()> = Router::new()
+    ///     // ...
+    ///     .layer(i.layer());
+    /// 
+    /// // Each router keeps its own layer, so every request is only instrumented once.
+    /// let app: Router<()> = Router::new()
+    ///     .nest("/billing", billing)
+    ///     .merge(everything_else);
+    /// ```
+    pub fn layer_with<F>(&self, configure: F) -> AppInsightsLayer<P, E>
+    where
+        F: FnOnce(AppInsightsLayer<P, E>) -> AppInsightsLayer<P, E>,
+    {
+        configure(self.layer())
+    }
+
+    /// Gets a handle that flushes and shuts down the telemetry pipeline, which guarantees delivery on graceful termination.
+    /// 
+    /// ```no_run
+    /// use axum::Router;
+    /// use axum_insights::{AppInsights, AppInsightsComplete};
+    /// 
+    /// # async fn example() {
+    /// let i: AppInsightsComplete<_, _> = AppInsights::default()
+    ///     .with_connection_string(None)
+    ///     .with_service_config("namespace", "name")
+    ///     .build_and_set_global_default()
+    ///     .unwrap();
+    /// 
+    /// let shutdown_handle = i.shutdown_handle();
+    /// let app: Router<()> = Router::new().layer(i.layer());
+    /// 
+    /// let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await.unwrap();
+    /// axum::serve(listener, app)
+    ///     .with_graceful_shutdown(async { tokio::signal::ctrl_c().await.unwrap() })
+    ///     .await
+    ///     .unwrap();
+    /// 
+    /// shutdown_handle.shutdown().await.unwrap();
+    /// # }
+    /// ```
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown_handle.clone()
+    }
+
+    /// Gets the error that triggered the fallback mode (see [`AppInsights::with_fallback_on_error`]), if the connection string was invalid.
+    /// 
+    /// ```
+    /// use axum_insights::{AppInsights, AppInsightsComplete, FallbackMode};
+    /// 
+    /// let i: AppInsightsComplete<_, _> = AppInsights::default()
+    ///     .with_connection_string(Some("not a connection string".to_owned()))
+    ///     .with_service_config("namespace", "name")
+    ///     .with_fallback_on_error(FallbackMode::Noop)
+    ///     .build_and_set_global_default()
+    ///     .unwrap();
+    /// 
+    /// if let Some(e) = i.fallback_error() {
+    ///     eprintln!("telemetry is disabled: {}", e);
+    /// }
+    /// ```
+    pub fn fallback_error(&self) -> Option<&str> {
+        self.fallback_error.as_deref()
+    }
+
+    /// Gets a handle that changes the settings of the telemetry subscriber (e.g., the minimum level) at runtime.
+    /// 
+    /// ```
+    /// use axum_insights::{AppInsights, AppInsightsComplete};
+    /// use tracing_subscriber::filter::LevelFilter;
+    /// 
+    /// let i: AppInsightsComplete<_, _> = AppInsights::default()
+    ///     .with_connection_string(None)
+    ///     .with_service_config("namespace", "name")
+    ///     .build_and_set_global_default()
+    ///     .unwrap();
+    /// 
+    /// i.reload_handle().set_minimum_level(LevelFilter::DEBUG);
+    /// ```
+    pub fn reload_handle(&self) -> ReloadHandle {
+        self.reload_handle.clone()
+    }
+
+    /// Gets a handle that reads the statistics of the requests that the layers of this pipeline have handled.
+    /// 
+    /// ```
+    /// use axum_insights::{AppInsights, AppInsightsComplete};
+    /// 
+    /// let i: AppInsightsComplete<_, _> = AppInsights::default()
+    ///     .with_connection_string(None)
+    ///     .with_service_config("namespace", "name")
+    ///     .build_and_set_global_default()
+    ///     .unwrap();
+    /// 
+    /// let stats_handle = i.stats_handle();
+    /// assert_eq!((stats_handle.requests(), stats_handle.failures()), (0, 0));
+    /// ```
+    pub fn stats_handle(&self) -> StatsHandle {
+        self.stats_handle.clone()
+    }
+
+    /// Installs a panic hook that emits an exception for each panic, and then calls the previous hook.
+    /// 
+    /// This is done by [`AppInsights::build_and_set_global_default`] when [`AppInsights::with_catch_panic`] is set, so it is only
+    /// needed with [`AppInsights::build_layer`].
+    /// 
+    /// ```
+    /// use axum_insights::AppInsights;
+    /// use tracing_subscriber::Registry;
+    /// 
+    /// let (i, telemetry) = AppInsights::default()
+    ///     .with_connection_string(None)
+    ///     .with_service_config("namespace", "name")
+    ///     .build_layer::<Registry>()
+    ///     .unwrap();
+    /// 
+    /// i.install_panic_hook();
+    /// ```
+    pub fn install_panic_hook(&self) {
+        if self.is_noop {
+            return;
+        }
+
+        let default_panic = panic::take_hook();
+
+        panic::set_hook(Box::new(move |p| {
+            let payload_string = format!("{:?}", p.payload().downcast_ref::<&str>());
+            let backtrace = Backtrace::force_capture().to_string();
+
+            emit_panic_exception(&payload_string, &backtrace);
+
+            default_panic(p);
+        }));
+
+        IS_PANIC_HOOK_INSTALLED.store(true, Ordering::Relaxed);
+    }
+}
+
+/// The built pipeline, and everything that is needed to set up the subscriber for it.
+struct BuiltPipeline<U, P, E> {
+    complete: AppInsightsComplete<P, E>,
+    subscriber: Option<U>,
+    debug_trace_filter: DebugTraceFilter,
+    context_layer: TelemetryContextLayer,
+    fallback: Option<Fallback>,
+    should_catch_panic: bool,
+    console_format: Option<ConsoleFormat>,
+}
+
+/// Creates the console layer in the given format, or an empty layer if the console is disabled (see [`AppInsights::with_console`]).
+fn console_layer<S>(console_format: Option<ConsoleFormat>) -> Box<dyn tracing_subscriber::Layer<S> + Send + Sync + 'static>
+where
+    S: tracing::Subscriber + for<'span> tracing_subscriber::registry::LookupSpan<'span>,
+{
+    match console_format {
+        Some(ConsoleFormat::Pretty) => Box::new(tracing_subscriber::fmt::layer().pretty()),
+        Some(ConsoleFormat::Compact) => Box::new(tracing_subscriber::fmt::layer().compact()),
+        None => Box::new(tracing_subscriber::layer::Identity::new()),
+    }
+}
+
+/// Everything that an application needs to wire up the telemetry, which is returned from [`AppInsights::install`].
+pub struct AppInsightsInstallation<P, E> {
+    /// The telemetry layer, which is given to [`axum::Router::layer`].
+    pub layer: AppInsightsLayer<P, E>,
+    /// The handle that flushes and shuts down the telemetry pipeline on graceful termination.
+    pub shutdown_handle: ShutdownHandle,
+    /// The handle that changes the settings of the telemetry subscriber (e.g., the minimum level) at runtime.
+    pub reload_handle: ReloadHandle,
+    /// The handle that reads the statistics of the requests that the middleware has handled.
+    pub stats_handle: StatsHandle,
+    /// The complete pipeline, which can create more layers (e.g., via [`AppInsightsComplete::layer_with`]).
+    pub complete: AppInsightsComplete<P, E>,
+}
+
+/// The telemetry layer.
+/// 
+/// This layer is created by [`AppInsightsComplete::layer`], and it can be used to instrument your [`axum::Router`].
+/// Generally, this type will not be used, other than to pass to [`axum::Router::layer`].
+#[derive(Clone)]
+pub struct AppInsightsLayer<P, E> {
+    is_noop: bool,
+    field_mapper: OptionalFieldMapper,
+    panic_mapper: OptionalPanicMapper<P>,
+    success_filter: OptionalSuccessFilter,
+    cloud_role: Option<Arc<String>>,
+    should_close_on_upgrade: bool,
+    heartbeat_interval: Option<Duration>,
+    should_record_multipart: bool,
+    should_record_compression: bool,
+    panic_context_headers: Arc<Vec<http::HeaderName>>,
+    request_sampler: OptionalRequestSampler,
+    debug_trace_header: Option<Arc<DebugTraceHeader>>,
+    operation_grouper: OptionalOperationGrouper,
+    should_extract_trace_context: bool,
+    should_inject_trace_context: bool,
+    error_response_headers: Arc<Vec<http::HeaderName>>,
+    request_metrics: Option<Arc<RequestMetrics>>,
+    propagator: OptionalPropagator,
+    async_field_mapper: OptionalAsyncFieldMapper,
+    clock: Arc<dyn Clock>,
+    should_deserialize_error_body: bool,
+    request_filter: OptionalRequestFilter,
+    excluded_routes: Arc<Vec<String>>,
+    captured_request_headers: Arc<Vec<http::HeaderName>>,
+    captured_response_headers: Arc<Vec<http::HeaderName>>,
+    captured_query_parameters: Arc<Vec<String>>,
+    capture_redactor: OptionalCaptureRedactor,
+    rate_report: Option<Arc<RateReport>>,
+    retry_detector: Option<Arc<RetryDetector>>,
+    user_mapper: OptionalUserMapper,
+    should_bias_errors: bool,
+    slow_request_threshold: Option<Duration>,
+    max_error_body_capture: usize,
+    stats_handle: StatsHandle,
+    _phantom: std::marker::PhantomData<E>,
+}
+
+impl<P, E> AppInsightsLayer<P, E> {
+    /// Overrides the cloud role for the requests handled by this layer.  The default is the role derived from the
+    /// service config.
+    /// 
+    /// This is useful when several routers (e.g., a public API and an admin API) are served from the same process, and
+    /// they should appear as separate components on the Application Insights application map.  The override applies
+    /// to the request span, and to every span within it (e.g., dependency calls and child spans).
+    /// 
+    /// The events within the request (e.g., exceptions and custom events) are part of the same operation, but they keep the
+    /// default role, since the exporter derives the tags of events from the resource only.  Likewise, spans that are not
+    /// children of the request span (e.g., spans created with `parent: None`) keep the default role.
+    /// 
+    /// ```
+    /// use axum::Router;
+    /// use axum_insights::{AppInsights, AppInsightsComplete};
+    /// 
+    /// let i: AppInsightsComplete<_, _> = AppInsights::default()
+    ///     .with_connection_string(None)
+    ///     .with_service_config("namespace", "name")
+    ///     .build_and_set_global_default()
+    ///     .unwrap();
+    /// 
+    /// let public: Router<()> = Router::new()
+    ///     // ...
+    ///     .layer(i.layer().with_cloud_role("namespace.public-api"));
+    /// 
+    /// let admin: Router<()> = Router::new()
+    ///     // ...
+    ///     .layer(i.layer().with_cloud_role("namespace.admin-api"));
+    /// ```
+    pub fn with_cloud_role(self, cloud_role: impl Into<String>) -> Self {
+        Self {
+            cloud_role: Some(Arc::new(cloud_role.into())),
+            ..self
+        }
+    }
+
+    /// Overrides the function that extracts extra fields from the request for this layer.
+    /// 
+    /// See [`AppInsights::with_field_mapper`].
+    pub fn with_field_mapper<F>(self, field_mapper: F) -> Self
+    where
+        F: Fn(&http::request::Parts) -> HashMap<String, String> + Send + Sync + 'static,
+    {
+        Self {
+            field_mapper: Some(Arc::new(field_mapper)),
+            ..self
+        }
+    }
+
+    /// Overrides the async function that extracts extra fields from the request for this layer.
+    /// 
+    /// See [`AppInsights::with_async_field_mapper`].
+    pub fn with_async_field_mapper<F>(self, async_field_mapper: F) -> Self
+    where
+        F: Fn(&http::request::Parts) -> BoxFuture<'static, HashMap<String, String>> + Send + Sync + 'static,
+    {
+        Self {
+            async_field_mapper: Some(Arc::new(async_field_mapper)),
+            ..self
+        }
+    }
+
+    /// Overrides the clock that the middleware uses to measure durations for this layer.
+    /// 
+    /// This applies to the request metrics and the heartbeat events of the response bodies; the span times (and the
+    /// [`ConnectionHeartbeat`]s) use the clock of the pipeline.  See [`AppInsights::with_clock`].
+    pub fn with_clock<T>(self, clock: T) -> Self
+    where
+        T: Clock,
+    {
+        Self {
+            clock: Arc::new(clock),
+            ..self
+        }
+    }
+
+    /// Overrides the function that maps a panic to a response for this layer.
+    /// 
+    /// See [`AppInsights::with_panic_mapper`].
+    pub fn with_panic_mapper<F>(self, panic_mapper: F) -> Self
+    where
+        F: Fn(String) -> (u16, P) + Send + Sync + 'static,
+    {
+        Self {
+            panic_mapper: Some(Arc::new(move |panic, _: &PanicContext| panic_mapper(panic))),
+            ..self
+        }
+    }
+
+    /// Overrides the function that maps a panic (with its request context) to a response for this layer.
+    /// 
+    /// See [`AppInsights::with_contextual_panic_mapper`].
+    pub fn with_contextual_panic_mapper<F>(self, panic_mapper: F) -> Self
+    where
+        F: Fn(String, &PanicContext) -> (u16, P) + Send + Sync + 'static,
+    {
+        Self {
+            panic_mapper: Some(Arc::new(panic_mapper)),
+            ..self
+        }
+    }
+
+    /// Overrides the function that determines the success-iness of a status for this layer.
+    /// 
+    /// See [`AppInsights::with_success_filter`].
+    pub fn with_success_filter<F>(self, success_filter: F) -> Self
+    where
+        F: Fn(StatusCode) -> bool + Send + Sync + 'static,
+    {
+        Self {
+            success_filter: Some(Arc::new(success_filter)),
+            ..self
+        }
+    }
+
+    /// Overrides the function that determines whether or not a request is tracked at all for this layer.
+    /// 
+    /// See [`AppInsights::with_request_filter`].
+    pub fn with_request_filter<F>(self, request_filter: F) -> Self
+    where
+        F: Fn(&http::request::Parts) -> bool + Send + Sync + 'static,
+    {
+        Self {
+            request_filter: Some(Arc::new(request_filter)),
+            ..self
+        }
+    }
+
+    /// Overrides the routes that are not tracked at all for this layer.
+    /// 
+    /// See [`AppInsights::with_excluded_routes`].
+    pub fn with_excluded_routes<I, K>(self, routes: I) -> Self
+    where
+        I: IntoIterator<Item = K>,
+        K: Into<String>,
+    {
+        Self {
+            excluded_routes: Arc::new(routes.into_iter().map(Into::into).collect()),
+            ..self
+        }
+    }
+
+    /// Overrides the function that extracts the user and session of each request for this layer.
+    /// 
+    /// See [`AppInsights::with_user_mapper`].
+    pub fn with_user_mapper<F>(self, user_mapper: F) -> Self
+    where
+        F: Fn(&http::request::Parts) -> UserContext + Send + Sync + 'static,
+    {
+        Self {
+            user_mapper: Some(Arc::new(user_mapper)),
+            ..self
+        }
+    }
+
+    /// Overrides the function that makes a sampling decision for each request for this layer.
+    /// 
+    /// See [`AppInsights::with_request_sampler`].
+    pub fn with_request_sampler<F>(self, request_sampler: F) -> Self
+    where
+        F: Fn(&http::request::Parts) -> SamplingDecision + Send + Sync + 'static,
+    {
+        Self {
+            request_sampler: Some(Arc::new(request_sampler)),
+            ..self
+        }
+    }
+
+    /// Overrides the success-iness of a status for this layer with a preset [`SuccessPolicy`].
+    /// 
+    /// See [`AppInsights::with_success_policy`].
+    pub fn with_success_policy(self, policy: SuccessPolicy) -> Self {
+        self.with_success_filter(move |status| policy.is_success(status))
+    }
+}
+
+impl<S, P, E> Layer<S> for AppInsightsLayer<P, E> {
+    type Service = AppInsightsMiddleware<S, P, E>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        AppInsightsMiddleware {
+            inner,
+            is_noop: self.is_noop,
+            field_mapper: self.field_mapper.clone(),
+            panic_mapper: self.panic_mapper.clone(),
+            success_filter: self.success_filter.clone(),
+            cloud_role: self.cloud_role.clone(),
+            should_close_on_upgrade: self.should_close_on_upgrade,
+            heartbeat_interval: self.heartbeat_interval,
+            should_record_multipart: self.should_record_multipart,
+            should_record_compression: self.should_record_compression,
+            panic_context_headers: self.panic_context_headers.clone(),
+            request_sampler: self.request_sampler.clone(),
+            debug_trace_header: self.debug_trace_header.clone(),
+            operation_grouper: self.operation_grouper.clone(),
+            should_extract_trace_context: self.should_extract_trace_context,
+            should_inject_trace_context: self.should_inject_trace_context,
+            error_response_headers: self.error_response_headers.clone(),
+            request_metrics: self.request_metrics.clone(),
+            propagator: self.propagator.clone(),
+            async_field_mapper: self.async_field_mapper.clone(),
+            clock: self.clock.clone(),
+            should_deserialize_error_body: self.should_deserialize_error_body,
+            request_filter: self.request_filter.clone(),
+            excluded_routes: self.excluded_routes.clone(),
+            captured_request_headers: self.captured_request_headers.clone(),
+            captured_response_headers: self.captured_response_headers.clone(),
+            captured_query_parameters: self.captured_query_parameters.clone(),
+            capture_redactor: self.capture_redactor.clone(),
+            rate_report: self.rate_report.clone(),
+            retry_detector: self.retry_detector.clone(),
+            user_mapper: self.user_mapper.clone(),
+            should_bias_errors: self.should_bias_errors,
+            slow_request_threshold: self.slow_request_threshold,
+            max_error_body_capture: self.max_error_body_capture,
+            stats_handle: self.stats_handle.clone(),
+            _phantom: std::marker::PhantomData,
+        }
+    }
+}
+
+/// The telemetry middleware.
+/// 
+/// This middleware is created by [`AppInsightsLayer::layer`], and it can be used to instrument your [`axum::Router`].
+/// Generally, this type will not be used at all, is it merely satisfies the requirement that [`Layer::Service`]
+/// is a [`Service`].
+#[derive(Clone)]
+pub struct AppInsightsMiddleware<S, P, E> {
+    inner: S,
+    is_noop: bool,
+    field_mapper: OptionalFieldMapper,
+    panic_mapper: OptionalPanicMapper<P>,
+    success_filter: OptionalSuccessFilter,
+    cloud_role: Option<Arc<String>>,
+    should_close_on_upgrade: bool,
+    heartbeat_interval: Option<Duration>,
+    should_record_multipart: bool,
+    should_record_compression: bool,
+    panic_context_headers: Arc<Vec<http::HeaderName>>,
+    request_sampler: OptionalRequestSampler,
+    debug_trace_header: Option<Arc<DebugTraceHeader>>,
+    operation_grouper: OptionalOperationGrouper,
+    should_extract_trace_context: bool,
+    should_inject_trace_context: bool,
+    error_response_headers: Arc<Vec<http::HeaderName>>,
+    request_metrics: Option<Arc<RequestMetrics>>,
+    propagator: OptionalPropagator,
+    async_field_mapper: OptionalAsyncFieldMapper,
+    clock: Arc<dyn Clock>,
+    should_deserialize_error_body: bool,
+    request_filter: OptionalRequestFilter,
+    excluded_routes: Arc<Vec<String>>,
+    captured_request_headers: Arc<Vec<http::HeaderName>>,
+    captured_response_headers: Arc<Vec<http::HeaderName>>,
+    captured_query_parameters: Arc<Vec<String>>,
+    capture_redactor: OptionalCaptureRedactor,
+    rate_report: Option<Arc<RateReport>>,
+    retry_detector: Option<Arc<RetryDetector>>,
+    user_mapper: OptionalUserMapper,
+    should_bias_errors: bool,
+    slow_request_threshold: Option<Duration>,
+    max_error_body_capture: usize,
+    stats_handle: StatsHandle,
+    _phantom: std::marker::PhantomData<E>,
+}
+
+impl<S, P, E> Service<Request<Body>> for AppInsightsMiddleware<S, P, E>
+where
+    S: Service<Request<Body>, Response = Response> + Send + 'static,
+    S::Future: Send + 'static,
+    S::Error: Send + 'static,
+    P: Serialize + Send + 'static,
+    E: AppInsightsError + Serialize + DeserializeOwned + Default + Send + 'static,
+{
+    type Error = S::Error;
+    type Future = AppInsightsFuture<S::Future, Result<Self::Response, Self::Error>>;
+    type Response = S::Response;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request<Body>) -> Self::Future {
+        // The noop path must not allocate or inspect the request in any way, so that the layer can be left in place.
+        if self.is_noop {
+            return AppInsightsFuture { kind: AppInsightsFutureKind::Noop { future: self.inner.call(request) } };
+        }
+
+        // Excluded and filtered requests (e.g., health checks) skip the middleware entirely, just like the noop path.
+        let is_excluded = !self.excluded_routes.is_empty() && {
+            let path = request.uri().path();
+            let route = request.extensions().get::<MatchedPath>().map(|m| m.as_str()).unwrap_or(path);
+
+            self.excluded_routes.iter().any(|r| r == route || r == path)
+        };
+        let (is_excluded, request) = match self.request_filter.as_ref() {
+            Some(request_filter) if !is_excluded => {
+                let (parts, body) = request.into_parts();
+                (!request_filter(&parts), Request::from_parts(parts, body))
+            },
+            _ => (is_excluded, request),
+        };
+
+        if is_excluded {
+            return AppInsightsFuture { kind: AppInsightsFutureKind::Noop { future: self.inner.call(request) } };
+        }
+
+        // Get all of the basic request information.
+        let start = self.clock.now();
+        let method = request.method().to_string();
+        let uri = request.uri().to_string();
+        let client_ip = request.headers().get("x-forwarded-for").and_then(|v| v.to_str().ok()).unwrap_or("unknown").to_string();
+        let client_ip = client_ip.split(',').next().unwrap_or("unknown");
+
+        // Spit the request into parts, and extract the route, and any extra fields.  Requests that never reach a route (e.g., 404s, or rejections
+        // from layers outside of the router) share a single operation name, since their paths are unbounded; the path itself is in `url.path`.
+        let (parts, body) = request.into_parts();
+        let path = parts.uri.path().to_owned();
+        let route = parts
+            .extensions
+            .get::<MatchedPath>()
+            .map(|m| m.as_str().to_owned())
+            .unwrap_or_else(|| UNMATCHED_ROUTE.to_owned());
+        let route = match self.operation_grouper.as_ref() {
+            Some(operation_grouper) => operation_grouper(&route, &parts),
+            None => route,
+        };
+        let extra_fields = self.field_mapper.as_ref().map(|f| f(&parts)).unwrap_or_default();
+        let async_extra_fields = self.async_field_mapper.as_ref().map(|f| f(&parts));
+        let user_context = self.user_mapper.as_ref().map(|f| f(&parts)).unwrap_or_default();
+        let is_debug_trace = self.debug_trace_header.as_ref().is_some_and(|h| h.matches(&parts.headers));
+        let sampling_decision = if is_debug_trace {
+            SamplingDecision::Sample
+        } else {
+            self.request_sampler.as_ref().map(|f| f(&parts)).unwrap_or_default()
+        };
+        let resend_count = self.retry_detector.as_ref().and_then(|d| d.observe(&parts.headers, start));
+        let sampling_priority = match sampling_decision {
+            SamplingDecision::Default => None,
+            SamplingDecision::Sample => Some(1),
+            SamplingDecision::Drop => Some(0),
+        };
+        let is_error_biased = self.should_bias_errors && sampling_priority.is_none();
+
+        // Wrap multipart bodies, so that the upload can be summarized as the handler reads it.
+        let multipart_boundary = if self.should_record_multipart { multipart_boundary(&parts.headers) } else { None };
+        let (body, multipart_stats) = match multipart_boundary {
+            Some(boundary) => {
+                let stats = Arc::new(Mutex::new(MultipartStats::new(boundary)));
+                (Body::new(MultipartBody { inner: body, stats: stats.clone() }), Some(stats))
+            },
+            None => (body, None),
+        };
+
+        // Get the compression information from the request.
+        let request_encoding = if self.should_record_compression { header_string(&parts.headers, http::header::CONTENT_ENCODING) } else { None };
+
+        // Snapshot the request for the panic mapper, since the request itself is handed off to the inner service.
+        let panic_context = self.panic_mapper.as_ref().map(|_| PanicContext {
+            method: method.clone(),
+            uri: uri.clone(),
+            route: route.clone(),
+            headers: self
+                .panic_context_headers
+                .iter()
+                .filter_map(|name| parts.headers.get(name).map(|value| (name.clone(), value.clone())))
+                .collect(),
+        });
+
+        // Get the remote parent of the request, if it is part of a distributed trace.
+        let remote_context = match self.propagator.as_ref() {
+            _ if !self.should_extract_trace_context => None,
+            Some(propagator) => Some(propagator.extract_with_context(&opentelemetry::Context::new(), &HeaderExtractor(&parts.headers)))
+                .filter(|context| context.span().span_context().is_valid()),
+            None => extract_remote_context(&parts.headers).map(|remote_context| opentelemetry::Context::new().with_remote_span_context(remote_context)),
+        };
+
+        // Get the selected request headers and query parameters.
+        let captured_request_attributes = captured_header_attributes("http.request.header", &parts.headers, &self.captured_request_headers, &self.capture_redactor)
+            .into_iter()
+            .chain(captured_query_attributes(parts.uri.query(), &self.captured_query_parameters, &self.capture_redactor))
+            .collect::<Vec<_>>();
+
+        // Put the request back together.
+        let request = Request::from_parts(parts, body);
+
+        // Create the span for the request, and leave empty fields for the response records.
+        let span = tracing::info_span!(
+            "request",
+            otel.kind = "server",
+            http.request.method = method.as_str(),
+            url.full = uri.as_str(),
+            url.path = path.as_str(),
+            client.address = client_ip,
+            http.route = route.as_str(),
+            ai.cloud.role = self.cloud_role.as_deref(),
+            sampling.priority = sampling_priority,
+            debug_trace = is_debug_trace.then_some(true),
+            http.request.resend_count = resend_count,
+            sampling.error_biased = is_error_biased.then_some(true),
+            sampling.retained = tracing::field::Empty,
+            ai.user.id = user_context.user_id.as_deref(),
+            ai.session.id = user_context.session_id.as_deref(),
+            ai.user.authUserId = user_context.auth_user_id.as_deref(),
+            enduser.id = user_context.auth_user_id.as_deref(),
+            http.request.body = tracing::field::Empty,
+            http.response.body = tracing::field::Empty,
+            http.response.status_code = tracing::field::Empty,
+            otel.status_code = tracing::field::Empty,
+            otel.status_message = tracing::field::Empty,
+            error.type = tracing::field::Empty,
+            error.code = tracing::field::Empty,
+            upgraded = tracing::field::Empty,
+            http.request.body.size = tracing::field::Empty,
+            multipart.parts = tracing::field::Empty,
+            multipart.content_types = tracing::field::Empty,
+            "http.request.header.content-encoding" = request_encoding,
+            "http.response.header.content-encoding" = tracing::field::Empty,
+            http.response.body.size = tracing::field::Empty,
+            extra_fields = serde_json::to_string_pretty(&extra_fields).unwrap()
+        );
+
+        // Continue the distributed trace, if there is one.
+        if let Some(remote_context) = remote_context {
+            span.set_parent(remote_context);
+        }
+
+        for (key, value) in captured_request_attributes {
+            span.set_attribute(key, value);
+        }
+
+        // Capture the request body of a debug trace as the handler reads it.
+        let request = if is_debug_trace {
+            request.map(|body| Body::new(CaptureBody::new(body, span.clone(), "http.request.body")))
+        } else {
+            request
+        };
+
+        // Count the (possibly compressed) request bytes as the handler reads them, since chunked bodies have no length up front.
+        let request = if self.should_record_compression {
+            request.map(|body| Body::new(SizeBody::new(body, span.clone(), "http.request.body.size")))
+        } else {
+            request
+        };
+
+        // Clone the panic mapper so that it can be used in the future.
+        let panic_mapper = self.panic_mapper.clone();
+        let success_filter = self.success_filter.clone();
+        let should_close_on_upgrade = self.should_close_on_upgrade;
+        let heartbeat_interval = self.heartbeat_interval;
+        let should_record_compression = self.should_record_compression;
+        let debug_trace_span = is_debug_trace.then(|| span.clone());
+        let should_inject_trace_context = self.should_inject_trace_context;
+        let error_response_headers = self.error_response_headers.clone();
+        let should_deserialize_error_body = self.should_deserialize_error_body;
+        let max_error_body_capture = self.max_error_body_capture;
+        let slow_request_threshold = self.slow_request_threshold;
+        let captured_response_headers = self.captured_response_headers.clone();
+        let capture_redactor = self.capture_redactor.clone();
+        let request_metrics = self.request_metrics.clone();
+        let rate_report = self.rate_report.clone();
+        let rate_report_route = rate_report.as_ref().map(|_| route.clone());
+        let stats_handle = self.stats_handle.clone();
+        let clock = self.clock.clone();
+        let metric_attributes = request_metrics.as_ref().map(|_| vec![KeyValue::new("http.route", route.clone()), KeyValue::new("http.request.method", method.clone())]);
+
+        // Kick off the request.
+        let future = self.inner.call(request);
+
+        // Create the pinned future that is the essence of this middleware after the response.
+        let instrumented = Box::pin(
+            async move {
+                // Get the extra fields that need to be awaited, before the handler runs.
+                if let Some(async_extra_fields) = async_extra_fields {
+                    let mut extra_fields = extra_fields;
+                    extra_fields.extend(async_extra_fields.await);
+
+                    Span::current().record("extra_fields", serde_json::to_string_pretty(&extra_fields).unwrap());
+                }
+
+                // Get the response, and catch any panics.
+                let response = AssertUnwindSafe(future).catch_unwind().instrument(Span::current()).await;
+
+                let response = match response {
+                    Ok(response) => response,
+                    Err(e) => {
+                        // Get the payload string from the panic (usually the panic message).
+                        let payload_string = format!("{:?}", e.downcast_ref::<&str>());
+
+                        // Use the given mapper, or create a default error.  For now, a feature of this library is to "panic handle".
+                        let (status, error_string) = if let (Some(panic_mapper), Some(panic_context)) = (panic_mapper.as_ref(), panic_context.as_ref()) {
+                            let (status, error) = panic_mapper(payload_string.clone(), panic_context);
+
+                            (status, serde_json::to_string(&error).unwrap())
+                        } else {
+                            (
+                                500,
+                                format!(
+                                    r#"{{
+                                    "status": 500,
+                                    "message": "A panic occurred: {}.",
+                                }}"#,
+                                    payload_string
+                                )
+                                .to_string(),
+                            )
+                        };
+
+                        // Build a response for the error in the panic case.
+                        Ok(Response::builder()
+                            .status(status)
+                            .header("content-type", "application/json")
+                            .body(Body::from(error_string))
+                            .unwrap())
+                    }
+                }?;
+
+                // Record the (possibly compressed) encoding as it appears on the wire (the size is counted as the body streams).
+                if should_record_compression {
+                    if let Some(response_encoding) = header_string(response.headers(), http::header::CONTENT_ENCODING) {
+                        Span::current().record("http.response.header.content-encoding", response_encoding);
+                    }
+                }
+
+                // Record the summary of a multipart upload (as much of it as the handler read).
+                if let Some(multipart_stats) = multipart_stats {
+                    let stats = multipart_stats.lock().unwrap();
+                    let span = Span::current();
+
+                    span.record("http.request.body.size", stats.bytes);
+                    span.record("multipart.parts", stats.parts);
+                    span.record("multipart.content_types", stats.content_types.join(","));
+                }
+
+                // Get the response status information, and determine success.
+                let status = response.status();
+
+                // If the connection was upgraded, finish the span now, and stop tracking the connection (this skips everything below,
+                // e.g., the captured response headers and the route tags).
+                if should_close_on_upgrade && status == StatusCode::SWITCHING_PROTOCOLS {
+                    let span = Span::current().entered();
+
+                    span.record("http.response.status_code", status.as_u16());
+                    span.record("otel.status_code", "OK");
+                    span.record("upgraded", true);
+                    stats_handle.record(true);
+
+                    return Ok(response);
+                }
+
+                let is_success = success_filter.as_ref().map(|f| f(status)).unwrap_or_else(|| status.is_success() || status.is_redirection() || status.is_informational());
+
+                // Get the span information about the response.
+                let (response, otel_status, otel_status_message, error_code) = if is_success {
+                    // The happy path!
+                    (response, "OK", String::new(), None)
+                } else {
+                    if let Some(body_size) = http_body::Body::size_hint(response.body()).exact() {
+                        Span::current().record("http.response.body.size", body_size);
+                    }
+
+                    // Prefer the error details that the handler stashed in the extensions, since they do not require the body.
+                    let (response, error) = match response.extensions().get::<ErrorDetails>().cloned() {
+                        Some(error) => (response, error),
+                        None => {
+                            // Breakup the response into parts.
+                            let (parts, body) = response.into_parts();
+
+                            // Get (at most the limit of) the body bytes.
+                            let (body_bytes, is_complete, body) = capture_error_body(body, max_error_body_capture).await;
+
+                            // Deserialize the error.  Errors that were not produced by the application (e.g., a rejection from an auth or body limit
+                            // layer) usually do not deserialize, so synthesize the exception message from the status and a snippet of the body.
+                            let error = match (should_deserialize_error_body && is_complete).then(|| serde_json::from_slice::<E>(&body_bytes).ok()).flatten() {
+                                Some(error) => ErrorDetails::new(&error),
+                                None => ErrorDetails {
+                                    message: Some(synthesize_exception_message(status, &body_bytes)),
+                                    ..Default::default()
+                                },
+                            };
+
+                            // Recreate the response.
+                            (Response::from_parts(parts, body), error)
+                        },
+                    };
+
+                    let exception_message = error.message.unwrap_or_default();
+
+                    // Get the selected response headers, since some errors are only described by them.
+                    let response_headers = error_response_headers
+                        .iter()
+                        .filter_map(|name| header_string(response.headers(), name.clone()).map(|value| (name.as_str(), value)))
+                        .collect::<HashMap<_, _>>();
+                    let response_headers = (!response_headers.is_empty()).then(|| serde_json::to_string_pretty(&response_headers).unwrap());
+
+                    // The exporter names the event after the message, so the message is what makes this an exception.
+                    tracing::event!(
+                        name: "exception",
+                        Level::ERROR,
+                        ai.customEvent.name = "exception",
+                        "exception.type" = format!("HTTP {}", status.as_u16()),
+                        exception.message = exception_message.as_str(),
+                        exception.stacktrace = error.backtrace.unwrap_or_default(),
+                        response_headers = response_headers.as_deref(),
+                        "exception"
+                    );
+
+                    (response, "ERROR", exception_message, error.code)
+                };
+
+                // Record the request metrics, and the rate report.
+                let duration = clock.now().saturating_duration_since(start);
+
+                if let (Some(request_metrics), Some(mut metric_attributes)) = (request_metrics, metric_attributes) {
+                    metric_attributes.push(KeyValue::new("http.response.status_code", status.as_u16() as i64));
+                    request_metrics.record(&metric_attributes, duration, otel_status == "OK");
+                }
+
+                if let (Some(rate_report), Some(rate_report_route)) = (rate_report, rate_report_route) {
+                    rate_report.record(rate_report_route, duration, otel_status == "OK");
+                }
+
+                stats_handle.record(otel_status == "OK");
+
+                // Finish the span.
+                let span = Span::current().entered();
+
+                // Decide whether a tentatively recorded request is retained (see the error-biased sampling).
+                if is_error_biased && !span.context().span().span_context().is_sampled() {
+                    let is_slow = slow_request_threshold.is_some_and(|threshold| duration >= threshold);
+                    span.record("sampling.retained", otel_status != "OK" || is_slow);
+                }
+
+                span.record("http.response.status_code", status.as_u16());
+                span.record("otel.status_code", otel_status);
+
+                if otel_status != "OK" {
+                    span.record("otel.status_message", otel_status_message);
+                    span.record("error.type", status.as_str());
+
+                    if let Some(error_code) = error_code {
+                        span.record("error.code", error_code);
+                    }
+                }
+
+                // Record the selected response headers.
+                for (key, value) in captured_header_attributes("http.response.header", response.headers(), &captured_response_headers, &capture_redactor) {
+                    span.set_attribute(key, value);
+                }
+
+                // Merge in any tags that were attached by a route tags layer.
+                if let Some(route_tags) = response.extensions().get::<RouteTags>() {
+                    for (key, value) in route_tags.0.iter() {
+                        span.set_attribute(key.clone(), value.clone());
+                    }
+                }
+
+                // Capture the response body of a debug trace as it streams.
+                let response = match debug_trace_span {
+                    Some(span) => response.map(|body| Body::new(CaptureBody::new(body, span, "http.response.body"))),
+                    None => response,
+                };
+
+                // Wrap the body so that long-lived responses emit heartbeats while they stream.
+                let response = match heartbeat_interval {
+                    Some(interval) => response.map(|body| Body::new(HeartbeatBody::new(body, interval, clock))),
+                    None => response,
+                };
+
+                // Count the (possibly compressed) response bytes as they stream, since chunked bodies have no length up front.
+                let mut response = if should_record_compression {
+                    response.map(|body| Body::new(SizeBody::new(body, Span::current(), "http.response.body.size")))
+                } else {
+                    response
+                };
+
+                // Tell the caller about the request span, so that it can correlate the response.
+
+                if should_inject_trace_context {
+                    if let Some(traceparent) = TraceContextToken::current().and_then(|t| http::HeaderValue::from_str(&t.to_string()).ok()) {
+                        response.headers_mut().insert("traceparent", traceparent);
+                    }
+                }
+
+                Ok(response)
+            }
+            .instrument(span),
+        );
+
+        AppInsightsFuture { kind: AppInsightsFutureKind::Instrumented { future: instrumented } }
+    }
+}
+
+pin_project_lite::pin_project! {
+    /// The future returned by the [`AppInsightsMiddleware`].
+    /// 
+    /// In noop mode, this is the inner service's future as-is, so no allocation happens per request.
+    pub struct AppInsightsFuture<F, T> {
+        #[pin]
+        kind: AppInsightsFutureKind<F, T>,
+    }
+}
+
+pin_project_lite::pin_project! {
+    #[project = AppInsightsFutureKindProjection]
+    enum AppInsightsFutureKind<F, T> {
+        Noop {
+            #[pin]
+            future: F,
+        },
+        Instrumented {
+            future: BoxFuture<'static, T>,
+        },
+    }
+}
+
+impl<F, T> Future for AppInsightsFuture<F, T>
+where
+    F: Future<Output = T>,
+{
+    type Output = T;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        match self.project().kind.project() {
+            AppInsightsFutureKindProjection::Noop { future } => future.poll(cx),
+            AppInsightsFutureKindProjection::Instrumented { future } => future.as_mut().poll(cx),
+        }
+    }
+}
+
+// Helpers.
+
+/// Gets a header as an owned string, if it is present and valid.
+fn header_string(headers: &http::HeaderMap, name: http::header::HeaderName) -> Option<String> {
+    headers.get(name).and_then(|v| v.to_str().ok()).map(str::to_owned)
+}
+
+/// The operation name for requests that never reach a route, which keeps the number of operation names bounded.
+const UNMATCHED_ROUTE: &str = "<unmatched>";
+
+/// The maximum number of bytes of a response body that are used to synthesize an exception message.
+const MAX_EXCEPTION_BODY_SNIPPET_SIZE: usize = 256;
+
+/// Whether or not the panic hook is installed, in which case it already emits the exceptions for panics.
+static IS_PANIC_HOOK_INSTALLED: AtomicBool = AtomicBool::new(false);
+
+/// Emits the exception event for a panic.
+fn emit_panic_exception(payload_string: &str, backtrace: &str) {
+    // The exporter names the event after the message, so the message is what makes this an exception.
+    tracing::event!(
+        name: "exception",
+        Level::ERROR,
+        ai.customEvent.name = "exception",
+        "exception.type" = "PANIC",
+        exception.message = payload_string,
+        exception.stacktrace = backtrace,
+        "exception"
+    );
+}
+
+/// The default maximum number of bytes of a failed response body that are buffered to extract the error.
+const DEFAULT_MAX_ERROR_BODY_CAPTURE: usize = 64 * 1024;
+
+/// Buffers (up to the given limit of) a failed response body, and returns the buffered bytes, whether they are the whole body, and the body
+/// to send on.  A body whose size is not known up front (i.e., a streaming body) is not buffered at all.
+/// 
+/// The body that is sent on is the original body: the frames that were read (including the trailers, or an error) are sent first, followed
+/// by the rest of the body.  Only the bytes up to the limit are copied, even if a single frame is larger than the limit.
+async fn capture_error_body(body: Body, max_bytes: usize) -> (Bytes, bool, Body) {
+    match http_body::Body::size_hint(&body).exact() {
+        None => (Bytes::new(), false, body),
+        Some(size) if size <= max_bytes as u64 => {
+            let body_bytes = body.collect().await.unwrap_or_default().to_bytes();
+
+            (body_bytes.clone(), true, Body::from(body_bytes))
+        },
+        Some(_) => {
+            let mut body = body;
+            let mut captured = Vec::new();
+            let mut frames = Vec::new();
+
+            while captured.len() < max_bytes {
+                match body.frame().await {
+                    Some(Ok(frame)) => {
+                        if let Some(data) = frame.data_ref() {
+                            let remaining = max_bytes - captured.len();
+                            captured.extend_from_slice(&data[..data.len().min(remaining)]);
+                        }
+
+                        frames.push(Ok(frame));
+                    },
+                    Some(Err(e)) => {
+                        frames.push(Err(e));
+                        break;
+                    },
+                    None => break,
+                }
+            }
+
+            // Send the frames that were read, followed by the rest of the body.
+            let rest = http_body_util::BodyStream::new(body);
+            let body = Body::new(http_body_util::StreamBody::new(futures::StreamExt::chain(futures::stream::iter(frames), rest)));
+
+            (Bytes::from(captured), false, body)
+        },
+    }
+}
+
+/// Synthesizes an exception message from a status and a snippet of the response body.
+fn synthesize_exception_message(status: StatusCode, body: &[u8]) -> String {
+    let reason = status.canonical_reason().unwrap_or("Unknown");
+    let snippet = String::from_utf8_lossy(&body[..body.len().min(MAX_EXCEPTION_BODY_SNIPPET_SIZE)]);
+    let snippet = snippet.trim();
+
+    if snippet.is_empty() {
+        format!("{} {}", status.as_u16(), reason)
+    } else {
+        format!("{} {}: {}", status.as_u16(), reason, snippet)
+    }
+}
+
+/// Resolves the service version to stamp onto the resource: an explicit version always wins, otherwise (unless the resource of the
+/// trace config already has one) the version of the build information is used.
+fn resolve_service_version(resource: &Resource, service_version: Option<String>, build_info: Option<&BuildInfo>) -> Option<String> {
+    let has_service_version = resource.get(opentelemetry::Key::from_static_str("service.version")).is_some();
+
+    match service_version {
+        Some(service_version) => Some(service_version),
+        None if has_service_version => None,
+        None => build_info.map(|b| b.version.clone()),
+    }
+}
+
+/// Detects the host name of the machine (or pod).
+fn detect_host_name() -> Option<String> {
+    std::env::var("HOSTNAME")
+        .or_else(|_| std::env::var("COMPUTERNAME"))
+        .ok()
+        .or_else(|| std::fs::read_to_string("/etc/hostname").ok())
+        .map(|h| h.trim().to_owned())
+        .filter(|h| !h.is_empty())
+}
+
+/// Gets the host resource attributes for the given host name, skipping any that the resource (or the attributes that will be
+/// merged into it) already has.
+fn host_resource_attributes(resource: &Resource, attributes: &[KeyValue], host_name: Option<String>) -> Vec<KeyValue> {
+    let Some(host_name) = host_name else {
+        return Vec::new();
+    };
+
+    let has = |key: &'static str| resource.get(opentelemetry::Key::from_static_str(key)).is_some() || attributes.iter().any(|kv| kv.key.as_str() == key);
+    let mut host_attributes = Vec::new();
+
+    if !has("host.name") {
+        host_attributes.push(KeyValue::new("host.name", host_name.clone()));
+    }
+
+    if !has("service.instance.id") && !has("k8s.pod.name") {
+        host_attributes.push(KeyValue::new("service.instance.id", host_name));
+    }
+
+    host_attributes
+}
+
+/// The `axum` release line that this crate is built against, which must match the `axum` dependency in `Cargo.toml`.  The patch
+/// version is chosen by the application's lockfile, so it cannot be known here.
+const AXUM_VERSION: &str = "0.7";
+
+/// Gets the `telemetry.sdk.*`, `axum_insights.version`, and `axum.version` resource attributes, skipping any that the resource (or the
+/// attributes that will be merged into it) already has.
+fn sdk_resource_attributes(resource: &Resource, attributes: &[KeyValue]) -> Vec<KeyValue> {
+    let sdk_resource = TelemetryResourceDetector.detect(Duration::ZERO);
+
+    sdk_resource
+        .iter()
+        .map(|(k, v)| KeyValue::new(k.clone(), v.clone()))
+        .chain([KeyValue::new("axum_insights.version", env!("CARGO_PKG_VERSION")), KeyValue::new("axum.version", AXUM_VERSION)])
+        .filter(|kv| resource.get(kv.key.clone()).is_none() && !attributes.iter().any(|a| a.key == kv.key))
+        .collect()
+}
+
+/// An error that caused the build to degrade to a [`FallbackMode`].
+type Fallback = (FallbackMode, Box<dyn Error + Send + Sync + 'static>);
+
+/// Validates the connection string, and resolves the connection string to use (and the fallback, if one was triggered).
+/// 
+/// An invalid connection string is an error, unless a fallback mode is set, in which case no connection string is used.
+fn resolve_connection_string(connection_string: Option<String>, fallback_mode: Option<FallbackMode>) -> Result<(Option<String>, Option<Fallback>), Box<dyn Error + Send + Sync + 'static>> {
+    let Some(connection_string) = connection_string else {
+        return Ok((None, None));
+    };
+
+    match (opentelemetry_application_insights::new_pipeline_from_connection_string(&connection_string), fallback_mode) {
+        (Ok(_), _) => Ok((Some(connection_string), None)),
+        (Err(e), None) => Err(e),
+        (Err(e), Some(fallback_mode)) => Ok((None, Some((fallback_mode, e)))),
+    }
+}
+
+/// Builds the tracer provider that exports to an OTLP collector.
+#[cfg(feature = "otlp")]
+fn build_otlp_tracer_provider<R: RuntimeChannel>(endpoint: String, config: Config, runtime: R) -> Result<opentelemetry_sdk::trace::TracerProvider, Box<dyn Error + Send + Sync + 'static>> {
+    use opentelemetry_otlp::WithExportConfig;
+
+    let pipeline = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(opentelemetry_otlp::new_exporter().http().with_endpoint(endpoint))
+        .with_trace_config(config);
+
+    Ok(pipeline.install_batch(runtime)?)
+}
+
+/// Builds the tracer provider that exports to an OTLP collector, which is unavailable without the `otlp` feature (and unreachable, since
+/// the backend cannot be selected).
+#[cfg(not(feature = "otlp"))]
+fn build_otlp_tracer_provider<R: RuntimeChannel>(_endpoint: String, _config: Config, _runtime: R) -> Result<opentelemetry_sdk::trace::TracerProvider, Box<dyn Error + Send + Sync + 'static>> {
+    Err("the OTLP backend requires the `otlp` feature".into())
+}
+
+/// The value that replaces a redacted capture.
+const REDACTED_CAPTURE: &str = "REDACTED";
+
+/// Redacts the captured values whose names look sensitive (e.g., credentials, cookies, tokens, and keys).
+fn redact_sensitive_capture(name: &str, value: &str) -> String {
+    let name = name.to_ascii_lowercase();
+    let is_sensitive = ["authorization", "cookie", "token", "secret", "password", "key", "signature", "session"].iter().any(|s| name.contains(s));
+
+    if is_sensitive {
+        REDACTED_CAPTURE.to_owned()
+    } else {
+        value.to_owned()
+    }
+}
+
+/// Redacts the captured values whose names look sensitive (see [`redact_sensitive_capture`]) or personal (e.g., client addresses,
+/// emails, and phone numbers).
+fn redact_strict_capture(name: &str, value: &str) -> String {
+    let lower_name = name.to_ascii_lowercase();
+    let is_personal = ["auth", "credential", "csrf", "xsrf", "jwt", "forwarded", "real-ip", "client-ip", "email", "phone", "address", "username", "ssn", "card"]
+        .iter()
+        .any(|s| lower_name.contains(s));
+
+    if is_personal {
+        REDACTED_CAPTURE.to_owned()
+    } else {
+        redact_sensitive_capture(name, value)
+    }
+}
+
+/// Gets the attributes of the selected headers, which are named `<prefix>.<name>`.  Multiple values of a header are joined with commas.
+fn captured_header_attributes(prefix: &str, headers: &http::HeaderMap, names: &[http::HeaderName], redactor: &OptionalCaptureRedactor) -> Vec<(String, String)> {
+    names
+        .iter()
+        .filter_map(|name| {
+            let values = headers.get_all(name).iter().filter_map(|v| v.to_str().ok()).collect::<Vec<_>>();
+            let value = (!values.is_empty()).then(|| values.join(","))?;
+            let value = redactor.as_ref().map(|r| r(name.as_str(), &value)).unwrap_or(value);
+
+            Some((format!("{}.{}", prefix, name.as_str()), value))
+        })
+        .collect()
+}
+
+/// Gets the attributes of the selected query parameters, which are named `url.query.<name>`.
+fn captured_query_attributes(query: Option<&str>, names: &[String], redactor: &OptionalCaptureRedactor) -> Vec<(String, String)> {
+    let Some(query) = query.filter(|_| !names.is_empty()) else {
+        return Vec::new();
+    };
+
+    form_urlencoded::parse(query.as_bytes())
+        .filter(|(name, _)| names.iter().any(|n| n == name))
+        .map(|(name, value)| {
+            let value = redactor.as_ref().map(|r| r(&name, &value)).unwrap_or_else(|| value.into_owned());
+
+            (format!("url.query.{}", name), value)
+        })
+        .collect()
+}
+
+// Route tags.
+
+/// Creates a layer that attaches static tags to the request span of the routes it wraps.
+/// 
+/// The tags are recorded as custom dimensions on the request, which allows latency and failures to be sliced by
+/// ownership (e.g., by domain or owning team).  Tags from a more specific layer (e.g., a route) win over tags from a
+/// less specific layer (e.g., a router) with the same key.
+/// 
+/// ```
+/// use axum::{Router, routing::get};
+/// use axum_insights::with_route_tags;
+/// 
+/// // You likely will not need to specify `Router<()>` in your implementation.  This is just for the example.
+/// let app: Router<()> = Router::new()
+///     .route("/invoices", get(|| async { "invoices" }).layer(with_route_tags([("domain", "billing"), ("owner", "team-x")])));
+/// ```
+pub fn with_route_tags<I, K, V>(tags: I) -> RouteTagsLayer
+where
+    I: IntoIterator<Item = (K, V)>,
+    K: Into<String>,
+    V: Into<String>,
+{
+    RouteTagsLayer {
+        tags: Arc::new(tags.into_iter().map(|(k, v)| (k.into(), v.into())).collect()),
+    }
+}
+
+/// The tags attached to a response by a [`RouteTagsLayer`], which are read by the [`AppInsightsMiddleware`].
+#[derive(Clone, Debug, Default)]
+struct RouteTags(Vec<(String, String)>);
+
+/// The route tags layer.
+/// 
+/// This layer is created by [`with_route_tags`].
+#[derive(Clone, Debug)]
+pub struct RouteTagsLayer {
+    tags: Arc<Vec<(String, String)>>,
+}
+
+impl<S> Layer<S> for RouteTagsLayer {
+    type Service = RouteTagsMiddleware<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RouteTagsMiddleware {
+            inner,
+            tags: self.tags.clone(),
+        }
+    }
+}
+
+/// The route tags middleware.
+/// 
+/// This middleware is created by [`RouteTagsLayer::layer`], and it stashes its tags in the response extensions.
+#[derive(Clone, Debug)]
+pub struct RouteTagsMiddleware<S> {
+    inner: S,
+    tags: Arc<Vec<(String, String)>>,
+}
+
+impl<S, B> Service<Request<B>> for RouteTagsMiddleware<S>
+where
+    S: Service<Request<B>, Response = Response> + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+    type Response = S::Response;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request<B>) -> Self::Future {
+        let tags = self.tags.clone();
+        let future = self.inner.call(request);
+
+        Box::pin(async move {
+            let mut response = future.await?;
+
+            // Inner (more specific) layers have already run, so only add the keys that they did not set.
+            let route_tags = response.extensions_mut().get_or_insert_default::<RouteTags>();
+
+            for (key, value) in tags.iter() {
+                if !route_tags.0.iter().any(|(k, _)| k == key) {
+                    route_tags.0.push((key.clone(), value.clone()));
+                }
+            }
+
+            Ok(response)
+        })
+    }
+}
+
+// Shutdown.
+
+/// A handle that flushes and shuts down the telemetry pipeline, which is returned from [`AppInsightsComplete::shutdown_handle`].
+/// 
+/// Without a connection string (or in noop mode), there is no pipeline, so these are no-ops.
+#[derive(Clone, Default)]
+pub struct ShutdownHandle {
+    tracer_provider: Option<opentelemetry_sdk::trace::TracerProvider>,
+    meter_provider: Option<SdkMeterProvider>,
+}
+
+impl ShutdownHandle {
+    /// Exports all of the telemetry that is buffered (e.g., in the batch exporter).
+    /// 
+    /// This does not require a Tokio runtime, so it can be awaited on any executor.
+    pub async fn flush(&self) -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
+        let handle = self.clone();
+
+        run_on_thread(move || {
+            if let Some(tracer_provider) = handle.tracer_provider.as_ref() {
+                tracer_provider.force_flush().into_iter().collect::<Result<Vec<_>, _>>()?;
+            }
+
+            if let Some(meter_provider) = handle.meter_provider.as_ref() {
+                meter_provider.force_flush()?;
+            }
+
+            Ok(())
+        })
+        .await
+    }
+
+    /// Exports all of the telemetry that is buffered, and then shuts down the pipeline.  Telemetry that is recorded afterwards is dropped.
+    /// 
+    /// This does not require a Tokio runtime, so it can be awaited on any executor.
+    pub async fn shutdown(&self) -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
+        let handle = self.clone();
+
+        run_on_thread(move || {
+            if let Some(tracer_provider) = handle.tracer_provider.as_ref() {
+                tracer_provider.shutdown()?;
+            }
+
+            if let Some(meter_provider) = handle.meter_provider.as_ref() {
+                meter_provider.shutdown()?;
+            }
+
+            Ok(())
+        })
+        .await
+    }
+}
+
+/// Runs a blocking pipeline operation (e.g., a flush, which blocks on the exporter) on its own thread, so that it neither blocks a
+/// runtime thread nor requires a particular runtime.
+async fn run_on_thread<F>(operation: F) -> Result<(), Box<dyn Error + Send + Sync + 'static>>
+where
+    F: FnOnce() -> Result<(), Box<dyn Error + Send + Sync + 'static>> + Send + 'static,
+{
+    let (sender, receiver) = futures::channel::oneshot::channel();
+
+    std::thread::spawn(move || {
+        let _ = sender.send(operation());
+    });
+
+    receiver.await?
+}
+
+// Reload.
+
+/// A handle that changes the settings of the telemetry subscriber at runtime, which is returned from [`AppInsightsComplete::reload_handle`].
+/// 
+/// This is useful to turn up the verbosity of a running service (e.g., from an admin endpoint) without a restart.
+#[derive(Clone, Debug)]
+pub struct ReloadHandle {
+    minimum_level: Arc<RwLock<LevelFilter>>,
+}
+
+impl ReloadHandle {
+    /// Gets the current minimum level of the spans and events that are exported.
+    pub fn minimum_level(&self) -> LevelFilter {
+        *self.minimum_level.read().unwrap()
+    }
+
+    /// Sets the minimum level of the spans and events that are exported (see [`AppInsights::with_minimum_level`]).
+    pub fn set_minimum_level(&self, minimum_level: LevelFilter) {
+        *self.minimum_level.write().unwrap() = minimum_level;
+
+        // The subscriber caches which callsites are enabled, so the cache has to be rebuilt for the new level to take effect.
+        tracing::callsite::rebuild_interest_cache();
+    }
+}
+
+// Stats.
+
+/// A handle that reads the statistics of the requests that the middleware has handled, which is returned from [`AppInsightsComplete::stats_handle`].
+/// 
+/// The counts are kept in-process, regardless of sampling, so they are useful for health checks and for local diagnostics.
+#[derive(Clone, Debug, Default)]
+pub struct StatsHandle {
+    stats: Arc<RequestStats>,
+}
+
+/// The request counts behind a [`StatsHandle`].
+#[derive(Debug, Default)]
+struct RequestStats {
+    requests: AtomicU64,
+    failures: AtomicU64,
+}
+
+impl StatsHandle {
+    /// Gets the number of requests that the middleware has handled (excluding the excluded and filtered ones).
+    pub fn requests(&self) -> u64 {
+        self.stats.requests.load(Ordering::Relaxed)
+    }
+
+    /// Gets the number of requests that failed (see [`AppInsights::with_success_filter`]).
+    pub fn failures(&self) -> u64 {
+        self.stats.failures.load(Ordering::Relaxed)
+    }
+
+    fn record(&self, is_success: bool) {
+        self.stats.requests.fetch_add(1, Ordering::Relaxed);
+
+        if !is_success {
+            self.stats.failures.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Creates the tracer for the telemetry layer.
+fn create_tracer(tracer_provider: &opentelemetry_sdk::trace::TracerProvider) -> opentelemetry_sdk::trace::Tracer {
+    opentelemetry::trace::TracerProvider::tracer_builder(tracer_provider, "axum-insights").with_version(env!("CARGO_PKG_VERSION")).build()
+}
+
+// Metrics.
+
+/// The request metrics that the middleware records.
+struct RequestMetrics {
+    duration: Histogram<f64>,
+    requests: Counter<u64>,
+    failures: Counter<u64>,
+}
+
+impl RequestMetrics {
+    fn new(meter: &Meter) -> Self {
+        Self {
+            duration: meter
+                .f64_histogram("http.server.request.duration")
+                .with_unit("s")
+                .with_description("The duration of HTTP server requests.")
+                .init(),
+            requests: meter.u64_counter("http.server.request.count").with_description("The number of HTTP server requests.").init(),
+            failures: meter.u64_counter("http.server.request.failures").with_description("The number of failed HTTP server requests.").init(),
+        }
+    }
+
+    fn record(&self, attributes: &[KeyValue], duration: Duration, is_success: bool) {
+        self.duration.record(duration.as_secs_f64(), attributes);
+        self.requests.add(1, attributes);
+
+        if !is_success {
+            self.failures.add(1, attributes);
+        }
+    }
+}
+
+/// An HTTP client that can be shared between the tracer and the metrics exporter.
+#[derive(Debug)]
+struct SharedClient<C>(Arc<C>);
+
+impl<C> Clone for SharedClient<C> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+#[async_trait::async_trait]
+impl<C: HttpClient> HttpClient for SharedClient<C> {
+    async fn send(&self, request: Request<Vec<u8>>) -> Result<Response<Bytes>, Box<dyn Error + Send + Sync + 'static>> {
+        self.0.send(request).await
+    }
+}
+
+// Connectivity.
+
+/// Creates the test item that is sent by [`AppInsights::verify_connectivity`].
+fn connectivity_check_span() -> SpanData {
+    let id_generator = RandomIdGenerator::default();
+    let now = SystemTime::now();
+
+    SpanData {
+        span_context: SpanContext::new(id_generator.new_trace_id(), id_generator.new_span_id(), TraceFlags::SAMPLED, false, Default::default()),
+        parent_span_id: SpanId::INVALID,
+        span_kind: SpanKind::Internal,
+        name: "axum-insights.connectivity_check".into(),
+        start_time: now,
+        end_time: now,
+        attributes: Vec::new(),
+        dropped_attributes_count: 0,
+        events: SpanEvents::default(),
+        links: SpanLinks::default(),
+        status: opentelemetry::trace::Status::Ok,
+        instrumentation_lib: InstrumentationLibrary::builder("axum-insights").build(),
+    }
+}
+
+// Rate reports.
+
+/// The maximum number of latencies that are kept per route and window of a rate report.
+const MAX_RATE_REPORT_SAMPLES: usize = 4096;
+
+/// The requests of a route within the current window of a rate report.
+#[derive(Default)]
+struct RouteWindow {
+    requests: u64,
+    failures: u64,
+    durations_ms: Vec<f64>,
+}
+
+/// The summary of a route for a window of a rate report.
+#[derive(Debug, PartialEq)]
+struct RouteSummary {
+    route: String,
+    requests: u64,
+    failures: u64,
+    p50_ms: f64,
+    p95_ms: f64,
+}
+
+/// Aggregates the requests of each route, so that a summary can be emitted at the end of each window.
+#[derive(Default)]
+struct RateReport {
+    routes: Mutex<HashMap<String, RouteWindow>>,
+}
+
+impl RateReport {
+    fn record(&self, route: String, duration: Duration, is_success: bool) {
+        let mut routes = self.routes.lock().unwrap();
+        let window = routes.entry(route).or_default();
+
+        // Once the samples are full, overwrite them as a ring, so that the latencies reflect the most recent requests.
+        let duration_ms = duration.as_secs_f64() * 1000.0;
+        if window.durations_ms.len() < MAX_RATE_REPORT_SAMPLES {
+            window.durations_ms.push(duration_ms);
+        } else {
+            window.durations_ms[window.requests as usize % MAX_RATE_REPORT_SAMPLES] = duration_ms;
+        }
+
+        window.requests += 1;
+
+        if !is_success {
+            window.failures += 1;
+        }
+    }
+
+    /// Ends the current window, and summarizes it.
+    fn take_summaries(&self) -> Vec<RouteSummary> {
+        let routes = std::mem::take(&mut *self.routes.lock().unwrap());
+        let mut summaries = routes
+            .into_iter()
+            .map(|(route, mut window)| {
+                window.durations_ms.sort_by(f64::total_cmp);
+
+                RouteSummary {
+                    route,
+                    requests: window.requests,
+                    failures: window.failures,
+                    p50_ms: percentile(&window.durations_ms, 0.50),
+                    p95_ms: percentile(&window.durations_ms, 0.95),
+                }
+            })
+            .collect::<Vec<_>>();
+
+        summaries.sort_by(|a, b| a.route.cmp(&b.route));
+        summaries
+    }
+
+    fn emit(&self, interval: Duration) {
+        let summaries = self.take_summaries();
+
+        if summaries.is_empty() {
+            return;
+        }
+
+        // The report gets its own root span, which is always sampled, so that it is exported immediately.
+        let span = tracing::info_span!(parent: None, "rate_report", otel.kind = "internal", sampling.priority = 1);
+        let _guard = span.enter();
+
+        for summary in summaries {
+            tracing::event!(
+                Level::INFO,
+                ai.customEvent.name = "rate_report",
+                http.route = summary.route,
+                window_seconds = interval.as_secs_f64(),
+                requests = summary.requests,
+                failures = summary.failures,
+                "duration.p50_ms" = summary.p50_ms,
+                "duration.p95_ms" = summary.p95_ms,
+                "ai.custom"
+            );
+        }
+    }
+}
+
+/// Gets the nearest-rank percentile of the given sorted values.
+fn percentile(sorted: &[f64], p: f64) -> f64 {
+    if sorted.is_empty() {
+        return 0.0;
+    }
+
+    let rank = (p * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+// Retry detection.
+
+/// The maximum number of idempotency keys that are remembered at once; beyond this, the oldest are forgotten.
+const MAX_RETRY_KEYS: usize = 4096;
+
+/// The maximum size of an idempotency key that is remembered (longer keys are not tracked).
+const MAX_RETRY_KEY_SIZE: usize = 256;
+
+/// Detects likely client retries, by remembering the values of an idempotency header within a window.
+struct RetryDetector {
+    header: http::HeaderName,
+    window: Duration,
+    seen: Mutex<RetryWindow>,
+}
+
+/// The idempotency keys seen within the window (with the time that they were first seen, and the number of resends), and the
+/// order in which they were first seen (so that the oldest can be forgotten first).
+#[derive(Default)]
+struct RetryWindow {
+    keys: HashMap<String, (Instant, u64)>,
+    order: VecDeque<(String, Instant)>,
+}
+
+impl RetryWindow {
+    /// Forgets the expired keys, and the oldest keys beyond the limit.
+    fn prune(&mut self, now: Instant, window: Duration) {
+        while let Some((key, first_seen)) = self.order.front() {
+            if now.saturating_duration_since(*first_seen) < window && self.order.len() <= MAX_RETRY_KEYS {
+                break;
+            }
+
+            // A key that expired and was seen again has a newer entry, which is left alone.
+            if self.keys.get(key).is_some_and(|(s, _)| s == first_seen) {
+                self.keys.remove(key);
+            }
+
+            self.order.pop_front();
+        }
+    }
+}
+
+impl RetryDetector {
+    fn new(header: http::HeaderName, window: Duration) -> Self {
+        Self {
+            header,
+            window,
+            seen: Mutex::new(RetryWindow::default()),
+        }
+    }
+
+    /// Observes a request, and returns the number of previous attempts if it is a likely retry.
+    fn observe(&self, headers: &http::HeaderMap, now: Instant) -> Option<u64> {
+        let key = headers.get(&self.header)?.to_str().ok().filter(|k| k.len() <= MAX_RETRY_KEY_SIZE)?;
+        let mut seen = self.seen.lock().unwrap();
+
+        match seen.keys.get_mut(key) {
+            Some((first_seen, resend_count)) if now.saturating_duration_since(*first_seen) < self.window => {
+                *resend_count += 1;
+                Some(*resend_count)
+            },
+            _ => {
+                seen.keys.insert(key.to_owned(), (now, 0));
+                seen.order.push_back((key.to_owned(), now));
+                seen.prune(now, self.window);
+                None
+            },
+        }
+    }
+}
+
+// Sampling.
+
+/// Resolves the sampler to use, and the sample rate to report to Application Insights.
+/// 
+/// An effective sample rate means that the configured sampler is custom, so it is kept; otherwise, a sample rate below 1.0
+/// replaces the configured sampler with a ratio sampler, so that the reported rate is the real one.
+fn resolve_sampler(config_sampler: Box<dyn ShouldSample>, sample_rate: f64, effective_sample_rate: Option<f64>) -> (Box<dyn ShouldSample>, f64) {
+    match effective_sample_rate {
+        Some(effective_sample_rate) => (config_sampler, effective_sample_rate),
+        None if sample_rate < 1.0 => (Box::new(Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(sample_rate)))), sample_rate),
+        None => (config_sampler, sample_rate),
+    }
+}
+
+/// A sampler that honors the `sampling.priority` attribute of the request span, and otherwise defers to the inner sampler.
+#[derive(Clone, Debug)]
+struct RequestSampler {
+    inner: Box<dyn ShouldSample>,
+}
+
+impl ShouldSample for RequestSampler {
+    fn should_sample(
+        &self,
+        parent_context: Option<&opentelemetry::Context>,
+        trace_id: TraceId,
+        name: &str,
+        span_kind: &SpanKind,
+        attributes: &[KeyValue],
+        links: &[Link],
+    ) -> SamplingResult {
+        let priority = attributes.iter().find(|kv| kv.key.as_str() == "sampling.priority").map(|kv| &kv.value);
+        let parent_trace_state = || parent_context.map(|c| c.span().span_context().trace_state().clone()).unwrap_or_default();
+
+        let result = match priority {
+            Some(opentelemetry::Value::I64(0)) => SamplingResult {
+                decision: opentelemetry::trace::SamplingDecision::Drop,
+                attributes: Vec::new(),
+                trace_state: parent_trace_state(),
+            },
+            Some(opentelemetry::Value::I64(_)) => SamplingResult {
+                decision: opentelemetry::trace::SamplingDecision::RecordAndSample,
+                attributes: Vec::new(),
+                trace_state: parent_trace_state(),
+            },
+            _ => {
+                let result = self.inner.should_sample(parent_context, trace_id, name, span_kind, attributes, links);
+                let is_tentative = attributes
+                    .iter()
+                    .any(|kv| (kv.key.as_str() == "sampling.error_biased" || kv.key.as_str() == TENTATIVE_ATTRIBUTE) && kv.value == opentelemetry::Value::Bool(true));
+
+                // An error-biased request that would be dropped (and every span within it) is only recorded, so that the error-biased
+                // processor can decide once the request finishes.  It stays unsampled, so that its propagated context does not claim a
+                // decision that has not been made yet.
+                if is_tentative && result.decision != opentelemetry::trace::SamplingDecision::RecordAndSample {
+                    SamplingResult {
+                        decision: opentelemetry::trace::SamplingDecision::RecordOnly,
+                        attributes: result.attributes,
+                        trace_state: result.trace_state,
+                    }
+                } else {
+                    result
+                }
+            },
+        };
+
+        // The undecided state is only kept in-process, so an inbound entry of this crate is neither trusted nor propagated.
+        match result.trace_state.delete(TRACE_STATE_KEY) {
+            Ok(trace_state) => SamplingResult { trace_state, ..result },
+            Err(_) => result,
+        }
+    }
+}
+
+/// The trace state key of this crate, which is stripped from the trace state of every span (see [`RequestSampler`]).
+const TRACE_STATE_KEY: &str = "axum-insights";
+
+/// The attribute that marks the spans within a tentatively recorded request (see [`AppInsights::with_error_biased_sampling`]).  It is
+/// stamped by the [`TelemetryContextLayer`], and removed by the [`ErrorBiasedProcessor`].
+const TENTATIVE_ATTRIBUTE: &str = "sampling.tentative";
+
+/// The maximum number of tentative traces that are buffered at once (and of decisions that are remembered for late children); beyond
+/// this, the oldest are evicted.
+const MAX_PENDING_TRACES: usize = 4096;
+
+/// The maximum number of spans that are buffered per tentative trace (further spans are dropped).
+const MAX_PENDING_SPANS: usize = 1024;
+
+/// A span processor that buffers the spans of tentatively sampled traces, and only forwards them to the inner processor if the request
+/// span is retained.
+#[derive(Debug)]
+struct ErrorBiasedProcessor<S> {
+    inner: S,
+    pending: Mutex<PendingTraces>,
+}
+
+/// The buffered spans of the undecided tentative traces, and the decisions of the recently finished ones (for late children).
+#[derive(Debug, Default)]
+struct PendingTraces {
+    spans: HashMap<TraceId, PendingTrace>,
+    decisions: HashMap<TraceId, bool>,
+    decision_order: VecDeque<TraceId>,
+    sequence: u64,
+}
+
+/// The buffered spans of an undecided tentative trace, and when the trace was first buffered (relative to the others).
+#[derive(Debug)]
+struct PendingTrace {
+    sequence: u64,
+    spans: Vec<SpanData>,
+}
+
+impl PendingTraces {
+    /// Remembers the decision for a trace, and forgets the oldest decisions beyond the limit.
+    fn decide(&mut self, trace_id: TraceId, is_retained: bool) {
+        if self.decisions.insert(trace_id, is_retained).is_none() {
+            self.decision_order.push_back(trace_id);
+        }
+
+        while self.decision_order.len() > MAX_PENDING_TRACES {
+            if let Some(oldest) = self.decision_order.pop_front() {
+                self.decisions.remove(&oldest);
+            }
+        }
+    }
+
+    /// Buffers a span of an undecided trace.  If the buffer is full, then the oldest trace is dropped to make room, since it is the most
+    /// likely to never be decided (e.g., the late children of a trace whose decision was already forgotten).
+    fn buffer(&mut self, span: SpanData) {
+        let trace_id = span.span_context.trace_id();
+
+        if !self.spans.contains_key(&trace_id) && self.spans.len() >= MAX_PENDING_TRACES {
+            let oldest = self.spans.iter().min_by_key(|(_, trace)| trace.sequence).map(|(trace_id, _)| *trace_id);
+
+            if let Some(oldest) = oldest {
+                self.spans.remove(&oldest);
+            }
+        }
+
+        let sequence = self.sequence;
+        let trace = self.spans.entry(trace_id).or_insert_with(|| PendingTrace { sequence, spans: Vec::new() });
+        self.sequence += 1;
+
+        if trace.spans.len() < MAX_PENDING_SPANS {
+            trace.spans.push(span);
+        }
+    }
+}
+
+impl<S> ErrorBiasedProcessor<S> {
+    fn new(inner: S) -> Self {
+        Self {
+            inner,
+            pending: Mutex::new(PendingTraces::default()),
+        }
+    }
+}
+
+impl<S: SpanProcessor> SpanProcessor for ErrorBiasedProcessor<S> {
+    fn on_start(&self, span: &mut opentelemetry_sdk::trace::Span, cx: &opentelemetry::Context) {
+        self.inner.on_start(span, cx);
+    }
+
+    fn on_end(&self, mut span: SpanData) {
+        let is_request = span.attributes.iter().any(|kv| kv.key.as_str() == "sampling.error_biased");
+        let is_marked = span.attributes.iter().any(|kv| kv.key.as_str() == TENTATIVE_ATTRIBUTE);
+
+        // A span is undecided if it is (within) an error-biased request, and the sampler only recorded it.
+        if !(is_request || is_marked) || span.span_context.is_sampled() {
+            span.attributes.retain(|kv| kv.key.as_str() != TENTATIVE_ATTRIBUTE);
+
+            return self.inner.on_end(span);
+        }
+
+        let trace_id = span.span_context.trace_id();
+        let mut pending = self.pending.lock().unwrap();
+
+        let retained = if is_request {
+            // The request span decides the whole trace.
+            let is_retained = span.attributes.iter().any(|kv| kv.key.as_str() == "sampling.retained" && kv.value == opentelemetry::Value::Bool(true));
+            let mut spans = pending.spans.remove(&trace_id).map(|trace| trace.spans).unwrap_or_default();
+
+            pending.decide(trace_id, is_retained);
+
+            spans.push(span);
+            is_retained.then_some(spans)
+        } else {
+            match pending.decisions.get(&trace_id) {
+                Some(true) => Some(vec![span]),
+                Some(false) => None,
+                None => {
+                    pending.buffer(span);
+
+                    None
+                },
+            }
+        };
+
+        drop(pending);
+
+        for span in retained.into_iter().flatten() {
+            self.inner.on_end(retain_span(span));
+        }
+    }
+
+    fn force_flush(&self) -> opentelemetry::trace::TraceResult<()> {
+        self.inner.force_flush()
+    }
+
+    fn shutdown(&self) -> opentelemetry::trace::TraceResult<()> {
+        self.inner.shutdown()
+    }
+
+    fn set_resource(&mut self, resource: &Resource) {
+        self.inner.set_resource(resource);
+    }
+}
+
+/// Marks a span of a retained tentative trace as sampled (so that the exporting processor does not drop it), and as retained (so that
+/// it is exported with an item count of one), and removes the in-process marker.
+fn retain_span(mut span: SpanData) -> SpanData {
+    let context = &span.span_context;
+
+    span.span_context = SpanContext::new(context.trace_id(), context.span_id(), context.trace_flags().with_sampled(true), context.is_remote(), context.trace_state().clone());
+    span.attributes.retain(|kv| kv.key.as_str() != TENTATIVE_ATTRIBUTE);
+    stamp_attributes(&mut span.attributes, &[KeyValue::new("sampling.retained", true)]);
+
+    span
+}
+
+/// Whether a span was exported regardless of the sample rate (it was forced, or retained by error-biased sampling), so that it stands
+/// for itself rather than for `1 / sample rate` items.
+fn is_certain_item(span: &SpanData) -> bool {
+    span.attributes.iter().any(|kv| match kv.key.as_str() {
+        "sampling.priority" => kv.value == opentelemetry::Value::I64(1),
+        "sampling.retained" => kv.value == opentelemetry::Value::Bool(true),
+        _ => false,
+    })
+}
+
+/// An exporter that exports the certain items (see [`is_certain_item`]) with an item count of one, and the others with the configured
+/// sample rate.
+#[derive(Debug)]
+struct ItemCountExporter<E> {
+    sampled: E,
+    certain: E,
+}
+
+impl<E: SpanExporter> SpanExporter for ItemCountExporter<E> {
+    fn export(&mut self, batch: Vec<SpanData>) -> BoxFuture<'static, opentelemetry_sdk::export::trace::ExportResult> {
+        let (certain, sampled): (Vec<_>, Vec<_>) = batch.into_iter().partition(is_certain_item);
+        let certain = (!certain.is_empty()).then(|| self.certain.export(certain));
+        let sampled = (!sampled.is_empty()).then(|| self.sampled.export(sampled));
+
+        Box::pin(async move {
+            let certain = match certain {
+                Some(export) => export.await,
+                None => Ok(()),
+            };
+            let sampled = match sampled {
+                Some(export) => export.await,
+                None => Ok(()),
+            };
+
+            certain.and(sampled)
+        })
+    }
+
+    fn shutdown(&mut self) {
+        self.sampled.shutdown();
+        self.certain.shutdown();
+    }
+
+    fn set_resource(&mut self, resource: &Resource) {
+        self.sampled.set_resource(resource);
+        self.certain.set_resource(resource);
+    }
+}
+
+/// A span processor that feeds live metrics when the pipeline is built by hand (see [`AppInsights::with_error_biased_sampling`]): the
+/// spans are replayed into a separate live metrics pipeline, which only records them, so it counts them without exporting them again.
+#[derive(Debug)]
+struct LiveMetricsProcessor {
+    tracer_provider: opentelemetry_sdk::trace::TracerProvider,
+    tracer: opentelemetry_sdk::trace::Tracer,
+}
+
+impl LiveMetricsProcessor {
+    fn new<C: HttpClient + 'static, R: RuntimeChannel>(connection_string: &str, client: C, resource: Resource, runtime: R) -> Result<Self, Box<dyn Error + Send + Sync + 'static>> {
+        let tracer_provider = opentelemetry_application_insights::new_pipeline_from_connection_string(connection_string)?
+            .with_client(client)
+            .with_live_metrics(true)
+            .with_trace_config(Config::default().with_resource(resource).with_sampler(RecordOnlySampler))
+            .build_batch(runtime);
+        let tracer = opentelemetry::trace::TracerProvider::tracer(&tracer_provider, "axum-insights");
+
+        Ok(Self { tracer_provider, tracer })
+    }
+}
+
+impl SpanProcessor for LiveMetricsProcessor {
+    fn on_start(&self, _span: &mut opentelemetry_sdk::trace::Span, _cx: &opentelemetry::Context) {}
+
+    fn on_end(&self, span: SpanData) {
+        let builder = opentelemetry::trace::SpanBuilder {
+            trace_id: Some(span.span_context.trace_id()),
+            span_id: Some(span.span_context.span_id()),
+            span_kind: Some(span.span_kind),
+            name: span.name,
+            start_time: Some(span.start_time),
+            end_time: Some(span.end_time),
+            attributes: Some(span.attributes),
+            events: Some(span.events.events),
+            links: Some(span.links.links),
+            status: span.status,
+            sampling_result: None,
+        };
+
+        opentelemetry::trace::Span::end_with_timestamp(&mut opentelemetry::trace::Tracer::build(&self.tracer, builder), span.end_time);
+    }
+
+    fn force_flush(&self) -> opentelemetry::trace::TraceResult<()> {
+        Ok(())
+    }
+
+    fn shutdown(&self) -> opentelemetry::trace::TraceResult<()> {
+        self.tracer_provider.shutdown()
+    }
+}
+
+/// A sampler that records every span without sampling it (see [`LiveMetricsProcessor`]).
+#[derive(Clone, Debug)]
+struct RecordOnlySampler;
+
+impl ShouldSample for RecordOnlySampler {
+    fn should_sample(&self, _parent_context: Option<&opentelemetry::Context>, _trace_id: TraceId, _name: &str, _span_kind: &SpanKind, _attributes: &[KeyValue], _links: &[Link]) -> SamplingResult {
+        SamplingResult {
+            decision: opentelemetry::trace::SamplingDecision::RecordOnly,
+            attributes: Vec::new(),
+            trace_state: Default::default(),
+        }
+    }
+}
+
+// Debug traces.
+
+/// The maximum number of bytes of a body that a debug trace captures.
+const MAX_DEBUG_TRACE_BODY_SIZE: usize = 64 * 1024;
+
+/// The header (and optional secret) that enables a debug trace for a request.
+#[derive(Debug)]
+struct DebugTraceHeader {
+    name: http::HeaderName,
+    secret: Option<String>,
+}
+
+impl DebugTraceHeader {
+    fn matches(&self, headers: &http::HeaderMap) -> bool {
+        match (headers.get(&self.name), self.secret.as_ref()) {
+            (Some(value), Some(secret)) => constant_time_eq(value.as_bytes(), secret.as_bytes()),
+            (Some(_), None) => true,
+            (None, _) => false,
+        }
+    }
+}
+
+/// Compares two byte strings in time that only depends on their lengths, so that a secret cannot be guessed byte by byte.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// A span extension that marks a span (and everything within it) as debug traced.
+struct DebugTraced;
+
+/// A visitor that finds the `debug_trace` field of a span.
+#[derive(Default)]
+struct DebugTraceVisitor(bool);
+
+impl tracing::field::Visit for DebugTraceVisitor {
+    fn record_bool(&mut self, field: &tracing::field::Field, value: bool) {
+        if field.name() == "debug_trace" {
+            self.0 = value;
+        }
+    }
+
+    fn record_debug(&mut self, _field: &tracing::field::Field, _value: &dyn std::fmt::Debug) {}
+}
+
+/// A level filter that also allows DEBUG events and spans within debug traced spans.
+struct DebugTraceFilter {
+    minimum_level: Arc<RwLock<LevelFilter>>,
+    allow_debug_traces: bool,
+}
+
+impl DebugTraceFilter {
+    fn minimum_level(&self) -> LevelFilter {
+        *self.minimum_level.read().unwrap()
+    }
+
+    fn is_debug_trace_level(&self, level: &Level) -> bool {
+        self.allow_debug_traces && LevelFilter::DEBUG >= *level
+    }
+
+    fn interest(&self, metadata: &'static tracing::Metadata<'static>) -> tracing::subscriber::Interest {
+        if self.minimum_level() >= *metadata.level() {
+            tracing::subscriber::Interest::always()
+        } else if self.is_debug_trace_level(metadata.level()) {
+            tracing::subscriber::Interest::sometimes()
+        } else {
+            tracing::subscriber::Interest::never()
+        }
+    }
+
+    fn is_enabled<S>(&self, metadata: &tracing::Metadata<'_>, ctx: &tracing_subscriber::layer::Context<'_, S>) -> bool
+    where
+        S: tracing::Subscriber + for<'span> tracing_subscriber::registry::LookupSpan<'span>,
+    {
+        if self.minimum_level() >= *metadata.level() {
+            return true;
+        }
+
+        self.is_debug_trace_level(metadata.level())
+            && ctx.lookup_current().is_some_and(|span| span.scope().any(|s| s.extensions().get::<DebugTraced>().is_some()))
+    }
+
+    fn mark_debug_traced<S>(&self, attrs: &tracing::span::Attributes<'_>, id: &tracing::span::Id, ctx: &tracing_subscriber::layer::Context<'_, S>)
+    where
+        S: tracing::Subscriber + for<'span> tracing_subscriber::registry::LookupSpan<'span>,
+    {
+        if !self.allow_debug_traces || attrs.metadata().fields().field("debug_trace").is_none() {
+            return;
+        }
+
+        let mut visitor = DebugTraceVisitor::default();
+        attrs.record(&mut visitor);
+
+        if visitor.0 {
+            if let Some(span) = ctx.span(id) {
+                span.extensions_mut().insert(DebugTraced);
+            }
+        }
+    }
+
+    fn level_hint(&self) -> Option<LevelFilter> {
+        // DEBUG has to be let through the global filter for debug traces, so that `is_enabled` gets to decide per span scope.
+        if self.allow_debug_traces {
+            Some(std::cmp::max(self.minimum_level(), LevelFilter::DEBUG))
+        } else {
+            Some(self.minimum_level())
+        }
+    }
+}
+
+impl<S> tracing_subscriber::Layer<S> for DebugTraceFilter
+where
+    S: tracing::Subscriber + for<'span> tracing_subscriber::registry::LookupSpan<'span>,
+{
+    fn register_callsite(&self, metadata: &'static tracing::Metadata<'static>) -> tracing::subscriber::Interest {
+        self.interest(metadata)
+    }
+
+    fn enabled(&self, metadata: &tracing::Metadata<'_>, ctx: tracing_subscriber::layer::Context<'_, S>) -> bool {
+        self.is_enabled(metadata, &ctx)
+    }
+
+    fn on_new_span(&self, attrs: &tracing::span::Attributes<'_>, id: &tracing::span::Id, ctx: tracing_subscriber::layer::Context<'_, S>) {
+        self.mark_debug_traced(attrs, id, &ctx);
+    }
+
+    fn max_level_hint(&self) -> Option<LevelFilter> {
+        self.level_hint()
+    }
+}
+
+/// The same filter can also be applied to a single layer (see [`AppInsights::build_layer`]), so that it does not filter the rest of
+/// the subscriber.
+impl<S> tracing_subscriber::layer::Filter<S> for DebugTraceFilter
+where
+    S: tracing::Subscriber + for<'span> tracing_subscriber::registry::LookupSpan<'span>,
+{
+    fn callsite_enabled(&self, metadata: &'static tracing::Metadata<'static>) -> tracing::subscriber::Interest {
+        self.interest(metadata)
+    }
+
+    fn enabled(&self, metadata: &tracing::Metadata<'_>, ctx: &tracing_subscriber::layer::Context<'_, S>) -> bool {
+        self.is_enabled(metadata, ctx)
+    }
+
+    fn on_new_span(&self, attrs: &tracing::span::Attributes<'_>, id: &tracing::span::Id, ctx: tracing_subscriber::layer::Context<'_, S>) {
+        self.mark_debug_traced(attrs, id, &ctx);
+    }
+
+    fn max_level_hint(&self) -> Option<LevelFilter> {
+        self.level_hint()
+    }
+}
+
+/// A layer that stamps the context of the pipeline onto the OpenTelemetry data: the global dimensions onto the events (and, with an
+/// existing tracer provider, the resource attributes onto the spans), the cloud role
+/// override of a request span (see [`AppInsightsLayer::with_cloud_role`]) onto the spans within it, the sampling markers of a
+/// request (see [`AppInsights::with_request_sampler`] and [`AppInsights::with_error_biased_sampling`]) onto the spans within it,
+/// and the start and end times of the configured clock (see [`AppInsights::with_clock`]) onto every span.
+/// 
+/// This layer has to come after the OpenTelemetry layer, so that the data of the span (and the event) already exists.
+#[derive(Clone, Default)]
+struct TelemetryContextLayer {
+    global_dimensions: Arc<Vec<KeyValue>>,
+    span_attributes: Arc<Vec<KeyValue>>,
+    clock: Option<Arc<dyn Clock>>,
+}
+
+impl<S> tracing_subscriber::Layer<S> for TelemetryContextLayer
+where
+    S: tracing::Subscriber + for<'span> tracing_subscriber::registry::LookupSpan<'span>,
+{
+    fn on_new_span(&self, attrs: &tracing::span::Attributes<'_>, id: &tracing::span::Id, ctx: tracing_subscriber::layer::Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+
+        let mut visitor = SpanContextVisitor::default();
+        attrs.record(&mut visitor);
+
+        let (parent_cloud_role, is_parent_error_biased, is_parent_forced) = match span.parent() {
+            Some(parent) => {
+                let extensions = parent.extensions();
+                (extensions.get::<CloudRole>().map(|r| r.0.clone()), extensions.get::<ErrorBiased>().is_some(), extensions.get::<Forced>().is_some())
+            },
+            None => (None, false, false),
+        };
+
+        // The resource attributes are stamped onto every span when the resource cannot be changed (with an existing tracer provider).
+        let mut stamps = self.span_attributes.to_vec();
+
+        // The request span records its own role, and the spans within it inherit the role (as an attribute, which the exporter
+        // turns into the cloud role tag).
+        let cloud_role = match visitor.cloud_role {
+            Some(cloud_role) => Some(cloud_role),
+            None => {
+                if let Some(cloud_role) = parent_cloud_role.as_ref() {
+                    stamps.push(KeyValue::new("ai.cloud.role", cloud_role.to_string()));
+                }
+
+                parent_cloud_role
+            },
+        };
+
+        // The spans within an error-biased request are marked, so that the sampler only records them (like the request) until the
+        // request is decided.
+        if is_parent_error_biased {
+            stamps.push(KeyValue::new(TENTATIVE_ATTRIBUTE, true));
+        }
+
+        // The spans within a forced request are marked as forced as well, so that they are exported with an item count of one (like
+        // the request).
+        if is_parent_forced {
+            stamps.push(KeyValue::new("sampling.priority", 1));
+        }
+
+        let mut extensions = span.extensions_mut();
+
+        if let Some(data) = extensions.get_mut::<tracing_opentelemetry::OtelData>() {
+            if let Some(clock) = self.clock.as_ref() {
+                data.builder.start_time = Some(clock.system_time());
+            }
+
+            if !stamps.is_empty() {
+                stamp_attributes(data.builder.attributes.get_or_insert_with(Vec::new), &stamps);
+            }
+        }
+
+        if let Some(cloud_role) = cloud_role {
+            extensions.insert(CloudRole(cloud_role));
+        }
+
+        if visitor.is_error_biased || is_parent_error_biased {
+            extensions.insert(ErrorBiased);
+        }
+
+        if visitor.is_forced || is_parent_forced {
+            extensions.insert(Forced);
+        }
+    }
+
+    fn on_exit(&self, id: &tracing::span::Id, ctx: tracing_subscriber::layer::Context<'_, S>) {
+        let Some(clock) = self.clock.as_ref() else {
+            return;
+        };
+
+        // The OpenTelemetry layer sets the end time on every exit (the last one is the end time of the span), so it is
+        // overwritten with the time of the clock.
+        if let Some(span) = ctx.span(id) {
+            if let Some(data) = span.extensions_mut().get_mut::<tracing_opentelemetry::OtelData>() {
+                data.builder.end_time = Some(clock.system_time());
+            }
+        }
+    }
+
+    fn on_event(&self, event: &tracing::Event<'_>, ctx: tracing_subscriber::layer::Context<'_, S>) {
+        let has_properties = event.metadata().fields().field(CUSTOM_EVENT_PROPERTIES_FIELD).is_some();
+
+        if self.global_dimensions.is_empty() && !has_properties {
+            return;
+        }
+
+        let Some(span) = ctx.event_span(event) else {
+            return;
+        };
+
+        // The OpenTelemetry layer has just appended the event to the span (unless it was filtered out, in which case the previous
+        // event is already stamped).
+        let mut extensions = span.extensions_mut();
+        let last_event = extensions.get_mut::<tracing_opentelemetry::OtelData>().and_then(|data| data.builder.events.as_mut()?.last_mut());
+
+        if let Some(last_event) = last_event {
+            if has_properties {
+                expand_custom_event_properties(&mut last_event.attributes);
+            }
+
+            stamp_attributes(&mut last_event.attributes, &self.global_dimensions);
+        }
+    }
+}
+
+/// The field of a custom event that carries its (serialized) properties (see [`events::track_event`]).
+const CUSTOM_EVENT_PROPERTIES_FIELD: &str = "custom_event.properties";
+
+/// Replaces the serialized properties of a custom event with an attribute per property (which the exporter turns into the
+/// properties of the event).
+fn expand_custom_event_properties(attributes: &mut Vec<KeyValue>) {
+    let Some(index) = attributes.iter().position(|kv| kv.key.as_str() == CUSTOM_EVENT_PROPERTIES_FIELD) else {
+        return;
+    };
+
+    let serialized = attributes.remove(index).value;
+    let properties = serde_json::from_str::<std::collections::BTreeMap<String, String>>(&serialized.as_str()).unwrap_or_default();
+    let properties = properties.into_iter().map(|(k, v)| KeyValue::new(k, v)).collect::<Vec<_>>();
+
+    stamp_attributes(attributes, &properties);
+}
+
+/// The cloud role of a span, which the spans within it inherit.
+struct CloudRole(Arc<str>);
+
+/// A span extension that marks an error-biased request span (and the spans within it).
+struct ErrorBiased;
+
+/// A span extension that marks a force-sampled span, i.e., `sampling.priority = 1` (and the spans within it).
+struct Forced;
+
+/// A visitor that finds the cloud role override, and the error-biased and forced markers of a span.
+#[derive(Default)]
+struct SpanContextVisitor {
+    cloud_role: Option<Arc<str>>,
+    is_error_biased: bool,
+    is_forced: bool,
+}
+
+impl tracing::field::Visit for SpanContextVisitor {
+    fn record_str(&mut self, field: &tracing::field::Field, value: &str) {
+        if field.name() == "ai.cloud.role" {
+            self.cloud_role = Some(value.into());
+        }
+    }
+
+    fn record_bool(&mut self, field: &tracing::field::Field, value: bool) {
+        if field.name() == "sampling.error_biased" {
+            self.is_error_biased = value;
+        }
+    }
+
+    fn record_i64(&mut self, field: &tracing::field::Field, value: i64) {
+        if field.name() == "sampling.priority" {
+            self.is_forced = value == 1;
+        }
+    }
+
+    fn record_debug(&mut self, _field: &tracing::field::Field, _value: &dyn std::fmt::Debug) {}
+}
+
+/// Adds the given attributes, unless attributes with the same keys are already present.
+fn stamp_attributes(attributes: &mut Vec<KeyValue>, stamps: &[KeyValue]) {
+    for stamp in stamps {
+        if !attributes.iter().any(|kv| kv.key == stamp.key) {
+            attributes.push(stamp.clone());
+        }
+    }
+}
+
+/// A body that captures (the beginning of) its bytes, and records them on the span once the body is dropped.
+struct CaptureBody {
+    inner: Body,
+    captured: Vec<u8>,
+    span: Span,
+    field: &'static str,
+}
+
+impl CaptureBody {
+    fn new(inner: Body, span: Span, field: &'static str) -> Self {
+        Self {
+            inner,
+            captured: Vec::new(),
+            span,
+            field,
+        }
+    }
+}
+
+impl http_body::Body for CaptureBody {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let this = &mut *self;
+        let frame = Pin::new(&mut this.inner).poll_frame(cx);
+
+        if let Poll::Ready(Some(Ok(frame))) = &frame {
+            if let Some(data) = frame.data_ref() {
+                let remaining = MAX_DEBUG_TRACE_BODY_SIZE.saturating_sub(this.captured.len());
+                this.captured.extend_from_slice(&data[..data.len().min(remaining)]);
+            }
+        }
+
+        frame
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+impl Drop for CaptureBody {
+    fn drop(&mut self) {
+        if !self.captured.is_empty() {
+            self.span.record(self.field, String::from_utf8_lossy(&self.captured).as_ref());
+        }
+    }
+}
+
+/// A body that counts its bytes, and records the count on the span once the body is dropped.
+struct SizeBody {
+    inner: Body,
+    bytes: u64,
+    span: Span,
+    field: &'static str,
+}
+
+impl SizeBody {
+    fn new(inner: Body, span: Span, field: &'static str) -> Self {
+        Self {
+            inner,
+            bytes: 0,
+            span,
+            field,
+        }
+    }
+}
+
+impl http_body::Body for SizeBody {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let frame = Pin::new(&mut self.inner).poll_frame(cx);
+
+        if let Poll::Ready(Some(Ok(frame))) = &frame {
+            if let Some(data) = frame.data_ref() {
+                self.bytes += data.len() as u64;
+            }
+        }
+
+        frame
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+impl Drop for SizeBody {
+    fn drop(&mut self) {
+        self.span.record(self.field, self.bytes);
+    }
+}
+
+// Multipart.
+
+/// The largest part header block that is inspected for a content type.
+const MAX_MULTIPART_HEADER_SIZE: usize = 8 * 1024;
+
+/// Gets the boundary of a multipart request, if the request is multipart.
+fn multipart_boundary(headers: &http::HeaderMap) -> Option<String> {
+    let content_type = headers.get(http::header::CONTENT_TYPE)?.to_str().ok()?;
+
+    if !content_type.trim_start().to_ascii_lowercase().starts_with("multipart/") {
+        return None;
+    }
+
+    content_type.split(';').skip(1).find_map(|param| {
+        let (key, value) = param.trim().split_once('=')?;
+
+        key.eq_ignore_ascii_case("boundary").then(|| value.trim_matches('"').to_owned())
+    })
+}
+
+/// The summary of a multipart upload, which is computed incrementally as the body streams through.
+struct MultipartStats {
+    delimiter: Vec<u8>,
+    pending: Vec<u8>,
+    is_done: bool,
+    bytes: u64,
+    parts: u64,
+    content_types: Vec<String>,
+}
+
+impl MultipartStats {
+    fn new(boundary: String) -> Self {
+        Self {
+            delimiter: format!("--{}", boundary).into_bytes(),
+            pending: Vec::new(),
+            is_done: false,
+            bytes: 0,
+            parts: 0,
+            content_types: Vec::new(),
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        self.bytes += data.len() as u64;
+
+        if self.is_done {
+            return;
+        }
+
+        self.pending.extend_from_slice(data);
+
+        loop {
+            let Some(start) = find_subslice(&self.pending, &self.delimiter) else {
+                // Only keep enough bytes to detect a delimiter that straddles chunks.
+                let keep = self.delimiter.len().saturating_sub(1).min(self.pending.len());
+                self.pending.drain(..self.pending.len() - keep);
+                return;
+            };
+
+            let after = start + self.delimiter.len();
+
+            // Wait for enough data to tell whether this is the closing delimiter.
+            if self.pending.len() < after + 2 {
+                self.pending.drain(..start);
+                return;
+            }
+
+            if &self.pending[after..after + 2] == b"--" {
+                self.is_done = true;
+                self.pending = Vec::new();
+                return;
+            }
+
+            // Wait for the end of the part headers, unless they are unreasonably large.
+            let Some(header_end) = find_subslice(&self.pending[after..], b"\r\n\r\n") else {
+                if self.pending.len() - after > MAX_MULTIPART_HEADER_SIZE {
+                    self.parts += 1;
+                    self.pending.drain(..after);
+                    continue;
+                }
+
+                self.pending.drain(..start);
+                return;
+            };
+
+            self.parts += 1;
+
+            let headers = String::from_utf8_lossy(&self.pending[after..after + header_end]);
+            let content_type = headers.lines().find_map(|line| {
+                let (key, value) = line.split_once(':')?;
+
+                key.trim().eq_ignore_ascii_case("content-type").then(|| value.trim().to_owned())
+            });
+
+            if let Some(content_type) = content_type {
+                if !self.content_types.contains(&content_type) {
+                    self.content_types.push(content_type);
+                }
+            }
+
+            self.pending.drain(..after + header_end + 4);
+        }
+    }
+}
+
+fn find_subslice(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+/// A request body that summarizes a multipart upload as it is read.
+struct MultipartBody {
+    inner: Body,
+    stats: Arc<Mutex<MultipartStats>>,
+}
+
+impl http_body::Body for MultipartBody {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let frame = Pin::new(&mut self.inner).poll_frame(cx);
+
+        if let Poll::Ready(Some(Ok(frame))) = &frame {
+            if let Some(data) = frame.data_ref() {
+                self.stats.lock().unwrap().update(data);
+            }
+        }
+
+        frame
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+// Heartbeats.
+
+/// The running totals of a long-lived connection, which are periodically emitted as heartbeat events.
+struct HeartbeatState {
+    parent: opentelemetry::Context,
+    clock: Arc<dyn Clock>,
+    started: Instant,
+    bytes: u64,
+    messages: u64,
+}
+
+impl HeartbeatState {
+    fn new(clock: Arc<dyn Clock>) -> Self {
+        Self {
+            parent: Span::current().context(),
+            started: clock.now(),
+            clock,
+            bytes: 0,
+            messages: 0,
+        }
+    }
+
+    fn record(&mut self, bytes: usize) {
+        self.bytes += bytes as u64;
+        self.messages += 1;
+    }
+
+    fn emit(&self) {
+        // The request span may already be finished, so the heartbeat gets its own short-lived span in the same operation,
+        // which causes it to be exported immediately.
+        let span = tracing::info_span!(parent: None, "heartbeat", otel.kind = "internal");
+        span.set_parent(self.parent.clone());
+
+        let _guard = span.enter();
+
+        tracing::event!(
+            Level::INFO,
+            ai.customEvent.name = "connection.heartbeat",
+            connection.bytes = self.bytes,
+            connection.messages = self.messages,
+            connection.duration_ms = self.clock.now().saturating_duration_since(self.started).as_millis() as u64,
+            "ai.custom"
+        );
+    }
+}
+
+/// A response body that emits heartbeat events while it is streaming.
+struct HeartbeatBody {
+    inner: Body,
+    state: HeartbeatState,
+    interval: Duration,
+    last: Instant,
+    sleep: Pin<Box<tokio::time::Sleep>>,
+}
+
+impl HeartbeatBody {
+    fn new(inner: Body, interval: Duration, clock: Arc<dyn Clock>) -> Self {
+        Self {
+            inner,
+            last: clock.now(),
+            state: HeartbeatState::new(clock),
+            interval,
+            sleep: Box::pin(tokio::time::sleep(interval)),
+        }
+    }
+}
+
+impl http_body::Body for HeartbeatBody {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let this = &mut *self;
+
+        // Emit a heartbeat if one is due by the clock.
+        let now = this.state.clock.now();
+
+        if now.saturating_duration_since(this.last) >= this.interval {
+            this.state.emit();
+            this.last = now;
+        }
+
+        // The timer only wakes the stream up when the next heartbeat is due, so that idle streams still get heartbeats.
+        while this.sleep.as_mut().poll(cx).is_ready() {
+            let remaining = this.interval.saturating_sub(this.state.clock.now().saturating_duration_since(this.last));
+            this.sleep.as_mut().reset(tokio::time::Instant::now() + remaining.max(Duration::from_millis(1)));
+        }
+
+        let frame = Pin::new(&mut this.inner).poll_frame(cx);
+
+        if let Poll::Ready(Some(Ok(frame))) = &frame {
+            if let Some(data) = frame.data_ref() {
+                this.state.record(data.len());
+            }
+        }
+
+        frame
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+/// A heartbeat for long-lived connections that the middleware cannot see, such as websockets.
+/// 
+/// Create this in the handler (so that it picks up the request's trace context), move it into the connection task,
+/// and record each message.  Every time a message is recorded after the interval has elapsed, a `connection.heartbeat`
+/// custom event is emitted with the bytes and messages transferred so far.  The interval is measured with the clock of the
+/// pipeline (see [`AppInsights::with_clock`]).
+/// 
+/// ```
+/// use axum_insights::ConnectionHeartbeat;
+/// use std::time::Duration;
+/// 
+/// let mut heartbeat = ConnectionHeartbeat::new(Duration::from_secs(60));
+/// 
+/// // In the websocket loop.
+/// heartbeat.record_message(128);
+/// ```
+pub struct ConnectionHeartbeat {
+    state: HeartbeatState,
+    interval: Duration,
+    last: Instant,
+}
+
+impl ConnectionHeartbeat {
+    /// Creates a new heartbeat in the context of the current span.
+    pub fn new(interval: Duration) -> Self {
+        // The clock of the pipeline is found on the context layer of the current subscriber (if there is one).
+        let clock = tracing::dispatcher::get_default(|dispatch| dispatch.downcast_ref::<TelemetryContextLayer>().and_then(|l| l.clock.clone()));
+        let clock = clock.unwrap_or_else(|| Arc::new(SystemClock));
+
+        Self {
+            last: clock.now(),
+            state: HeartbeatState::new(clock),
+            interval,
+        }
+    }
+
+    /// Records a message of the given size, and emits a heartbeat event if one is due.
+    pub fn record_message(&mut self, bytes: usize) {
+        self.state.record(bytes);
+
+        let now = self.state.clock.now();
+
+        if now.saturating_duration_since(self.last) >= self.interval {
+            self.state.emit();
+            self.last = now;
+        }
+    }
+}
+
+// Trace context tokens.
+
+/// A compact token that carries a trace context, so that deferred work (such as an asynchronous callback to the requester)
+/// can join the original trace.
+/// 
+/// The token is serialized in the W3C `traceparent` format (e.g., `00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01`).
+/// 
+/// ```
+/// use axum_insights::TraceContextToken;
+/// 
+/// // In the request handler, capture the token, and hand it to the deferred work.
+/// let token = TraceContextToken::current().map(|t| t.to_string());
+/// 
+/// // Later, join the original trace from the deferred work.
+/// let span = tracing::info_span!("callback");
+/// 
+/// if let Some(token) = token.and_then(|t| t.parse::<TraceContextToken>().ok()) {
+///     token.attach(&span);
+/// }
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TraceContextToken {
+    trace_id: TraceId,
+    span_id: SpanId,
+    trace_flags: TraceFlags,
+}
+
+impl TraceContextToken {
+    /// Captures the trace context of the current span, if it has one.
+    pub fn current() -> Option<Self> {
+        let context = Span::current().context();
+        let span = context.span();
+        let span_context = span.span_context();
+
+        if !span_context.is_valid() {
+            return None;
+        }
+
+        Some(Self {
+            trace_id: span_context.trace_id(),
+            span_id: span_context.span_id(),
+            trace_flags: span_context.trace_flags(),
+        })
+    }
+
+    /// Sets the trace context of this token as the parent of the given span.
+    /// 
+    /// This must be called before the span is entered for the first time, since the parent cannot change after that.
+    pub fn attach(&self, span: &Span) {
+        let span_context = SpanContext::new(self.trace_id, self.span_id, self.trace_flags, true, Default::default());
+
+        span.set_parent(opentelemetry::Context::new().with_remote_span_context(span_context));
+    }
+}
+
+impl std::fmt::Display for TraceContextToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "00-{}-{}-{:02x}", self.trace_id, self.span_id, self.trace_flags.to_u8())
+    }
+}
+
+impl std::str::FromStr for TraceContextToken {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts = s.trim().split('-').collect::<Vec<_>>();
+
+        let [version, trace_id, span_id, trace_flags] = parts[..] else {
+            return Err(format!("Invalid trace context token: {}.", s));
+        };
+
+        let trace_id = (version == "00" && trace_id.len() == 32).then(|| TraceId::from_hex(trace_id).ok()).flatten();
+        let span_id = (span_id.len() == 16).then(|| SpanId::from_hex(span_id).ok()).flatten();
+        let trace_flags = (trace_flags.len() == 2).then(|| u8::from_str_radix(trace_flags, 16).ok()).flatten();
+
+        match (trace_id, span_id, trace_flags) {
+            (Some(trace_id), Some(span_id), Some(trace_flags)) if trace_id != TraceId::INVALID && span_id != SpanId::INVALID => Ok(Self {
+                trace_id,
+                span_id,
+                trace_flags: TraceFlags::new(trace_flags),
+            }),
+            _ => Err(format!("Invalid trace context token: {}.", s)),
+        }
+    }
+}
+
+/// Extracts the remote parent of a request from the W3C `traceparent` and `tracestate` headers, or from the legacy
+/// `Request-Id` header.
+fn extract_remote_context(headers: &http::HeaderMap) -> Option<SpanContext> {
+    let traceparent = headers.get("traceparent").and_then(|v| v.to_str().ok()).and_then(|v| v.parse::<TraceContextToken>().ok());
+
+    if let Some(token) = traceparent {
+        let trace_state = headers.get("tracestate").and_then(|v| v.to_str().ok()).and_then(|v| v.parse().ok()).unwrap_or_default();
+
+        return Some(SpanContext::new(token.trace_id, token.span_id, token.trace_flags, true, trace_state));
+    }
+
+    // The legacy header is hierarchical (e.g., `|<root id>.<span id>.`), and it can only be continued if the ids are W3C compatible.
+    let request_id = headers.get("request-id").and_then(|v| v.to_str().ok())?;
+    let mut segments = request_id.trim_start_matches('|').split('.');
+    let trace_id = segments.next().filter(|s| s.len() == 32).and_then(|s| TraceId::from_hex(s).ok())?;
+    let span_id = segments.next().filter(|s| s.len() == 16).and_then(|s| SpanId::from_hex(s).ok())?;
+
+    (trace_id != TraceId::INVALID && span_id != SpanId::INVALID)
+        .then(|| SpanContext::new(trace_id, span_id, TraceFlags::SAMPLED, true, Default::default()))
+}
+
+/// A propagator that can be shared between the middleware and the global propagator.
+#[derive(Debug)]
+struct SharedPropagator(Arc<dyn TextMapPropagator + Send + Sync + 'static>);
+
+impl TextMapPropagator for SharedPropagator {
+    fn inject_context(&self, cx: &opentelemetry::Context, injector: &mut dyn Injector) {
+        self.0.inject_context(cx, injector)
+    }
+
+    fn extract_with_context(&self, cx: &opentelemetry::Context, extractor: &dyn Extractor) -> opentelemetry::Context {
+        self.0.extract_with_context(cx, extractor)
+    }
+
+    fn fields(&self) -> opentelemetry::propagation::text_map_propagator::FieldIter<'_> {
+        self.0.fields()
+    }
+}
+
+/// Extracts propagated values from request headers.
+struct HeaderExtractor<'a>(&'a http::HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|v| v.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|k| k.as_str()).collect()
+    }
+}
+
+/// Injects propagated values into request headers.
+struct HeaderInjector<'a>(&'a mut http::HeaderMap);
+
+impl Injector for HeaderInjector<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        if let (Ok(name), Ok(value)) = (http::HeaderName::try_from(key), http::HeaderValue::try_from(value)) {
+            self.0.insert(name, value);
+        }
+    }
+}
+
+// Client correlation.
+
+/// The correlation information that lets frontend telemetry (from the Application Insights JavaScript SDK) be correlated with
+/// the backend request that served it.
+/// 
+/// The application id is the "Application ID" of the Application Insights resource (from the API Access blade), and it is
+/// formatted as the `cid-v1:` correlation id that the JavaScript SDK expects.  The trace context is the W3C `traceparent` of the
+/// current request.
+/// 
+/// ```
+/// use axum_insights::ClientCorrelation;
+/// 
+/// // In a handler that renders a page, emit the correlation information for the frontend.
+/// let correlation = ClientCorrelation::current(Some("00000000-0000-0000-0000-000000000000"));
+/// 
+/// assert_eq!(correlation.request_context().unwrap(), "appId=cid-v1:00000000-0000-0000-0000-000000000000");
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ClientCorrelation {
+    /// The correlation id of the application (e.g., `cid-v1:<application id>`).
+    pub app_id: Option<String>,
+    /// The W3C `traceparent` of the current request.
+    pub traceparent: Option<String>,
+}
+
+impl ClientCorrelation {
+    /// Captures the correlation information for the current request.
+    pub fn current(app_id: Option<&str>) -> Self {
+        Self {
+            app_id: app_id.map(|app_id| format!("cid-v1:{}", app_id)),
+            traceparent: TraceContextToken::current().map(|t| t.to_string()),
+        }
+    }
+
+    /// The value of the `Request-Context` header, which the JavaScript SDK reads from responses to correlate its calls.
+    pub fn request_context(&self) -> Option<String> {
+        self.app_id.as_ref().map(|app_id| format!("appId={}", app_id))
+    }
+
+    /// The correlation information as JSON (e.g., `{ "appId": "cid-v1:...", "traceparent": "00-..." }`).
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "appId": self.app_id,
+            "traceparent": self.traceparent,
+        })
+    }
+}
+
+/// Creates a route that returns the [`ClientCorrelation`] of the request as JSON, along with the `Request-Context` header.
+/// 
+/// ```
+/// use axum::Router;
+/// use axum_insights::client_correlation_route;
+/// 
+/// let app: Router<()> = Router::new()
+///     .route("/_correlation", client_correlation_route(Some("00000000-0000-0000-0000-000000000000".to_owned())));
+/// ```
+pub fn client_correlation_route<S>(app_id: Option<String>) -> axum::routing::MethodRouter<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    axum::routing::get(move || {
+        let app_id = app_id.clone();
+
+        async move {
+            let correlation = ClientCorrelation::current(app_id.as_deref());
+            let mut response = axum::Json(correlation.to_json()).into_response();
+
+            if let Some(request_context) = correlation.request_context().and_then(|v| http::HeaderValue::from_str(&v).ok()) {
+                response.headers_mut().insert("request-context", request_context);
+            }
+
+            response
+        }
+    })
+}
+
+// Dependencies.
+
+/// Helpers for tracking outgoing dependency calls (e.g., HTTP or SQL) made within handlers.
+/// 
+/// The spans created here have client semantics (`otel.kind = "client"`), so they are exported as dependency telemetry
+/// with a type, target, and result code, and the Application Map shows the downstream calls.
+pub mod deps {
+    use std::{fmt::Display, future::Future};
+
+    use http::StatusCode;
+    use tracing::{Instrument, Span};
+    use opentelemetry::propagation::TextMapPropagator;
+    use opentelemetry_sdk::propagation::TraceContextPropagator;
+    use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+    use crate::HeaderInjector;
+
+    /// Creates a span for an outgoing call to a dependency of the given type (e.g., `HTTP` or `SQL`) and target (e.g., the host).
+    /// 
+    /// ```
+    /// use axum_insights::deps::dependency_span;
+    /// 
+    /// let span = dependency_span("SQL", "db.contoso.com", "SELECT users");
+    /// ```
+    pub fn dependency_span(dependency_type: &str, target: &str, name: &str) -> Span {
+        let span = tracing::info_span!(
+            "dependency",
+            otel.name = name,
+            otel.kind = "client",
+            http.request.header.host = tracing::field::Empty,
+            server.address = tracing::field::Empty,
+            db.system = tracing::field::Empty,
+            rpc.system = tracing::field::Empty,
+            http.request.method = tracing::field::Empty,
+            url.full = tracing::field::Empty,
+            http.response.status_code = tracing::field::Empty,
+            otel.status_code = tracing::field::Empty,
+            otel.status_message = tracing::field::Empty,
+        );
+
+        // The exporter derives the dependency type from these attributes.
+        if dependency_type.eq_ignore_ascii_case("HTTP") {
+            span.record("http.request.header.host", target);
+        } else if dependency_type.eq_ignore_ascii_case("SQL") {
+            span.record("db.system", dependency_type);
+            span.record("server.address", target);
+        } else {
+            span.record("rpc.system", dependency_type);
+            span.record("server.address", target);
+        }
+
+        span
+    }
+
+    /// Runs the given dependency call in a dependency span, and records whether it succeeded.
+    /// 
+    /// ```
+    /// use axum_insights::deps::instrument_dependency;
+    /// 
+    /// # async fn run() -> Result<(), std::io::Error> {
+    /// let users = instrument_dependency("SQL", "db.contoso.com", "SELECT users", async {
+    ///     Ok::<_, std::io::Error>(vec!["user"])
+    /// }).await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn instrument_dependency<F, T, E>(dependency_type: &str, target: &str, name: &str, future: F) -> Result<T, E>
+    where
+        F: Future<Output = Result<T, E>>,
+        E: Display,
+    {
+        let span = dependency_span(dependency_type, target, name);
+        let result = future.instrument(span.clone()).await;
+
+        match &result {
+            Ok(_) => {
+                span.record("otel.status_code", "OK");
+            },
+            Err(e) => {
+                span.record("otel.status_code", "ERROR");
+                span.record("otel.status_message", e.to_string());
+            },
+        }
+
+        result
+    }
+
+    /// Records the status of an HTTP dependency call on the given dependency span.
+    pub fn record_http_status(span: &Span, status: StatusCode) {
+        span.record("http.response.status_code", status.as_u16());
+
+        if status.is_client_error() || status.is_server_error() {
+            span.record("otel.status_code", "ERROR");
+        } else {
+            span.record("otel.status_code", "OK");
+        }
+    }
+
+    /// Sends the given request as an HTTP dependency call, and propagates the trace context to the callee.
+    /// 
+    /// The trace context is injected with the global propagator (see
+    /// [`AppInsights::with_propagator`](crate::AppInsights::with_propagator)), or as the W3C trace context if no global propagator is
+    /// installed.
+    /// 
+    /// ```
+    /// use axum_insights::deps::track_reqwest;
+    /// 
+    /// # async fn run() -> reqwest::Result<()> {
+    /// let client = reqwest::Client::new();
+    /// let response = track_reqwest(client.get("https://contoso.com/users")).await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn track_reqwest(request: reqwest::RequestBuilder) -> reqwest::Result<reqwest::Response> {
+        let (client, request) = request.build_split();
+        let mut request = request?;
+
+        let url = request.url();
+        let target = match (url.host_str(), url.port()) {
+            (Some(host), Some(port)) => format!("{}:{}", host, port),
+            (Some(host), None) => host.to_owned(),
+            (None, _) => "unknown".to_owned(),
+        };
+        let name = format!("{} {}", request.method(), url.path());
+        let span = dependency_span("HTTP", &target, &name);
+
+        span.record("http.request.method", request.method().as_str());
+        span.record("url.full", url.as_str());
+
+        async move {
+            let span = Span::current();
+
+            // The global propagator is used, unless none was installed, in which case the W3C trace context is injected.
+            let context = span.context();
+            opentelemetry::global::get_text_map_propagator(|propagator| match propagator.fields().next() {
+                Some(_) => propagator.inject_context(&context, &mut HeaderInjector(request.headers_mut())),
+                None => TraceContextPropagator::new().inject_context(&context, &mut HeaderInjector(request.headers_mut())),
+            });
+
+            let result = client.execute(request).await;
+
+            match &result {
+                Ok(response) => record_http_status(&span, response.status()),
+                Err(e) => {
+                    span.record("otel.status_code", "ERROR");
+                    span.record("otel.status_message", e.to_string());
+                },
+            }
+
+            result
+        }
+        .instrument(span)
+        .await
+    }
+}
+
+// Events.
+
+/// Helpers for tracking custom events (e.g., `order_placed`) and availability results (e.g., from background probes).
+/// 
+/// The events are emitted through the installed pipeline with the `ai.*` conventions of the exporter.  Within a request (or any other
+/// span), they are part of that operation; otherwise, they get their own root span (since events outside of a span are not exported),
+/// which is exported as an (internal) dependency alongside the event.
+pub mod events {
+    use std::{collections::BTreeMap, time::Duration};
+
+    use tracing::{Level, Span};
+
+    /// Runs the given closure within the current span, or within the given root span (since events outside of a span are not exported).
+    fn in_span<T>(root_span: impl FnOnce() -> Span, f: impl FnOnce() -> T) -> T {
+        if Span::current().is_none() {
+            root_span().in_scope(f)
+        } else {
+            f()
+        }
+    }
+
+    /// Tracks a custom event with the given name and properties, which shows up in the `customEvents` table.
+    /// 
+    /// Each property becomes a property of the event (the pipeline expands them into attributes, since the field names of an event
+    /// are static).  Outside of a span, the event gets its own `custom_event` root span, which shows up as a dependency as well.
+    /// 
+    /// ```
+    /// use axum_insights::events::track_event;
+    /// 
+    /// track_event("order_placed", [("order_id", "42"), ("currency", "USD")]);
+    /// ```
+    pub fn track_event<K, V>(name: &str, properties: impl IntoIterator<Item = (K, V)>)
+    where
+        K: Into<String>,
+        V: Into<String>,
+    {
+        let properties: BTreeMap<String, String> = properties.into_iter().map(|(k, v)| (k.into(), v.into())).collect();
+
+        in_span(|| tracing::info_span!(parent: None, "custom_event", otel.kind = "internal"), || {
+            if properties.is_empty() {
+                tracing::event!(Level::INFO, ai.customEvent.name = name, "ai.custom");
+            } else {
+                tracing::event!(Level::INFO, ai.customEvent.name = name, custom_event.properties = serde_json::to_string(&properties).unwrap(), "ai.custom");
+            }
+        });
+    }
+
+    /// Tracks the result of an availability test (e.g., a background probe), with the time it took and whether it succeeded.
+    /// 
+    /// The exporter has no availability telemetry, so the result is a custom event named `availability`, with the
+    /// `availability.test_name`, `availability.duration_ms`, and `availability.success` properties.  Outside of a span, the event
+    /// gets its own `availability` root span, which shows up as a dependency as well.
+    /// 
+    /// ```
+    /// use axum_insights::events::track_availability;
+    /// use std::time::Duration;
+    /// 
+    /// track_availability("storage_probe", Duration::from_millis(120), true);
+    /// ```
+    pub fn track_availability(test_name: &str, duration: Duration, success: bool) {
+        in_span(|| tracing::info_span!(parent: None, "availability", otel.kind = "internal"), || {
+            tracing::event!(
+                Level::INFO,
+                ai.customEvent.name = "availability",
+                availability.test_name = test_name,
+                availability.duration_ms = duration.as_millis() as u64,
+                availability.success = success,
+                "ai.custom"
+            );
+        });
+    }
+}
+
+// Tasks.
+
+/// Helpers for instrumenting background work (e.g., queue consumers or cron-style jobs) that is not part of a request.
+/// 
+/// Each job gets its own root span, so that it is exported as its own operation (with the service resource of the pipeline), and
+/// the span records whether the job succeeded.  Panics within a job are reported as exceptions (like panics within a request),
+/// and then they continue to unwind.
+pub mod tasks {
+    use std::{fmt::Display, future::Future, panic::AssertUnwindSafe, sync::atomic::Ordering};
+
+    use futures::FutureExt;
+    use tracing::{Instrument, Span};
+
+    use crate::{emit_panic_exception, IS_PANIC_HOOK_INSTALLED};
+
+    /// The root span of a background job.
+    /// 
+    /// The span is exported when it is dropped, and its duration is the duration of the job.
+    pub struct JobSpan {
+        span: Span,
+    }
+
+    impl JobSpan {
+        /// Creates the root span of an internal job (`otel.kind = "internal"`), which is exported as dependency telemetry.
+        /// 
+        /// ```
+        /// use axum_insights::tasks::JobSpan;
+        /// 
+        /// let job = JobSpan::new("nightly_cleanup");
+        /// 
+        /// job.span().in_scope(|| {
+        ///     // ...
+        /// });
+        /// 
+        /// job.record_success();
+        /// ```
+        pub fn new(name: &str) -> Self {
+            Self::with_kind(name, "internal")
+        }
+
+        /// Creates the root span of a job that consumes a message (`otel.kind = "consumer"`), which is exported as request telemetry.
+        /// 
+        /// ```
+        /// use axum_insights::tasks::JobSpan;
+        /// 
+        /// let job = JobSpan::consumer("orders_queue");
+        /// ```
+        pub fn consumer(name: &str) -> Self {
+            Self::with_kind(name, "consumer")
+        }
+
+        fn with_kind(name: &str, kind: &'static str) -> Self {
+            let span = tracing::info_span!(
+                parent: None,
+                "job",
+                otel.name = name,
+                otel.kind = kind,
+                otel.status_code = tracing::field::Empty,
+                otel.status_message = tracing::field::Empty,
+            );
+
+            Self { span }
+        }
+
+        /// Gets the span of the job, which can be entered (or used to instrument futures) to attribute the work to the job.
+        pub fn span(&self) -> &Span {
+            &self.span
+        }
+
+        /// Records that the job succeeded.
+        pub fn record_success(&self) {
+            self.span.record("otel.status_code", "OK");
+        }
+
+        /// Records that the job failed, with the given message.
+        pub fn record_failure(&self, message: impl Display) {
+            self.span.record("otel.status_code", "ERROR");
+            self.span.record("otel.status_message", message.to_string());
+        }
+
+        /// Runs the given job in the span, and records whether it succeeded.  If the job panics, then the panic is reported
+        /// as an exception, and it continues to unwind.
+        /// 
+        /// ```
+        /// use axum_insights::tasks::JobSpan;
+        /// 
+        /// # async fn run() -> Result<(), std::io::Error> {
+        /// let processed = JobSpan::consumer("orders_queue").run(async {
+        ///     Ok::<_, std::io::Error>(42)
+        /// }).await?;
+        /// # Ok(())
+        /// # }
+        /// ```
+        pub async fn run<F, T, E>(self, future: F) -> Result<T, E>
+        where
+            F: Future<Output = Result<T, E>>,
+            E: Display,
+        {
+            let result = AssertUnwindSafe(future).catch_unwind().instrument(self.span.clone()).await;
+
+            match result {
+                Ok(Ok(value)) => {
+                    self.record_success();
+                    Ok(value)
+                },
+                Ok(Err(e)) => {
+                    self.record_failure(&e);
+                    Err(e)
+                },
+                Err(panic) => {
+                    let payload_string = format!("{:?}", panic.downcast_ref::<&str>());
+
+                    // The panic hook (if it is installed) already emitted the exception, with the backtrace.
+                    if !IS_PANIC_HOOK_INSTALLED.load(Ordering::Relaxed) {
+                        self.span.in_scope(|| emit_panic_exception(&payload_string, ""));
+                    }
+
+                    self.record_failure(format!("A panic occurred: {}.", payload_string));
+                    drop(self);
+
+                    std::panic::resume_unwind(panic)
+                },
+            }
+        }
+    }
+
+    /// Spawns the given job onto the tokio runtime, in its own root span (see [`JobSpan::run`]).
+    /// 
+    /// ```
+    /// use axum_insights::tasks::spawn_instrumented;
+    /// 
+    /// # async fn run() {
+    /// let handle = spawn_instrumented("nightly_cleanup", async {
+    ///     Ok::<_, std::io::Error>(())
+    /// });
+    /// 
+    /// let result = handle.await;
+    /// # }
+    /// ```
+    pub fn spawn_instrumented<F, T, E>(name: &str, future: F) -> tokio::task::JoinHandle<Result<T, E>>
+    where
+        F: Future<Output = Result<T, E>> + Send + 'static,
+        T: Send + 'static,
+        E: Display + Send + 'static,
+    {
+        tokio::spawn(JobSpan::new(name).run(future))
+    }
 }
 
-impl<S, P, E> Service<Request<Body>> for AppInsightsMiddleware<S, P, E>
-where
-    S: Service<Request<Body>, Response = Response> + Send + 'static,
-    S::Future: Send + 'static,
-    S::Error: Send + 'static,
-    P: Serialize + Send + 'static,
-    E: AppInsightsError + Serialize + DeserializeOwned + Default + Send + 'static,
-{
-    type Error = S::Error;
-    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
-    type Response = S::Response;
+// Tests.
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc::Sender;
+
+    use axum::{Router, routing::get, response::IntoResponse};
+    use http::StatusCode;
+    use opentelemetry_sdk::propagation::{BaggagePropagator, TraceContextPropagator};
+    use serde::Deserialize;
+    use tracing::{Subscriber, span};
+    use tracing_subscriber::Layer;
+
+    use super::*;
+
+    #[derive(Clone, Default, Serialize, Deserialize)]
+    struct WebError {
+        status: u16,
+        message: String,
+    }
+
+    impl AppInsightsError for WebError {
+        fn message(&self) -> Option<String> {
+            Some(self.message.clone())
+        }
+
+        fn backtrace(&self) -> Option<String> {
+            None
+        }
+
+        fn code(&self) -> Option<String> {
+            Some(format!("E{}", self.status))
+        }
+    }
+
+    impl IntoResponse for WebError {
+        fn into_response(self) -> Response {
+            let code = StatusCode::from_u16(self.status).unwrap();
+            let body = serde_json::to_string(&self).unwrap();
+
+            (code, body).into_response()
+        }
+    }
+
+    struct TestSubscriberLayer {
+        sender: Sender<String>,
+    }
+
+    impl<S> Layer<S> for TestSubscriberLayer
+    where
+        S: Subscriber
+    {
+        fn on_new_span(&self, attrs: &span::Attributes<'_>, _id: &span::Id, _ctx: tracing_subscriber::layer::Context<'_, S>) {
+            self.sender.send(format!("new|{}", attrs.metadata().name())).unwrap();
+        }
+
+        fn on_event(&self, event: &tracing::Event<'_>, _ctx: tracing_subscriber::layer::Context<'_, S>) {
+            self.sender.send(format!("event|{}", event.metadata().name())).unwrap();
+        }
+
+        fn on_record(&self, _id: &span::Id, values: &span::Record<'_>, _ctx: tracing_subscriber::layer::Context<'_, S>) {
+            self.sender.send(format!("record|{:?}", values)).unwrap();
+        }
+
+        fn on_close(&self, _id: span::Id, _ctx: tracing_subscriber::layer::Context<'_, S>) {
+            self.sender.send("close".to_string()).unwrap();
+        }
+    }
+
+    // The panicking handler relies on the never type falling back to `()`, which newer compilers deny by default.
+    #[allow(dependency_on_unit_never_type_fallback)]
+    #[tokio::test]
+    async fn test_integration() {
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let subscriber = tracing_subscriber::registry().with(TestSubscriberLayer {
+            sender: sender.clone(),
+        });
+
+        let i = AppInsights::default()
+            .with_connection_string(None)
+            .with_service_config("namespace", "name")
+            .with_client(reqwest::Client::new())
+            .with_sample_rate(1.0)
+            .with_minimum_level(LevelFilter::INFO)
+            .with_runtime(Tokio)
+            .with_catch_panic(true)
+            .with_subscriber(subscriber)
+            .with_field_mapper(|_| {
+                let mut map = HashMap::new();
+                map.insert("extra_field".to_owned(), "extra_value".to_owned());
+                map
+            })
+            .with_panic_mapper(|panic| {
+                (500, WebError { status: 500, message: panic })
+            })
+            .with_success_filter(|status| {
+                status.is_success() || status.is_redirection() || status.is_informational() || status == StatusCode::NOT_FOUND
+            })
+            .with_error_type::<WebError>()
+            .build_and_set_global_default()
+            .unwrap();
+
+        let layer = i.layer();
+
+        let mut app: Router<()> = Router::new()
+            .route("/succeed1", get(|| async { Response::new(Body::empty()) }))
+            .route("/succeed2", get(|| async { (StatusCode::NOT_MODIFIED, "") }))
+            .route("/succeed3", get(|| async { (StatusCode::NOT_FOUND, "") }))
+            .route("/fail1", get(|| async { WebError { status: 429, message: "foo".to_string() } }))
+            .route("/fail2", get(|| async { panic!("panic") }))
+            .layer(layer);
+
+        // Regular success.
+
+        let request = Request::builder().uri("/succeed1").body(Body::empty()).unwrap();
+        // This is required because there are multiple impls of `ready` for `Router`. 🙄
+        let response = <axum::Router as tower::ServiceExt<Request<Body>>>::ready(&mut app).await.unwrap().call(request).await.unwrap();
+        assert_eq!(response.status(), 200);
+
+        assert_eq!("new|request", receiver.recv().unwrap());
+        assert!(receiver.recv().unwrap().starts_with("record|Record { values: ValueSet { http.response.status_code: 200"));
+        assert!(receiver.recv().unwrap().starts_with("record|Record { values: ValueSet { otel.status_code: \"OK\""));
+        assert_eq!("close", receiver.recv().unwrap());
+
+        // Redirect success.
+
+        let request = Request::builder().uri("/succeed2").body(Body::empty()).unwrap();
+        let response = <axum::Router as tower::ServiceExt<Request<Body>>>::ready(&mut app).await.unwrap().call(request).await.unwrap();
+        assert_eq!(response.status(), 304);
+
+        assert_eq!("new|request", receiver.recv().unwrap());
+        assert!(receiver.recv().unwrap().starts_with("record|Record { values: ValueSet { http.response.status_code: 304"));
+        assert!(receiver.recv().unwrap().starts_with("record|Record { values: ValueSet { otel.status_code: \"OK\""));
+        assert_eq!("close", receiver.recv().unwrap());
+
+        // Custom success.
+
+        let request = Request::builder().uri("/succeed3").body(Body::empty()).unwrap();
+        let response = <axum::Router as tower::ServiceExt<Request<Body>>>::ready(&mut app).await.unwrap().call(request).await.unwrap();
+        assert_eq!(response.status(), 404);
+
+        assert_eq!("new|request", receiver.recv().unwrap());
+        assert!(receiver.recv().unwrap().starts_with("record|Record { values: ValueSet { http.response.status_code: 404"));
+        assert!(receiver.recv().unwrap().starts_with("record|Record { values: ValueSet { otel.status_code: \"OK\""));
+        assert_eq!("close", receiver.recv().unwrap());
+
+        // Failure.
+
+        let request = Request::builder().uri("/fail1").body(Body::empty()).unwrap();
+        let response = <axum::Router as tower::ServiceExt<Request<Body>>>::ready(&mut app).await.unwrap().call(request).await.unwrap();
+        assert_eq!(response.status(), 429);
+
+        assert_eq!("new|request", receiver.recv().unwrap());
+        assert!(receiver.recv().unwrap().starts_with("record|Record { values: ValueSet { http.response.body.size: "));
+        assert!(receiver.recv().unwrap().starts_with("event|exception"));
+        assert!(receiver.recv().unwrap().starts_with("record|Record { values: ValueSet { http.response.status_code: 429"));
+        assert!(receiver.recv().unwrap().starts_with("record|Record { values: ValueSet { otel.status_code: \"ERROR\""));
+        assert!(receiver.recv().unwrap().starts_with("record|Record { values: ValueSet { otel.status_message: \"foo\""));
+        assert!(receiver.recv().unwrap().starts_with("record|Record { values: ValueSet { error.type: \"429\""));
+        assert!(receiver.recv().unwrap().starts_with("record|Record { values: ValueSet { error.code: \"E429\""));
+        assert_eq!("close", receiver.recv().unwrap());
+
+        // Panic.
+
+        let request = Request::builder().uri("/fail2").body(Body::empty()).unwrap();
+        let response = <axum::Router as tower::ServiceExt<Request<Body>>>::ready(&mut app).await.unwrap().call(request).await.unwrap();
+        assert_eq!(response.status(), 500);
+
+        assert_eq!("new|request", receiver.recv().unwrap());
+        assert!(receiver.recv().unwrap().starts_with("event|exception"));
+        assert!(receiver.recv().unwrap().starts_with("record|Record { values: ValueSet { http.response.body.size: "));
+        assert!(receiver.recv().unwrap().starts_with("event|exception"));
+        assert!(receiver.recv().unwrap().starts_with("record|Record { values: ValueSet { http.response.status_code: 500"));
+        assert!(receiver.recv().unwrap().starts_with("record|Record { values: ValueSet { otel.status_code: \"ERROR\""));
+        assert!(receiver.recv().unwrap().starts_with("record|Record { values: ValueSet { otel.status_message: \"Some(\\\"panic\\\")\""));
+        assert!(receiver.recv().unwrap().starts_with("record|Record { values: ValueSet { error.type: \"500\""));
+        assert!(receiver.recv().unwrap().starts_with("record|Record { values: ValueSet { error.code: \"E500\""));
+        assert_eq!("close", receiver.recv().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_typed_error_details() {
+        let spans = Arc::new(Mutex::new(Vec::new()));
+        let tracer_provider = opentelemetry_sdk::trace::TracerProvider::builder().with_span_processor(RecordingProcessor { spans: spans.clone() }).build();
+
+        let (i, telemetry): (AppInsightsComplete<(), WebError>, _) = AppInsights::from_tracer_provider(tracer_provider).with_error_type::<WebError>().build_layer().unwrap();
+        let _guard = tracing::subscriber::set_default(tracing_subscriber::registry().with(telemetry));
+
+        let handler = || async {
+            let mut response = (StatusCode::CONFLICT, "not json").into_response();
+            response.extensions_mut().insert(ErrorDetails::new(&WebError { status: 409, message: "typed".to_string() }));
+            response
+        };
+        let mut app: Router<()> = Router::new().route("/fail4", get(handler)).layer(i.layer());
+
+        let request = Request::builder().uri("/fail4").body(Body::empty()).unwrap();
+        let response = <axum::Router as tower::ServiceExt<Request<Body>>>::ready(&mut app).await.unwrap().call(request).await.unwrap();
+        assert_eq!(response.status(), 409);
+
+        // The typed error details are preferred over the body, which is passed through untouched.
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body, "not json");
+
+        let spans = spans.lock().unwrap();
+        let attribute = |key: &str| spans[0].attributes.iter().rfind(|kv| kv.key.as_str() == key).map(|kv| kv.value.to_string());
+
+        assert_eq!(spans[0].status, opentelemetry::trace::Status::error("typed"));
+        assert_eq!(attribute("error.type").as_deref(), Some("409"));
+        assert_eq!(attribute("error.code").as_deref(), Some("E409"));
+        assert_eq!(spans[0].events.events[0].name, "exception");
+    }
+
+    #[tokio::test]
+    async fn test_contextual_panic_mapper() {
+        let spans = Arc::new(Mutex::new(Vec::new()));
+        let tracer_provider = opentelemetry_sdk::trace::TracerProvider::builder().with_span_processor(RecordingProcessor { spans: spans.clone() }).build();
+
+        let (i, telemetry) = AppInsights::from_tracer_provider(tracer_provider)
+            .with_catch_panic(true)
+            .with_panic_mapper(|panic| (500, WebError { status: 500, message: panic }))
+            .with_error_type::<WebError>()
+            .build_layer()
+            .unwrap();
+        let _guard = tracing::subscriber::set_default(tracing_subscriber::registry().with(telemetry));
+
+        let mut app: Router<()> = Router::new()
+            .route("/fail3/:id", get(|| async { panic!("panic") as StatusCode }))
+            .layer(i.layer_with(|l| {
+                l.with_contextual_panic_mapper(|panic, context| {
+                    let request_id = context.headers.get("x-request-id").and_then(|v| v.to_str().ok()).unwrap_or_default();
+                    (503, WebError { status: 503, message: format!("{} {} {} {}", context.method, context.route, request_id, panic) })
+                })
+            }));
+
+        let request = Request::builder().uri("/fail3/42").header("x-request-id", "abc").body(Body::empty()).unwrap();
+        let response = <axum::Router as tower::ServiceExt<Request<Body>>>::ready(&mut app).await.unwrap().call(request).await.unwrap();
+        assert_eq!(response.status(), 503);
+
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let error: WebError = serde_json::from_slice(&body).unwrap();
+        // No headers were selected for the panic context, so the request id is not captured.
+        assert_eq!(error.message, "GET /fail3/:id  Some(\"panic\")");
+
+        let spans = spans.lock().unwrap();
+        assert!(spans[0].attributes.iter().any(|kv| kv.key.as_str() == "http.response.status_code" && kv.value.to_string() == "503"));
+    }
+
+    #[tokio::test]
+    async fn test_async_field_mapper() {
+        let spans = Arc::new(Mutex::new(Vec::new()));
+        let tracer_provider = opentelemetry_sdk::trace::TracerProvider::builder().with_span_processor(RecordingProcessor { spans: spans.clone() }).build();
+
+        let (i, telemetry): (AppInsightsComplete<(), ()>, _) = AppInsights::from_tracer_provider(tracer_provider).build_layer().unwrap();
+        let _guard = tracing::subscriber::set_default(tracing_subscriber::registry().with(telemetry));
+
+        let mut app: Router<()> = Router::new()
+            .route("/", get(|| async { "ok" }))
+            .layer(i.layer_with(|l| l.with_async_field_mapper(|_| async { HashMap::from([("tenant".to_owned(), "contoso".to_owned())]) }.boxed())));
+
+        let request = Request::builder().uri("/").body(Body::empty()).unwrap();
+        let response = <axum::Router as tower::ServiceExt<Request<Body>>>::ready(&mut app).await.unwrap().call(request).await.unwrap();
+        assert_eq!(response.status(), 200);
+
+        // The async extra fields are recorded on the request span (after the synchronous ones).
+        let spans = spans.lock().unwrap();
+        let extra_fields = spans[0].attributes.iter().rfind(|kv| kv.key.as_str() == "extra_fields").unwrap();
+        assert!(extra_fields.value.as_str().contains("contoso"));
+    }
+
+    #[tokio::test]
+    async fn test_excluded_routes() {
+        let spans = Arc::new(Mutex::new(Vec::new()));
+        let tracer_provider = opentelemetry_sdk::trace::TracerProvider::builder().with_span_processor(RecordingProcessor { spans: spans.clone() }).build();
+
+        let (i, telemetry): (AppInsightsComplete<(), ()>, _) = AppInsights::from_tracer_provider(tracer_provider).build_layer().unwrap();
+        let _guard = tracing::subscriber::set_default(tracing_subscriber::registry().with(telemetry));
+
+        let mut app: Router<()> = Router::new()
+            .route("/healthz", get(|| async { "ok" }))
+            .route("/ping", get(|| async { "pong" }))
+            .route("/tracked", get(|| async { "tracked" }))
+            .layer(i.layer_with(|l| l.with_excluded_routes(["/healthz"]).with_request_filter(|parts| parts.uri.path() != "/ping")));
+
+        for uri in ["/healthz", "/ping", "/tracked"] {
+            let request = Request::builder().uri(uri).body(Body::empty()).unwrap();
+            let response = <axum::Router as tower::ServiceExt<Request<Body>>>::ready(&mut app).await.unwrap().call(request).await.unwrap();
+            assert_eq!(response.status(), 200);
+        }
+
+        // Only the tracked request has a span.
+        let spans = spans.lock().unwrap();
+        assert_eq!(spans.len(), 1);
+        assert!(spans[0].attributes.contains(&KeyValue::new("url.path", "/tracked")));
+    }
+
+    #[tokio::test]
+    async fn test_user_mapper() {
+        struct SpanFieldsLayer {
+            sender: Sender<String>,
+        }
+
+        impl<S: Subscriber> Layer<S> for SpanFieldsLayer {
+            fn on_new_span(&self, attrs: &span::Attributes<'_>, _id: &span::Id, _ctx: tracing_subscriber::layer::Context<'_, S>) {
+                self.sender.send(format!("{:?}", attrs.values())).unwrap();
+            }
+        }
+
+        // Build a noop pipeline (which does not set the global default), and then activate it for this test only.
+        let mut i: AppInsightsComplete<(), ()> = AppInsights::default()
+            .with_connection_string(None)
+            .with_service_config("namespace", "name")
+            .with_noop(true)
+            .build_and_set_global_default()
+            .unwrap();
+        i.is_noop = false;
+
+        let mut app: Router<()> = Router::new().route("/", get(|| async { "ok" })).layer(i.layer_with(|l| {
+            l.with_user_mapper(|parts| UserContext {
+                user_id: Some("anonymous-1".to_owned()),
+                session_id: parts.headers.get("x-session-id").and_then(|v| v.to_str().ok()).map(str::to_owned),
+                auth_user_id: Some("alice".to_owned()),
+            })
+        }));
+
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let _guard = tracing::subscriber::set_default(tracing_subscriber::registry().with(SpanFieldsLayer { sender }));
+
+        let request = Request::builder().uri("/").header("x-session-id", "session-1").body(Body::empty()).unwrap();
+        let response = <axum::Router as tower::ServiceExt<Request<Body>>>::ready(&mut app).await.unwrap().call(request).await.unwrap();
+        assert_eq!(response.status(), 200);
+
+        let fields = receiver.try_recv().unwrap();
+        assert!(fields.contains("ai.user.id: \"anonymous-1\""));
+        assert!(fields.contains("ai.session.id: \"session-1\""));
+        assert!(fields.contains("ai.user.authUserId: \"alice\""));
+        assert!(fields.contains("enduser.id: \"alice\""));
+    }
+
+    #[test]
+    fn test_profile_overrides() {
+        let i = AppInsights::default()
+            .with_connection_string(None)
+            .with_service_config("namespace", "name")
+            .with_profile(Profile::Development);
+
+        assert_eq!(i.minimum_level, LevelFilter::DEBUG);
+        assert!(!i.enable_live_metrics);
+        assert!(i.capture_redactor.is_none());
+
+        // Panic catching and the console are opt-in, and the profile only picks the format of the console.
+        assert!(!i.should_catch_panic);
+        assert!(!i.should_log_to_console);
+        assert_eq!(i.console_format, ConsoleFormat::Pretty);
+
+        let i = i.with_minimum_level(LevelFilter::WARN).with_live_metrics(true);
+
+        assert_eq!(i.minimum_level, LevelFilter::WARN);
+        assert!(i.enable_live_metrics);
+
+        // The explicit settings win, even when the profile is applied after them.
+        let i = i.with_profile(Profile::Production);
+
+        assert_eq!(i.minimum_level, LevelFilter::WARN);
+        assert_eq!(i.sample_rate, 0.25);
+        assert_eq!(i.max_error_body_capture, 4 * 1024);
+
+        let i = AppInsights::default()
+            .with_connection_string(None)
+            .with_service_config("namespace", "name")
+            .with_sample_rate(1.0)
+            .with_console(true)
+            .with_profile(Profile::Production);
+
+        assert_eq!(i.sample_rate, 1.0);
+        assert!(i.should_log_to_console);
+        assert_eq!(i.console_format, ConsoleFormat::Compact);
+
+        // The profiles differ in how strictly the captured values are redacted.
+        let redact = |profile: Profile, name: &str| {
+            let i = AppInsights::default().with_connection_string(None).with_service_config("namespace", "name").with_profile(profile);
+            i.capture_redactor.as_ref().map(|r| r(name, "value")).unwrap_or_else(|| "value".to_owned())
+        };
+
+        assert_eq!(redact(Profile::Development, "authorization"), "value");
+        assert_eq!(redact(Profile::Staging, "authorization"), "REDACTED");
+        assert_eq!(redact(Profile::Staging, "x-tenant"), "value");
+        assert_eq!(redact(Profile::Staging, "x-forwarded-for"), "value");
+        assert_eq!(redact(Profile::Production, "authorization"), "REDACTED");
+        assert_eq!(redact(Profile::Production, "x-forwarded-for"), "REDACTED");
+        assert_eq!(redact(Profile::Production, "email"), "REDACTED");
+        assert_eq!(redact(Profile::Production, "x-tenant"), "value");
+        assert_eq!(redact(Profile::Production, "user-agent"), "value");
+    }
+
+    #[test]
+    fn test_from_tracer_provider() {
+        let i = AppInsights::from_tracer_provider(opentelemetry_sdk::trace::TracerProvider::builder().build()).with_catch_panic(true);
+
+        assert!(i.tracer_provider.is_some());
+        assert!(i.connection_string.is_none());
+        assert!(i.should_catch_panic);
+    }
+
+    #[cfg(feature = "otlp")]
+    #[tokio::test]
+    async fn test_otlp_exporter() {
+        let i = AppInsights::default().with_exporter(ExporterBackend::Otlp { endpoint: "http://localhost:4318/v1/traces".to_owned() });
+
+        assert!(i.connection_string.is_none());
+        assert_eq!(i.otlp_endpoint.as_deref(), Some("http://localhost:4318/v1/traces"));
+
+        let tracer_provider = build_otlp_tracer_provider("http://localhost:4318/v1/traces".to_owned(), Config::default(), opentelemetry_sdk::runtime::Tokio);
+
+        assert!(tracer_provider.is_ok());
+    }
+
+    #[test]
+    fn test_success_policy() {
+        let statuses = [StatusCode::OK, StatusCode::NOT_MODIFIED, StatusCode::BAD_REQUEST, StatusCode::NOT_FOUND, StatusCode::INTERNAL_SERVER_ERROR, StatusCode::from_u16(600).unwrap()];
+        let successes = |policy: SuccessPolicy| statuses.iter().map(|s| policy.is_success(*s)).collect::<Vec<_>>();
+
+        assert_eq!(successes(SuccessPolicy::StrictServerErrorsOnly), [true, true, true, true, false, true]);
+        assert_eq!(successes(SuccessPolicy::IgnoreNotFound), [true, true, false, true, false, false]);
+        assert_eq!(successes(SuccessPolicy::IgnoreClientErrors), [true, true, true, true, false, false]);
+    }
+
+    #[test]
+    fn test_request_sampler() {
+        let sample = |inner: Sampler, attributes: &[KeyValue]| {
+            RequestSampler { inner: Box::new(inner) }
+                .should_sample(None, TraceId::from_bytes([1; 16]), "request", &SpanKind::Server, attributes, &[])
+                .decision
+        };
+
+        let forced = [KeyValue::new("sampling.priority", 1)];
+        let dropped = [KeyValue::new("sampling.priority", 0)];
+
+        assert_eq!(sample(Sampler::AlwaysOff, &forced), opentelemetry::trace::SamplingDecision::RecordAndSample);
+        assert_eq!(sample(Sampler::AlwaysOn, &dropped), opentelemetry::trace::SamplingDecision::Drop);
+        assert_eq!(sample(Sampler::AlwaysOff, &[]), opentelemetry::trace::SamplingDecision::Drop);
+        assert_eq!(sample(Sampler::AlwaysOn, &[]), opentelemetry::trace::SamplingDecision::RecordAndSample);
+
+        let biased = [KeyValue::new("sampling.error_biased", true)];
+        let tentative = RequestSampler { inner: Box::new(Sampler::AlwaysOff) }.should_sample(None, TraceId::from_bytes([1; 16]), "request", &SpanKind::Server, &biased, &[]);
+        let certain = RequestSampler { inner: Box::new(Sampler::AlwaysOn) }.should_sample(None, TraceId::from_bytes([1; 16]), "request", &SpanKind::Server, &biased, &[]);
+
+        assert_eq!(tentative.decision, opentelemetry::trace::SamplingDecision::RecordOnly);
+        assert_eq!(certain.decision, opentelemetry::trace::SamplingDecision::RecordAndSample);
+
+        // The spans within a tentative request are only recorded, unless the request was sampled after all.
+        let marked = [KeyValue::new(TENTATIVE_ATTRIBUTE, true)];
+        let parent = |flags: TraceFlags| {
+            let trace_state = opentelemetry::trace::TraceState::from_key_value([(TRACE_STATE_KEY, "tentative"), ("congo", "t61rcWkgMzE")]).unwrap();
+            opentelemetry::Context::new().with_remote_span_context(SpanContext::new(TraceId::from_bytes([1; 16]), SpanId::from_bytes([1; 8]), flags, true, trace_state))
+        };
+        let sampler = RequestSampler { inner: Box::new(Sampler::ParentBased(Box::new(Sampler::AlwaysOff))) };
+        let child = |flags: TraceFlags, attributes: &[KeyValue]| sampler.should_sample(Some(&parent(flags)), TraceId::from_bytes([1; 16]), "child", &SpanKind::Internal, attributes, &[]);
+
+        assert_eq!(child(TraceFlags::default(), &marked).decision, opentelemetry::trace::SamplingDecision::RecordOnly);
+        assert_eq!(child(TraceFlags::SAMPLED, &marked).decision, opentelemetry::trace::SamplingDecision::RecordAndSample);
+        assert_eq!(child(TraceFlags::default(), &[]).decision, opentelemetry::trace::SamplingDecision::Drop);
+
+        // An inbound entry of this crate is stripped from the trace state, whatever the decision.
+        for result in [child(TraceFlags::SAMPLED, &[]), child(TraceFlags::default(), &marked), child(TraceFlags::default(), &forced)] {
+            assert_eq!(result.trace_state.get(TRACE_STATE_KEY), None);
+            assert_eq!(result.trace_state.get("congo"), Some("t61rcWkgMzE"));
+        }
+    }
+
+    #[derive(Debug, Default)]
+    struct RecordingProcessor {
+        spans: Arc<Mutex<Vec<SpanData>>>,
+    }
+
+    impl RecordingProcessor {
+        fn names(spans: &Mutex<Vec<SpanData>>) -> Vec<String> {
+            spans.lock().unwrap().iter().map(|span| span.name.to_string()).collect()
+        }
+    }
+
+    impl SpanProcessor for RecordingProcessor {
+        fn on_start(&self, _span: &mut opentelemetry_sdk::trace::Span, _cx: &opentelemetry::Context) {}
+
+        fn on_end(&self, span: SpanData) {
+            self.spans.lock().unwrap().push(span);
+        }
+
+        fn force_flush(&self) -> opentelemetry::trace::TraceResult<()> {
+            Ok(())
+        }
+
+        fn shutdown(&self) -> opentelemetry::trace::TraceResult<()> {
+            Ok(())
+        }
+    }
+
+    /// A metric reader that can be collected from after it is handed to the meter provider.
+    #[derive(Clone, Debug, Default)]
+    struct SharedReader(Arc<opentelemetry_sdk::metrics::ManualReader>);
+
+    impl opentelemetry_sdk::metrics::reader::TemporalitySelector for SharedReader {
+        fn temporality(&self, kind: opentelemetry_sdk::metrics::InstrumentKind) -> opentelemetry_sdk::metrics::data::Temporality {
+            self.0.temporality(kind)
+        }
+    }
+
+    impl opentelemetry_sdk::metrics::reader::AggregationSelector for SharedReader {
+        fn aggregation(&self, kind: opentelemetry_sdk::metrics::InstrumentKind) -> opentelemetry_sdk::metrics::Aggregation {
+            self.0.aggregation(kind)
+        }
+    }
+
+    impl opentelemetry_sdk::metrics::reader::MetricReader for SharedReader {
+        fn register_pipeline(&self, pipeline: std::sync::Weak<opentelemetry_sdk::metrics::Pipeline>) {
+            self.0.register_pipeline(pipeline)
+        }
+
+        fn collect(&self, rm: &mut opentelemetry_sdk::metrics::data::ResourceMetrics) -> opentelemetry::metrics::Result<()> {
+            self.0.collect(rm)
+        }
+
+        fn force_flush(&self) -> opentelemetry::metrics::Result<()> {
+            self.0.force_flush()
+        }
+
+        fn shutdown(&self) -> opentelemetry::metrics::Result<()> {
+            self.0.shutdown()
+        }
+    }
+
+    #[test]
+    fn test_build_layer() {
+        let spans = Arc::new(Mutex::new(Vec::new()));
+        let tracer_provider = opentelemetry_sdk::trace::TracerProvider::builder().with_span_processor(RecordingProcessor { spans: spans.clone() }).build();
+
+        let (i, telemetry) = AppInsights::from_tracer_provider(tracer_provider).build_layer().unwrap();
+        assert!(!i.is_noop);
+
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let subscriber = tracing_subscriber::registry().with(telemetry).with(TestSubscriberLayer { sender });
+
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info_span!("exported").in_scope(|| {});
+            tracing::debug_span!("local").in_scope(|| {});
+        });
+
+        // Only the telemetry is filtered by the minimum level, and the other layers still see everything.
+        assert_eq!(RecordingProcessor::names(&spans), ["exported"]);
+        assert_eq!(receiver.try_iter().filter(|m| m.starts_with("new|")).collect::<Vec<_>>(), ["new|exported", "new|local"]);
+    }
+
+    #[tokio::test]
+    async fn test_cloud_role_on_child_spans() {
+        let spans = Arc::new(Mutex::new(Vec::new()));
+        let tracer_provider = opentelemetry_sdk::trace::TracerProvider::builder().with_span_processor(RecordingProcessor { spans: spans.clone() }).build();
+
+        let (i, telemetry): (AppInsightsComplete<(), ()>, _) = AppInsights::from_tracer_provider(tracer_provider).build_layer().unwrap();
+        let _guard = tracing::subscriber::set_default(tracing_subscriber::registry().with(telemetry));
+
+        let handler = || async {
+            tracing::info_span!("child").in_scope(|| deps::dependency_span("SQL", "db", "SELECT").in_scope(|| {}));
+            "ok"
+        };
+        let mut app: Router<()> = Router::new().route("/", get(handler)).layer(i.layer().with_cloud_role("namespace.admin-api"));
+
+        let request = Request::builder().uri("/").body(Body::empty()).unwrap();
+        let response = <axum::Router as tower::ServiceExt<Request<Body>>>::ready(&mut app).await.unwrap().call(request).await.unwrap();
+        assert_eq!(response.status(), 200);
+
+        let spans = spans.lock().unwrap();
+        assert_eq!(spans.len(), 3);
+
+        for span in spans.iter() {
+            assert!(span.attributes.contains(&KeyValue::new("ai.cloud.role", "namespace.admin-api")), "{}", span.name);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_stats_handle() {
+        let (i, telemetry): (AppInsightsComplete<(), ()>, _) = AppInsights::from_tracer_provider(opentelemetry_sdk::trace::TracerProvider::builder().build()).build_layer().unwrap();
+        let _guard = tracing::subscriber::set_default(tracing_subscriber::registry().with(telemetry));
+
+        let mut app: Router<()> = Router::new()
+            .route("/succeed", get(|| async { "ok" }))
+            .route("/fail", get(|| async { (StatusCode::INTERNAL_SERVER_ERROR, "error") }))
+            .layer(i.layer());
+
+        for uri in ["/succeed", "/succeed", "/fail"] {
+            let request = Request::builder().uri(uri).body(Body::empty()).unwrap();
+            <axum::Router as tower::ServiceExt<Request<Body>>>::ready(&mut app).await.unwrap().call(request).await.unwrap();
+        }
+
+        let stats_handle = i.stats_handle();
+        assert_eq!((stats_handle.requests(), stats_handle.failures()), (3, 1));
+    }
+
+    #[test]
+    fn test_reload_handle() {
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let debug_trace_filter = DebugTraceFilter { minimum_level: Arc::new(RwLock::new(LevelFilter::INFO)), allow_debug_traces: false };
+        let reload_handle = ReloadHandle { minimum_level: debug_trace_filter.minimum_level.clone() };
+        let subscriber = tracing_subscriber::registry().with(TestSubscriberLayer { sender }).with(debug_trace_filter);
+
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::debug!("ignored");
+
+            reload_handle.set_minimum_level(LevelFilter::DEBUG);
+            assert_eq!(reload_handle.minimum_level(), LevelFilter::DEBUG);
+            tracing::debug!("emitted");
+        });
+
+        let events = receiver.try_iter().filter(|m| m.starts_with("event|")).count();
+        assert_eq!(events, 1);
+    }
+
+    #[tokio::test]
+    async fn test_unmatched_operation_name() {
+        let spans = Arc::new(Mutex::new(Vec::new()));
+        let tracer_provider = opentelemetry_sdk::trace::TracerProvider::builder().with_span_processor(RecordingProcessor { spans: spans.clone() }).build();
+
+        let (i, telemetry): (AppInsightsComplete<(), ()>, _) = AppInsights::from_tracer_provider(tracer_provider).build_layer().unwrap();
+        let _guard = tracing::subscriber::set_default(tracing_subscriber::registry().with(telemetry));
+
+        let mut app: Router<()> = Router::new().route("/", get(|| async { "ok" })).layer(i.layer());
+
+        for uri in ["/missing/1", "/missing/2"] {
+            let request = Request::builder().uri(uri).body(Body::empty()).unwrap();
+            let response = <axum::Router as tower::ServiceExt<Request<Body>>>::ready(&mut app).await.unwrap().call(request).await.unwrap();
+            assert_eq!(response.status(), 404);
+        }
+
+        let spans = spans.lock().unwrap();
+        assert_eq!(spans.len(), 2);
+
+        assert!(spans[0].attributes.contains(&KeyValue::new("http.route", "<unmatched>")));
+        assert!(spans[1].attributes.contains(&KeyValue::new("http.route", "<unmatched>")));
+        assert!(spans[0].attributes.contains(&KeyValue::new("url.path", "/missing/1")));
+        assert!(spans[1].attributes.contains(&KeyValue::new("url.path", "/missing/2")));
+    }
+
+    #[test]
+    fn test_global_dimensions_on_events() {
+        let spans = Arc::new(Mutex::new(Vec::new()));
+        let tracer_provider = opentelemetry_sdk::trace::TracerProvider::builder().with_span_processor(RecordingProcessor { spans: spans.clone() }).build();
+
+        let (_i, telemetry) = AppInsights::from_tracer_provider(tracer_provider)
+            .with_global_dimensions(HashMap::from([("cluster".to_owned(), "west-1".to_owned())]))
+            .build_layer()
+            .unwrap();
+
+        tracing::subscriber::with_default(tracing_subscriber::registry().with(telemetry), || {
+            events::track_event("order_placed", [("order_id", "42")]);
+            events::track_availability("storage_probe", Duration::from_millis(120), true);
+        });
+
+        let spans = spans.lock().unwrap();
+        let events = spans.iter().flat_map(|span| span.events.iter()).collect::<Vec<_>>();
 
-    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
-        self.inner.poll_ready(cx)
+        assert_eq!(events.len(), 2);
+
+        for event in events {
+            assert!(event.attributes.contains(&KeyValue::new("cluster", "west-1")));
+        }
     }
 
-    fn call(&mut self, request: Request<Body>) -> Self::Future {
-        if self.is_noop {
-            return Box::pin(self.inner.call(request));
+    #[test]
+    fn test_resource_attributes_on_spans() {
+        let spans = Arc::new(Mutex::new(Vec::new()));
+        let tracer_provider = opentelemetry_sdk::trace::TracerProvider::builder().with_span_processor(RecordingProcessor { spans: spans.clone() }).build();
+
+        let (_i, telemetry) = AppInsights::from_tracer_provider(tracer_provider)
+            .with_global_dimensions(HashMap::from([("cluster".to_owned(), "west-1".to_owned())]))
+            .with_service_version("1.2.3")
+            .with_resource_attributes([KeyValue::new("deployment.environment", "production")])
+            .build_layer()
+            .unwrap();
+
+        tracing::subscriber::with_default(tracing_subscriber::registry().with(telemetry), || {
+            tracing::info_span!("request").in_scope(|| tracing::info_span!("child").in_scope(|| {}));
+        });
+
+        // The resource of the given provider cannot be changed, so every span carries the attributes instead.
+        let spans = spans.lock().unwrap();
+        assert_eq!(spans.len(), 2);
+
+        for span in spans.iter() {
+            assert!(span.attributes.contains(&KeyValue::new("cluster", "west-1")), "{}", span.name);
+            assert!(span.attributes.contains(&KeyValue::new("service.version", "1.2.3")), "{}", span.name);
+            assert!(span.attributes.contains(&KeyValue::new("deployment.environment", "production")), "{}", span.name);
+            assert!(span.attributes.contains(&KeyValue::new("axum_insights.version", env!("CARGO_PKG_VERSION"))), "{}", span.name);
         }
+    }
 
-        // Get all of the basic request information.
-        let method = request.method().to_string();
-        let uri = request.uri().to_string();
-        let client_ip = request.headers().get("x-forwarded-for").and_then(|v| v.to_str().ok()).unwrap_or("unknown").to_string();
-        let client_ip = client_ip.split(',').next().unwrap_or("unknown");
+    #[test]
+    fn test_build_info_on_resource() {
+        let spans = Arc::new(Mutex::new(Vec::new()));
+        let tracer_provider = opentelemetry_sdk::trace::TracerProvider::builder().with_span_processor(RecordingProcessor { spans: spans.clone() }).build();
 
-        // Spit the request into parts, and extract the route, and any extra fields.
-        let (mut parts, body) = request.into_parts();
-        let route = futures::executor::block_on(parts.extract::<MatchedPath>())
-            .map(|m| m.as_str().to_owned())
-            .unwrap_or_else(|_| "unknown".to_owned());
-        let extra_fields = self.field_mapper.as_ref().map(|f| f(&parts)).unwrap_or_default();
+        let (_i, telemetry) = AppInsights::from_tracer_provider(tracer_provider)
+            .with_build_info(BuildInfo { version: "1.2.3".to_owned(), git_sha: Some("abc123".to_owned()), build_timestamp: Some("1700000000".to_owned()) })
+            .build_layer()
+            .unwrap();
 
-        // Put the request back together.
-        let request = Request::from_parts(parts, body);
+        tracing::subscriber::with_default(tracing_subscriber::registry().with(telemetry), || {
+            tracing::info_span!("request").in_scope(|| {});
+        });
 
-        // Create the span for the request, and leave empty fields for the response records.
-        let span = tracing::info_span!(
-            "request",
-            otel.kind = "server",
-            http.request.method = method.as_str(),
-            url.full = uri.as_str(),
-            client.address = client_ip,
-            http.route = route.as_str(),
-            http.response.status_code = tracing::field::Empty,
-            otel.status_code = tracing::field::Empty,
-            otel.status_message = tracing::field::Empty,
-            extra_fields = serde_json::to_string_pretty(&extra_fields).unwrap()
-        );
+        // The build information is part of the resource (which is stamped onto the spans with an existing tracer provider).
+        let spans = spans.lock().unwrap();
+        assert!(spans[0].attributes.contains(&KeyValue::new("service.version", "1.2.3")));
+        assert!(spans[0].attributes.contains(&KeyValue::new("build.git_sha", "abc123")));
+        assert!(spans[0].attributes.contains(&KeyValue::new("build.timestamp", "1700000000")));
+    }
 
-        // Clone the panic mapper so that it can be used in the future.
-        let panic_mapper = self.panic_mapper.clone();
-        let success_filter = self.success_filter.clone();
+    #[test]
+    fn test_error_biased_processor() {
+        let spans = Arc::new(Mutex::new(Vec::new()));
+        let processor = ErrorBiasedProcessor::new(RecordingProcessor { spans: spans.clone() });
+
+        let span = |trace: u8, name: &'static str, attributes: Vec<KeyValue>| {
+            let mut span = connectivity_check_span();
+            span.span_context = SpanContext::new(TraceId::from_bytes([trace; 16]), SpanId::from_bytes([1; 8]), TraceFlags::default(), false, Default::default());
+            span.name = name.into();
+            span.attributes = attributes;
+            span
+        };
+        let child = || vec![KeyValue::new(TENTATIVE_ATTRIBUTE, true)];
+        let request = |retained: bool| vec![KeyValue::new("sampling.error_biased", true), KeyValue::new("sampling.retained", retained)];
+
+        // A certain span is forwarded right away, and so is a marked span that was sampled after all.
+        processor.on_end(connectivity_check_span());
+        processor.on_end(SpanData { attributes: child(), ..connectivity_check_span() });
+
+        // A retained trace is forwarded with its children, including the late ones.
+        processor.on_end(span(1, "child1", child()));
+        processor.on_end(span(1, "request1", request(true)));
+        processor.on_end(span(1, "late1", child()));
+
+        // A trace that is not retained is dropped.
+        processor.on_end(span(2, "child2", child()));
+        processor.on_end(span(2, "request2", request(false)));
+        processor.on_end(span(2, "late2", child()));
+
+        assert_eq!(RecordingProcessor::names(&spans), ["axum-insights.connectivity_check", "axum-insights.connectivity_check", "child1", "request1", "late1"]);
+        assert!(processor.pending.lock().unwrap().spans.is_empty());
+
+        // The forwarded spans are sampled, and the marker does not leave the process.
+        for span in spans.lock().unwrap().iter() {
+            assert!(span.span_context.is_sampled(), "{}", span.name);
+            assert!(!span.attributes.iter().any(|kv| kv.key.as_str() == TENTATIVE_ATTRIBUTE), "{}", span.name);
+        }
+
+        // The retained spans (but not the certain ones) are marked as such, so that they are exported with an item count of one.
+        let retained = spans.lock().unwrap().iter().filter(|span| is_certain_item(span)).map(|span| span.name.to_string()).collect::<Vec<_>>();
+        assert_eq!(retained, ["child1", "request1", "late1"]);
+    }
+
+    #[test]
+    fn test_error_biased_processor_eviction() {
+        let spans = Arc::new(Mutex::new(Vec::new()));
+        let processor = ErrorBiasedProcessor::new(RecordingProcessor { spans: spans.clone() });
+
+        let span = |trace: u128, name: &'static str, attributes: Vec<KeyValue>| {
+            let mut span = connectivity_check_span();
+            span.span_context = SpanContext::new(TraceId::from_bytes(trace.to_be_bytes()), SpanId::from_bytes([1; 8]), TraceFlags::default(), false, Default::default());
+            span.name = name.into();
+            span.attributes = attributes;
+            span
+        };
+        let child = || vec![KeyValue::new(TENTATIVE_ATTRIBUTE, true)];
+        let request = |retained: bool| vec![KeyValue::new("sampling.error_biased", true), KeyValue::new("sampling.retained", retained)];
+
+        // The oldest decisions are forgotten, so the late children of the first trace become orphans.
+        for trace in 0..(MAX_PENDING_TRACES as u128 + 100) {
+            processor.on_end(span(trace, "request", request(false)));
+        }
+        processor.on_end(span(0, "orphan", child()));
+
+        {
+            let pending = processor.pending.lock().unwrap();
+            assert_eq!(pending.decisions.len(), MAX_PENDING_TRACES);
+            assert_eq!(pending.decision_order.len(), MAX_PENDING_TRACES);
+            assert!(pending.spans.contains_key(&TraceId::from_bytes(0u128.to_be_bytes())));
+        }
+
+        // Filling the buffer evicts the orphan, rather than dropping the spans of newer traces.
+        let first = 1_000_000;
+        for trace in first..(first + MAX_PENDING_TRACES as u128) {
+            processor.on_end(span(trace, "child", child()));
+        }
+
+        {
+            let pending = processor.pending.lock().unwrap();
+            assert_eq!(pending.spans.len(), MAX_PENDING_TRACES);
+            assert!(!pending.spans.contains_key(&TraceId::from_bytes(0u128.to_be_bytes())));
+        }
+
+        // The newest trace is still buffered, so it is forwarded when it is retained.
+        let last = first + MAX_PENDING_TRACES as u128 - 1;
+        processor.on_end(span(last, "request", request(true)));
+
+        assert_eq!(RecordingProcessor::names(&spans), ["child", "request"]);
+        assert_eq!(processor.pending.lock().unwrap().spans.len(), MAX_PENDING_TRACES - 1);
+    }
+
+    #[tokio::test]
+    async fn test_error_biased_sampling() {
+        let spans = Arc::new(Mutex::new(Vec::new()));
+        let tracer_provider = opentelemetry_sdk::trace::TracerProvider::builder()
+            .with_config(Config::default().with_sampler(RequestSampler { inner: Box::new(Sampler::ParentBased(Box::new(Sampler::AlwaysOff))) }))
+            .with_span_processor(ErrorBiasedProcessor::new(RecordingProcessor { spans: spans.clone() }))
+            .build();
+
+        // The error-biased processor is only built into the Application Insights pipeline, so it is switched on by hand here.
+        let (mut i, telemetry): (AppInsightsComplete<(), ()>, _) = AppInsights::from_tracer_provider(tracer_provider)
+            .with_trace_context_injection(true)
+            .with_request_sampler(|parts| if parts.headers.contains_key("x-force") { SamplingDecision::Sample } else { SamplingDecision::Default })
+            .build_layer()
+            .unwrap();
+        i.should_bias_errors = true;
+        let _guard = tracing::subscriber::set_default(tracing_subscriber::registry().with(telemetry));
+
+        let mut app: Router<()> = Router::new()
+            .route("/ok", get(|| async { tracing::info_span!("child").in_scope(|| "ok") }))
+            .route("/fail", get(|| async { tracing::info_span!("child").in_scope(|| (StatusCode::INTERNAL_SERVER_ERROR, "error")) }))
+            .layer(i.layer());
+
+        // Every request continues a (new) remote trace, whose trace state carries an untrusted entry of this crate.
+        let mut trace = 0u8;
+        let mut send = |uri: &str, flags: &str, is_forced: bool| {
+            trace += 1;
+            let mut request = Request::builder()
+                .uri(uri)
+                .header("traceparent", format!("00-0af7651916cd43dd8448eb211c8031{trace:02x}-b7ad6b7169203331-{flags}"))
+                .header("tracestate", "axum-insights=tentative,congo=t61rcWkgMzE");
+            if is_forced {
+                request = request.header("x-force", "1");
+            }
+
+            let response = futures::executor::block_on(async {
+                <axum::Router as tower::ServiceExt<Request<Body>>>::ready(&mut app).await.unwrap().call(request.body(Body::empty()).unwrap()).await.unwrap()
+            });
+            let names = std::mem::take(&mut *spans.lock().unwrap());
+
+            (response.headers()["traceparent"].to_str().unwrap().to_owned(), names)
+        };
+
+        // An undecided request does not claim to be sampled downstream, and it is dropped unless it fails.
+        let (traceparent, exported) = send("/ok", "00", false);
+        assert!(traceparent.ends_with("-00"), "{traceparent}");
+        assert!(exported.is_empty());
+
+        let (traceparent, exported) = send("/fail", "00", false);
+        assert!(traceparent.ends_with("-00"), "{traceparent}");
+        assert_eq!(exported.iter().map(|s| s.name.as_ref()).collect::<Vec<_>>(), ["child", "request"]);
+
+        // A sampled remote parent, or a forced decision, is exported right away, whatever the inbound trace state says.
+        for (flags, is_forced) in [("01", false), ("00", true)] {
+            let (traceparent, exported) = send("/ok", flags, is_forced);
+            assert!(traceparent.ends_with("-01"), "{traceparent}");
+            assert_eq!(exported.iter().map(|s| s.name.as_ref()).collect::<Vec<_>>(), ["child", "request"]);
+
+            // The whole forced trace counts once, while a sampled trace stands for its sample.
+            assert!(exported.iter().all(|span| is_certain_item(span) == is_forced));
+        }
+
+        // The marker never leaves the process.
+        let (_, exported) = send("/fail", "00", false);
+        for span in exported.iter() {
+            assert!(span.span_context.is_sampled());
+            assert_eq!(span.span_context.trace_state().get(TRACE_STATE_KEY), None);
+            assert_eq!(span.span_context.trace_state().get("congo"), Some("t61rcWkgMzE"));
+            assert!(!span.attributes.iter().any(|kv| kv.key.as_str() == TENTATIVE_ATTRIBUTE));
+        }
+    }
+
+    #[derive(Debug, Default)]
+    struct RecordingExporter {
+        spans: Arc<Mutex<Vec<SpanData>>>,
+    }
+
+    impl SpanExporter for RecordingExporter {
+        fn export(&mut self, batch: Vec<SpanData>) -> BoxFuture<'static, opentelemetry_sdk::export::trace::ExportResult> {
+            self.spans.lock().unwrap().extend(batch);
+
+            Box::pin(async { Ok(()) })
+        }
+    }
+
+    #[test]
+    fn test_item_count_exporter() {
+        let sampled = Arc::new(Mutex::new(Vec::new()));
+        let certain = Arc::new(Mutex::new(Vec::new()));
+        let mut exporter = ItemCountExporter { sampled: RecordingExporter { spans: sampled.clone() }, certain: RecordingExporter { spans: certain.clone() } };
+
+        let span = |name: &'static str, attributes: Vec<KeyValue>| SpanData { name: name.into(), attributes, ..connectivity_check_span() };
+        let batch = vec![
+            span("plain", Vec::new()),
+            span("retained", vec![KeyValue::new("sampling.retained", true)]),
+            span("decided", vec![KeyValue::new("sampling.retained", false)]),
+            span("forced", vec![KeyValue::new("sampling.priority", 1)]),
+            span("dropped", vec![KeyValue::new("sampling.priority", 0)]),
+        ];
+
+        futures::executor::block_on(exporter.export(batch)).unwrap();
+
+        assert_eq!(RecordingProcessor::names(&sampled), ["plain", "decided", "dropped"]);
+        assert_eq!(RecordingProcessor::names(&certain), ["retained", "forced"]);
+    }
+
+    #[tokio::test]
+    async fn test_live_metrics_processor() {
+        let connection_string = "InstrumentationKey=00000000-0000-0000-0000-000000000000;IngestionEndpoint=https://localhost/";
+        let client = TestClient { status: 200, ..Default::default() };
+        let processor = LiveMetricsProcessor::new(connection_string, client.clone(), Resource::empty(), Tokio).unwrap();
+
+        // The span is replayed into the live metrics pipeline, but it is not exported again (the batch processor blocks on the runtime,
+        // so it is flushed from another thread).
+        processor.on_end(connectivity_check_span());
+
+        let result = tokio::task::spawn_blocking(move || processor.tracer_provider.force_flush().into_iter().all(|r| r.is_ok()) && processor.shutdown().is_ok());
+        assert!(result.await.unwrap());
+        assert!(!client.uris.lock().unwrap().iter().any(|uri| uri.contains("/v2/track")));
+    }
+
+    #[test]
+    fn test_debug_trace() {
+        let header = DebugTraceHeader { name: http::HeaderName::from_static("x-debug-trace"), secret: Some("s3cr3t".to_owned()) };
+        let mut headers = http::HeaderMap::new();
+
+        assert!(!header.matches(&headers));
+        headers.insert("x-debug-trace", "1".parse().unwrap());
+        assert!(!header.matches(&headers));
+        headers.insert("x-debug-trace", "s3cr3T".parse().unwrap());
+        assert!(!header.matches(&headers));
+        headers.insert("x-debug-trace", "s3cr3t".parse().unwrap());
+        assert!(header.matches(&headers));
+
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let subscriber = tracing_subscriber::registry()
+            .with(TestSubscriberLayer { sender })
+            .with(DebugTraceFilter { minimum_level: Arc::new(RwLock::new(LevelFilter::INFO)), allow_debug_traces: true });
+
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::debug!("outside");
+            tracing::info_span!("request", debug_trace = Some(true)).in_scope(|| tracing::debug!("inside"));
+            tracing::info_span!("request", debug_trace = None::<bool>).in_scope(|| tracing::debug!("ignored"));
+        });
+
+        let events = receiver.try_iter().filter(|m| m.starts_with("event|")).count();
+        assert_eq!(events, 1);
+    }
+
+    /// A body with an exact size hint, since the bodies that are built from frames do not have one.
+    struct SizedBody<B> {
+        inner: B,
+        size: u64,
+    }
+
+    impl<B: http_body::Body + Unpin> http_body::Body for SizedBody<B> {
+        type Data = B::Data;
+        type Error = B::Error;
+
+        fn poll_frame(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<http_body::Frame<Self::Data>, Self::Error>>> {
+            Pin::new(&mut self.inner).poll_frame(cx)
+        }
+
+        fn size_hint(&self) -> http_body::SizeHint {
+            http_body::SizeHint::with_exact(self.size)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_capture_error_body() {
+        // A small body is buffered completely.
+        let (captured, is_complete, body) = capture_error_body(Body::from("not found"), 16).await;
+        assert_eq!((&captured[..], is_complete), (&b"not found"[..], true));
+        assert_eq!(&body.collect().await.unwrap().to_bytes()[..], b"not found");
+
+        // A large body is only buffered up to the limit, but it is sent on completely.
+        let (captured, is_complete, body) = capture_error_body(Body::from("a".repeat(64)), 16).await;
+        assert_eq!((captured.len(), is_complete), (16, false));
+        assert_eq!(body.collect().await.unwrap().to_bytes().len(), 64);
+
+        // A streaming body is not buffered at all.
+        let stream = futures::stream::iter([Ok::<_, std::io::Error>("data: 1\n\n"), Ok("data: 2\n\n")]);
+        let (captured, is_complete, body) = capture_error_body(Body::from_stream(stream), 16).await;
+        assert_eq!((captured.len(), is_complete), (0, false));
+        assert_eq!(&body.collect().await.unwrap().to_bytes()[..], b"data: 1\n\ndata: 2\n\n");
+
+        // A single frame that is larger than the limit is only captured up to the limit, and the trailers are sent on.
+        let trailers = http::HeaderMap::from_iter([(http::HeaderName::from_static("grpc-status"), http::HeaderValue::from_static("13"))]);
+        let frames = vec![Ok::<_, std::convert::Infallible>(http_body::Frame::data(Bytes::from("a".repeat(64)))), Ok(http_body::Frame::trailers(trailers.clone()))];
+        let body = http_body_util::StreamBody::new(futures::stream::iter(frames));
+        let body = Body::new(SizedBody { inner: body, size: 64 });
+        let (captured, is_complete, body) = capture_error_body(body, 16).await;
+        assert_eq!((captured.len(), is_complete), (16, false));
+
+        let collected = body.collect().await.unwrap();
+        assert_eq!(collected.trailers(), Some(&trailers));
+        assert_eq!(collected.to_bytes().len(), 64);
+
+        // An error is sent on, rather than ending the body early.
+        let frames = vec![Ok(http_body::Frame::data(Bytes::from("a".repeat(8)))), Err(std::io::Error::other("reset"))];
+        let body = http_body_util::StreamBody::new(futures::stream::iter(frames));
+        let body = Body::new(SizedBody { inner: body, size: 64 });
+        let (captured, is_complete, body) = capture_error_body(body, 16).await;
+        assert_eq!((captured.len(), is_complete), (8, false));
+        assert!(body.collect().await.is_err());
+    }
+
+    #[test]
+    fn test_synthesize_exception_message() {
+        assert_eq!(synthesize_exception_message(StatusCode::UNAUTHORIZED, b""), "401 Unauthorized");
+        assert_eq!(synthesize_exception_message(StatusCode::PAYLOAD_TOO_LARGE, b"length limit exceeded\n"), "413 Payload Too Large: length limit exceeded");
+        assert_eq!(synthesize_exception_message(StatusCode::BAD_REQUEST, &[b'a'; 1024]).len(), "400 Bad Request: ".len() + MAX_EXCEPTION_BODY_SNIPPET_SIZE);
+    }
+
+    #[test]
+    fn test_trace_context_token() {
+        let tracer = opentelemetry::trace::TracerProvider::tracer(&opentelemetry_sdk::trace::TracerProvider::builder().build(), "test");
+        let subscriber = tracing_subscriber::registry().with(tracing_opentelemetry::layer().with_tracer(tracer));
+
+        tracing::subscriber::with_default(subscriber, || {
+            assert!(TraceContextToken::current().is_none());
+
+            let token = tracing::info_span!("request").in_scope(|| TraceContextToken::current().unwrap());
+            let parsed = token.to_string().parse::<TraceContextToken>().unwrap();
+            assert_eq!(parsed, token);
+
+            let callback = tracing::info_span!("callback");
+            parsed.attach(&callback);
+            assert_eq!(callback.context().span().span_context().trace_id(), token.trace_id);
+        });
+
+        assert!("00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01".parse::<TraceContextToken>().is_ok());
+        assert!("00-00000000000000000000000000000000-b7ad6b7169203331-01".parse::<TraceContextToken>().is_err());
+        assert!("01-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01".parse::<TraceContextToken>().is_err());
+        assert!("garbage".parse::<TraceContextToken>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_client_correlation_route() {
+        let mut app: Router<()> = Router::new().route("/_correlation", client_correlation_route(Some("abc".to_owned())));
+
+        let request = Request::builder().uri("/_correlation").body(Body::empty()).unwrap();
+        let response = <axum::Router as tower::ServiceExt<Request<Body>>>::ready(&mut app).await.unwrap().call(request).await.unwrap();
+        assert_eq!(response.headers().get("request-context").unwrap(), "appId=cid-v1:abc");
+
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json, serde_json::json!({ "appId": "cid-v1:abc", "traceparent": null }));
+    }
+
+    #[test]
+    fn test_extract_remote_context() {
+        let mut headers = http::HeaderMap::new();
+        assert!(extract_remote_context(&headers).is_none());
+
+        headers.insert("request-id", "|0af7651916cd43dd8448eb211c80319c.b7ad6b7169203331.".parse().unwrap());
+        let context = extract_remote_context(&headers).unwrap();
+        assert_eq!(context.trace_id().to_string(), "0af7651916cd43dd8448eb211c80319c");
+        assert_eq!(context.span_id().to_string(), "b7ad6b7169203331");
+        assert!(context.is_remote());
+
+        headers.insert("traceparent", "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-00".parse().unwrap());
+        headers.insert("tracestate", "congo=t61rcWkgMzE".parse().unwrap());
+        let context = extract_remote_context(&headers).unwrap();
+        assert_eq!(context.trace_id().to_string(), "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(context.span_id().to_string(), "00f067aa0ba902b7");
+        assert!(!context.is_sampled());
+        assert_eq!(context.trace_state().get("congo"), Some("t61rcWkgMzE"));
+
+        let mut headers = http::HeaderMap::new();
+        headers.insert("request-id", "|legacy-root.1.".parse().unwrap());
+        assert!(extract_remote_context(&headers).is_none());
+    }
+
+    #[test]
+    fn test_resolve_sampler() {
+        let (sampler, sample_rate) = resolve_sampler(Box::new(Sampler::AlwaysOn), 1.0, None);
+        assert_eq!(format!("{:?}", sampler), "AlwaysOn");
+        assert_eq!(sample_rate, 1.0);
+
+        let (sampler, sample_rate) = resolve_sampler(Box::new(Sampler::AlwaysOn), 0.25, None);
+        assert_eq!(format!("{:?}", sampler), "ParentBased(TraceIdRatioBased(0.25))");
+        assert_eq!(sample_rate, 0.25);
+
+        let (sampler, sample_rate) = resolve_sampler(Box::new(Sampler::TraceIdRatioBased(0.1)), 0.25, Some(0.1));
+        assert_eq!(format!("{:?}", sampler), "TraceIdRatioBased(0.1)");
+        assert_eq!(sample_rate, 0.1);
+    }
+
+    #[test]
+    fn test_instrument_dependency() {
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let subscriber = tracing_subscriber::registry().with(TestSubscriberLayer { sender });
+
+        tracing::subscriber::with_default(subscriber, || {
+            let result = futures::executor::block_on(deps::instrument_dependency("SQL", "db", "SELECT", async { Err::<(), _>("timeout") }));
+            assert!(result.is_err());
+        });
+
+        assert_eq!("new|dependency", receiver.recv().unwrap());
+        assert!(receiver.recv().unwrap().starts_with("record|Record { values: ValueSet { db.system: \"SQL\""));
+        assert!(receiver.recv().unwrap().starts_with("record|Record { values: ValueSet { server.address: \"db\""));
+        assert!(receiver.recv().unwrap().starts_with("record|Record { values: ValueSet { otel.status_code: \"ERROR\""));
+        assert!(receiver.recv().unwrap().starts_with("record|Record { values: ValueSet { otel.status_message: \"timeout\""));
+        assert_eq!("close", receiver.recv().unwrap());
+    }
+
+    #[test]
+    fn test_track_event() {
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let subscriber = tracing_subscriber::registry().with(TestSubscriberLayer { sender });
+
+        tracing::subscriber::with_default(subscriber, || {
+            events::track_event("order_placed", [("order_id", "42")]);
+            events::track_availability("storage_probe", Duration::from_millis(120), false);
+
+            tracing::info_span!("request").in_scope(|| events::track_event("order_placed", Vec::<(String, String)>::new()));
+        });
+
+        let messages = receiver.try_iter().collect::<Vec<_>>();
+
+        assert_eq!(messages[0], "new|custom_event");
+        assert!(messages[1].starts_with("event|"));
+        assert_eq!(messages[3], "new|availability");
+        assert!(messages[4].starts_with("event|"));
+
+        // Within a span, the event is part of that span.
+        assert_eq!(messages[6..], ["new|request".to_owned(), messages[7].clone(), "close".to_owned()]);
+        assert!(messages[7].starts_with("event|"));
+    }
+
+    #[test]
+    fn test_custom_event_properties() {
+        let spans = Arc::new(Mutex::new(Vec::new()));
+        let tracer_provider = opentelemetry_sdk::trace::TracerProvider::builder().with_span_processor(RecordingProcessor { spans: spans.clone() }).build();
+
+        let (_i, telemetry) = AppInsights::from_tracer_provider(tracer_provider).build_layer().unwrap();
+
+        tracing::subscriber::with_default(tracing_subscriber::registry().with(telemetry), || {
+            events::track_event("order_placed", [("order_id", "42"), ("currency", "USD")]);
+        });
+
+        // Outside of a span, the event gets its own root span.
+        assert_eq!(RecordingProcessor::names(&spans), vec!["custom_event"]);
+
+        let spans = spans.lock().unwrap();
+
+        let event = &spans[0].events.events[0];
+        assert!(event.attributes.contains(&KeyValue::new("ai.customEvent.name", "order_placed")));
+        assert!(event.attributes.contains(&KeyValue::new("order_id", "42")));
+        assert!(event.attributes.contains(&KeyValue::new("currency", "USD")));
+        assert!(!event.attributes.iter().any(|kv| kv.key.as_str() == CUSTOM_EVENT_PROPERTIES_FIELD));
+    }
+
+    #[test]
+    fn test_job_span() {
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let subscriber = tracing_subscriber::registry().with(TestSubscriberLayer { sender });
+
+        tracing::subscriber::with_default(subscriber, || {
+            let result = futures::executor::block_on(tasks::JobSpan::consumer("orders").run(async { Err::<(), _>("timeout") }));
+            assert!(result.is_err());
+
+            let result = std::panic::catch_unwind(|| futures::executor::block_on(tasks::JobSpan::new("cleanup").run(async {
+                panic!("boom");
+                #[allow(unreachable_code)]
+                Ok::<(), String>(())
+            })));
+            assert!(result.is_err());
+        });
+
+        let messages = receiver.try_iter().collect::<Vec<_>>();
+
+        assert_eq!("new|job", messages[0]);
+        assert!(messages[1].starts_with("record|Record { values: ValueSet { otel.status_code: \"ERROR\""));
+        assert!(messages[2].starts_with("record|Record { values: ValueSet { otel.status_message: \"timeout\""));
+        assert_eq!("close", messages[3]);
+
+        // The panic is reported as an exception (by the panic hook, or by the job itself).
+        assert_eq!("new|job", messages[4]);
+        assert_eq!(messages.iter().filter(|m| m.starts_with("event|exception")).count(), 1);
+        assert!(messages.iter().any(|m| m.contains("A panic occurred: Some(\\\"boom\\\")")));
+        assert_eq!("close", messages[messages.len() - 1]);
+    }
+
+    #[test]
+    fn test_shared_propagator() {
+        let propagator = SharedPropagator(Arc::new(TraceContextPropagator::new()));
+        let span_context = SpanContext::new(TraceId::from_bytes([1; 16]), SpanId::from_bytes([2; 8]), TraceFlags::SAMPLED, true, Default::default());
+
+        let mut headers = http::HeaderMap::new();
+        propagator.inject_context(&opentelemetry::Context::new().with_remote_span_context(span_context.clone()), &mut HeaderInjector(&mut headers));
+        assert_eq!(headers.get("traceparent").unwrap(), "00-01010101010101010101010101010101-0202020202020202-01");
+
+        let context = propagator.extract_with_context(&opentelemetry::Context::new(), &HeaderExtractor(&headers));
+        assert_eq!(context.span().span_context(), &span_context);
+    }
+
+    #[test]
+    fn test_global_propagator() {
+        let fields = || opentelemetry::global::get_text_map_propagator(|propagator| propagator.fields().map(str::to_owned).collect::<Vec<_>>());
+        let tracer_provider = || opentelemetry_sdk::trace::TracerProvider::builder().build();
+
+        // The global propagator of the application is left alone, unless a propagator is given.
+        opentelemetry::global::set_text_map_propagator(BaggagePropagator::new());
+
+        let _ = AppInsights::from_tracer_provider(tracer_provider()).build_layer::<Registry>().unwrap();
+        assert_eq!(fields(), ["baggage"]);
+
+        let _ = AppInsights::from_tracer_provider(tracer_provider()).with_propagator(TraceContextPropagator::new()).build_layer::<Registry>().unwrap();
+        assert_eq!(fields(), ["traceparent", "tracestate"]);
+    }
+
+    #[tokio::test]
+    async fn test_route_tags() {
+        let spans = Arc::new(Mutex::new(Vec::new()));
+        let tracer_provider = opentelemetry_sdk::trace::TracerProvider::builder().with_span_processor(RecordingProcessor { spans: spans.clone() }).build();
+
+        let (i, telemetry): (AppInsightsComplete<(), ()>, _) = AppInsights::from_tracer_provider(tracer_provider).build_layer().unwrap();
+        let _guard = tracing::subscriber::set_default(tracing_subscriber::registry().with(telemetry));
+
+        let mut app: Router<()> = Router::new()
+            .route("/tagged", get(|| async { "" }).layer(with_route_tags([("owner", "team-x"), ("domain", "billing")])))
+            .layer(with_route_tags([("domain", "other"), ("tier", "1")]))
+            .layer(i.layer());
+
+        let request = Request::builder().uri("/tagged").body(Body::empty()).unwrap();
+        let response = <axum::Router as tower::ServiceExt<Request<Body>>>::ready(&mut app).await.unwrap().call(request).await.unwrap();
+        let route_tags = response.extensions().get::<RouteTags>().unwrap();
+
+        assert_eq!(route_tags.0, vec![
+            ("owner".to_owned(), "team-x".to_owned()),
+            ("domain".to_owned(), "billing".to_owned()),
+            ("tier".to_owned(), "1".to_owned()),
+        ]);
+
+        // The innermost tags win, and they are all stamped onto the request span.
+        drop(response);
+
+        let spans = spans.lock().unwrap();
+        assert_eq!(spans.len(), 1);
+        assert!(spans[0].attributes.contains(&KeyValue::new("owner", "team-x")));
+        assert!(spans[0].attributes.contains(&KeyValue::new("domain", "billing")));
+        assert!(spans[0].attributes.contains(&KeyValue::new("tier", "1")));
+        assert!(!spans[0].attributes.contains(&KeyValue::new("domain", "other")));
+    }
+
+    #[tokio::test]
+    async fn test_compression_sizes() {
+        let spans = Arc::new(Mutex::new(Vec::new()));
+        let tracer_provider = opentelemetry_sdk::trace::TracerProvider::builder().with_span_processor(RecordingProcessor { spans: spans.clone() }).build();
+
+        let (i, telemetry): (AppInsightsComplete<(), ()>, _) = AppInsights::from_tracer_provider(tracer_provider).with_compression_attributes(true).build_layer().unwrap();
+        let _guard = tracing::subscriber::set_default(tracing_subscriber::registry().with(telemetry));
+
+        // Neither body has a length up front, so the sizes are counted as they stream.
+        let chunked = || Body::from_stream(futures::stream::iter([Ok::<_, std::io::Error>(Bytes::from("hello ")), Ok(Bytes::from("world"))]));
+        let handler = move |_body: String| async move { ([(http::header::CONTENT_ENCODING, "gzip")], chunked()) };
+        let mut app: Router<()> = Router::new().route("/", axum::routing::post(handler)).layer(i.layer());
+
+        let request = Request::builder().method("POST").uri("/").header(http::header::CONTENT_ENCODING, "br").body(chunked()).unwrap();
+        let response = <axum::Router as tower::ServiceExt<Request<Body>>>::ready(&mut app).await.unwrap().call(request).await.unwrap();
+        assert!(response.headers().get(http::header::CONTENT_LENGTH).is_none());
+        assert_eq!(response.into_body().collect().await.unwrap().to_bytes(), "hello world");
+
+        let spans = spans.lock().unwrap();
+        let attribute = |key: &str| spans[0].attributes.iter().find(|kv| kv.key.as_str() == key).map(|kv| kv.value.to_string());
+
+        assert_eq!(attribute("http.request.body.size").as_deref(), Some("11"));
+        assert_eq!(attribute("http.response.body.size").as_deref(), Some("11"));
+        assert_eq!(attribute("http.request.header.content-encoding").as_deref(), Some("br"));
+        assert_eq!(attribute("http.response.header.content-encoding").as_deref(), Some("gzip"));
+    }
+
+    #[tokio::test]
+    async fn test_close_on_upgrade() {
+        let spans = Arc::new(Mutex::new(Vec::new()));
+        let tracer_provider = opentelemetry_sdk::trace::TracerProvider::builder().with_span_processor(RecordingProcessor { spans: spans.clone() }).build();
+
+        let (i, telemetry): (AppInsightsComplete<(), ()>, _) = AppInsights::from_tracer_provider(tracer_provider).with_close_on_upgrade(true).build_layer().unwrap();
+        let _guard = tracing::subscriber::set_default(tracing_subscriber::registry().with(telemetry));
+
+        let mut app: Router<()> = Router::new().route("/ws", get(|| async { StatusCode::SWITCHING_PROTOCOLS })).layer(i.layer());
+
+        let request = Request::builder().uri("/ws").body(Body::empty()).unwrap();
+        let response = <axum::Router as tower::ServiceExt<Request<Body>>>::ready(&mut app).await.unwrap().call(request).await.unwrap();
+        assert_eq!(response.status(), 101);
+
+        // The span is finished (and exported) while the response, and so the connection, is still alive.
+        let spans = spans.lock().unwrap();
+        let attribute = |key: &str| spans[0].attributes.iter().rfind(|kv| kv.key.as_str() == key).map(|kv| kv.value.to_string());
+
+        assert_eq!(spans.len(), 1);
+        assert_eq!(attribute("upgraded").as_deref(), Some("true"));
+        assert_eq!(attribute("http.response.status_code").as_deref(), Some("101"));
+        assert_eq!(spans[0].status, opentelemetry::trace::Status::Ok);
+    }
+
+    #[tokio::test]
+    async fn test_heartbeat_body() {
+        let mut body = HeartbeatBody::new(Body::from("hello"), Duration::from_secs(60), Arc::new(SystemClock));
+
+        let frame = body.frame().await.unwrap().unwrap();
+
+        assert_eq!(frame.into_data().unwrap(), "hello");
+        assert_eq!(body.state.bytes, 5);
+        assert_eq!(body.state.messages, 1);
+        assert!(body.frame().await.is_none());
+    }
+
+    #[derive(Clone, Debug, Default)]
+    struct TestClient {
+        status: u16,
+        uris: Arc<Mutex<Vec<String>>>,
+    }
+
+    #[async_trait::async_trait]
+    impl HttpClient for TestClient {
+        async fn send(&self, request: Request<Vec<u8>>) -> Result<http::Response<Bytes>, Box<dyn Error + Send + Sync + 'static>> {
+            self.uris.lock().unwrap().push(request.uri().to_string());
+
+            Ok(http::Response::builder().status(self.status).body(Bytes::new()).unwrap())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_handle() {
+        let connection_string = "InstrumentationKey=00000000-0000-0000-0000-000000000000;IngestionEndpoint=https://localhost/";
+        let client = TestClient { status: 200, ..Default::default() };
+        let tracer_provider = opentelemetry_application_insights::new_pipeline_from_connection_string(connection_string).unwrap().with_client(client.clone()).build_batch(Tokio);
+        let handle = ShutdownHandle { tracer_provider: Some(tracer_provider.clone()), meter_provider: None };
+
+        // Nothing to flush or shut down.
+        assert!(ShutdownHandle::default().flush().await.is_ok());
+        assert!(ShutdownHandle::default().shutdown().await.is_ok());
+
+        // The buffered span is exported by the flush.
+        let tracer = opentelemetry::trace::TracerProvider::tracer(&tracer_provider, "test");
+        drop(opentelemetry::trace::Tracer::start(&tracer, "request"));
+        assert!(client.uris.lock().unwrap().is_empty());
+
+        handle.flush().await.unwrap();
+        assert_eq!(client.uris.lock().unwrap().len(), 1);
+
+        handle.shutdown().await.unwrap();
+    }
+
+    #[test]
+    fn test_shutdown_handle_without_runtime() {
+        let spans = Arc::new(Mutex::new(Vec::new()));
+        let tracer_provider = opentelemetry_sdk::trace::TracerProvider::builder().with_span_processor(RecordingProcessor { spans: spans.clone() }).build();
+        let handle = ShutdownHandle { tracer_provider: Some(tracer_provider), meter_provider: None };
+
+        // Neither needs a Tokio runtime.
+        futures::executor::block_on(handle.flush()).unwrap();
+        futures::executor::block_on(handle.shutdown()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_verify_connectivity() {
+        let connection_string = "InstrumentationKey=00000000-0000-0000-0000-000000000000;IngestionEndpoint=https://localhost/".to_owned();
+
+        // Nothing to verify.
+        let client = TestClient { status: 200, ..Default::default() };
+        let i = AppInsights::default().with_connection_string(None).with_service_config("namespace", "name").with_client(client.clone());
+        assert!(i.verify_connectivity().await.is_ok());
+        assert!(client.uris.lock().unwrap().is_empty());
+
+        // Accepted.
+        let i = AppInsights::default().with_connection_string(Some(connection_string.clone())).with_service_config("namespace", "name").with_client(client.clone());
+        assert!(i.verify_connectivity().await.is_ok());
+        assert_eq!(client.uris.lock().unwrap().as_slice(), ["https://localhost/v2/track"]);
+
+        // Rejected.
+        let client = TestClient { status: 400, ..Default::default() };
+        let i = AppInsights::default().with_connection_string(Some(connection_string)).with_service_config("namespace", "name").with_client(client);
+        assert!(i.verify_connectivity().await.is_err());
+    }
 
-        // Kick off the request.
-        let future = self.inner.call(request);
+    #[test]
+    fn test_host_resource_attributes() {
+        let resource = Resource::new([KeyValue::new("service.name", "name")]);
+        let keys = |attributes: Vec<KeyValue>| attributes.into_iter().map(|kv| format!("{}={}", kv.key, kv.value)).collect::<Vec<_>>();
 
-        // Create the pinned future that is the essence of this middleware after the response.
-        Box::pin(
-            async move {
-                // Get the response, and catch any panics.
-                let response = AssertUnwindSafe(future).catch_unwind().instrument(Span::current()).await;
+        assert!(host_resource_attributes(&resource, &[], None).is_empty());
+        assert_eq!(keys(host_resource_attributes(&resource, &[], Some("pod-1".to_owned()))), ["host.name=pod-1", "service.instance.id=pod-1"]);
 
-                let response = match response {
-                    Ok(response) => response,
-                    Err(e) => {
-                        // Get the payload string from the panic (usually the panic message).
-                        let payload_string = format!("{:?}", e.downcast_ref::<&str>());
+        // Explicit attributes (or ones already on the resource) win.
+        let attributes = [KeyValue::new("k8s.pod.name", "pod-2")];
+        assert_eq!(keys(host_resource_attributes(&resource, &attributes, Some("pod-1".to_owned()))), ["host.name=pod-1"]);
 
-                        // Use the given mapper, or create a default error.  For now, a feature of this library is to "panic handle".
-                        let (status, error_string) = if let Some(panic_mapper) = panic_mapper.as_ref() {
-                            let (status, error) = panic_mapper(payload_string.clone());
+        let resource = Resource::new([KeyValue::new("host.name", "vm-1"), KeyValue::new("service.instance.id", "instance-1")]);
+        assert!(host_resource_attributes(&resource, &[], Some("pod-1".to_owned())).is_empty());
+    }
 
-                            (status, serde_json::to_string(&error).unwrap())
-                        } else {
-                            (
-                                500,
-                                format!(
-                                    r#"{{
-                                    "status": 500,
-                                    "message": "A panic occurred: {}.",
-                                }}"#,
-                                    payload_string
-                                )
-                                .to_string(),
-                            )
-                        };
+    #[test]
+    fn test_resolve_service_version() {
+        let build_info = BuildInfo { version: "1.0.0".to_owned(), ..Default::default() };
+        let empty = Resource::empty();
+        let configured = Resource::new([KeyValue::new("service.version", "2.0.0")]);
 
-                        // Build a response for the error in the panic case.
-                        Ok(Response::builder()
-                            .status(status)
-                            .header("content-type", "application/json")
-                            .body(Body::from(error_string))
-                            .unwrap())
-                    }
-                }?;
+        // An explicit version wins over the trace config, which wins over the build information.
+        assert_eq!(resolve_service_version(&configured, Some("3.0.0".to_owned()), Some(&build_info)).as_deref(), Some("3.0.0"));
+        assert_eq!(resolve_service_version(&configured, None, Some(&build_info)), None);
+        assert_eq!(resolve_service_version(&empty, None, Some(&build_info)).as_deref(), Some("1.0.0"));
+        assert_eq!(resolve_service_version(&empty, None, None), None);
 
-                // Get the response status information, and determine success.
-                let status = response.status();
+        assert_eq!(service_version!(), env!("CARGO_PKG_VERSION"));
+    }
 
-                let is_success = success_filter.as_ref().map(|f| f(status)).unwrap_or_else(|| status.is_success() || status.is_redirection() || status.is_informational());
+    #[test]
+    fn test_sdk_resource_attributes() {
+        let keys = |attributes: Vec<KeyValue>| {
+            let mut keys = attributes.into_iter().map(|kv| kv.key.to_string()).collect::<Vec<_>>();
+            keys.sort();
+            keys
+        };
+        let attributes = sdk_resource_attributes(&Resource::new([KeyValue::new("service.name", "name")]), &[]);
 
-                // Get the span information about the response.
-                let (response, otel_status, otel_status_message) = if is_success {
-                    // The happy path!
-                    (response, "OK", format!(r#"{{ "status": {} }}"#, status.as_u16()))
-                } else {
-                    // Extract the error from the response, so we can get some data for the response part of the span.
+        assert!(attributes.contains(&KeyValue::new("telemetry.sdk.name", "opentelemetry")));
+        assert!(attributes.contains(&KeyValue::new("telemetry.sdk.language", "rust")));
+        assert!(attributes.contains(&KeyValue::new("axum_insights.version", env!("CARGO_PKG_VERSION"))));
+        assert!(attributes.contains(&KeyValue::new("axum.version", "0.7")));
+        assert!(keys(attributes).contains(&"telemetry.sdk.version".to_owned()));
 
-                    // Breakup the response into parts.
-                    let (parts, body) = response.into_parts();
+        // Existing attributes win.
+        let resource = Resource::new([KeyValue::new("telemetry.sdk.name", "custom")]);
+        let attributes = sdk_resource_attributes(&resource, &[KeyValue::new("axum_insights.version", "0.0.0")]);
+        assert_eq!(keys(attributes), ["axum.version", "telemetry.sdk.language", "telemetry.sdk.version"]);
+    }
 
-                    // Get the body bytes.
-                    let body_bytes = body.collect().await.unwrap_or_default().to_bytes();
+    #[test]
+    fn test_captured_attributes() {
+        let mut headers = http::HeaderMap::new();
+        headers.insert("authorization", "Bearer secret".parse().unwrap());
+        headers.append("x-tag", "a".parse().unwrap());
+        headers.append("x-tag", "b".parse().unwrap());
 
-                    // Deserialize the error.
-                    let error: E = serde_json::from_slice(&body_bytes).unwrap_or_default();
+        let names = ["authorization", "x-tag", "x-missing"].map(http::HeaderName::from_static);
+        let redactor: OptionalCaptureRedactor = Some(Arc::new(|name, value| if name == "authorization" || name == "token" { "REDACTED".to_owned() } else { value.to_owned() }));
 
-                    // Get the stringified error.
-                    let error_string = serde_json::to_string_pretty(&error).unwrap();
+        assert_eq!(captured_header_attributes("http.request.header", &headers, &names, &None), [
+            ("http.request.header.authorization".to_owned(), "Bearer secret".to_owned()),
+            ("http.request.header.x-tag".to_owned(), "a,b".to_owned()),
+        ]);
+        assert_eq!(captured_header_attributes("http.request.header", &headers, &names, &redactor), [
+            ("http.request.header.authorization".to_owned(), "REDACTED".to_owned()),
+            ("http.request.header.x-tag".to_owned(), "a,b".to_owned()),
+        ]);
 
-                    // This doesn't work because this macro prescribes the name without allowing it to be overriden.
-                    tracing::event!(
-                        name: "exception",
-                        Level::ERROR,
-                        ai.customEvent.name = "exception",
-                        "exception.type" = format!("HTTP {}", status.as_u16()),
-                        exception.message = error.message().unwrap_or_default(),
-                        exception.stacktrace = error.backtrace().unwrap_or_default()
-                    );
+        let names = ["page".to_owned(), "token".to_owned()];
 
-                    // Recreate the body.
-                    let body = Body::from(body_bytes);
+        assert!(captured_query_attributes(None, &names, &redactor).is_empty());
+        assert_eq!(captured_query_attributes(Some("page=2&token=abc&other=1&q=a%20b"), &names, &redactor), [
+            ("url.query.page".to_owned(), "2".to_owned()),
+            ("url.query.token".to_owned(), "REDACTED".to_owned()),
+        ]);
+    }
 
-                    // Recreate the response.
-                    let response = Response::from_parts(parts, body);
+    #[test]
+    fn test_rate_report() {
+        let rate_report = RateReport::default();
 
-                    (response, "ERROR", error_string)
-                };
+        for ms in 1..=100 {
+            rate_report.record("/a".to_owned(), Duration::from_millis(ms), ms % 10 != 0);
+        }
+        rate_report.record("/b".to_owned(), Duration::from_millis(5), true);
 
-                // Finish the span.
-                let span = Span::current().entered();
+        assert_eq!(rate_report.take_summaries(), [
+            RouteSummary { route: "/a".to_owned(), requests: 100, failures: 10, p50_ms: 50.0, p95_ms: 95.0 },
+            RouteSummary { route: "/b".to_owned(), requests: 1, failures: 0, p50_ms: 5.0, p95_ms: 5.0 },
+        ]);
 
-                span.record("http.response.status_code", status.as_u16());
-                span.record("otel.status_code", otel_status);
+        // The window is reset, and empty windows are not emitted.
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let subscriber = tracing_subscriber::registry().with(TestSubscriberLayer { sender });
 
-                if otel_status != "OK" {
-                    span.record("otel.status_message", otel_status_message);
-                }
+        tracing::subscriber::with_default(subscriber, || {
+            rate_report.emit(Duration::from_secs(60));
+            rate_report.record("/c".to_owned(), Duration::from_millis(5), true);
+            rate_report.emit(Duration::from_secs(60));
+        });
 
-                Ok(response)
-            }
-            .instrument(span),
-        )
+        let messages = receiver.try_iter().collect::<Vec<_>>();
+        assert_eq!(messages.iter().filter(|m| *m == "new|rate_report").count(), 1);
+        assert_eq!(messages.iter().filter(|m| m.starts_with("event|")).count(), 1);
     }
-}
 
-// Tests.
+    #[test]
+    fn test_retry_detector() {
+        let clock = ManualClock::new();
+        let detector = RetryDetector::new(http::HeaderName::from_static("idempotency-key"), Duration::from_secs(60));
+        let mut headers = http::HeaderMap::new();
 
-#[cfg(test)]
-mod tests {
-    use std::sync::mpsc::Sender;
+        assert_eq!(detector.observe(&headers, clock.now()), None);
 
-    use axum::{Router, routing::get, response::IntoResponse};
-    use http::StatusCode;
-    use serde::Deserialize;
-    use tracing::{Subscriber, span};
-    use tracing_subscriber::Layer;
+        headers.insert("idempotency-key", "abc".parse().unwrap());
+        assert_eq!(detector.observe(&headers, clock.now()), None);
 
-    use super::*;
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(detector.observe(&headers, clock.now()), Some(1));
+        assert_eq!(detector.observe(&headers, clock.now()), Some(2));
 
-    #[derive(Clone, Default, Serialize, Deserialize)]
-    struct WebError {
-        status: u16,
-        message: String,
-    }
+        // Once the window has passed, the key starts over (and expired keys are pruned).
+        clock.advance(Duration::from_secs(60));
+        assert_eq!(detector.observe(&headers, clock.now()), None);
+        assert_eq!(detector.seen.lock().unwrap().keys.len(), 1);
+        assert_eq!(detector.seen.lock().unwrap().order.len(), 1);
 
-    impl AppInsightsError for WebError {
-        fn message(&self) -> Option<String> {
-            Some(self.message.clone())
-        }
+        // Overly long keys are not tracked.
+        headers.insert("idempotency-key", "x".repeat(MAX_RETRY_KEY_SIZE + 1).parse().unwrap());
+        assert_eq!(detector.observe(&headers, clock.now()), None);
+        assert_eq!(detector.observe(&headers, clock.now()), None);
 
-        fn backtrace(&self) -> Option<String> {
-            None
+        // Beyond the limit, the oldest keys are forgotten first.
+        for key in 0..MAX_RETRY_KEYS {
+            headers.insert("idempotency-key", key.to_string().parse().unwrap());
+            detector.observe(&headers, clock.now());
         }
+
+        assert_eq!(detector.seen.lock().unwrap().keys.len(), MAX_RETRY_KEYS);
+        assert!(!detector.seen.lock().unwrap().keys.contains_key("abc"));
+
+        headers.insert("idempotency-key", (MAX_RETRY_KEYS - 1).to_string().parse().unwrap());
+        assert_eq!(detector.observe(&headers, clock.now()), Some(1));
     }
 
-    impl IntoResponse for WebError {
-        fn into_response(self) -> Response {
-            let code = StatusCode::from_u16(self.status).unwrap();
-            let body = serde_json::to_string(&self).unwrap();
+    #[test]
+    fn test_resolve_connection_string() {
+        let valid = "InstrumentationKey=00000000-0000-0000-0000-000000000000;IngestionEndpoint=https://localhost/".to_owned();
 
-            (code, body).into_response()
-        }
+        // Valid (or absent) connection strings pass through.
+        let (connection_string, fallback) = resolve_connection_string(Some(valid.clone()), Some(FallbackMode::Noop)).unwrap();
+        assert_eq!(connection_string, Some(valid));
+        assert!(fallback.is_none());
+
+        let (connection_string, fallback) = resolve_connection_string(None, None).unwrap();
+        assert!(connection_string.is_none());
+        assert!(fallback.is_none());
+
+        // Invalid connection strings fail, unless there is a fallback.
+        assert!(resolve_connection_string(Some("bogus".to_owned()), None).is_err());
+
+        let (connection_string, fallback) = resolve_connection_string(Some("bogus".to_owned()), Some(FallbackMode::LocalLogOnly)).unwrap();
+        assert!(connection_string.is_none());
+        assert_eq!(fallback.unwrap().0, FallbackMode::LocalLogOnly);
     }
 
-    struct TestSubscriberLayer {
-        sender: Sender<String>,
+    #[test]
+    fn test_tracer_provider_sampling_warning() {
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let subscriber = tracing_subscriber::registry().with(TestSubscriberLayer { sender });
+
+        tracing::subscriber::with_default(subscriber, || {
+            let _ = AppInsights::from_tracer_provider(opentelemetry_sdk::trace::TracerProvider::builder().build()).build_layer::<Registry>().unwrap();
+            assert!(receiver.try_recv().is_err());
+
+            let _ = AppInsights::from_tracer_provider(opentelemetry_sdk::trace::TracerProvider::builder().build())
+                .with_request_sampler(|_| SamplingDecision::Sample)
+                .build_layer::<Registry>()
+                .unwrap();
+            assert!(receiver.try_recv().unwrap().starts_with("event|"));
+
+            // The exporter settings are ignored as well.
+            let _ = AppInsights::from_tracer_provider(opentelemetry_sdk::trace::TracerProvider::builder().build())
+                .with_sample_rate(0.5)
+                .with_metrics(true)
+                .build_layer::<Registry>()
+                .unwrap();
+            assert!(receiver.try_recv().unwrap().starts_with("event|"));
+            assert!(receiver.try_recv().is_err());
+        });
     }
 
-    impl<S> Layer<S> for TestSubscriberLayer
-    where
-        S: Subscriber
-    {
-        fn on_new_span(&self, attrs: &span::Attributes<'_>, _id: &span::Id, _ctx: tracing_subscriber::layer::Context<'_, S>) {
-            self.sender.send(format!("new|{}", attrs.metadata().name())).unwrap();
-        }
+    #[tokio::test]
+    async fn test_request_metrics() {
+        use opentelemetry::metrics::MeterProvider;
+        use opentelemetry_sdk::metrics::{data, reader::MetricReader};
 
-        fn on_event(&self, event: &tracing::Event<'_>, _ctx: tracing_subscriber::layer::Context<'_, S>) {
-            self.sender.send(format!("event|{}", event.metadata().name())).unwrap();
-        }
+        let reader = SharedReader::default();
+        let meter_provider = SdkMeterProvider::builder().with_reader(reader.clone()).build();
 
-        fn on_record(&self, _id: &span::Id, values: &span::Record<'_>, _ctx: tracing_subscriber::layer::Context<'_, S>) {
-            self.sender.send(format!("record|{:?}", values)).unwrap();
-        }
+        let (mut i, telemetry): (AppInsightsComplete<(), ()>, _) = AppInsights::from_tracer_provider(opentelemetry_sdk::trace::TracerProvider::builder().build()).build_layer().unwrap();
+        i.request_metrics = Some(Arc::new(RequestMetrics::new(&meter_provider.meter("axum-insights"))));
+        let _guard = tracing::subscriber::set_default(tracing_subscriber::registry().with(telemetry));
 
-        fn on_close(&self, _id: span::Id, _ctx: tracing_subscriber::layer::Context<'_, S>) {
-            self.sender.send("close".to_string()).unwrap();
+        let mut app: Router<()> = Router::new()
+            .route("/succeed", get(|| async { "ok" }))
+            .route("/fail", get(|| async { (StatusCode::INTERNAL_SERVER_ERROR, "error") }))
+            .layer(i.layer());
+
+        for uri in ["/succeed", "/succeed", "/fail"] {
+            let request = Request::builder().uri(uri).body(Body::empty()).unwrap();
+            <axum::Router as tower::ServiceExt<Request<Body>>>::ready(&mut app).await.unwrap().call(request).await.unwrap();
         }
+
+        let mut metrics = data::ResourceMetrics { resource: Resource::empty(), scope_metrics: Vec::new() };
+        reader.collect(&mut metrics).unwrap();
+
+        let metric = |name: &str| metrics.scope_metrics.iter().flat_map(|s| s.metrics.iter()).find(|m| m.name == name).unwrap();
+        let is_series = |attributes: &[KeyValue], route: &str, status: i64| {
+            attributes.contains(&KeyValue::new("http.route", route.to_owned()))
+                && attributes.contains(&KeyValue::new("http.request.method", "GET"))
+                && attributes.contains(&KeyValue::new("http.response.status_code", status))
+        };
+        let sum = |name: &str, route: &str, status: i64| {
+            let sum = metric(name).data.as_any().downcast_ref::<data::Sum<u64>>().unwrap();
+            sum.data_points.iter().find(|p| is_series(&p.attributes, route, status)).map(|p| p.value)
+        };
+
+        let duration = metric("http.server.request.duration").data.as_any().downcast_ref::<data::Histogram<f64>>().unwrap();
+        let count = |route: &str, status: i64| duration.data_points.iter().find(|p| is_series(&p.attributes, route, status)).map(|p| p.count);
+        assert_eq!(count("/succeed", 200), Some(2));
+        assert_eq!(count("/fail", 500), Some(1));
+
+        assert_eq!(sum("http.server.request.count", "/succeed", 200), Some(2));
+        assert_eq!(sum("http.server.request.count", "/fail", 500), Some(1));
+        assert_eq!(sum("http.server.request.failures", "/succeed", 200), None);
+        assert_eq!(sum("http.server.request.failures", "/fail", 500), Some(1));
     }
 
-    #[tokio::test]
-    async fn test_integration() {
+    #[test]
+    fn test_metrics_disabled_warning() {
         let (sender, receiver) = std::sync::mpsc::channel();
-        let subscriber = tracing_subscriber::registry().with(TestSubscriberLayer {
-            sender: sender.clone(),
+        let subscriber = tracing_subscriber::registry().with(TestSubscriberLayer { sender });
+
+        tracing::subscriber::with_default(subscriber, || {
+            let _ = AppInsights::default().with_connection_string(None).with_service_config("namespace", "name").build_layer::<Registry>().unwrap();
+            assert!(receiver.try_recv().is_err());
+
+            // Without a connection string, the request metrics have nowhere to go.
+            let (i, _) = AppInsights::default()
+                .with_connection_string(None)
+                .with_service_config("namespace", "name")
+                .with_metrics(true)
+                .build_layer::<Registry>()
+                .unwrap();
+            assert!(i.request_metrics.is_none());
+            assert!(receiver.try_recv().unwrap().starts_with("event|"));
+            assert!(receiver.try_recv().is_err());
         });
+    }
 
-        let i = AppInsights::default()
+    #[test]
+    fn test_fallback_error() {
+        let (i, _telemetry): (AppInsightsComplete<(), ()>, _) = AppInsights::default()
+            .with_connection_string(Some("bogus".to_owned()))
+            .with_service_config("namespace", "name")
+            .with_fallback_on_error(FallbackMode::Noop)
+            .build_layer::<Registry>()
+            .unwrap();
+
+        assert!(i.is_noop);
+        assert!(i.fallback_error().is_some());
+
+        let (i, _telemetry): (AppInsightsComplete<(), ()>, _) = AppInsights::default()
+            .with_connection_string(Some("bogus".to_owned()))
+            .with_service_config("namespace", "name")
+            .with_fallback_on_error(FallbackMode::LocalLogOnly)
+            .build_layer::<Registry>()
+            .unwrap();
+
+        assert!(!i.is_noop);
+        assert!(i.fallback_error().is_some());
+
+        let (i, _telemetry): (AppInsightsComplete<(), ()>, _) = AppInsights::default()
             .with_connection_string(None)
             .with_service_config("namespace", "name")
-            .with_client(reqwest::Client::new())
-            .with_sample_rate(1.0)
-            .with_minimum_level(LevelFilter::INFO)
-            .with_runtime(Tokio)
-            .with_catch_panic(true)
-            .with_subscriber(subscriber)
-            .with_field_mapper(|_| {
-                let mut map = HashMap::new();
-                map.insert("extra_field".to_owned(), "extra_value".to_owned());
-                map
-            })
-            .with_panic_mapper(|panic| {
-                (500, WebError { status: 500, message: panic })
-            })
-            .with_success_filter(|status| {
-                status.is_success() || status.is_redirection() || status.is_informational() || status == StatusCode::NOT_FOUND
-            })
-            .with_error_type::<WebError>()
-            .build_and_set_global_default()
+            .build_layer::<Registry>()
             .unwrap();
 
-        let layer = i.layer();
+        assert!(i.fallback_error().is_none());
+    }
 
-        let mut app: Router<()> = Router::new()
-            .route("/succeed1", get(|| async { Response::new(Body::empty()) }))
-            .route("/succeed2", get(|| async { (StatusCode::NOT_MODIFIED, "") }))
-            .route("/succeed3", get(|| async { (StatusCode::NOT_FOUND, "") }))
-            .route("/fail1", get(|| async { WebError { status: 429, message: "foo".to_string() } }))
-            .route("/fail2", get(|| async { panic!("panic") }))
-            .layer(layer);
+    #[test]
+    fn test_manual_clock() {
+        let clock = Arc::new(ManualClock::new());
+        let state = HeartbeatState::new(clock.clone());
+        let start = clock.now();
 
-        // Regular success.
+        assert_eq!(clock.now(), start);
 
-        let request = Request::builder().uri("/succeed1").body(Body::empty()).unwrap();
-        // This is required because there are multiple impls of `ready` for `Router`. 🙄
-        let response = <axum::Router as tower::ServiceExt<Request<Body>>>::ready(&mut app).await.unwrap().call(request).await.unwrap();
-        assert_eq!(response.status(), 200);
+        clock.advance(Duration::from_millis(1500));
+        clock.advance(Duration::from_millis(500));
 
-        assert_eq!("new|request", receiver.recv().unwrap());
-        assert!(receiver.recv().unwrap().starts_with("record|Record { values: ValueSet { http.response.status_code: 200"));
-        assert!(receiver.recv().unwrap().starts_with("record|Record { values: ValueSet { otel.status_code: \"OK\""));
-        assert_eq!("close", receiver.recv().unwrap());
+        assert_eq!(clock.now() - start, Duration::from_secs(2));
+        assert_eq!(state.clock.now().saturating_duration_since(state.started), Duration::from_secs(2));
+    }
 
-        // Redirect success.
+    #[tokio::test]
+    async fn test_clock_durations() {
+        let spans = Arc::new(Mutex::new(Vec::new()));
+        let tracer_provider = opentelemetry_sdk::trace::TracerProvider::builder().with_span_processor(RecordingProcessor { spans: spans.clone() }).build();
+        let clock = Arc::new(ManualClock::new());
 
-        let request = Request::builder().uri("/succeed2").body(Body::empty()).unwrap();
-        let response = <axum::Router as tower::ServiceExt<Request<Body>>>::ready(&mut app).await.unwrap().call(request).await.unwrap();
-        assert_eq!(response.status(), 304);
+        let (i, telemetry): (AppInsightsComplete<(), ()>, _) = AppInsights::from_tracer_provider(tracer_provider)
+            .with_connection_heartbeat(Duration::from_secs(60))
+            .with_clock(clock.clone())
+            .build_layer()
+            .unwrap();
+        let _guard = tracing::subscriber::set_default(tracing_subscriber::registry().with(telemetry));
 
-        assert_eq!("new|request", receiver.recv().unwrap());
-        assert!(receiver.recv().unwrap().starts_with("record|Record { values: ValueSet { http.response.status_code: 304"));
-        assert!(receiver.recv().unwrap().starts_with("record|Record { values: ValueSet { otel.status_code: \"OK\""));
-        assert_eq!("close", receiver.recv().unwrap());
+        let handler = {
+            let clock = clock.clone();
 
-        // Custom success.
+            move || async move {
+                tracing::info_span!("child").in_scope(|| clock.advance(Duration::from_millis(250)));
 
-        let request = Request::builder().uri("/succeed3").body(Body::empty()).unwrap();
+                // A connection that the middleware cannot see measures its heartbeats with the same clock.
+                let mut heartbeat = ConnectionHeartbeat::new(Duration::from_secs(60));
+                clock.advance(Duration::from_secs(60));
+                heartbeat.record_message(128);
+
+                "ok"
+            }
+        };
+        let mut app: Router<()> = Router::new().route("/", get(handler)).layer(i.layer());
+
+        let request = Request::builder().uri("/").body(Body::empty()).unwrap();
         let response = <axum::Router as tower::ServiceExt<Request<Body>>>::ready(&mut app).await.unwrap().call(request).await.unwrap();
-        assert_eq!(response.status(), 404);
 
-        assert_eq!("new|request", receiver.recv().unwrap());
-        assert!(receiver.recv().unwrap().starts_with("record|Record { values: ValueSet { http.response.status_code: 404"));
-        assert!(receiver.recv().unwrap().starts_with("record|Record { values: ValueSet { otel.status_code: \"OK\""));
-        assert_eq!("close", receiver.recv().unwrap());
+        // The response body emits its heartbeat as soon as it is polled after the interval has elapsed on the clock.
+        clock.advance(Duration::from_secs(60));
+        assert_eq!(response.into_body().collect().await.unwrap().to_bytes(), "ok");
 
-        // Failure.
+        let spans = spans.lock().unwrap();
+        let duration = |span: &SpanData| span.end_time.duration_since(span.start_time).unwrap();
 
-        let request = Request::builder().uri("/fail1").body(Body::empty()).unwrap();
-        let response = <axum::Router as tower::ServiceExt<Request<Body>>>::ready(&mut app).await.unwrap().call(request).await.unwrap();
-        assert_eq!(response.status(), 429);
+        let request = spans.iter().find(|s| s.span_kind == SpanKind::Server).unwrap();
+        let child = spans.iter().find(|s| s.name == "child").unwrap();
+        assert_eq!(duration(request), Duration::from_millis(60_250));
+        assert_eq!(duration(child), Duration::from_millis(250));
 
-        assert_eq!("new|request", receiver.recv().unwrap());
-        assert!(receiver.recv().unwrap().starts_with("event|exception"));
-        assert!(receiver.recv().unwrap().starts_with("record|Record { values: ValueSet { http.response.status_code: 429"));
-        assert!(receiver.recv().unwrap().starts_with("record|Record { values: ValueSet { otel.status_code: \"ERROR\""));
-        assert!(receiver.recv().unwrap().starts_with("record|Record { values: ValueSet { otel.status_message: \"{\\n  \\\"status\\\": 429,\\n  \\\"message\\\": \\\"foo\\\"\\n}\""));
-        assert_eq!("close", receiver.recv().unwrap());
+        let heartbeats = spans
+            .iter()
+            .flat_map(|s| s.events.iter())
+            .filter_map(|e| e.attributes.iter().find(|kv| kv.key.as_str() == "connection.duration_ms"))
+            .map(|kv| kv.value.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(heartbeats, vec!["60000", "60000"]);
+    }
 
-        // Panic.
+    #[test]
+    fn test_multipart_stats() {
+        let mut headers = http::HeaderMap::new();
+        headers.insert(http::header::CONTENT_TYPE, "multipart/form-data; boundary=\"XyZ\"".parse().unwrap());
 
-        let request = Request::builder().uri("/fail2").body(Body::empty()).unwrap();
-        let response = <axum::Router as tower::ServiceExt<Request<Body>>>::ready(&mut app).await.unwrap().call(request).await.unwrap();
-        assert_eq!(response.status(), 500);
+        let boundary = multipart_boundary(&headers).unwrap();
+        assert_eq!(boundary, "XyZ");
 
-        assert_eq!("new|request", receiver.recv().unwrap());
-        assert!(receiver.recv().unwrap().starts_with("event|exception"));
-        assert!(receiver.recv().unwrap().starts_with("event|exception"));
-        assert!(receiver.recv().unwrap().starts_with("record|Record { values: ValueSet { http.response.status_code: 500"));
-        assert!(receiver.recv().unwrap().starts_with("record|Record { values: ValueSet { otel.status_code: \"ERROR\""));
-        assert!(receiver.recv().unwrap().starts_with("record|Record { values: ValueSet { otel.status_message: \"{\\n  \\\"status\\\": 500,\\n  \\\"message\\\": \\\"Some(\\\\\\\"panic\\\\\\\")\\\"\\n}\""));
-        assert_eq!("close", receiver.recv().unwrap());
+        let body = "--XyZ\r\nContent-Disposition: form-data; name=\"a\"\r\n\r\nhello\r\n\
+            --XyZ\r\nContent-Disposition: form-data; name=\"b\"; filename=\"b.png\"\r\nContent-Type: image/png\r\n\r\n\x01\x02\r\n\
+            --XyZ\r\ncontent-type: image/png\r\n\r\n\x03\r\n\
+            --XyZ--\r\n";
+
+        // Feed the body in small chunks to exercise delimiters and headers that straddle chunks.
+        let mut stats = MultipartStats::new(boundary);
+        for chunk in body.as_bytes().chunks(3) {
+            stats.update(chunk);
+        }
+
+        assert_eq!(stats.bytes, body.len() as u64);
+        assert_eq!(stats.parts, 3);
+        assert_eq!(stats.content_types, vec!["image/png".to_owned()]);
+        assert!(stats.is_done);
+    }
+
+    struct CountingAllocator;
+
+    thread_local! {
+        static ALLOCATIONS: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+    }
+
+    unsafe impl std::alloc::GlobalAlloc for CountingAllocator {
+        unsafe fn alloc(&self, layout: std::alloc::Layout) -> *mut u8 {
+            let _ = ALLOCATIONS.try_with(|a| a.set(a.get() + 1));
+            std::alloc::System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: std::alloc::Layout) {
+            std::alloc::System.dealloc(ptr, layout)
+        }
+    }
+
+    #[global_allocator]
+    static GLOBAL: CountingAllocator = CountingAllocator;
+
+    struct PrebuiltService(Vec<Response>);
+
+    impl Service<Request<Body>> for PrebuiltService {
+        type Error = std::convert::Infallible;
+        type Future = std::future::Ready<Result<Response, Self::Error>>;
+        type Response = Response;
+
+        fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, _request: Request<Body>) -> Self::Future {
+            std::future::ready(Ok(self.0.pop().unwrap()))
+        }
+    }
+
+    #[test]
+    fn test_noop_zero_allocations() {
+        let i = AppInsights::default()
+            .with_connection_string(None)
+            .with_service_config("namespace", "name")
+            .with_noop(true)
+            .build_and_set_global_default()
+            .unwrap();
+
+        let count = 1000;
+        let mut requests: Vec<_> = (0..count).map(|_| Request::builder().uri("/").header("x-forwarded-for", "1.1.1.1").body(Body::empty()).unwrap()).collect();
+        let responses = (0..count).map(|_| Response::new(Body::empty())).collect();
+        let mut service = tower::Layer::layer(&i.layer(), PrebuiltService(responses));
+        let mut cx = Context::from_waker(futures::task::noop_waker_ref());
+
+        let before = ALLOCATIONS.with(|a| a.get());
+        let start = Instant::now();
+
+        for request in requests.drain(..) {
+            assert!(service.poll_ready(&mut cx).is_ready());
+
+            let mut future = service.call(request);
+
+            assert!(Pin::new(&mut future).poll(&mut cx).is_ready());
+        }
+
+        let elapsed = start.elapsed();
+        let after = ALLOCATIONS.with(|a| a.get());
+
+        assert_eq!(before, after, "the noop middleware allocated during {} requests (took {:?})", count, elapsed);
     }
 
     #[tokio::test]